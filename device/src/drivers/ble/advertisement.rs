@@ -0,0 +1,206 @@
+//! Encoding of BLE advertising and scan response data
+//!
+//! Advertising data is a sequence of structures made of a length, an AD type and its value, which
+//! must fit in a legacy advertising PDU payload of 31 bytes.
+use {core::ops::Deref, heapless::Vec};
+
+/// Maximum length of legacy advertising and scan response data.
+pub const MAX_ADVERTISEMENT_LEN: usize = 31;
+
+/// LE limited discoverable mode.
+pub const FLAG_LE_LIMITED_DISCOVERABLE: u8 = 0x01;
+/// LE general discoverable mode.
+pub const FLAG_LE_GENERAL_DISCOVERABLE: u8 = 0x02;
+/// BR/EDR not supported, set by LE only devices.
+pub const FLAG_BR_EDR_NOT_SUPPORTED: u8 = 0x04;
+
+const AD_FLAGS: u8 = 0x01;
+const AD_SERVICES_16: u8 = 0x03;
+const AD_SERVICES_128: u8 = 0x07;
+const AD_SHORT_NAME: u8 = 0x08;
+const AD_NAME: u8 = 0x09;
+const AD_TX_POWER: u8 = 0x0A;
+const AD_MANUFACTURER_DATA: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdvertisementError {
+    /// The fields do not fit in [`MAX_ADVERTISEMENT_LEN`] bytes.
+    TooLong,
+}
+
+/// Encoded advertising or scan response data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdvertisementData(Vec<u8, MAX_ADVERTISEMENT_LEN>);
+
+impl Deref for AdvertisementData {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Builder of [`AdvertisementData`], appending fields in the order they are given.
+///
+/// ```ignore
+/// let adv_data = AdvertisementBuilder::new()
+///     .flags(FLAG_LE_GENERAL_DISCOVERABLE | FLAG_BR_EDR_NOT_SUPPORTED)
+///     .services_16(&[0x181A])
+///     .name("Drogue Low Energy")
+///     .build()?;
+/// ```
+#[derive(Default)]
+pub struct AdvertisementBuilder {
+    data: Vec<u8, MAX_ADVERTISEMENT_LEN>,
+    overflow: bool,
+}
+
+impl AdvertisementBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discoverability flags, a combination of the `FLAG_*` constants.
+    pub fn flags(self, flags: u8) -> Self {
+        self.field(AD_FLAGS, &[&[flags]])
+    }
+
+    /// Complete list of 16-bit service UUIDs.
+    pub fn services_16(mut self, uuids: &[u16]) -> Self {
+        if self.start(AD_SERVICES_16, uuids.len() * 2) {
+            for uuid in uuids {
+                self.append(&uuid.to_le_bytes());
+            }
+        }
+        self
+    }
+
+    /// Complete list of 128-bit service UUIDs.
+    pub fn services_128(mut self, uuids: &[u128]) -> Self {
+        if self.start(AD_SERVICES_128, uuids.len() * 16) {
+            for uuid in uuids {
+                self.append(&uuid.to_le_bytes());
+            }
+        }
+        self
+    }
+
+    /// Complete local name.
+    pub fn name(self, name: &str) -> Self {
+        self.field(AD_NAME, &[name.as_bytes()])
+    }
+
+    /// Shortened local name, when the complete name does not fit.
+    pub fn short_name(self, name: &str) -> Self {
+        self.field(AD_SHORT_NAME, &[name.as_bytes()])
+    }
+
+    /// Manufacturer specific data, prefixed with the Bluetooth SIG company identifier.
+    pub fn manufacturer_data(self, company: u16, data: &[u8]) -> Self {
+        self.field(AD_MANUFACTURER_DATA, &[&company.to_le_bytes(), data])
+    }
+
+    /// Transmit power level in dBm.
+    pub fn tx_power(self, dbm: i8) -> Self {
+        self.field(AD_TX_POWER, &[&[dbm as u8]])
+    }
+
+    pub fn build(self) -> Result<AdvertisementData, AdvertisementError> {
+        if self.overflow {
+            Err(AdvertisementError::TooLong)
+        } else {
+            Ok(AdvertisementData(self.data))
+        }
+    }
+
+    fn field(mut self, ad_type: u8, parts: &[&[u8]]) -> Self {
+        let len = parts.iter().map(|p| p.len()).sum();
+        if self.start(ad_type, len) {
+            for part in parts {
+                self.append(part);
+            }
+        }
+        self
+    }
+
+    /// Write the header of a field with a value of `len` bytes, returning false if the field
+    /// does not fit.
+    fn start(&mut self, ad_type: u8, len: usize) -> bool {
+        if self.overflow || self.data.len() + 2 + len > MAX_ADVERTISEMENT_LEN {
+            self.overflow = true;
+            return false;
+        }
+        self.append(&[len as u8 + 1, ad_type]);
+        true
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        // Capacity is checked by start
+        let _ = self.data.extend_from_slice(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build() {
+        let data = AdvertisementBuilder::new()
+            .flags(FLAG_LE_GENERAL_DISCOVERABLE | FLAG_BR_EDR_NOT_SUPPORTED)
+            .services_16(&[0x181A, 0x180A])
+            .name("Drogue")
+            .tx_power(-4)
+            .build()
+            .unwrap();
+        #[rustfmt::skip]
+        assert_eq!(
+            &[
+                0x02, 0x01, 0x06,
+                0x05, 0x03, 0x1A, 0x18, 0x0A, 0x18,
+                0x07, 0x09, b'D', b'r', b'o', b'g', b'u', b'e',
+                0x02, 0x0A, 0xFC,
+            ],
+            &data[..]
+        );
+
+        let data = AdvertisementBuilder::new()
+            .services_128(&[0xe95d6100_251d_470a_a062_fa1922dfa9a8])
+            .manufacturer_data(0xFFFF, &[1, 2])
+            .build()
+            .unwrap();
+        assert_eq!(24, data.len());
+        assert_eq!(&[0x11, 0x07, 0xa8, 0xa9], &data[..4]);
+        assert_eq!(&[0x62, 0xa0, 0x0a, 0x47], &data[8..12]);
+        assert_eq!(&[0x00, 0x61, 0x5d, 0xe9], &data[14..18]);
+        assert_eq!(&[0x05, 0xFF, 0xFF, 0xFF, 1, 2], &data[18..]);
+    }
+
+    #[test]
+    fn test_too_long() {
+        // 3 + 28 bytes fill the advertisement exactly
+        let data = AdvertisementBuilder::new()
+            .flags(FLAG_LE_GENERAL_DISCOVERABLE)
+            .name("Drogue Low Energy Sensors!")
+            .build()
+            .unwrap();
+        assert_eq!(MAX_ADVERTISEMENT_LEN, data.len());
+
+        assert_eq!(
+            Err(AdvertisementError::TooLong),
+            AdvertisementBuilder::new()
+                .flags(FLAG_LE_GENERAL_DISCOVERABLE)
+                .name("Drogue Low Energy Sensors!!")
+                .build()
+        );
+
+        // Fields after an overflow are not added either
+        assert_eq!(
+            Err(AdvertisementError::TooLong),
+            AdvertisementBuilder::new()
+                .services_128(&[0; 2])
+                .tx_power(0)
+                .build()
+        );
+    }
+}
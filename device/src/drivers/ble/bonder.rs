@@ -0,0 +1,217 @@
+use {
+    super::security::{
+        Bond, BondStore, IoCapabilities, PairingHandler, SecurityLevel, MAX_SYS_ATTRS_LEN,
+    },
+    core::cell::RefCell,
+    embassy_sync::{
+        blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+        signal::Signal,
+    },
+    embedded_storage_async::nor_flash::AsyncNorFlash,
+    heapless::Vec,
+    nrf_softdevice::{
+        ble::{
+            gatt_server,
+            security::{self, PasskeyReply, SecurityHandler},
+            Connection, EncryptionInfo, IdentityKey, MasterId, SecurityMode,
+        },
+        raw,
+    },
+};
+
+/// Security level of the link of `connection`.
+pub fn security_level(connection: &Connection) -> SecurityLevel {
+    match connection.security_mode() {
+        SecurityMode::JustWorks => SecurityLevel::Encrypted,
+        SecurityMode::Mitm | SecurityMode::LescMitm => SecurityLevel::Authenticated,
+        _ => SecurityLevel::Open,
+    }
+}
+
+/// Security handler of the softdevice, bonding with peers and keeping their keys and system
+/// attributes in a [`BondStore`].
+///
+/// Changed bonds are written to flash by [`Bonder::persist`], which should run in its own task.
+/// The pairing method is negotiated by the softdevice from the I/O capabilities of the
+/// [`PairingHandler`], which also decides when new peers may bond.
+pub struct Bonder<H, const N: usize>
+where
+    H: PairingHandler,
+{
+    handler: H,
+    store: Mutex<CriticalSectionRawMutex, RefCell<BondStore<N>>>,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<H, const N: usize> Bonder<H, N>
+where
+    H: PairingHandler,
+{
+    /// Create a handler with bonds loaded from flash with [`BondStore::load`].
+    pub fn new(handler: H, store: BondStore<N>) -> Self {
+        Self {
+            handler,
+            store: Mutex::new(RefCell::new(store)),
+            changed: Signal::new(),
+        }
+    }
+
+    /// Forget all bonded peers.
+    pub fn clear(&self) {
+        self.update(|store| store.clear());
+    }
+
+    /// Write bonds to `flash` whenever they change.
+    pub async fn persist<F: AsyncNorFlash>(&self, flash: &mut F) -> Result<(), F::Error> {
+        loop {
+            self.changed.wait().await;
+            let mut store = self.store.lock(|s| s.borrow_mut().snapshot());
+            store.save(flash).await?;
+        }
+    }
+
+    /// Identity address of the bonded peer of `conn`, resolving private addresses with the
+    /// IRKs of the bonded peers.
+    fn identity(&self, conn: &Connection) -> Option<(u8, [u8; 6])> {
+        let addr = conn.peer_address();
+        self.store.lock(|s| {
+            s.borrow()
+                .resolve_peer(
+                    addr.address_type() as u8,
+                    &addr.bytes(),
+                    random_address_hash,
+                )
+                .map(|bond| (bond.addr_type, bond.addr))
+        })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut BondStore<N>)) {
+        let changed = self.store.lock(|s| {
+            let mut store = s.borrow_mut();
+            f(&mut store);
+            store.is_dirty()
+        });
+        if changed {
+            self.changed.signal(());
+        }
+    }
+}
+
+impl<H, const N: usize> SecurityHandler for Bonder<H, N>
+where
+    H: PairingHandler,
+{
+    fn io_capabilities(&self) -> security::IoCapabilities {
+        match self.handler.io_capabilities() {
+            IoCapabilities::None => security::IoCapabilities::None,
+            IoCapabilities::DisplayOnly => security::IoCapabilities::DisplayOnly,
+            IoCapabilities::KeyboardOnly => security::IoCapabilities::KeyboardOnly,
+            IoCapabilities::KeyboardDisplay => security::IoCapabilities::KeyboardDisplay,
+        }
+    }
+
+    fn can_bond(&self, _conn: &Connection) -> bool {
+        self.handler.can_bond()
+    }
+
+    fn display_passkey(&self, passkey: &[u8; 6]) {
+        self.handler.display_passkey(passkey);
+    }
+
+    fn enter_passkey(&self, reply: PasskeyReply) {
+        if let Err(e) = reply.reply(self.handler.enter_passkey().as_ref()) {
+            warn!("Error replying passkey: {:?}", e);
+        }
+    }
+
+    fn on_security_update(&self, _conn: &Connection, security_mode: SecurityMode) {
+        debug!("Security mode is {:?}", security_mode);
+    }
+
+    fn on_bonded(
+        &self,
+        _conn: &Connection,
+        master_id: MasterId,
+        key: EncryptionInfo,
+        peer_id: IdentityKey,
+    ) {
+        info!("Bonded with peer");
+        let bond = Bond {
+            ediv: master_id.ediv,
+            rand: master_id.rand,
+            ltk: key.ltk,
+            ltk_flags: key.flags,
+            addr_type: peer_id.addr.address_type() as u8,
+            addr: peer_id.addr.bytes(),
+            irk: peer_id.irk.as_raw().irk,
+            sys_attrs: Vec::new(),
+        };
+        self.update(|store| store.add(bond));
+    }
+
+    fn get_key(&self, _conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        self.store.lock(|s| {
+            s.borrow()
+                .find_key(master_id.ediv, &master_id.rand)
+                .map(|bond| EncryptionInfo {
+                    ltk: bond.ltk,
+                    flags: bond.ltk_flags,
+                })
+        })
+    }
+
+    fn save_sys_attrs(&self, conn: &Connection) {
+        // Only bonded peers have their system attributes kept
+        let (addr_type, addr) = match self.identity(conn) {
+            Some(identity) => identity,
+            None => return,
+        };
+        let mut buf = [0; MAX_SYS_ATTRS_LEN];
+        match gatt_server::get_sys_attrs(conn, &mut buf) {
+            Ok(len) => self.update(|store| {
+                store.set_sys_attrs(addr_type, &addr, &buf[..len]).ok();
+            }),
+            Err(e) => warn!("Error reading system attributes: {:?}", e),
+        }
+    }
+
+    fn load_sys_attrs(&self, conn: &Connection) {
+        let addr = conn.peer_address();
+        let sys_attrs = self.store.lock(|s| {
+            s.borrow()
+                .resolve_peer(
+                    addr.address_type() as u8,
+                    &addr.bytes(),
+                    random_address_hash,
+                )
+                .map(|bond| bond.sys_attrs.clone())
+        });
+        let sys_attrs = sys_attrs.as_deref().filter(|attrs| !attrs.is_empty());
+        if let Err(e) = gatt_server::set_sys_attrs(conn, sys_attrs) {
+            warn!("Error restoring system attributes: {:?}", e);
+        }
+    }
+}
+
+/// Random address hash function `ah` of the Bluetooth Core specification, using the AES block
+/// cipher of the softdevice.
+fn random_address_hash(irk: &[u8; 16], prand: &[u8; 3]) -> Option<[u8; 3]> {
+    // The block cipher takes the most significant byte first
+    let mut ecb = raw::nrf_ecb_hal_data_t {
+        key: [0; 16],
+        cleartext: [0; 16],
+        ciphertext: [0; 16],
+    };
+    for (key, irk) in ecb.key.iter_mut().zip(irk.iter().rev()) {
+        *key = *irk;
+    }
+    ecb.cleartext[13] = prand[2];
+    ecb.cleartext[14] = prand[1];
+    ecb.cleartext[15] = prand[0];
+    let ret = unsafe { raw::sd_ecb_block_encrypt(&mut ecb) };
+    if ret != raw::NRF_SUCCESS {
+        warn!("Error hashing private address: {}", ret);
+        return None;
+    }
+    Some([ecb.ciphertext[15], ecb.ciphertext[14], ecb.ciphertext[13]])
+}
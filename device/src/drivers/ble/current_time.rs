@@ -0,0 +1,322 @@
+//! Current time of the Current Time Service
+//!
+//! The Current Time characteristic holds the exact time as a calendar date and time, which is
+//! converted to and from seconds since the Unix epoch to keep the device clock.
+use embassy_time::Instant;
+
+/// Length of an encoded Current Time characteristic.
+pub const CURRENT_TIME_LEN: usize = 10;
+
+/// Adjust reason flag set when the time was set manually.
+pub const ADJUST_MANUAL: u8 = 0x01;
+/// Adjust reason flag set when the time was updated from an external reference.
+pub const ADJUST_EXTERNAL_REFERENCE: u8 = 0x02;
+/// Adjust reason flag set when the time zone changed.
+pub const ADJUST_TIME_ZONE: u8 = 0x04;
+/// Adjust reason flag set when the daylight saving time changed.
+pub const ADJUST_DST: u8 = 0x08;
+
+const SECS_PER_DAY: u64 = 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TimeError {
+    /// The data is not the length of the characteristic.
+    InvalidLength,
+    /// A field is out of its range.
+    InvalidValue,
+}
+
+/// Exact time of the Current Time characteristic. Fields of the date and time are 0 when unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CurrentTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    /// Day of the week from 1 for Monday to 7 for Sunday.
+    pub day_of_week: u8,
+    /// Fractions of a second in 1/256 s.
+    pub fractions256: u8,
+    /// Reasons of the last adjustment, a combination of the `ADJUST_*` constants.
+    pub adjust_reason: u8,
+}
+
+impl CurrentTime {
+    /// The time `secs` seconds and `fractions256` 1/256 seconds after the Unix epoch.
+    pub fn from_unix(secs: u64, fractions256: u8) -> Self {
+        let days = secs / SECS_PER_DAY;
+        let time = secs % SECS_PER_DAY;
+        let (year, month, day) = civil_from_days(days as i64);
+        Self {
+            year: year as u16,
+            month,
+            day,
+            hours: (time / 3600) as u8,
+            minutes: (time / 60 % 60) as u8,
+            seconds: (time % 60) as u8,
+            // 1970-01-01 was a Thursday
+            day_of_week: ((days + 3) % 7 + 1) as u8,
+            fractions256,
+            adjust_reason: 0,
+        }
+    }
+
+    /// Seconds since the Unix epoch, if the date is known, valid and not before the epoch.
+    pub fn to_unix(&self) -> Option<u64> {
+        if self.year < 1970 || self.month == 0 || self.day == 0 {
+            return None;
+        }
+        if self.month > 12 || self.day > days_in_month(self.year, self.month) {
+            return None;
+        }
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        let time = self.hours as u64 * 3600 + self.minutes as u64 * 60 + self.seconds as u64;
+        Some(days as u64 * SECS_PER_DAY + time)
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, TimeError> {
+        let data: &[u8; CURRENT_TIME_LEN] =
+            data.try_into().map_err(|_| TimeError::InvalidLength)?;
+        let time = Self {
+            year: u16::from_le_bytes([data[0], data[1]]),
+            month: data[2],
+            day: data[3],
+            hours: data[4],
+            minutes: data[5],
+            seconds: data[6],
+            day_of_week: data[7],
+            fractions256: data[8],
+            adjust_reason: data[9],
+        };
+        let valid = (time.year == 0 || (1582..=9999).contains(&time.year))
+            && time.month <= 12
+            && time.day <= days_in_month(time.year, time.month)
+            && time.hours <= 23
+            && time.minutes <= 59
+            && time.seconds <= 59
+            && time.day_of_week <= 7;
+        if valid {
+            Ok(time)
+        } else {
+            Err(TimeError::InvalidValue)
+        }
+    }
+
+    pub fn to_gatt(&self) -> [u8; CURRENT_TIME_LEN] {
+        let year = self.year.to_le_bytes();
+        [
+            year[0],
+            year[1],
+            self.month,
+            self.day,
+            self.hours,
+            self.minutes,
+            self.seconds,
+            self.day_of_week,
+            self.fractions256,
+            self.adjust_reason,
+        ]
+    }
+}
+
+/// Wall clock of the device, set by the Current Time Service.
+pub trait Clock {
+    /// Seconds since the Unix epoch and fractions in 1/256 s, if the clock has been set.
+    fn now(&self) -> Option<(u64, u8)>;
+
+    fn set(&mut self, secs: u64, fractions256: u8);
+}
+
+/// Clock keeping the wall clock time as an offset from the embassy time driver.
+#[derive(Default)]
+pub struct SystemClock {
+    /// Instant at which the Unix epoch would have been, in 1/256 s
+    epoch: Option<i64>,
+}
+
+impl SystemClock {
+    pub const fn new() -> Self {
+        Self { epoch: None }
+    }
+
+    /// The wall clock time at `instant`.
+    pub fn at(&self, instant: Instant) -> Option<(u64, u8)> {
+        let time = to_256ths(instant) - self.epoch?;
+        if time < 0 {
+            return None;
+        }
+        Some(((time >> 8) as u64, time as u8))
+    }
+
+    /// Set the wall clock time at `instant`.
+    pub fn set_at(&mut self, instant: Instant, secs: u64, fractions256: u8) {
+        let time = ((secs as i64) << 8) + fractions256 as i64;
+        self.epoch.replace(to_256ths(instant) - time);
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Option<(u64, u8)> {
+        self.at(Instant::now())
+    }
+
+    fn set(&mut self, secs: u64, fractions256: u8) {
+        self.set_at(Instant::now(), secs, fractions256)
+    }
+}
+
+fn to_256ths(instant: Instant) -> i64 {
+    let since_boot = instant.duration_since(Instant::from_ticks(0));
+    (since_boot.as_millis() * 256 / 1000) as i64
+}
+
+/// Number of days in `month` of `year`. Unknown months and years, encoded as 0, allow the
+/// longest month.
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if year == 0 => 29,
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Date of the proleptic Gregorian calendar `days` after 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix() {
+        let time = CurrentTime::from_unix(0, 0);
+        assert_eq!(
+            (1970, 1, 1, 4),
+            (time.year, time.month, time.day, time.day_of_week)
+        );
+        assert_eq!(Some(0), time.to_unix());
+
+        // 2024-02-29T13:45:30, a Thursday
+        let time = CurrentTime::from_unix(1709214330, 128);
+        assert_eq!(
+            CurrentTime {
+                year: 2024,
+                month: 2,
+                day: 29,
+                hours: 13,
+                minutes: 45,
+                seconds: 30,
+                day_of_week: 4,
+                fractions256: 128,
+                adjust_reason: 0,
+            },
+            time
+        );
+        assert_eq!(Some(1709214330), time.to_unix());
+
+        // 2000-03-01T00:00:00, a Wednesday
+        let time = CurrentTime::from_unix(951868800, 0);
+        assert_eq!(
+            (2000, 3, 1, 3),
+            (time.year, time.month, time.day, time.day_of_week)
+        );
+        assert_eq!(Some(951868800), time.to_unix());
+
+        assert_eq!(None, CurrentTime::default().to_unix());
+    }
+
+    #[test]
+    fn test_encoding() {
+        let time = CurrentTime {
+            adjust_reason: ADJUST_MANUAL | ADJUST_EXTERNAL_REFERENCE,
+            ..CurrentTime::from_unix(1709214330, 128)
+        };
+        let data = time.to_gatt();
+        assert_eq!([0xE8, 0x07, 2, 29, 13, 45, 30, 4, 128, 3], data);
+        assert_eq!(Ok(time), CurrentTime::from_slice(&data));
+
+        assert_eq!(
+            Err(TimeError::InvalidLength),
+            CurrentTime::from_slice(&data[..9])
+        );
+        let mut invalid = data;
+        invalid[2] = 13;
+        assert_eq!(
+            Err(TimeError::InvalidValue),
+            CurrentTime::from_slice(&invalid)
+        );
+        let mut invalid = data;
+        invalid[4] = 24;
+        assert_eq!(
+            Err(TimeError::InvalidValue),
+            CurrentTime::from_slice(&invalid)
+        );
+    }
+
+    #[test]
+    fn test_days_of_month() {
+        let date = |year: u16, month, day| {
+            let year = year.to_le_bytes();
+            CurrentTime::from_slice(&[year[0], year[1], month, day, 0, 0, 0, 0, 0, 0])
+        };
+        assert!(date(2024, 2, 29).is_ok());
+        assert_eq!(Err(TimeError::InvalidValue), date(2024, 2, 30));
+        assert_eq!(Err(TimeError::InvalidValue), date(2024, 2, 31));
+        assert_eq!(Err(TimeError::InvalidValue), date(2023, 2, 29));
+        assert_eq!(Err(TimeError::InvalidValue), date(1900, 2, 29));
+        assert!(date(2000, 2, 29).is_ok());
+        assert_eq!(Err(TimeError::InvalidValue), date(2024, 4, 31));
+        assert!(date(2024, 12, 31).is_ok());
+        // Unknown years and months allow the longest month
+        assert!(date(0, 2, 29).is_ok());
+        assert!(date(2024, 0, 31).is_ok());
+
+        let time = CurrentTime {
+            year: 2024,
+            month: 2,
+            day: 31,
+            ..CurrentTime::default()
+        };
+        assert_eq!(None, time.to_unix());
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut clock = SystemClock::new();
+        assert_eq!(None, clock.at(Instant::from_secs(10)));
+
+        clock.set_at(Instant::from_secs(10), 1709214330, 128);
+        assert_eq!(Some((1709214330, 128)), clock.at(Instant::from_secs(10)));
+        assert_eq!(Some((1709214341, 0)), clock.at(Instant::from_millis(20500)));
+    }
+}
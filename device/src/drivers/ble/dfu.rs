@@ -0,0 +1,431 @@
+//! Firmware update protocol of the FirmwareUpdate GATT service
+//!
+//! The protocol logic is independent of the BLE stack: the stack passes writes of the control,
+//! version and firmware characteristics to [`DfuProtocol`] and publishes the resulting offset and
+//! status.
+//!
+//! The offset is kept when the connection is lost, so a client can resume an upload by reading
+//! the status and offset after reconnecting and writing the remaining firmware. A failed command
+//! does not end the update either: its error is reported in the status until the next command
+//! succeeds, and the client resumes by writing the firmware from the current offset.
+//!
+//! Firmware may be written without response for throughput. The client then controls the flow
+//! using offset notifications, keeping only a few chunks in flight and resending from the
+//! notified offset when it does not match what was sent.
+use {embedded_update::FirmwareDevice, heapless::Vec};
+
+/// Control value starting an update of the written next version.
+pub const CONTROL_START: u8 = 1;
+/// Control value marking the written firmware for update and resetting the device.
+pub const CONTROL_UPDATE: u8 = 2;
+/// Control value telling the device that it is in sync with the latest firmware.
+pub const CONTROL_SYNC: u8 = 3;
+/// Control value abandoning the update in progress.
+pub const CONTROL_ABORT: u8 = 4;
+
+/// Largest firmware chunk, filling a link layer packet of maximum length.
+pub const MAX_CHUNK_SIZE: usize = 244;
+
+const ATT_HEADER_LEN: usize = 3;
+
+pub type Version = Vec<u8, 16>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DfuError<E> {
+    /// The control value is not a known command.
+    InvalidControl(u8),
+    /// The version does not fit in the version characteristic.
+    InvalidVersion,
+    /// Firmware was written without starting an update.
+    NotStarted,
+    /// The firmware device failed to execute the command.
+    Device(E),
+    /// The link is not secure enough to update the firmware.
+    InsufficientSecurity,
+}
+
+impl<E> DfuError<E> {
+    /// Code of the error reported in the status characteristic.
+    pub fn code(&self) -> u8 {
+        match self {
+            DfuError::InvalidControl(_) => 1,
+            DfuError::InvalidVersion => 2,
+            DfuError::NotStarted => 3,
+            DfuError::Device(_) => 4,
+            DfuError::InsufficientSecurity => 5,
+        }
+    }
+}
+
+/// State of the firmware update, as reported in the status characteristic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DfuStatus {
+    /// No update in progress.
+    Idle,
+    /// Firmware is being written.
+    Receiving,
+    /// The written firmware is being verified and marked for update.
+    Verifying,
+    /// The last command failed with the given error code.
+    Error(u8),
+}
+
+impl DfuStatus {
+    /// Encode the status as its state followed by the error code.
+    pub fn to_gatt(&self) -> [u8; 2] {
+        match self {
+            DfuStatus::Idle => [0, 0],
+            DfuStatus::Receiving => [1, 0],
+            DfuStatus::Verifying => [2, 0],
+            DfuStatus::Error(code) => [3, *code],
+        }
+    }
+}
+
+/// Resets the device once a firmware update has been marked, to let the bootloader swap
+/// firmware.
+pub trait ResetHook {
+    fn reset(&mut self);
+}
+
+impl<F> ResetHook for F
+where
+    F: FnMut(),
+{
+    fn reset(&mut self) {
+        self()
+    }
+}
+
+/// Resets the device using the system reset of the Cortex-M core.
+#[cfg(feature = "cortex-m")]
+pub struct SystemReset;
+
+#[cfg(feature = "cortex-m")]
+impl ResetHook for SystemReset {
+    fn reset(&mut self) {
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+/// State machine of a firmware update over BLE, writing firmware to a [`FirmwareDevice`].
+pub struct DfuProtocol<F, R>
+where
+    F: FirmwareDevice,
+    R: ResetHook,
+{
+    dfu: F,
+    reset: R,
+    next_version: Version,
+    offset: u32,
+    /// State of the update, never [`DfuStatus::Error`]
+    state: DfuStatus,
+    /// Code of the error of the last command, if it failed
+    error: Option<u8>,
+}
+
+impl<F, R> DfuProtocol<F, R>
+where
+    F: FirmwareDevice,
+    R: ResetHook,
+{
+    pub fn new(dfu: F, reset: R) -> Self {
+        Self {
+            dfu,
+            reset,
+            next_version: Version::new(),
+            offset: 0,
+            state: DfuStatus::Idle,
+            error: None,
+        }
+    }
+
+    /// State of the update, or the error of the last command if it failed.
+    pub fn status(&self) -> DfuStatus {
+        match self.error {
+            Some(code) => DfuStatus::Error(code),
+            None => self.state,
+        }
+    }
+
+    /// Size of the firmware chunks to write over a connection with an ATT MTU of `att_mtu`: the
+    /// largest multiple of the device MTU fitting in a write and [`MAX_CHUNK_SIZE`].
+    pub fn chunk_size(att_mtu: u16) -> usize {
+        let payload = (att_mtu as usize)
+            .saturating_sub(ATT_HEADER_LEN)
+            .min(MAX_CHUNK_SIZE);
+        if payload >= F::MTU {
+            payload - payload % F::MTU
+        } else {
+            payload
+        }
+    }
+
+    /// Offset at which the next firmware block is written.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Version of the firmware being written.
+    pub fn next_version(&self) -> &[u8] {
+        &self.next_version
+    }
+
+    pub fn set_next_version(&mut self, version: &[u8]) -> Result<(), DfuError<F::Error>> {
+        let version = Version::from_slice(version).map_err(|_| DfuError::InvalidVersion);
+        self.next_version = self.check(version)?;
+        Ok(())
+    }
+
+    /// Execute a command written to the control characteristic.
+    pub async fn control(&mut self, value: u8) -> Result<(), DfuError<F::Error>> {
+        info!("Write firmware control: {}", value);
+        let result = match value {
+            CONTROL_START => {
+                self.offset = 0;
+                self.dfu
+                    .start(&self.next_version)
+                    .await
+                    .map(|_| self.state = DfuStatus::Receiving)
+                    .map_err(DfuError::Device)
+            }
+            CONTROL_UPDATE => {
+                let state = core::mem::replace(&mut self.state, DfuStatus::Verifying);
+                match self.dfu.update(&self.next_version, &[]).await {
+                    Ok(_) => {
+                        self.reset.reset();
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.state = state;
+                        Err(DfuError::Device(e))
+                    }
+                }
+            }
+            CONTROL_SYNC => self
+                .dfu
+                .synced()
+                .await
+                .map(|_| self.state = DfuStatus::Idle)
+                .map_err(DfuError::Device),
+            CONTROL_ABORT => {
+                self.offset = 0;
+                self.state = DfuStatus::Idle;
+                Ok(())
+            }
+            _ => Err(DfuError::InvalidControl(value)),
+        };
+        self.check(result)
+    }
+
+    /// Write a block of firmware at the current offset.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), DfuError<F::Error>> {
+        let result = if self.state == DfuStatus::Receiving {
+            self.dfu
+                .write(self.offset, data)
+                .await
+                .map_err(DfuError::Device)
+        } else {
+            Err(DfuError::NotStarted)
+        };
+        self.check(result)?;
+        self.offset += data.len() as u32;
+        Ok(())
+    }
+
+    /// Record the error of a failed command, or clear it when the command succeeded.
+    fn check<T>(&mut self, result: Result<T, DfuError<F::Error>>) -> Result<T, DfuError<F::Error>> {
+        match &result {
+            Ok(_) => self.error = None,
+            Err(e) => {
+                warn!("Firmware update error {}", e.code());
+                self.error.replace(e.code());
+            }
+        }
+        result
+    }
+
+    /// The firmware device being updated.
+    pub fn device(&mut self) -> &mut F {
+        &mut self.dfu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, embedded_update::FirmwareStatus, futures::executor::block_on};
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum MockError {
+        WrongOffset,
+        Failed,
+    }
+
+    #[derive(Default)]
+    struct MockDevice {
+        started: Option<Version>,
+        firmware: Vec<u8, 64>,
+        updated: Option<Version>,
+        synced: bool,
+        fail_update: bool,
+    }
+
+    impl FirmwareDevice for MockDevice {
+        const MTU: usize = 16;
+        type Version = Version;
+        type Error = MockError;
+
+        async fn status(&mut self) -> Result<FirmwareStatus<Version>, MockError> {
+            Ok(FirmwareStatus {
+                current_version: Version::from_slice(b"1.0").unwrap(),
+                next_offset: self.firmware.len() as u32,
+                next_version: self.started.clone(),
+            })
+        }
+
+        async fn start(&mut self, version: &[u8]) -> Result<(), MockError> {
+            self.started = Some(Version::from_slice(version).unwrap());
+            self.firmware.clear();
+            Ok(())
+        }
+
+        async fn synced(&mut self) -> Result<(), MockError> {
+            self.synced = true;
+            Ok(())
+        }
+
+        async fn update(&mut self, version: &[u8], _: &[u8]) -> Result<(), MockError> {
+            if self.fail_update {
+                return Err(MockError::Failed);
+            }
+            self.updated = Some(Version::from_slice(version).unwrap());
+            Ok(())
+        }
+
+        async fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), MockError> {
+            if offset as usize != self.firmware.len() {
+                return Err(MockError::WrongOffset);
+            }
+            self.firmware
+                .extend_from_slice(data)
+                .map_err(|_| MockError::Failed)
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let mut resets = 0;
+        let mut protocol = DfuProtocol::new(MockDevice::default(), || resets += 1);
+        block_on(async {
+            assert_eq!(DfuStatus::Idle, protocol.status());
+            protocol.set_next_version(b"1.1").unwrap();
+            protocol.control(CONTROL_START).await.unwrap();
+            assert_eq!(DfuStatus::Receiving, protocol.status());
+            protocol.write(b"abcd").await.unwrap();
+            protocol.write(b"efgh").await.unwrap();
+            assert_eq!(8, protocol.offset());
+            assert_eq!(b"abcdefgh", &protocol.device().firmware[..]);
+
+            // Aborting stops receiving firmware
+            protocol.control(CONTROL_ABORT).await.unwrap();
+            assert_eq!((DfuStatus::Idle, 0), (protocol.status(), protocol.offset()));
+            assert_eq!(Err(DfuError::NotStarted), protocol.write(b"ijkl").await);
+
+            // Starting over resets the offset
+            protocol.control(CONTROL_START).await.unwrap();
+            assert_eq!(0, protocol.offset());
+            protocol.write(b"ijkl").await.unwrap();
+
+            protocol.control(CONTROL_UPDATE).await.unwrap();
+            assert_eq!(DfuStatus::Verifying, protocol.status());
+            assert_eq!(Some(&b"1.1"[..]), protocol.device().updated.as_deref());
+        });
+        drop(protocol);
+        assert_eq!(1, resets);
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        type Protocol = DfuProtocol<MockDevice, fn()>;
+        assert_eq!(16, Protocol::chunk_size(23));
+        assert_eq!(112, Protocol::chunk_size(128));
+        assert_eq!(240, Protocol::chunk_size(256));
+        assert_eq!(7, Protocol::chunk_size(10));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut resets = 0;
+        let device = MockDevice {
+            fail_update: true,
+            ..Default::default()
+        };
+        let mut protocol = DfuProtocol::new(device, || resets += 1);
+        block_on(async {
+            assert_eq!(
+                Err(DfuError::InvalidVersion),
+                protocol.set_next_version(&[b'1'; 17])
+            );
+            assert_eq!(Err(DfuError::InvalidControl(9)), protocol.control(9).await);
+            assert_eq!(DfuStatus::Error(1), protocol.status());
+            assert_eq!([3, 1], protocol.status().to_gatt());
+            assert_eq!(Err(DfuError::NotStarted), protocol.write(b"abcd").await);
+
+            protocol.control(CONTROL_START).await.unwrap();
+            protocol.device().firmware.push(0).unwrap();
+            assert_eq!(
+                Err(DfuError::Device(MockError::WrongOffset)),
+                protocol.write(b"abcd").await
+            );
+            assert_eq!(0, protocol.offset());
+            assert_eq!(DfuStatus::Error(4), protocol.status());
+
+            // The device is not reset when the update fails
+            assert_eq!(
+                Err(DfuError::Device(MockError::Failed)),
+                protocol.control(CONTROL_UPDATE).await
+            );
+            assert_eq!(DfuStatus::Error(4), protocol.status());
+
+            protocol.control(CONTROL_SYNC).await.unwrap();
+            assert!(protocol.device().synced);
+            assert_eq!(DfuStatus::Idle, protocol.status());
+        });
+        drop(protocol);
+        assert_eq!(0, resets);
+    }
+
+    #[test]
+    fn test_resume_after_error() {
+        let mut protocol = DfuProtocol::new(MockDevice::default(), || {});
+        block_on(async {
+            protocol.set_next_version(b"1.1").unwrap();
+            protocol.control(CONTROL_START).await.unwrap();
+            protocol.write(b"abcd").await.unwrap();
+
+            // A failed command is reported without ending the update
+            assert_eq!(Err(DfuError::InvalidControl(9)), protocol.control(9).await);
+            assert_eq!(DfuStatus::Error(1), protocol.status());
+            protocol.device().firmware.push(0).unwrap();
+            assert_eq!(
+                Err(DfuError::Device(MockError::WrongOffset)),
+                protocol.write(b"efgh").await
+            );
+            assert_eq!(
+                (DfuStatus::Error(4), 4),
+                (protocol.status(), protocol.offset())
+            );
+
+            // Writing from the current offset resumes the upload
+            protocol.device().firmware.pop();
+            protocol.write(b"efgh").await.unwrap();
+            assert_eq!(
+                (DfuStatus::Receiving, 8),
+                (protocol.status(), protocol.offset())
+            );
+            assert_eq!(b"abcdefgh", &protocol.device().firmware[..]);
+        });
+    }
+}
@@ -0,0 +1,828 @@
+//! Measurements and descriptors of the Environmental Sensing Service
+//!
+//! Encoding and decoding of the ES Measurement, ES Trigger Setting and ES Configuration
+//! descriptors follows the Environmental Sensing Service specification 1.0, independently of the
+//! BLE stack. Decoding is fallible, as descriptor values may be written by clients.
+use {
+    embassy_time::{Duration, Instant},
+    heapless::Vec,
+};
+
+/// Length of an encoded ES Measurement descriptor.
+pub const MEASUREMENT_DESCRIPTOR_LEN: usize = 11;
+/// Maximum length of an encoded ES Trigger Setting descriptor, with a 32-bit value operand.
+pub const MAX_TRIGGER_SETTING_LEN: usize = 5;
+
+/// Maximum number of measurements made at once, one for each [`Quantity`].
+pub const MAX_MEASUREMENTS: usize = 4;
+
+/// Number of ES Trigger Setting descriptors of a characteristic.
+pub const MAX_TRIGGER_SETTINGS: usize = 2;
+
+const MAX_U24: u32 = 0xFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DescriptorError {
+    /// The data is too short or too long for the descriptor.
+    InvalidLength,
+    /// A field has a value reserved for future use.
+    InvalidValue,
+    /// A value does not fit in its field.
+    OutOfRange,
+}
+
+/// Quantity measured by a characteristic of the Environmental Sensing Service.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+    Pressure,
+    Illuminance,
+}
+
+/// A measured value, in the unit of its characteristic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Measurement {
+    /// Temperature in 0.1 °C
+    Temperature(i16),
+    /// Relative humidity in 0.01 %
+    Humidity(u16),
+    /// Pressure in 0.1 Pa
+    Pressure(u32),
+    /// Illuminance in 0.01 lux, saturating at 24 bits
+    Illuminance(u32),
+}
+
+impl Measurement {
+    pub fn quantity(&self) -> Quantity {
+        match self {
+            Self::Temperature(_) => Quantity::Temperature,
+            Self::Humidity(_) => Quantity::Humidity,
+            Self::Pressure(_) => Quantity::Pressure,
+            Self::Illuminance(_) => Quantity::Illuminance,
+        }
+    }
+
+    /// The value as a number, for comparisons.
+    pub fn value(&self) -> i64 {
+        match *self {
+            Self::Temperature(v) => v as i64,
+            Self::Humidity(v) => v as i64,
+            Self::Pressure(v) => v as i64,
+            Self::Illuminance(v) => v.min(MAX_U24) as i64,
+        }
+    }
+
+    /// Encode the value in the format of its characteristic.
+    pub fn to_gatt(&self) -> Vec<u8, 4> {
+        let bytes = match *self {
+            Self::Temperature(v) => &v.to_le_bytes()[..],
+            Self::Humidity(v) => &v.to_le_bytes()[..],
+            Self::Pressure(v) => &v.to_le_bytes()[..],
+            Self::Illuminance(v) => &v.min(MAX_U24).to_le_bytes()[..3],
+        };
+        // All formats fit in 4 bytes
+        Vec::from_slice(bytes).unwrap()
+    }
+
+    /// Decode a value of `quantity` in the format of its characteristic.
+    pub fn from_gatt(quantity: Quantity, data: &[u8]) -> Result<Self, DescriptorError> {
+        Ok(match (quantity, data) {
+            (Quantity::Temperature, &[a, b]) => Self::Temperature(i16::from_le_bytes([a, b])),
+            (Quantity::Humidity, &[a, b]) => Self::Humidity(u16::from_le_bytes([a, b])),
+            (Quantity::Pressure, &[a, b, c, d]) => Self::Pressure(u32::from_le_bytes([a, b, c, d])),
+            (Quantity::Illuminance, &[a, b, c]) => {
+                Self::Illuminance(u32::from_le_bytes([a, b, c, 0]))
+            }
+            _ => return Err(DescriptorError::InvalidLength),
+        })
+    }
+}
+
+/// A sensor measuring one or more quantities of the Environmental Sensing Service.
+pub trait EnvironmentSensor {
+    type Error;
+
+    async fn measure(&mut self) -> Result<Vec<Measurement, MAX_MEASUREMENTS>, Self::Error>;
+}
+
+/// ES Measurement descriptor, describing how a characteristic value is measured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MeasurementDescriptor {
+    /// Reserved for future use, must be 0.
+    pub flags: u16,
+    pub sampling_fn: SamplingFunction,
+    pub measurement_period: Period,
+    pub update_interval: Interval,
+    pub application: MeasurementApp,
+    pub uncertainty: Uncertainty,
+}
+
+impl Default for MeasurementDescriptor {
+    fn default() -> Self {
+        Self {
+            flags: 0,
+            sampling_fn: SamplingFunction::Unspecified,
+            measurement_period: Period::Unknown,
+            update_interval: Interval::Unknown,
+            application: MeasurementApp::Unspecified,
+            uncertainty: Uncertainty::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum SamplingFunction {
+    Unspecified = 0x00,
+    Instantaneous = 0x01,
+    ArithmeticMean = 0x02,
+    RMS = 0x03,
+    Max = 0x04,
+    Min = 0x05,
+    Accum = 0x06,
+    Count = 0x07,
+}
+
+impl TryFrom<u8> for SamplingFunction {
+    type Error = DescriptorError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0x00 => Self::Unspecified,
+            0x01 => Self::Instantaneous,
+            0x02 => Self::ArithmeticMean,
+            0x03 => Self::RMS,
+            0x04 => Self::Max,
+            0x05 => Self::Min,
+            0x06 => Self::Accum,
+            0x07 => Self::Count,
+            _ => return Err(DescriptorError::InvalidValue),
+        })
+    }
+}
+
+/// Period over which a measurement is made, in seconds.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Period {
+    Unknown,
+    Value(u32),
+}
+
+impl Period {
+    fn to_gatt(self) -> Result<[u8; 3], DescriptorError> {
+        match self {
+            Self::Unknown => Ok([0, 0, 0]),
+            Self::Value(val) => encode_u24(val),
+        }
+    }
+}
+
+impl From<u32> for Period {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Unknown,
+            v => Self::Value(v),
+        }
+    }
+}
+
+/// Interval between internal updates of a measurement, in seconds.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Interval {
+    Unknown,
+    Value(u32),
+}
+
+impl Interval {
+    fn to_gatt(self) -> Result<[u8; 3], DescriptorError> {
+        match self {
+            Self::Unknown => Ok([0, 0, 0]),
+            Self::Value(val) => encode_u24(val),
+        }
+    }
+}
+
+impl From<u32> for Interval {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Unknown,
+            v => Self::Value(v),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum MeasurementApp {
+    Unspecified = 0x00,
+    Air = 0x01,
+    Water = 0x02,
+    Barometric = 0x03,
+    Soil = 0x04,
+    Infrared = 0x05,
+    MapDatabase = 0x06,
+    BarometricElevation = 0x07,
+    GpsElevation = 0x08,
+    GpsMapElevation = 0x09,
+    VerticalDatumElevation = 0x0A,
+    Onshore = 0x0B,
+    OnboardVessel = 0x0C,
+    Front = 0x0D,
+    Back = 0x0E,
+    Upper = 0x0F,
+    Lower = 0x10,
+    Primary = 0x11,
+    Secondary = 0x12,
+    Outdoor = 0x13,
+    Indoor = 0x14,
+    Top = 0x15,
+    Bottom = 0x16,
+    Main = 0x17,
+    Backup = 0x18,
+    Auxiliary = 0x19,
+    Supplementary = 0x1A,
+    Inside = 0x1B,
+    Outside = 0x1C,
+    Left = 0x1D,
+    Right = 0x1E,
+    Internal = 0x1F,
+    External = 0x20,
+    Solar = 0x21,
+}
+
+impl TryFrom<u8> for MeasurementApp {
+    type Error = DescriptorError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0x00 => Self::Unspecified,
+            0x01 => Self::Air,
+            0x02 => Self::Water,
+            0x03 => Self::Barometric,
+            0x04 => Self::Soil,
+            0x05 => Self::Infrared,
+            0x06 => Self::MapDatabase,
+            0x07 => Self::BarometricElevation,
+            0x08 => Self::GpsElevation,
+            0x09 => Self::GpsMapElevation,
+            0x0A => Self::VerticalDatumElevation,
+            0x0B => Self::Onshore,
+            0x0C => Self::OnboardVessel,
+            0x0D => Self::Front,
+            0x0E => Self::Back,
+            0x0F => Self::Upper,
+            0x10 => Self::Lower,
+            0x11 => Self::Primary,
+            0x12 => Self::Secondary,
+            0x13 => Self::Outdoor,
+            0x14 => Self::Indoor,
+            0x15 => Self::Top,
+            0x16 => Self::Bottom,
+            0x17 => Self::Main,
+            0x18 => Self::Backup,
+            0x19 => Self::Auxiliary,
+            0x1A => Self::Supplementary,
+            0x1B => Self::Inside,
+            0x1C => Self::Outside,
+            0x1D => Self::Left,
+            0x1E => Self::Right,
+            0x1F => Self::Internal,
+            0x20 => Self::External,
+            0x21 => Self::Solar,
+            _ => return Err(DescriptorError::InvalidValue),
+        })
+    }
+}
+
+/// Uncertainty of a measurement, in units of 0.5 % up to 100 %.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Uncertainty {
+    Value(u8),
+    Unknown,
+}
+
+const UNCERTAINTY_UNKNOWN: u8 = 0xFF;
+const MAX_UNCERTAINTY: u8 = 200;
+
+impl Uncertainty {
+    fn to_gatt(self) -> Result<u8, DescriptorError> {
+        match self {
+            Self::Unknown => Ok(UNCERTAINTY_UNKNOWN),
+            Self::Value(val) if val <= MAX_UNCERTAINTY => Ok(val),
+            Self::Value(_) => Err(DescriptorError::OutOfRange),
+        }
+    }
+}
+
+impl TryFrom<u8> for Uncertainty {
+    type Error = DescriptorError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            UNCERTAINTY_UNKNOWN => Ok(Self::Unknown),
+            v if v <= MAX_UNCERTAINTY => Ok(Self::Value(v)),
+            _ => Err(DescriptorError::InvalidValue),
+        }
+    }
+}
+
+impl MeasurementDescriptor {
+    pub fn from_slice(data: &[u8]) -> Result<Self, DescriptorError> {
+        let data: &[u8; MEASUREMENT_DESCRIPTOR_LEN] = data
+            .try_into()
+            .map_err(|_| DescriptorError::InvalidLength)?;
+        Ok(Self {
+            flags: u16::from_le_bytes([data[0], data[1]]),
+            sampling_fn: data[2].try_into()?,
+            measurement_period: decode_u24(&data[3..6]).into(),
+            update_interval: decode_u24(&data[6..9]).into(),
+            application: data[9].try_into()?,
+            uncertainty: data[10].try_into()?,
+        })
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8, MEASUREMENT_DESCRIPTOR_LEN>, DescriptorError> {
+        let mut data = Vec::new();
+        let _ = data.extend_from_slice(&self.flags.to_le_bytes());
+        let _ = data.push(self.sampling_fn as u8);
+        let _ = data.extend_from_slice(&self.measurement_period.to_gatt()?);
+        let _ = data.extend_from_slice(&self.update_interval.to_gatt()?);
+        let _ = data.push(self.application as u8);
+        let _ = data.push(self.uncertainty.to_gatt()?);
+        Ok(data)
+    }
+}
+
+/// ES Trigger Setting descriptor, the condition under which a characteristic is notified.
+///
+/// Intervals are in seconds. Value conditions compare measurements with the operand, which must
+/// be a measurement of the same quantity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TriggerSetting {
+    Inactive,
+    FixedInterval(u32),
+    MinimumInterval(u32),
+    ValueChanged,
+    LessThan(Measurement),
+    LessOrEqual(Measurement),
+    GreaterThan(Measurement),
+    GreaterOrEqual(Measurement),
+    Equal(Measurement),
+    NotEqual(Measurement),
+}
+
+impl Default for TriggerSetting {
+    fn default() -> Self {
+        Self::Inactive
+    }
+}
+
+impl TriggerSetting {
+    /// Decode a trigger setting of a characteristic of `quantity`.
+    pub fn from_slice(data: &[u8], quantity: Quantity) -> Result<Self, DescriptorError> {
+        let (condition, operand) = data.split_first().ok_or(DescriptorError::InvalidLength)?;
+        let interval = || match operand.len() {
+            3 => Ok(decode_u24(operand)),
+            _ => Err(DescriptorError::InvalidLength),
+        };
+        let value = || Measurement::from_gatt(quantity, operand);
+        Ok(match condition {
+            0x00 | 0x03 if !operand.is_empty() => return Err(DescriptorError::InvalidLength),
+            0x00 => Self::Inactive,
+            0x01 => Self::FixedInterval(interval()?),
+            0x02 => Self::MinimumInterval(interval()?),
+            0x03 => Self::ValueChanged,
+            0x04 => Self::LessThan(value()?),
+            0x05 => Self::LessOrEqual(value()?),
+            0x06 => Self::GreaterThan(value()?),
+            0x07 => Self::GreaterOrEqual(value()?),
+            0x08 => Self::Equal(value()?),
+            0x09 => Self::NotEqual(value()?),
+            _ => return Err(DescriptorError::InvalidValue),
+        })
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8, MAX_TRIGGER_SETTING_LEN>, DescriptorError> {
+        let interval = |val: u32| encode_u24(val).map(|v| Vec::from_slice(&v).unwrap());
+        let (condition, operand): (u8, Vec<u8, 4>) = match self {
+            Self::Inactive => (0x00, Vec::new()),
+            Self::FixedInterval(val) => (0x01, interval(*val)?),
+            Self::MinimumInterval(val) => (0x02, interval(*val)?),
+            Self::ValueChanged => (0x03, Vec::new()),
+            Self::LessThan(val) => (0x04, val.to_gatt()),
+            Self::LessOrEqual(val) => (0x05, val.to_gatt()),
+            Self::GreaterThan(val) => (0x06, val.to_gatt()),
+            Self::GreaterOrEqual(val) => (0x07, val.to_gatt()),
+            Self::Equal(val) => (0x08, val.to_gatt()),
+            Self::NotEqual(val) => (0x09, val.to_gatt()),
+        };
+        let mut data = Vec::new();
+        let _ = data.push(condition);
+        let _ = data.extend_from_slice(&operand);
+        Ok(data)
+    }
+}
+
+/// ES Configuration descriptor, combining multiple trigger settings of a characteristic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Configuration {
+    /// Notify when all trigger conditions are met.
+    And,
+    /// Notify when any trigger condition is met.
+    Or,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self::And
+    }
+}
+
+impl Configuration {
+    pub fn from_slice(data: &[u8]) -> Result<Self, DescriptorError> {
+        match data {
+            [0x00] => Ok(Self::And),
+            [0x01] => Ok(Self::Or),
+            [_] => Err(DescriptorError::InvalidValue),
+            _ => Err(DescriptorError::InvalidLength),
+        }
+    }
+
+    pub fn to_gatt(&self) -> u8 {
+        match self {
+            Self::And => 0x00,
+            Self::Or => 0x01,
+        }
+    }
+
+    /// Combine the results of evaluating each trigger condition.
+    pub fn evaluate(&self, mut results: impl Iterator<Item = bool>) -> bool {
+        match self {
+            Self::And => results.all(|r| r),
+            Self::Or => results.any(|r| r),
+        }
+    }
+}
+
+/// Runtime state of a trigger setting, deciding when to notify measurements.
+pub struct Trigger {
+    setting: TriggerSetting,
+    last: Option<(Instant, Measurement)>,
+}
+
+impl Trigger {
+    pub fn new(setting: TriggerSetting) -> Self {
+        Self {
+            setting,
+            last: None,
+        }
+    }
+
+    pub fn setting(&self) -> TriggerSetting {
+        self.setting
+    }
+
+    /// Replace the setting, as if nothing had been notified yet.
+    pub fn set(&mut self, setting: TriggerSetting) {
+        self.setting = setting;
+        self.last = None;
+    }
+
+    /// Evaluate the trigger condition for `measurement` made at `now`, returning true and
+    /// recording the measurement as notified if it should be notified.
+    pub fn evaluate(&mut self, measurement: Measurement, now: Instant) -> bool {
+        let notify = self.check(measurement, now);
+        if notify {
+            self.notified(measurement, now);
+        }
+        notify
+    }
+
+    /// Evaluate the trigger condition for `measurement` made at `now`.
+    fn check(&self, measurement: Measurement, now: Instant) -> bool {
+        let elapsed = |secs: u32| match self.last {
+            Some((at, _)) => now.saturating_duration_since(at) >= Duration::from_secs(secs as u64),
+            None => true,
+        };
+        let changed = match self.last {
+            Some((_, last)) => last != measurement,
+            None => true,
+        };
+        let value = measurement.value();
+        match self.setting {
+            TriggerSetting::Inactive => false,
+            TriggerSetting::FixedInterval(secs) => elapsed(secs),
+            TriggerSetting::MinimumInterval(secs) => changed && elapsed(secs),
+            TriggerSetting::ValueChanged => changed,
+            TriggerSetting::LessThan(operand) => value < operand.value(),
+            TriggerSetting::LessOrEqual(operand) => value <= operand.value(),
+            TriggerSetting::GreaterThan(operand) => value > operand.value(),
+            TriggerSetting::GreaterOrEqual(operand) => value >= operand.value(),
+            TriggerSetting::Equal(operand) => value == operand.value(),
+            TriggerSetting::NotEqual(operand) => value != operand.value(),
+        }
+    }
+
+    fn notified(&mut self, measurement: Measurement, now: Instant) {
+        self.last.replace((now, measurement));
+    }
+}
+
+/// Trigger settings of a characteristic, combined according to its ES Configuration.
+///
+/// Unset and inactive trigger settings take no part in the combination. A characteristic without
+/// any active trigger setting is notified on every measurement.
+pub struct Triggers {
+    triggers: [Option<Trigger>; MAX_TRIGGER_SETTINGS],
+    configuration: Configuration,
+}
+
+impl Triggers {
+    pub fn new(
+        settings: [Option<TriggerSetting>; MAX_TRIGGER_SETTINGS],
+        configuration: Configuration,
+    ) -> Self {
+        Self {
+            triggers: settings.map(|setting| setting.map(Trigger::new)),
+            configuration,
+        }
+    }
+
+    /// The trigger setting at `index`, if set.
+    pub fn setting(&self, index: usize) -> Option<TriggerSetting> {
+        self.triggers[index].as_ref().map(Trigger::setting)
+    }
+
+    /// Replace the trigger setting at `index`, as if nothing had been notified yet.
+    pub fn set(&mut self, index: usize, setting: TriggerSetting) {
+        self.triggers[index].replace(Trigger::new(setting));
+    }
+
+    pub fn configuration(&self) -> Configuration {
+        self.configuration
+    }
+
+    pub fn set_configuration(&mut self, configuration: Configuration) {
+        self.configuration = configuration;
+    }
+
+    /// Evaluate the trigger conditions for `measurement` made at `now`, returning true and
+    /// recording the measurement as notified by all triggers if it should be notified.
+    pub fn evaluate(&mut self, measurement: Measurement, now: Instant) -> bool {
+        let mut results = [None; MAX_TRIGGER_SETTINGS];
+        for (result, trigger) in results.iter_mut().zip(&self.triggers) {
+            *result = trigger
+                .as_ref()
+                .filter(|t| t.setting != TriggerSetting::Inactive)
+                .map(|t| t.check(measurement, now));
+        }
+        let notify = results.iter().all(Option::is_none)
+            || self
+                .configuration
+                .evaluate(results.iter().flatten().copied());
+        if notify {
+            for trigger in self.triggers.iter_mut().flatten() {
+                trigger.notified(measurement, now);
+            }
+        }
+        notify
+    }
+}
+
+fn encode_u24(value: u32) -> Result<[u8; 3], DescriptorError> {
+    if value > MAX_U24 {
+        return Err(DescriptorError::OutOfRange);
+    }
+    let value = value.to_le_bytes();
+    Ok([value[0], value[1], value[2]])
+}
+
+fn decode_u24(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measurement_descriptor() {
+        let descriptor = MeasurementDescriptor {
+            flags: 0,
+            sampling_fn: SamplingFunction::ArithmeticMean,
+            measurement_period: Period::Value(0x030201),
+            update_interval: Interval::Value(5),
+            application: MeasurementApp::Air,
+            uncertainty: Uncertainty::Value(4),
+        };
+        let data = descriptor.to_vec().unwrap();
+        assert_eq!(&[0, 0, 2, 1, 2, 3, 5, 0, 0, 1, 4], &data[..]);
+        assert_eq!(Ok(descriptor), MeasurementDescriptor::from_slice(&data));
+
+        let data = MeasurementDescriptor::default().to_vec().unwrap();
+        assert_eq!(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF], &data[..]);
+        assert_eq!(
+            Ok(MeasurementDescriptor::default()),
+            MeasurementDescriptor::from_slice(&data)
+        );
+    }
+
+    #[test]
+    fn test_invalid_measurement_descriptor() {
+        let data = [0, 0, 2, 1, 2, 3, 5, 0, 0, 1, 4];
+        assert_eq!(
+            Err(DescriptorError::InvalidLength),
+            MeasurementDescriptor::from_slice(&data[..10])
+        );
+        let mut invalid = data;
+        invalid[2] = 0x08;
+        assert_eq!(
+            Err(DescriptorError::InvalidValue),
+            MeasurementDescriptor::from_slice(&invalid)
+        );
+        let mut invalid = data;
+        invalid[9] = 0x22;
+        assert_eq!(
+            Err(DescriptorError::InvalidValue),
+            MeasurementDescriptor::from_slice(&invalid)
+        );
+        let mut invalid = data;
+        invalid[10] = 201;
+        assert_eq!(
+            Err(DescriptorError::InvalidValue),
+            MeasurementDescriptor::from_slice(&invalid)
+        );
+
+        let descriptor = MeasurementDescriptor {
+            measurement_period: Period::Value(0x1000000),
+            ..Default::default()
+        };
+        assert_eq!(Err(DescriptorError::OutOfRange), descriptor.to_vec());
+    }
+
+    #[test]
+    fn test_trigger_setting() {
+        let settings = [
+            (TriggerSetting::Inactive, &[0x00][..]),
+            (TriggerSetting::FixedInterval(0x030201), &[0x01, 1, 2, 3]),
+            (TriggerSetting::MinimumInterval(60), &[0x02, 60, 0, 0]),
+            (TriggerSetting::ValueChanged, &[0x03]),
+            (
+                TriggerSetting::LessThan(Measurement::Pressure(0x04030201)),
+                &[0x04, 1, 2, 3, 4],
+            ),
+            (
+                TriggerSetting::LessOrEqual(Measurement::Humidity(0x0201)),
+                &[0x05, 1, 2],
+            ),
+            (
+                TriggerSetting::GreaterThan(Measurement::Temperature(-2)),
+                &[0x06, 0xFE, 0xFF],
+            ),
+            (
+                TriggerSetting::GreaterOrEqual(Measurement::Illuminance(0x030201)),
+                &[0x07, 1, 2, 3],
+            ),
+            (
+                TriggerSetting::Equal(Measurement::Temperature(1)),
+                &[0x08, 1, 0],
+            ),
+            (
+                TriggerSetting::NotEqual(Measurement::Temperature(1)),
+                &[0x09, 1, 0],
+            ),
+        ];
+        for (setting, data) in settings {
+            assert_eq!(data, &setting.to_vec().unwrap()[..]);
+            let quantity = match setting {
+                TriggerSetting::LessThan(m)
+                | TriggerSetting::LessOrEqual(m)
+                | TriggerSetting::GreaterThan(m)
+                | TriggerSetting::GreaterOrEqual(m)
+                | TriggerSetting::Equal(m)
+                | TriggerSetting::NotEqual(m) => m.quantity(),
+                _ => Quantity::Temperature,
+            };
+            assert_eq!(Ok(setting), TriggerSetting::from_slice(data, quantity));
+        }
+    }
+
+    #[test]
+    fn test_invalid_trigger_setting() {
+        let temperature = Quantity::Temperature;
+        let invalid = [
+            (&[][..], DescriptorError::InvalidLength),
+            (&[0x00, 1], DescriptorError::InvalidLength),
+            (&[0x01, 1, 2], DescriptorError::InvalidLength),
+            (&[0x04, 1, 2, 3], DescriptorError::InvalidLength),
+            (&[0x0A], DescriptorError::InvalidValue),
+        ];
+        for (data, error) in invalid {
+            assert_eq!(Err(error), TriggerSetting::from_slice(data, temperature));
+        }
+        assert_eq!(
+            Err(DescriptorError::OutOfRange),
+            TriggerSetting::FixedInterval(0x1000000).to_vec()
+        );
+    }
+
+    #[test]
+    fn test_configuration() {
+        for configuration in [Configuration::And, Configuration::Or] {
+            assert_eq!(
+                Ok(configuration),
+                Configuration::from_slice(&[configuration.to_gatt()])
+            );
+        }
+        assert_eq!(
+            Err(DescriptorError::InvalidValue),
+            Configuration::from_slice(&[2])
+        );
+        assert_eq!(
+            Err(DescriptorError::InvalidLength),
+            Configuration::from_slice(&[])
+        );
+        assert!(!Configuration::And.evaluate([true, false].into_iter()));
+        assert!(Configuration::Or.evaluate([true, false].into_iter()));
+    }
+
+    #[test]
+    fn test_trigger() {
+        let at = Instant::from_secs;
+        let temperature = Measurement::Temperature;
+
+        let mut trigger = Trigger::new(TriggerSetting::FixedInterval(10));
+        assert!(trigger.evaluate(temperature(1), at(0)));
+        assert!(!trigger.evaluate(temperature(2), at(9)));
+        assert!(trigger.evaluate(temperature(2), at(10)));
+
+        let mut trigger = Trigger::new(TriggerSetting::MinimumInterval(10));
+        assert!(trigger.evaluate(temperature(1), at(0)));
+        assert!(!trigger.evaluate(temperature(2), at(5)));
+        assert!(!trigger.evaluate(temperature(1), at(15)));
+        assert!(trigger.evaluate(temperature(2), at(15)));
+
+        let mut trigger = Trigger::new(TriggerSetting::ValueChanged);
+        assert!(trigger.evaluate(temperature(1), at(0)));
+        assert!(!trigger.evaluate(temperature(1), at(1)));
+        assert!(trigger.evaluate(temperature(2), at(2)));
+
+        let mut trigger = Trigger::new(TriggerSetting::GreaterThan(temperature(250)));
+        assert!(!trigger.evaluate(temperature(250), at(0)));
+        assert!(trigger.evaluate(temperature(251), at(1)));
+        assert!(trigger.evaluate(temperature(251), at(2)));
+
+        let mut trigger = Trigger::new(TriggerSetting::LessOrEqual(temperature(-10)));
+        assert!(trigger.evaluate(temperature(-10), at(0)));
+        assert!(!trigger.evaluate(temperature(-9), at(1)));
+
+        trigger.set(TriggerSetting::Inactive);
+        assert!(!trigger.evaluate(temperature(-10), at(2)));
+    }
+
+    #[test]
+    fn test_triggers() {
+        let at = Instant::from_secs;
+        let temperature = Measurement::Temperature;
+
+        // Without active trigger settings, every measurement is notified
+        let mut triggers = Triggers::new([None, None], Configuration::And);
+        assert!(triggers.evaluate(temperature(1), at(0)));
+        assert!(triggers.evaluate(temperature(1), at(0)));
+        let mut triggers =
+            Triggers::new([Some(TriggerSetting::Inactive), None], Configuration::And);
+        assert!(triggers.evaluate(temperature(1), at(0)));
+
+        triggers.set(0, TriggerSetting::ValueChanged);
+        assert!(triggers.evaluate(temperature(2), at(1)));
+        assert!(!triggers.evaluate(temperature(2), at(2)));
+        // An inactive setting does not hold back the others
+        triggers.set(1, TriggerSetting::Inactive);
+        assert!(!triggers.evaluate(temperature(2), at(2)));
+        assert!(triggers.evaluate(temperature(3), at(2)));
+
+        triggers.set(1, TriggerSetting::GreaterThan(temperature(250)));
+        assert_eq!(
+            Some(TriggerSetting::GreaterThan(temperature(250))),
+            triggers.setting(1)
+        );
+        assert!(!triggers.evaluate(temperature(4), at(3)));
+        assert!(triggers.evaluate(temperature(251), at(4)));
+        assert!(!triggers.evaluate(temperature(251), at(5)));
+
+        triggers.set_configuration(Configuration::Or);
+        assert!(triggers.evaluate(temperature(252), at(6)));
+        assert!(triggers.evaluate(temperature(252), at(7)));
+        assert!(triggers.evaluate(temperature(4), at(8)));
+        assert!(!triggers.evaluate(temperature(4), at(9)));
+    }
+}
@@ -0,0 +1,60 @@
+use nrf_softdevice::ble::Connection;
+
+#[nrf_softdevice::gatt_service(uuid = "180f")]
+pub struct BatteryService {
+    /// Remaining battery capacity in percent
+    #[characteristic(uuid = "2a19", read, notify)]
+    pub battery_level: u8,
+}
+
+/// Source of the battery level of the device.
+pub trait BatteryMonitor {
+    type Error;
+
+    /// Remaining battery capacity in percent, from 0 to 100.
+    async fn level(&mut self) -> Result<u8, Self::Error>;
+}
+
+/// Publishes the battery level of a [`BatteryMonitor`] on a connection, notifying the client of
+/// changes when subscribed.
+pub struct BatteryGattService<'a> {
+    service: &'a BatteryService,
+    level: Option<u8>,
+    notify: bool,
+}
+
+impl<'a> BatteryGattService<'a> {
+    pub fn new(service: &'a BatteryService) -> Self {
+        Self {
+            service,
+            level: service.battery_level_get().ok(),
+            notify: false,
+        }
+    }
+
+    pub fn handle(&mut self, event: &BatteryServiceEvent) {
+        match event {
+            BatteryServiceEvent::BatteryLevelCccdWrite { notifications } => {
+                self.notify = *notifications;
+            }
+        }
+    }
+
+    /// Read the battery level from `monitor` and publish it.
+    pub async fn update<M: BatteryMonitor>(
+        &mut self,
+        connection: &Connection,
+        monitor: &mut M,
+    ) -> Result<(), M::Error> {
+        let level = monitor.level().await?.min(100);
+        if self.level != Some(level) {
+            debug!("Battery level is {}%", level);
+            self.level.replace(level);
+            self.service.battery_level_set(&level).ok();
+            if self.notify {
+                self.service.battery_level_notify(connection, &level).ok();
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+pub use crate::drivers::ble::current_time::*;
+use nrf_softdevice::ble::Connection;
+
+#[nrf_softdevice::gatt_service(uuid = "1805")]
+pub struct CurrentTimeService {
+    #[characteristic(uuid = "2a2b", read, write, notify)]
+    pub current_time: [u8; CURRENT_TIME_LEN],
+}
+
+/// Keeps the Current Time characteristic and the device [`Clock`] in sync: the clock is set when a
+/// client writes the current time, and the characteristic is updated from the clock.
+pub struct CurrentTimeGattService<'a, C>
+where
+    C: Clock,
+{
+    service: &'a CurrentTimeService,
+    clock: C,
+    notify: bool,
+}
+
+impl<'a, C> CurrentTimeGattService<'a, C>
+where
+    C: Clock,
+{
+    pub fn new(service: &'a CurrentTimeService, clock: C) -> Self {
+        let s = Self {
+            service,
+            clock,
+            notify: false,
+        };
+        s.update();
+        s
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Handle an event of the service on `connection`, setting the clock to the written time and
+    /// notifying the adjustment when subscribed.
+    pub fn handle(
+        &mut self,
+        connection: &Connection,
+        event: &CurrentTimeServiceEvent,
+    ) -> Result<(), TimeError> {
+        match event {
+            CurrentTimeServiceEvent::CurrentTimeWrite(data) => {
+                let time = CurrentTime::from_slice(data)?;
+                let secs = time.to_unix().ok_or(TimeError::InvalidValue)?;
+                info!("Setting clock to {} s since epoch", secs);
+                self.clock.set(secs, time.fractions256);
+                if self.notify {
+                    self.service.current_time_notify(connection, data).ok();
+                }
+            }
+            CurrentTimeServiceEvent::CurrentTimeCccdWrite { notifications } => {
+                self.notify = *notifications;
+            }
+        }
+        Ok(())
+    }
+
+    /// Update the Current Time characteristic from the clock, to be called periodically so that
+    /// reads return the current time.
+    pub fn update(&self) {
+        let time = match self.clock.now() {
+            Some((secs, fractions256)) => CurrentTime::from_unix(secs, fractions256),
+            None => CurrentTime::default(),
+        };
+        self.service.current_time_set(&time.to_gatt()).ok();
+    }
+}
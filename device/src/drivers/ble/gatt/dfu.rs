@@ -1,84 +1,373 @@
-use {embedded_update::FirmwareDevice, heapless::Vec};
+use {
+    crate::drivers::ble::dfu::{
+        DfuError, DfuProtocol, DfuStatus, ResetHook, SystemReset, CONTROL_UPDATE, MAX_CHUNK_SIZE,
+    },
+    embedded_update::FirmwareDevice,
+    heapless::Vec,
+    nrf_softdevice::{
+        ble::{
+            gatt_server::{
+                self,
+                builder::ServiceBuilder,
+                characteristic::{Attribute, Metadata, Properties},
+                GetValueError, NotifyValueError, RegisterError, Service, SetValueError,
+            },
+            Connection, SecurityMode, Uuid,
+        },
+        Softdevice,
+    },
+};
 
-// The FirmwareUpdate GATT service
-#[nrf_softdevice::gatt_service(uuid = "00001000-b0cd-11ec-871f-d45ddf138840")]
+#[cfg(feature = "ble-security")]
+use crate::drivers::ble::{bonder::security_level, security::SecurityLevel};
+
+/// Security required by the stack to write the version, control and firmware characteristics.
+#[cfg(feature = "ble-security")]
+const WRITE_SECURITY: SecurityMode = SecurityMode::JustWorks;
+#[cfg(not(feature = "ble-security"))]
+const WRITE_SECURITY: SecurityMode = SecurityMode::Open;
+
+const MAX_VERSION_LEN: usize = 16;
+
+/// The UUID `000010xx-b0cd-11ec-871f-d45ddf138840` of the service (`0x00`) or of a characteristic.
+fn uuid(id: u8) -> Uuid {
+    Uuid::new_128(&[
+        0x40, 0x88, 0x13, 0xdf, 0x5d, 0xd4, 0x1f, 0x87, 0xec, 0x11, 0xcd, 0xb0, id, 0x10, 0x00,
+        0x00,
+    ])
+}
+
+/// The FirmwareUpdate GATT service
+///
+/// The service is registered with the service builder of the softdevice to set the security of
+/// its attributes. With the `ble-security` feature, the stack only accepts writes of the version,
+/// control and firmware characteristics over an encrypted link, so that peers pair before they
+/// can update the firmware.
 pub struct FirmwareService {
     /// Version of current running firmware
-    #[characteristic(uuid = "00001001-b0cd-11ec-871f-d45ddf138840", read)]
-    version: Vec<u8, 16>,
+    version: u16,
+    /// Max firmware block size for device, derived from the ATT MTU
+    mtu: u16,
+    /// State control
+    control: u16,
+    /// Version being written
+    next_version: u16,
+    /// Current write offset
+    offset: u16,
+    offset_cccd: u16,
+    /// Firmware data to be written
+    firmware: u16,
+    /// Update state and error code of the last failed command
+    status: u16,
+    status_cccd: u16,
+}
 
-    /// Max firmware block size for device
-    #[characteristic(uuid = "00001002-b0cd-11ec-871f-d45ddf138840", read)]
-    mtu: u8,
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirmwareServiceEvent {
+    ControlWrite(u8),
+    NextVersionWrite(Vec<u8, MAX_VERSION_LEN>),
+    FirmwareWrite(Vec<u8, MAX_CHUNK_SIZE>),
+    OffsetCccdWrite { notifications: bool },
+    StatusCccdWrite { notifications: bool },
+}
 
-    /// State control
-    #[characteristic(uuid = "00001003-b0cd-11ec-871f-d45ddf138840", write)]
-    control: u8,
+impl FirmwareService {
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut service = ServiceBuilder::new(sd, uuid(0x00))?;
+        let version = service
+            .add_characteristic(
+                uuid(0x01),
+                Attribute::new([0u8; 0]).variable_len(MAX_VERSION_LEN as u16),
+                Metadata::new(Properties::new().read()),
+            )?
+            .build();
+        let mtu = service
+            .add_characteristic(
+                uuid(0x02),
+                Attribute::new([0u8]),
+                Metadata::new(Properties::new().read()),
+            )?
+            .build();
+        let control = service
+            .add_characteristic(
+                uuid(0x03),
+                Attribute::new([0u8]).write_security(WRITE_SECURITY),
+                Metadata::new(Properties::new().write()),
+            )?
+            .build();
+        let next_version = service
+            .add_characteristic(
+                uuid(0x04),
+                Attribute::new([0u8; 0])
+                    .variable_len(MAX_VERSION_LEN as u16)
+                    .write_security(WRITE_SECURITY),
+                Metadata::new(Properties::new().write().read()),
+            )?
+            .build();
+        let offset = service
+            .add_characteristic(
+                uuid(0x05),
+                Attribute::new([0u8; 4]),
+                Metadata::new(Properties::new().read().notify()),
+            )?
+            .build();
+        let firmware = service
+            .add_characteristic(
+                uuid(0x06),
+                Attribute::new([0u8; 0])
+                    .variable_len(MAX_CHUNK_SIZE as u16)
+                    .write_security(WRITE_SECURITY),
+                Metadata::new(Properties::new().write().write_without_response()),
+            )?
+            .build();
+        let status = service
+            .add_characteristic(
+                uuid(0x07),
+                Attribute::new([0u8; 2]),
+                Metadata::new(Properties::new().read().notify()),
+            )?
+            .build();
+        service.build();
 
-    /// Version being written
-    #[characteristic(uuid = "00001004-b0cd-11ec-871f-d45ddf138840", write, read)]
-    next_version: Vec<u8, 16>,
+        Ok(Self {
+            version: version.value_handle,
+            mtu: mtu.value_handle,
+            control: control.value_handle,
+            next_version: next_version.value_handle,
+            offset: offset.value_handle,
+            offset_cccd: offset.cccd_handle,
+            firmware: firmware.value_handle,
+            status: status.value_handle,
+            status_cccd: status.cccd_handle,
+        })
+    }
 
-    /// Current write offset
-    #[characteristic(uuid = "00001005-b0cd-11ec-871f-d45ddf138840", read)]
-    offset: u32,
+    pub fn version_set(&self, version: &Vec<u8, MAX_VERSION_LEN>) -> Result<(), SetValueError> {
+        self.set(self.version, version)
+    }
 
-    /// Firmware data to be written
-    #[characteristic(uuid = "00001006-b0cd-11ec-871f-d45ddf138840", write)]
-    firmware: Vec<u8, 64>,
+    pub fn mtu_set(&self, mtu: &u8) -> Result<(), SetValueError> {
+        self.set(self.mtu, &[*mtu])
+    }
+
+    pub fn next_version_get(&self) -> Result<Vec<u8, MAX_VERSION_LEN>, GetValueError> {
+        let mut data = [0; MAX_VERSION_LEN];
+        let sd = unsafe { Softdevice::steal() };
+        let len = gatt_server::get_value(sd, self.next_version, &mut data)?;
+        Ok(data[..len].iter().copied().collect())
+    }
+
+    pub fn next_version_set(
+        &self,
+        version: &Vec<u8, MAX_VERSION_LEN>,
+    ) -> Result<(), SetValueError> {
+        self.set(self.next_version, version)
+    }
+
+    pub fn offset_set(&self, offset: &u32) -> Result<(), SetValueError> {
+        self.set(self.offset, &offset.to_le_bytes())
+    }
+
+    pub fn offset_notify(
+        &self,
+        connection: &Connection,
+        offset: &u32,
+    ) -> Result<(), NotifyValueError> {
+        gatt_server::notify_value(connection, self.offset, &offset.to_le_bytes())
+    }
+
+    pub fn status_set(&self, status: &[u8; 2]) -> Result<(), SetValueError> {
+        self.set(self.status, status)
+    }
+
+    pub fn status_notify(
+        &self,
+        connection: &Connection,
+        status: &[u8; 2],
+    ) -> Result<(), NotifyValueError> {
+        gatt_server::notify_value(connection, self.status, status)
+    }
+
+    fn set(&self, handle: u16, data: &[u8]) -> Result<(), SetValueError> {
+        let sd = unsafe { Softdevice::steal() };
+        gatt_server::set_value(sd, handle, data)
+    }
+}
+
+impl Service for FirmwareService {
+    type Event = FirmwareServiceEvent;
+
+    fn on_write(&self, handle: u16, data: &[u8]) -> Option<Self::Event> {
+        let notifications = || data.first().map_or(false, |cccd| cccd & 0x01 != 0);
+        if handle == self.control {
+            data.first()
+                .map(|value| FirmwareServiceEvent::ControlWrite(*value))
+        } else if handle == self.next_version {
+            Vec::from_slice(data)
+                .ok()
+                .map(FirmwareServiceEvent::NextVersionWrite)
+        } else if handle == self.firmware {
+            Vec::from_slice(data)
+                .ok()
+                .map(FirmwareServiceEvent::FirmwareWrite)
+        } else if handle == self.offset_cccd {
+            Some(FirmwareServiceEvent::OffsetCccdWrite {
+                notifications: notifications(),
+            })
+        } else if handle == self.status_cccd {
+            Some(FirmwareServiceEvent::StatusCccdWrite {
+                notifications: notifications(),
+            })
+        } else {
+            None
+        }
+    }
 }
 
-pub struct FirmwareGattService<'a, F>
+pub struct FirmwareGattService<'a, F, R = SystemReset>
 where
     F: FirmwareDevice + 'static,
+    R: ResetHook,
 {
     service: &'a FirmwareService,
-    dfu: F,
+    protocol: DfuProtocol<F, R>,
+    status: DfuStatus,
+    notify_offset: bool,
+    notify_status: bool,
+    #[cfg(feature = "ble-security")]
+    security: SecurityLevel,
 }
 
 impl<'a, F> FirmwareGattService<'a, F>
 where
     F: FirmwareDevice,
 {
-    pub fn new(service: &'a FirmwareService, dfu: F, version: &[u8], mtu: u8) -> Result<Self, ()> {
+    /// Create a service resetting the device after marking the firmware for update, with firmware
+    /// chunks sized for the ATT MTU `att_mtu` configured for the softdevice.
+    pub fn new(
+        service: &'a FirmwareService,
+        dfu: F,
+        version: &[u8],
+        att_mtu: u16,
+    ) -> Result<Self, ()> {
+        Self::new_with_reset(service, dfu, SystemReset, version, att_mtu)
+    }
+}
+
+impl<'a, F, R> FirmwareGattService<'a, F, R>
+where
+    F: FirmwareDevice,
+    R: ResetHook,
+{
+    /// Create a service calling `reset` after marking the firmware for update.
+    pub fn new_with_reset(
+        service: &'a FirmwareService,
+        dfu: F,
+        reset: R,
+        version: &[u8],
+        att_mtu: u16,
+    ) -> Result<Self, ()> {
         service
             .version_set(&Vec::from_slice(version)?)
             .map_err(|_| ())?;
         service.next_version_set(&Vec::new()).map_err(|_| ())?;
         service.offset_set(&0).map_err(|_| ())?;
-        service.mtu_set(&mtu).map_err(|_| ())?;
-        Ok(Self { service, dfu })
+        service
+            .mtu_set(&(DfuProtocol::<F, R>::chunk_size(att_mtu) as u8))
+            .map_err(|_| ())?;
+        service
+            .status_set(&DfuStatus::Idle.to_gatt())
+            .map_err(|_| ())?;
+        Ok(Self {
+            service,
+            protocol: DfuProtocol::new(dfu, reset),
+            status: DfuStatus::Idle,
+            notify_offset: false,
+            notify_status: false,
+            #[cfg(feature = "ble-security")]
+            security: SecurityLevel::Open,
+        })
+    }
+
+    /// Require a link of at least `level` to write the version, control and firmware
+    /// characteristics. The stack already rejects writes over unencrypted links; a higher level,
+    /// e.g. [`SecurityLevel::Authenticated`], is checked on every write. Writes over a less secure
+    /// link fail with [`DfuError::InsufficientSecurity`], leaving the update in progress as is.
+    #[cfg(feature = "ble-security")]
+    pub fn security(mut self, level: SecurityLevel) -> Self {
+        self.security = level;
+        self
     }
 
-    pub async fn handle(&mut self, event: &FirmwareServiceEvent) -> Result<(), ()> {
-        match event {
+    /// Update the chunk size for the ATT MTU negotiated with the client, as read from
+    /// [`Connection::att_mtu`] when connected and after the client exchanged the MTU.
+    pub fn set_att_mtu(&mut self, att_mtu: u16) {
+        let chunk_size = DfuProtocol::<F, R>::chunk_size(att_mtu);
+        debug!("Firmware chunk size is {} bytes", chunk_size);
+        self.service.mtu_set(&(chunk_size as u8)).ok();
+    }
+
+    /// Handle an event of the service on `connection`, notifying the client of offset and status
+    /// changes when subscribed.
+    pub async fn handle(
+        &mut self,
+        connection: &Connection,
+        event: &FirmwareServiceEvent,
+    ) -> Result<(), DfuError<F::Error>> {
+        let result = match event {
+            #[cfg(feature = "ble-security")]
+            FirmwareServiceEvent::ControlWrite(_)
+            | FirmwareServiceEvent::FirmwareWrite(_)
+            | FirmwareServiceEvent::NextVersionWrite(_)
+                if security_level(connection) < self.security =>
+            {
+                // Leave the update of another connection undisturbed
+                warn!("Firmware write over insecure link rejected");
+                if let Ok(version) = Vec::from_slice(self.protocol.next_version()) {
+                    self.service.next_version_set(&version).ok();
+                }
+                return Err(DfuError::InsufficientSecurity);
+            }
+            FirmwareServiceEvent::NextVersionWrite(version) => {
+                self.protocol.set_next_version(version)
+            }
             FirmwareServiceEvent::ControlWrite(value) => {
-                info!("Write firmware control: {}", value);
-                let next_version = self.service.next_version_get().unwrap();
-                if *value == 1 {
-                    self.service.offset_set(&0).ok();
-                    self.dfu.start(&next_version[..]).await.map_err(|_| ())?;
-                } else if *value == 2 {
-                    let r = self.dfu.update(&next_version[..], &[]).await;
-                    if let Err(_e) = r {
-                        warn!("Update error");
-                    } else {
-                        cortex_m::peripheral::SCB::sys_reset();
-                    }
-                } else if *value == 3 {
-                    self.dfu.synced().await.map_or(Err(()), |_| Ok(()))?;
+                if *value == CONTROL_UPDATE {
+                    // The device resets once the update is marked
+                    self.publish_status(connection, DfuStatus::Verifying);
                 }
+                self.protocol.control(*value).await
+            }
+            FirmwareServiceEvent::FirmwareWrite(value) => self.protocol.write(value).await,
+            FirmwareServiceEvent::OffsetCccdWrite { notifications } => {
+                self.notify_offset = *notifications;
+                return Ok(());
             }
-            FirmwareServiceEvent::FirmwareWrite(value) => {
-                let offset = self.service.offset_get().unwrap();
-                self.dfu
-                    .write(offset, value)
-                    .await
-                    .map_or(Err(()), |_| Ok(()))?;
-                self.service.offset_set(&(offset + value.len() as u32)).ok();
+            FirmwareServiceEvent::StatusCccdWrite { notifications } => {
+                self.notify_status = *notifications;
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+
+        let offset = self.protocol.offset();
+        self.service.offset_set(&offset).ok();
+        if self.notify_offset {
+            self.service.offset_notify(connection, &offset).ok();
+        }
+        self.publish_status(connection, self.protocol.status());
+        result
+    }
+
+    fn publish_status(&mut self, connection: &Connection, status: DfuStatus) {
+        if status != self.status {
+            self.status = status;
+            self.service.status_set(&status.to_gatt()).ok();
+            if self.notify_status {
+                self.service
+                    .status_notify(connection, &status.to_gatt())
+                    .ok();
             }
-            _ => {}
         }
-        Ok(())
     }
 }
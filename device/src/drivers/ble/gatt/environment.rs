@@ -1,317 +1,401 @@
-use heapless::Vec;
+pub use crate::drivers::ble::environment::*;
+use {
+    embassy_time::Instant,
+    nrf_softdevice::{
+        ble::{
+            gatt_server::{
+                self,
+                builder::ServiceBuilder,
+                characteristic::{Attribute, Metadata, Properties},
+                DeferredWriteReply, RegisterError, Service, WriteOp,
+            },
+            Connection, GattError, SecurityMode, Uuid,
+        },
+        Softdevice,
+    },
+};
 
-#[nrf_softdevice::gatt_service(uuid = "181a")]
-pub struct EnvironmentSensingService {
-    #[characteristic(uuid = "290c", read)]
-    pub descriptor: Vec<u8, 11>,
-
-    #[characteristic(uuid = "290d", read)]
-    pub trigger: Vec<u8, 4>,
+const QUANTITIES: [Quantity; MAX_MEASUREMENTS] = [
+    Quantity::Temperature,
+    Quantity::Humidity,
+    Quantity::Pressure,
+    Quantity::Illuminance,
+];
 
-    #[characteristic(uuid = "2a1f", read, notify)]
-    pub temperature: i16,
-
-    #[characteristic(uuid = "2a21", read, write)]
-    pub period: u8,
-}
+const ES_CONFIGURATION_UUID: u16 = 0x290B;
+const ES_MEASUREMENT_UUID: u16 = 0x290C;
+const ES_TRIGGER_SETTING_UUID: u16 = 0x290D;
 
-pub struct MeasurementDescriptor {
-    pub flags: u16,
-    pub sampling_fn: SamplingFunction,
-    pub measurement_period: Period,
-    pub update_interval: Interval,
-    pub application: MeasurementApp,
-    pub uncertainty: Uncertainty,
-}
-
-impl Default for MeasurementDescriptor {
-    fn default() -> Self {
-        Self {
-            flags: 0,
-            sampling_fn: SamplingFunction::Unspecified,
-            measurement_period: Period::Unknown,
-            update_interval: Interval::Unknown,
-            application: MeasurementApp::Unspecified,
-            uncertainty: Uncertainty::Unknown,
-        }
+/// Characteristic UUID and value length of a measured quantity.
+fn characteristic(quantity: Quantity) -> (u16, usize) {
+    match quantity {
+        // Temperature in 0.1 °C
+        Quantity::Temperature => (0x2A1F, 2),
+        // Relative humidity in 0.01 %
+        Quantity::Humidity => (0x2A6F, 2),
+        // Pressure in 0.1 Pa
+        Quantity::Pressure => (0x2A6D, 4),
+        // Illuminance in 0.01 lux, as a 24-bit value
+        Quantity::Illuminance => (0x2AFB, 3),
     }
 }
 
-#[derive(Copy, Clone)]
-#[repr(u8)]
-pub enum SamplingFunction {
-    Unspecified = 0x00,
-    Instantaneous = 0x01,
-    ArithmeticMean = 0x02,
-    RMS = 0x03,
-    Max = 0x04,
-    Min = 0x05,
-    Accum = 0x06,
-    Count = 0x07,
+/// Attribute handles of a measured characteristic and its descriptors.
+#[derive(Clone, Copy)]
+struct MeasurementHandles {
+    value: u16,
+    cccd: u16,
+    descriptor: u16,
+    triggers: [u16; MAX_TRIGGER_SETTINGS],
+    configuration: u16,
 }
 
-impl From<u8> for SamplingFunction {
-    fn from(value: u8) -> Self {
-        match value {
-            0x01 => Self::Instantaneous,
-            0x02 => Self::ArithmeticMean,
-            0x03 => Self::RMS,
-            0x04 => Self::Max,
-            0x05 => Self::Min,
-            0x06 => Self::Accum,
-            0x07 => Self::Count,
-            _ => Self::Unspecified,
-        }
-    }
+/// Environmental Sensing Service, with ES Measurement, ES Trigger Setting and ES Configuration
+/// descriptors on each measured characteristic.
+///
+/// Clients may write the trigger settings and configuration of a characteristic, which apply to
+/// the connections handled by an [`EnvironmentGattService`] from then on. The writes are
+/// authorized by the service, which rejects invalid values with an ATT error and keeps the
+/// previous ones.
+///
+/// The `gatt_service` macro can only declare characteristics, so the service is registered
+/// with the service builder of the softdevice to attach the descriptors.
+pub struct EnvironmentSensingService {
+    measurements: [MeasurementHandles; MAX_MEASUREMENTS],
+    period: u16,
 }
 
-#[derive(Copy, Clone)]
-pub enum Period {
-    Unknown,
-    Value(u32),
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EnvironmentSensingServiceEvent {
+    /// The client subscribed to or unsubscribed from the characteristic of `quantity`.
+    CccdWrite {
+        quantity: Quantity,
+        notifications: bool,
+    },
+    /// The client wrote the trigger setting at `index` of the characteristic of `quantity`.
+    TriggerWrite {
+        quantity: Quantity,
+        index: usize,
+        setting: TriggerSetting,
+    },
+    /// The client wrote the configuration of the characteristic of `quantity`.
+    ConfigurationWrite {
+        quantity: Quantity,
+        configuration: Configuration,
+    },
+    /// The client wrote the measurement period in seconds.
+    PeriodWrite(u8),
 }
 
-impl Period {
-    fn to_gatt(&self) -> [u8; 3] {
-        match self {
-            Self::Unknown => [0, 0, 0],
-            Self::Value(val) => {
-                let val = val.to_le_bytes();
-                [val[1], val[2], val[3]]
+impl EnvironmentSensingService {
+    pub fn new(sd: &mut Softdevice) -> Result<Self, RegisterError> {
+        let mut service = ServiceBuilder::new(sd, Uuid::new_16(0x181A))?;
+        let mut measurements = [MeasurementHandles {
+            value: 0,
+            cccd: 0,
+            descriptor: 0,
+            triggers: [0; MAX_TRIGGER_SETTINGS],
+            configuration: 0,
+        }; MAX_MEASUREMENTS];
+        for (quantity, handles) in QUANTITIES.iter().zip(measurements.iter_mut()) {
+            let (uuid, len) = characteristic(*quantity);
+            let mut builder = service.add_characteristic(
+                Uuid::new_16(uuid),
+                Attribute::new(&[0u8; 4][..len]),
+                Metadata::new(Properties::new().read().notify()),
+            )?;
+            let descriptor = builder.add_descriptor(
+                Uuid::new_16(ES_MEASUREMENT_UUID),
+                Attribute::new(MeasurementDescriptor::default().to_vec().unwrap())
+                    .write_security(SecurityMode::NoAccess),
+            )?;
+            // Characteristics without an active trigger setting are notified on every measurement
+            let inactive = TriggerSetting::Inactive.to_vec().unwrap();
+            let mut triggers = [0; MAX_TRIGGER_SETTINGS];
+            for trigger in triggers.iter_mut() {
+                *trigger = builder
+                    .add_descriptor(
+                        Uuid::new_16(ES_TRIGGER_SETTING_UUID),
+                        Attribute::new(inactive.clone())
+                            .variable_len(MAX_TRIGGER_SETTING_LEN as u16)
+                            .deferred_write(),
+                    )?
+                    .handle();
             }
+            let configuration = builder.add_descriptor(
+                Uuid::new_16(ES_CONFIGURATION_UUID),
+                Attribute::new([Configuration::default().to_gatt()]).deferred_write(),
+            )?;
+            let built = builder.build();
+            *handles = MeasurementHandles {
+                value: built.value_handle,
+                cccd: built.cccd_handle,
+                descriptor: descriptor.handle(),
+                triggers,
+                configuration: configuration.handle(),
+            };
         }
+        let period = service
+            .add_characteristic(
+                Uuid::new_16(0x2A21),
+                Attribute::new([0u8]),
+                Metadata::new(Properties::new().read().write()),
+            )?
+            .build();
+        service.build();
+
+        Ok(Self {
+            measurements,
+            period: period.value_handle,
+        })
     }
-}
 
-impl From<u32> for Period {
-    fn from(value: u32) -> Self {
-        match value {
-            0 => Self::Unknown,
-            v => Self::Value(v),
-        }
+    /// Set the measurement descriptor and first trigger setting of the characteristic of
+    /// `quantity`.
+    pub fn configure(
+        &self,
+        quantity: Quantity,
+        descriptor: &MeasurementDescriptor,
+        trigger: &TriggerSetting,
+    ) -> Result<(), ()> {
+        let descriptor = descriptor.to_vec().map_err(|_| ())?;
+        self.set(self.measurements[quantity as usize].descriptor, &descriptor)
+            .map_err(|_| ())?;
+        self.trigger_set(quantity, 0, Some(trigger))
     }
-}
 
-#[derive(Copy, Clone)]
-pub enum Interval {
-    Unknown,
-    Value(u32),
-}
+    /// The trigger setting at `index` of the characteristic of `quantity`, if one is set.
+    pub fn trigger_setting(&self, quantity: Quantity, index: usize) -> Option<TriggerSetting> {
+        let mut data = [0; MAX_TRIGGER_SETTING_LEN];
+        let handle = self.measurements[quantity as usize].triggers[index];
+        let len = self.get(handle, &mut data).ok()?;
+        TriggerSetting::from_slice(&data[..len], quantity).ok()
+    }
 
-impl Interval {
-    fn to_gatt(&self) -> [u8; 3] {
-        match self {
-            Self::Unknown => [0, 0, 0],
-            Self::Value(val) => {
-                let val = val.to_le_bytes();
-                [val[1], val[2], val[3]]
-            }
-        }
+    /// Set the trigger setting at `index` of the characteristic of `quantity`, or make it
+    /// inactive.
+    pub fn trigger_set(
+        &self,
+        quantity: Quantity,
+        index: usize,
+        setting: Option<&TriggerSetting>,
+    ) -> Result<(), ()> {
+        let data = setting
+            .copied()
+            .unwrap_or_default()
+            .to_vec()
+            .map_err(|_| ())?;
+        let handle = self.measurements[quantity as usize].triggers[index];
+        self.set(handle, &data).map_err(|_| ())
     }
-}
 
-impl From<u32> for Interval {
-    fn from(value: u32) -> Self {
-        match value {
-            0 => Self::Unknown,
-            v => Self::Value(v),
+    /// The configuration combining the trigger settings of the characteristic of `quantity`.
+    pub fn configuration(&self, quantity: Quantity) -> Configuration {
+        let mut data = [0];
+        let handle = self.measurements[quantity as usize].configuration;
+        match self.get(handle, &mut data) {
+            Ok(len) => Configuration::from_slice(&data[..len]).unwrap_or_default(),
+            Err(_) => Configuration::default(),
         }
     }
-}
 
-#[derive(Copy, Clone)]
-#[repr(u8)]
-pub enum MeasurementApp {
-    Unspecified = 0x00,
-    Air = 0x01,
-    Water = 0x02,
-    Barometric = 0x03,
-    Soil = 0x04,
-    Infrared = 0x05,
-    MapDatabase = 0x06,
-    BarometricElevation = 0x07,
-    GpsElevation = 0x08,
-    GpsMapElevation = 0x09,
-    VerticalDatumElevation = 0x0A,
-    Onshore = 0x0B,
-    OnboardVessel = 0x0C,
-    Front = 0x0D,
-    Back = 0x0E,
-    Upper = 0x0F,
-    Lower = 0x10,
-    Primary = 0x11,
-    Secondary = 0x12,
-    Outdoor = 0x13,
-    Indoor = 0x14,
-    Top = 0x15,
-    Bottom = 0x16,
-    Main = 0x17,
-    Backup = 0x18,
-    Auxiliary = 0x19,
-    Supplementary = 0x1A,
-    Inside = 0x1B,
-    Outside = 0x1C,
-    Left = 0x1D,
-    Right = 0x1E,
-    Internal = 0x1F,
-    External = 0x20,
-    Solar = 0x21,
-}
+    pub fn configuration_set(
+        &self,
+        quantity: Quantity,
+        configuration: &Configuration,
+    ) -> Result<(), ()> {
+        let handle = self.measurements[quantity as usize].configuration;
+        self.set(handle, &[configuration.to_gatt()]).map_err(|_| ())
+    }
 
-impl From<u8> for MeasurementApp {
-    fn from(value: u8) -> Self {
-        match value {
-            0x01 => Self::Air,
-            0x02 => Self::Water,
-            0x03 => Self::Barometric,
-            0x04 => Self::Soil,
-            0x05 => Self::Infrared,
-            0x06 => Self::MapDatabase,
-            0x07 => Self::BarometricElevation,
-            0x08 => Self::GpsElevation,
-            0x09 => Self::GpsMapElevation,
-            0x0A => Self::VerticalDatumElevation,
-            0x0B => Self::Onshore,
-            0x0C => Self::OnboardVessel,
-            0x0D => Self::Front,
-            0x0E => Self::Back,
-            0x0F => Self::Upper,
-            0x10 => Self::Lower,
-            0x11 => Self::Primary,
-            0x12 => Self::Secondary,
-            0x13 => Self::Outdoor,
-            0x14 => Self::Indoor,
-            0x15 => Self::Top,
-            0x16 => Self::Bottom,
-            0x17 => Self::Main,
-            0x18 => Self::Backup,
-            0x19 => Self::Auxiliary,
-            0x1A => Self::Supplementary,
-            0x1B => Self::Inside,
-            0x1C => Self::Outside,
-            0x1D => Self::Left,
-            0x1E => Self::Right,
-            0x1F => Self::Internal,
-            0x20 => Self::External,
-            0x21 => Self::Solar,
-            _ => Self::Unspecified,
+    /// The trigger settings of the characteristic of `quantity`.
+    pub fn triggers(&self, quantity: Quantity) -> Triggers {
+        let mut settings = [None; MAX_TRIGGER_SETTINGS];
+        for (index, setting) in settings.iter_mut().enumerate() {
+            *setting = self.trigger_setting(quantity, index);
         }
+        Triggers::new(settings, self.configuration(quantity))
     }
-}
 
-#[derive(Copy, Clone)]
-pub enum Uncertainty {
-    Value(u8),
-    Unknown,
-}
+    /// Set the value of the characteristic of the measurement.
+    pub fn measurement_set(&self, measurement: &Measurement) -> Result<(), ()> {
+        let handle = self.measurements[measurement.quantity() as usize].value;
+        self.set(handle, &measurement.to_gatt()).map_err(|_| ())
+    }
 
-impl Uncertainty {
-    fn to_gatt(&self) -> u8 {
-        match self {
-            Self::Unknown => 0,
-            Self::Value(val) => *val,
-        }
+    /// Notify the client of the measurement on `connection`.
+    pub fn measurement_notify(
+        &self,
+        connection: &Connection,
+        measurement: &Measurement,
+    ) -> Result<(), ()> {
+        let handle = self.measurements[measurement.quantity() as usize].value;
+        gatt_server::notify_value(connection, handle, &measurement.to_gatt()).map_err(|_| ())
     }
-}
 
-impl From<u8> for Uncertainty {
-    fn from(value: u8) -> Self {
-        match value {
-            0 => Self::Unknown,
-            v => Self::Value(v),
-        }
+    /// The measurement period in seconds.
+    pub fn period_get(&self) -> Result<u8, ()> {
+        let mut data = [0];
+        self.get(self.period, &mut data).map_err(|_| ())?;
+        Ok(data[0])
+    }
+
+    pub fn period_set(&self, period: &u8) -> Result<(), ()> {
+        self.set(self.period, &[*period]).map_err(|_| ())
     }
-}
 
-impl MeasurementDescriptor {
-    pub fn from_slice(data: &[u8]) -> Self {
-        let flags = u16::from_le_bytes([data[0], data[1]]);
-        let sampling_fn = data[2].into();
-        let measurement_period = u32::from_le_bytes([0, data[3], data[4], data[5]]);
-        let measurement_period = if measurement_period == 0 {
-            Period::Unknown
-        } else {
-            Period::Value(measurement_period)
-        };
+    fn get(&self, handle: u16, data: &mut [u8]) -> Result<usize, gatt_server::GetValueError> {
+        let sd = unsafe { Softdevice::steal() };
+        gatt_server::get_value(sd, handle, data)
+    }
 
-        let update_interval = u32::from_le_bytes([0, data[6], data[7], data[8]]);
-        let update_interval = if update_interval == 0 {
-            Interval::Unknown
-        } else {
-            Interval::Value(update_interval)
-        };
+    fn set(&self, handle: u16, data: &[u8]) -> Result<(), gatt_server::SetValueError> {
+        let sd = unsafe { Softdevice::steal() };
+        gatt_server::set_value(sd, handle, data)
+    }
+}
 
-        let application = data[9].into();
-        let uncertainty = data[10].into();
+impl Service for EnvironmentSensingService {
+    type Event = EnvironmentSensingServiceEvent;
 
-        Self {
-            flags,
-            sampling_fn,
-            measurement_period,
-            update_interval,
-            application,
-            uncertainty,
+    fn on_write(&self, handle: u16, data: &[u8]) -> Option<Self::Event> {
+        if handle == self.period {
+            return data
+                .first()
+                .map(|period| EnvironmentSensingServiceEvent::PeriodWrite(*period));
+        }
+        for (quantity, handles) in QUANTITIES.iter().zip(self.measurements.iter()) {
+            let quantity = *quantity;
+            if handle == handles.cccd {
+                return Some(EnvironmentSensingServiceEvent::CccdWrite {
+                    quantity,
+                    notifications: data.first().map_or(false, |cccd| cccd & 0x01 != 0),
+                });
+            }
         }
+        None
     }
 
-    pub fn to_vec(&self) -> Vec<u8, 11> {
-        let flags = self.flags.to_le_bytes();
-        let sampling_fn = self.sampling_fn as u8;
-        let measurement_period = self.measurement_period.to_gatt();
-        let update_interval = self.update_interval.to_gatt();
-        let application = self.application as u8;
-        let uncertainty = self.uncertainty.to_gatt();
-
-        Vec::from_slice(&[
-            flags[0],
-            flags[1],
-            sampling_fn,
-            measurement_period[0],
-            measurement_period[1],
-            measurement_period[2],
-            update_interval[0],
-            update_interval[1],
-            update_interval[2],
-            application,
-            uncertainty,
-        ])
-        .unwrap()
+    fn on_deferred_write(
+        &self,
+        handle: u16,
+        _op: WriteOp,
+        offset: usize,
+        data: &[u8],
+        reply: DeferredWriteReply,
+    ) -> Option<Self::Event> {
+        for (quantity, handles) in QUANTITIES.iter().zip(self.measurements.iter()) {
+            let quantity = *quantity;
+            // The descriptors are short enough to never be written in parts
+            let data = if offset == 0 { data } else { &[][..] };
+            let event = if handle == handles.configuration {
+                Configuration::from_slice(data).map(|configuration| {
+                    EnvironmentSensingServiceEvent::ConfigurationWrite {
+                        quantity,
+                        configuration,
+                    }
+                })
+            } else if let Some(index) = handles.triggers.iter().position(|h| *h == handle) {
+                TriggerSetting::from_slice(data, quantity).map(|setting| {
+                    EnvironmentSensingServiceEvent::TriggerWrite {
+                        quantity,
+                        index,
+                        setting,
+                    }
+                })
+            } else {
+                continue;
+            };
+            return match event {
+                Ok(event) => {
+                    reply.reply(Ok(Some(data))).ok();
+                    Some(event)
+                }
+                Err(e) => {
+                    warn!("Rejected invalid descriptor of {:?}: {:?}", quantity, e);
+                    let error = match e {
+                        DescriptorError::InvalidLength => GattError::AtterrInvalidAttValLength,
+                        _ => GattError::AtterrCpsOutOfRange,
+                    };
+                    reply.reply(Err(error)).ok();
+                    None
+                }
+            };
+        }
+        None
     }
 }
 
-pub enum TriggerSetting {
-    Inactive,
-    FixedInterval(u32),
-    ValueChanged,
+/// Publishes measurements of the [`EnvironmentSensingService`] on a connection, notifying the
+/// characteristics the client subscribed to when their trigger conditions are met.
+pub struct EnvironmentGattService<'a> {
+    service: &'a EnvironmentSensingService,
+    notify: [bool; MAX_MEASUREMENTS],
+    triggers: [Triggers; MAX_MEASUREMENTS],
 }
 
-impl Default for TriggerSetting {
-    fn default() -> Self {
-        Self::Inactive
+impl<'a> EnvironmentGattService<'a> {
+    pub fn new(service: &'a EnvironmentSensingService) -> Self {
+        Self {
+            service,
+            notify: [false; MAX_MEASUREMENTS],
+            triggers: QUANTITIES.map(|q| service.triggers(q)),
+        }
     }
-}
 
-impl TriggerSetting {
-    pub fn from_slice(data: &[u8]) -> Self {
-        match data[0] {
-            0x01 => {
-                let interval = u32::from_le_bytes([0, data[1], data[2], data[3]]);
-                Self::FixedInterval(interval)
+    /// Track the subscriptions of the client and apply the trigger settings and configurations
+    /// it writes. Other events are left to the application.
+    pub fn handle(&mut self, event: &EnvironmentSensingServiceEvent) {
+        match *event {
+            EnvironmentSensingServiceEvent::CccdWrite {
+                quantity,
+                notifications,
+            } => {
+                self.notify[quantity as usize] = notifications;
             }
-            0x03 => Self::ValueChanged,
-            _ => Self::Inactive,
+            EnvironmentSensingServiceEvent::TriggerWrite {
+                quantity,
+                index,
+                setting,
+            } => {
+                debug!("Trigger setting {} of {:?}: {:?}", index, quantity, setting);
+                self.triggers[quantity as usize].set(index, setting);
+            }
+            EnvironmentSensingServiceEvent::ConfigurationWrite {
+                quantity,
+                configuration,
+            } => {
+                self.triggers[quantity as usize].set_configuration(configuration);
+            }
+            EnvironmentSensingServiceEvent::PeriodWrite(_) => {}
         }
     }
 
-    pub fn to_vec(&self) -> Vec<u8, 4> {
-        match self {
-            Self::Inactive => Vec::from_slice(&[0x00]),
-            Self::FixedInterval(val) => {
-                let val = val.to_le_bytes();
-                Vec::from_slice(&[0x02, val[1], val[2], val[3]])
-            }
-            Self::ValueChanged => Vec::from_slice(&[0x03]),
+    /// Set the value of the characteristic of the measurement, and notify it if subscribed and
+    /// its trigger condition is met.
+    pub fn publish(&mut self, connection: &Connection, measurement: Measurement) {
+        let index = measurement.quantity() as usize;
+        let notify =
+            self.notify[index] && self.triggers[index].evaluate(measurement, Instant::now());
+        self.service.measurement_set(&measurement).ok();
+        if notify {
+            self.service
+                .measurement_notify(connection, &measurement)
+                .ok();
+        }
+    }
+
+    /// Measure all quantities of `sensor` and publish them.
+    pub async fn update<S: EnvironmentSensor>(
+        &mut self,
+        connection: &Connection,
+        sensor: &mut S,
+    ) -> Result<(), S::Error> {
+        for measurement in sensor.measure().await? {
+            trace!("Publishing {:?}", measurement);
+            self.publish(connection, measurement);
         }
-        .unwrap()
+        Ok(())
     }
 }
@@ -0,0 +1,218 @@
+pub use crate::drivers::ble::microbit::*;
+use {heapless::Vec, nrf_softdevice::ble::Connection};
+
+#[nrf_softdevice::gatt_service(uuid = "e95dd91d-251d-470a-a062-fa1922dfa9a8")]
+pub struct LedService {
+    #[characteristic(uuid = "e95d7b77-251d-470a-a062-fa1922dfa9a8", read, write)]
+    pub matrix: [u8; LED_ROWS],
+    #[characteristic(uuid = "e95d93ee-251d-470a-a062-fa1922dfa9a8", write)]
+    pub text: Vec<u8, MAX_LED_TEXT_LEN>,
+    /// Delay between shifts of scrolling text in ms
+    #[characteristic(uuid = "e95d0d2d-251d-470a-a062-fa1922dfa9a8", read, write)]
+    pub scrolling_delay: u16,
+}
+
+impl LedService {
+    pub fn initialize(&self) -> Result<(), ()> {
+        self.matrix_set(&[0; LED_ROWS]).map_err(|_| ())?;
+        self.scrolling_delay_set(&DEFAULT_SCROLLING_DELAY)
+            .map_err(|_| ())
+    }
+
+    /// The command to the LED matrix written by the client, if any.
+    pub fn handle(&self, event: &LedServiceEvent) -> Option<LedCommand> {
+        match event {
+            LedServiceEvent::MatrixWrite(rows) => Some(LedCommand::matrix(*rows)),
+            LedServiceEvent::TextWrite(text) => {
+                let delay = self
+                    .scrolling_delay_get()
+                    .unwrap_or(DEFAULT_SCROLLING_DELAY);
+                LedCommand::text(text, delay)
+            }
+            LedServiceEvent::ScrollingDelayWrite(_) => None,
+        }
+    }
+}
+
+#[nrf_softdevice::gatt_service(uuid = "e95d9882-251d-470a-a062-fa1922dfa9a8")]
+pub struct ButtonService {
+    #[characteristic(uuid = "e95dda90-251d-470a-a062-fa1922dfa9a8", read, notify)]
+    pub button_a: u8,
+    #[characteristic(uuid = "e95dda91-251d-470a-a062-fa1922dfa9a8", read, notify)]
+    pub button_b: u8,
+}
+
+/// Publishes the state of the buttons on a connection, notifying the client of changes when
+/// subscribed.
+pub struct ButtonGattService<'a> {
+    service: &'a ButtonService,
+    notify_a: bool,
+    notify_b: bool,
+}
+
+impl<'a> ButtonGattService<'a> {
+    pub fn new(service: &'a ButtonService) -> Self {
+        Self {
+            service,
+            notify_a: false,
+            notify_b: false,
+        }
+    }
+
+    pub fn handle(&mut self, event: &ButtonServiceEvent) {
+        match event {
+            ButtonServiceEvent::ButtonACccdWrite { notifications } => {
+                self.notify_a = *notifications;
+            }
+            ButtonServiceEvent::ButtonBCccdWrite { notifications } => {
+                self.notify_b = *notifications;
+            }
+        }
+    }
+
+    pub fn update(&self, connection: &Connection, button: ButtonId, state: ButtonState) {
+        let state = state as u8;
+        match button {
+            ButtonId::A => {
+                self.service.button_a_set(&state).ok();
+                if self.notify_a {
+                    self.service.button_a_notify(connection, &state).ok();
+                }
+            }
+            ButtonId::B => {
+                self.service.button_b_set(&state).ok();
+                if self.notify_b {
+                    self.service.button_b_notify(connection, &state).ok();
+                }
+            }
+        }
+    }
+}
+
+#[nrf_softdevice::gatt_service(uuid = "e95d0753-251d-470a-a062-fa1922dfa9a8")]
+pub struct AccelerometerService {
+    /// Acceleration along the X, Y and Z axes in milli-g
+    #[characteristic(uuid = "e95dca4b-251d-470a-a062-fa1922dfa9a8", read, notify)]
+    pub data: [u8; ACCELEROMETER_DATA_LEN],
+    /// Interval between measurements in ms
+    #[characteristic(uuid = "e95dfb24-251d-470a-a062-fa1922dfa9a8", read, write)]
+    pub period: u16,
+}
+
+impl AccelerometerService {
+    pub fn initialize(&self) -> Result<(), ()> {
+        self.data_set(&Acceleration::default().to_gatt())
+            .map_err(|_| ())?;
+        self.period_set(&DEFAULT_ACCELEROMETER_PERIOD)
+            .map_err(|_| ())
+    }
+}
+
+/// Publishes accelerometer measurements on a connection, notifying the client when subscribed.
+pub struct AccelerometerGattService<'a> {
+    service: &'a AccelerometerService,
+    notify: bool,
+}
+
+impl<'a> AccelerometerGattService<'a> {
+    pub fn new(service: &'a AccelerometerService) -> Self {
+        Self {
+            service,
+            notify: false,
+        }
+    }
+
+    /// Handle an event of the service, returning the period in ms written by the client rounded
+    /// to a supported period.
+    pub fn handle(&mut self, event: &AccelerometerServiceEvent) -> Option<u16> {
+        match event {
+            AccelerometerServiceEvent::DataCccdWrite { notifications } => {
+                self.notify = *notifications;
+                None
+            }
+            AccelerometerServiceEvent::PeriodWrite(period) => {
+                let period = accelerometer_period(*period);
+                self.service.period_set(&period).ok();
+                Some(period)
+            }
+        }
+    }
+
+    pub fn update(&self, connection: &Connection, acceleration: &Acceleration) {
+        let data = acceleration.to_gatt();
+        self.service.data_set(&data).ok();
+        if self.notify {
+            self.service.data_notify(connection, &data).ok();
+        }
+    }
+}
+
+/// UART service of the micro:bit. The UUIDs are those of the Nordic UART Service, but the roles of
+/// the TX and RX characteristics are swapped: the device sends data with TX and receives data
+/// with RX, and data sent by the device is indicated as well as notified.
+#[nrf_softdevice::gatt_service(uuid = "6e400001-b5a3-f393-e0a9-e50e24dcca9e")]
+pub struct UartService {
+    /// Data sent by the device
+    #[characteristic(uuid = "6e400002-b5a3-f393-e0a9-e50e24dcca9e", notify, indicate)]
+    pub tx: Vec<u8, MAX_UART_LEN>,
+    /// Data sent by the client
+    #[characteristic(
+        uuid = "6e400003-b5a3-f393-e0a9-e50e24dcca9e",
+        write,
+        write_without_response
+    )]
+    pub rx: Vec<u8, MAX_UART_LEN>,
+}
+
+/// Exchanges data with the client of a connection over the UART service.
+pub struct UartGattService<'a> {
+    service: &'a UartService,
+    notify: bool,
+    indicate: bool,
+}
+
+impl<'a> UartGattService<'a> {
+    pub fn new(service: &'a UartService) -> Self {
+        Self {
+            service,
+            notify: false,
+            indicate: false,
+        }
+    }
+
+    /// Handle an event of the service, returning the data sent by the client if any.
+    pub fn handle<'e>(&mut self, event: &'e UartServiceEvent) -> Option<&'e [u8]> {
+        match event {
+            UartServiceEvent::TxCccdWrite {
+                indications,
+                notifications,
+            } => {
+                self.notify = *notifications;
+                self.indicate = *indications;
+                None
+            }
+            UartServiceEvent::RxWrite(data) => Some(&data[..]),
+        }
+    }
+
+    /// Send `data` to the client in chunks of [`MAX_UART_LEN`] bytes, notified if the client
+    /// subscribed to notifications and indicated otherwise. Data is dropped when the client is not
+    /// subscribed.
+    ///
+    /// The client confirms each indication before the next one can be sent, so data longer than
+    /// [`MAX_UART_LEN`] bytes can only be sent to clients subscribed to notifications.
+    pub fn send(&self, connection: &Connection, data: &[u8]) -> Result<(), ()> {
+        if self.notify {
+            for chunk in data.chunks(MAX_UART_LEN) {
+                self.service
+                    .tx_notify(connection, &Vec::from_slice(chunk)?)
+                    .map_err(|_| ())?;
+            }
+        } else if self.indicate {
+            self.service
+                .tx_indicate(connection, &Vec::from_slice(data)?)
+                .map_err(|_| ())?;
+        }
+        Ok(())
+    }
+}
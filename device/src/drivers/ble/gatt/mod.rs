@@ -1,44 +1,15 @@
+pub mod battery;
+pub mod current_time;
 pub mod device_info;
 pub mod dfu;
 pub mod environment;
+pub mod microbit;
 pub mod temperature;
 
 // DROGUE GATT BASE UUID: "-b0cd-11ec-871f-d45ddf138840"
-#[cfg(feature = "ble-peripheral")]
-use nrf_softdevice::{raw, Softdevice};
+use {super::softdevice::SoftdeviceConfig, nrf_softdevice::Softdevice};
 
-/// Enable softdevice with a given name
-#[cfg(feature = "ble-peripheral")]
+/// Enable softdevice with a given name and the default [`SoftdeviceConfig`].
 pub fn enable_softdevice(name: &'static str) -> &'static Softdevice {
-    let config = nrf_softdevice::Config {
-        clock: Some(raw::nrf_clock_lf_cfg_t {
-            source: raw::NRF_CLOCK_LF_SRC_RC as u8,
-            rc_ctiv: 4,
-            rc_temp_ctiv: 2,
-            accuracy: 7,
-        }),
-        conn_gap: Some(raw::ble_gap_conn_cfg_t {
-            conn_count: 2,
-            event_length: 24,
-        }),
-        conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: 256 }),
-        gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
-            attr_tab_size: 32768,
-        }),
-        gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
-            adv_set_count: 1,
-            periph_role_count: 3,
-        }),
-        gap_device_name: Some(raw::ble_gap_cfg_device_name_t {
-            p_value: name.as_ptr() as *const u8 as _,
-            current_len: name.len() as u16,
-            max_len: name.len() as u16,
-            write_perm: unsafe { core::mem::zeroed() },
-            _bitfield_1: raw::ble_gap_cfg_device_name_t::new_bitfield_1(
-                raw::BLE_GATTS_VLOC_STACK as u8,
-            ),
-        }),
-        ..Default::default()
-    };
-    Softdevice::enable(&config)
+    SoftdeviceConfig::new(name).enable()
 }
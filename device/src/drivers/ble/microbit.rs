@@ -0,0 +1,236 @@
+//! Values of the micro:bit BLE profile
+//!
+//! The LED, button, accelerometer and UART services of the micro:bit profile let stock micro:bit
+//! apps draw on the LED matrix, scroll text and read the buttons and accelerometer of the board.
+use {
+    embassy_time::{Duration, Instant},
+    heapless::String,
+};
+
+/// Rows of the LED matrix, one byte each in the LED Matrix State characteristic.
+pub const LED_ROWS: usize = 5;
+/// Columns of the LED matrix, from bit 4 for the leftmost LED of a row to bit 0.
+pub const LED_COLS: usize = 5;
+/// Maximum length of text scrolled across the LED matrix.
+pub const MAX_LED_TEXT_LEN: usize = 20;
+/// Default delay between shifts of scrolling text in ms.
+pub const DEFAULT_SCROLLING_DELAY: u16 = 120;
+
+/// Maximum length of data of the UART characteristics.
+pub const MAX_UART_LEN: usize = 20;
+
+/// Length of an encoded Accelerometer Data characteristic.
+pub const ACCELEROMETER_DATA_LEN: usize = 6;
+/// Accelerometer periods in ms supported by the micro:bit.
+pub const ACCELEROMETER_PERIODS: [u16; 8] = [1, 2, 5, 10, 20, 80, 160, 640];
+/// Default accelerometer period in ms.
+pub const DEFAULT_ACCELEROMETER_PERIOD: u16 = 20;
+
+/// Time a button must be held to be long pressed.
+pub const LONG_PRESS: Duration = Duration::from_millis(1000);
+
+/// Command of the LED service to the LED matrix.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedCommand {
+    /// Show the LEDs set in each row.
+    Matrix([u8; LED_ROWS]),
+    /// Scroll text across the matrix within `duration`.
+    Text {
+        text: String<MAX_LED_TEXT_LEN>,
+        duration: Duration,
+    },
+}
+
+impl LedCommand {
+    /// Show the LEDs of a written LED Matrix State.
+    pub fn matrix(rows: [u8; LED_ROWS]) -> Self {
+        LedCommand::Matrix(rows.map(|row| row & 0x1F))
+    }
+
+    /// Scroll written LED Text, shifting it by one column every `delay` ms. Empty text and text
+    /// that is not UTF-8 is ignored.
+    pub fn text(text: &[u8], delay: u16) -> Option<Self> {
+        let text = core::str::from_utf8(text).ok().filter(|t| !t.is_empty())?;
+        let columns = (text.len() * LED_COLS) as u64;
+        Some(LedCommand::Text {
+            text: String::from(text),
+            duration: Duration::from_millis(delay as u64 * columns),
+        })
+    }
+}
+
+/// Button of the micro:bit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ButtonId {
+    A,
+    B,
+}
+
+/// State of a button as reported in the Button State characteristics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum ButtonState {
+    Released = 0,
+    Pressed = 1,
+    /// Held for at least [`LONG_PRESS`].
+    LongPressed = 2,
+}
+
+/// Follows the state of a button from its level, detecting long presses.
+#[derive(Default)]
+pub struct ButtonTracker {
+    pressed_at: Option<Instant>,
+    long_pressed: bool,
+}
+
+impl ButtonTracker {
+    pub const fn new() -> Self {
+        Self {
+            pressed_at: None,
+            long_pressed: false,
+        }
+    }
+
+    pub fn state(&self) -> ButtonState {
+        match self.pressed_at {
+            None => ButtonState::Released,
+            Some(_) if self.long_pressed => ButtonState::LongPressed,
+            Some(_) => ButtonState::Pressed,
+        }
+    }
+
+    /// Instant at which the held button becomes long pressed, to update it again then.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.state() {
+            ButtonState::Pressed => self.pressed_at.map(|at| at + LONG_PRESS),
+            _ => None,
+        }
+    }
+
+    /// Update the state with the button level at `now`, returning the new state if it changed.
+    pub fn update(&mut self, pressed: bool, now: Instant) -> Option<ButtonState> {
+        let state = self.state();
+        match self.pressed_at {
+            None if pressed => self.pressed_at = Some(now),
+            Some(_) if !pressed => {
+                self.pressed_at = None;
+                self.long_pressed = false;
+            }
+            Some(at) => self.long_pressed = now >= at + LONG_PRESS,
+            None => {}
+        }
+        Some(self.state()).filter(|s| *s != state)
+    }
+}
+
+/// Acceleration of the Accelerometer Data characteristic in milli-g.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Acceleration {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+impl Acceleration {
+    /// Acceleration measured in milli-g, saturated to the range of the characteristic.
+    pub fn from_milli_g(x: i32, y: i32, z: i32) -> Self {
+        let saturate = |v: i32| v.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        Self {
+            x: saturate(x),
+            y: saturate(y),
+            z: saturate(z),
+        }
+    }
+
+    pub fn to_gatt(&self) -> [u8; ACCELEROMETER_DATA_LEN] {
+        let (x, y, z) = (
+            self.x.to_le_bytes(),
+            self.y.to_le_bytes(),
+            self.z.to_le_bytes(),
+        );
+        [x[0], x[1], y[0], y[1], z[0], z[1]]
+    }
+}
+
+/// The supported accelerometer period closest to `period` ms.
+pub fn accelerometer_period(period: u16) -> u16 {
+    ACCELEROMETER_PERIODS
+        .iter()
+        .copied()
+        .min_by_key(|p| p.abs_diff(period))
+        .unwrap_or(DEFAULT_ACCELEROMETER_PERIOD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_led() {
+        assert_eq!(
+            LedCommand::Matrix([0x1F, 0x11, 0, 0x04, 0x0A]),
+            LedCommand::matrix([0xFF, 0x11, 0, 0x04, 0xEA])
+        );
+        assert_eq!(
+            Some(LedCommand::Text {
+                text: String::from("Hi!"),
+                duration: Duration::from_millis(1800),
+            }),
+            LedCommand::text(b"Hi!", 120)
+        );
+        assert_eq!(None, LedCommand::text(b"", 120));
+        assert_eq!(None, LedCommand::text(&[0xFF, 0xFE], 120));
+    }
+
+    #[test]
+    fn test_button() {
+        let mut button = ButtonTracker::new();
+        assert_eq!(None, button.update(false, Instant::from_millis(0)));
+        assert_eq!(None, button.deadline());
+
+        assert_eq!(
+            Some(ButtonState::Pressed),
+            button.update(true, Instant::from_millis(100))
+        );
+        assert_eq!(Some(Instant::from_millis(1100)), button.deadline());
+        assert_eq!(None, button.update(true, Instant::from_millis(500)));
+        assert_eq!(
+            Some(ButtonState::LongPressed),
+            button.update(true, Instant::from_millis(1100))
+        );
+        assert_eq!(None, button.deadline());
+        assert_eq!(
+            Some(ButtonState::Released),
+            button.update(false, Instant::from_millis(1500))
+        );
+
+        // A short press is released without being long pressed
+        button.update(true, Instant::from_millis(2000));
+        assert_eq!(
+            Some(ButtonState::Released),
+            button.update(false, Instant::from_millis(2100))
+        );
+    }
+
+    #[test]
+    fn test_accelerometer() {
+        let acceleration = Acceleration::from_milli_g(-1000, 40000, 1);
+        assert_eq!(
+            Acceleration {
+                x: -1000,
+                y: i16::MAX,
+                z: 1
+            },
+            acceleration
+        );
+        assert_eq!([0x18, 0xFC, 0xFF, 0x7F, 0x01, 0x00], acceleration.to_gatt());
+
+        assert_eq!(1, accelerometer_period(0));
+        assert_eq!(20, accelerometer_period(20));
+        assert_eq!(80, accelerometer_period(60));
+        assert_eq!(640, accelerometer_period(1000));
+    }
+}
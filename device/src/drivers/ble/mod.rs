@@ -1,2 +1,14 @@
+pub mod advertisement;
+#[cfg(feature = "ble-security")]
+pub mod bonder;
+pub mod current_time;
+pub mod dfu;
+pub mod environment;
 #[cfg(feature = "ble+softdevice")]
 pub mod gatt;
+pub mod microbit;
+pub mod security;
+#[cfg(feature = "ble+softdevice")]
+pub mod softdevice;
+#[cfg(feature = "ble+softdevice")]
+pub mod supervisor;
@@ -0,0 +1,459 @@
+//! Pairing, bonding and security levels of BLE links
+//!
+//! Bonds are kept in a [`BondStore`] and persisted in a flash region as fixed-size records, so
+//! bonded peers can reconnect with encryption after a reset. The store is independent of the BLE
+//! stack, which fills in keys on bonding and looks them up when a peer reconnects.
+//!
+//! Peers pair with legacy pairing, either with Just Works or with a passkey. LE Secure Connections
+//! and its numeric comparison are not supported.
+use {
+    embedded_storage_async::nor_flash::{AsyncNorFlash, AsyncReadNorFlash},
+    heapless::Vec,
+};
+
+/// Maximum length of the system attributes, such as CCCD values, kept for a bonded peer.
+pub const MAX_SYS_ATTRS_LEN: usize = 62;
+
+/// Length of a bond record in flash, including padding to a multiple of common write sizes.
+pub const BOND_RECORD_LEN: usize = 128;
+
+const RECORD_VALID: u8 = 0xB0;
+
+/// Address type of a resolvable private address, as encoded by the stack.
+const ADDR_TYPE_RANDOM_PRIVATE_RESOLVABLE: u8 = 2;
+
+/// Security of a link, in increasing order of protection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SecurityLevel {
+    /// No encryption.
+    Open,
+    /// Encrypted with unauthenticated pairing (Just Works).
+    Encrypted,
+    /// Encrypted with authenticated pairing, protecting against man-in-the-middle attacks.
+    Authenticated,
+}
+
+/// Input and output capabilities of the device, determining the pairing method.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IoCapabilities {
+    /// No input nor output, pairing with Just Works.
+    None,
+    /// Can display a passkey.
+    DisplayOnly,
+    /// Can ask the user to enter a passkey.
+    KeyboardOnly,
+    /// Can display a passkey and ask the user to enter one.
+    KeyboardDisplay,
+}
+
+/// Interaction with the user during pairing.
+pub trait PairingHandler {
+    fn io_capabilities(&self) -> IoCapabilities;
+
+    /// Whether to bond with a peer pairing now, e.g. only while the user put the device in
+    /// pairing mode. A peer pairing while bonding is refused gets an encrypted link for the
+    /// connection only and is not remembered.
+    fn can_bond(&self) -> bool;
+
+    /// Display `passkey` for the user to enter on the peer.
+    fn display_passkey(&self, _passkey: &[u8; 6]) {}
+
+    /// Ask the user for the passkey displayed by the peer, or `None` to abort pairing.
+    fn enter_passkey(&self) -> Option<[u8; 6]> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BondError {
+    /// The system attributes are longer than [`MAX_SYS_ATTRS_LEN`].
+    TooLarge,
+    /// The peer is not bonded.
+    NotFound,
+}
+
+/// Keys exchanged with a bonded peer.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Bond {
+    /// Encrypted diversifier identifying the long term key.
+    pub ediv: u16,
+    /// Random number identifying the long term key.
+    pub rand: [u8; 8],
+    /// Long term key.
+    pub ltk: [u8; 16],
+    /// Flags of the long term key, as encoded by the stack.
+    pub ltk_flags: u8,
+    /// Identity address type of the peer.
+    pub addr_type: u8,
+    /// Identity address of the peer.
+    pub addr: [u8; 6],
+    /// Identity resolving key of the peer.
+    pub irk: [u8; 16],
+    /// System attributes of the peer, restored on reconnection.
+    pub sys_attrs: Vec<u8, MAX_SYS_ATTRS_LEN>,
+}
+
+impl Bond {
+    fn encode(&self, buf: &mut [u8; BOND_RECORD_LEN]) {
+        buf.fill(0);
+        buf[0] = RECORD_VALID;
+        buf[1..3].copy_from_slice(&self.ediv.to_le_bytes());
+        buf[3..11].copy_from_slice(&self.rand);
+        buf[11..27].copy_from_slice(&self.ltk);
+        buf[27] = self.ltk_flags;
+        buf[28] = self.addr_type;
+        buf[29..35].copy_from_slice(&self.addr);
+        buf[35..51].copy_from_slice(&self.irk);
+        buf[51] = self.sys_attrs.len() as u8;
+        buf[52..52 + self.sys_attrs.len()].copy_from_slice(&self.sys_attrs);
+    }
+
+    /// Decode a record, returning `None` for erased or invalid records.
+    fn decode(buf: &[u8; BOND_RECORD_LEN]) -> Option<Self> {
+        let sys_attrs_len = buf[51] as usize;
+        if buf[0] != RECORD_VALID || sys_attrs_len > MAX_SYS_ATTRS_LEN {
+            return None;
+        }
+        Some(Self {
+            ediv: u16::from_le_bytes([buf[1], buf[2]]),
+            rand: buf[3..11].try_into().unwrap(),
+            ltk: buf[11..27].try_into().unwrap(),
+            ltk_flags: buf[27],
+            addr_type: buf[28],
+            addr: buf[29..35].try_into().unwrap(),
+            irk: buf[35..51].try_into().unwrap(),
+            sys_attrs: Vec::from_slice(&buf[52..52 + sys_attrs_len]).unwrap(),
+        })
+    }
+}
+
+/// Bonds with up to `N` peers, persisted in a flash region starting at `offset`.
+///
+/// The region spans `N` records of [`BOND_RECORD_LEN`] bytes rounded up to whole erase blocks, and
+/// `offset` must be aligned to an erase block. When the store is full, the least recently bonded
+/// peer is forgotten.
+#[derive(Clone)]
+pub struct BondStore<const N: usize> {
+    offset: u32,
+    bonds: Vec<Bond, N>,
+    dirty: bool,
+}
+
+impl<const N: usize> BondStore<N> {
+    pub const fn new(offset: u32) -> Self {
+        Self {
+            offset,
+            bonds: Vec::new(),
+            dirty: false,
+        }
+    }
+
+    pub fn bonds(&self) -> &[Bond] {
+        &self.bonds
+    }
+
+    /// Whether bonds changed since they were last loaded or saved.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Copy of the store to be saved, leaving the store clean until it is changed again.
+    pub fn snapshot(&mut self) -> Self {
+        let snapshot = self.clone();
+        self.dirty = false;
+        snapshot
+    }
+
+    /// Add a bond, replacing any bond with the same peer.
+    pub fn add(&mut self, bond: Bond) {
+        self.bonds
+            .retain(|b| (b.addr_type, b.addr) != (bond.addr_type, bond.addr));
+        if self.bonds.is_full() {
+            self.bonds.remove(0);
+        }
+        let _ = self.bonds.push(bond);
+        self.dirty = true;
+    }
+
+    /// Forget the peer with the identity address `addr`.
+    pub fn remove(&mut self, addr_type: u8, addr: &[u8; 6]) {
+        let len = self.bonds.len();
+        self.bonds
+            .retain(|b| (b.addr_type, &b.addr) != (addr_type, addr));
+        self.dirty |= len != self.bonds.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.dirty |= !self.bonds.is_empty();
+        self.bonds.clear();
+    }
+
+    /// The bond of the long term key identified by `ediv` and `rand`.
+    pub fn find_key(&self, ediv: u16, rand: &[u8; 8]) -> Option<&Bond> {
+        self.bonds
+            .iter()
+            .find(|b| b.ediv == ediv && &b.rand == rand)
+    }
+
+    /// The bond of the peer with the identity address `addr`.
+    pub fn find_peer(&self, addr_type: u8, addr: &[u8; 6]) -> Option<&Bond> {
+        self.bonds
+            .iter()
+            .find(|b| b.addr_type == addr_type && &b.addr == addr)
+    }
+
+    /// The bond of the peer connected with the address `addr`, which is either its identity
+    /// address or a resolvable private address generated from its IRK.
+    ///
+    /// `ah` is the random address hash function of the Bluetooth Core specification, hashing
+    /// `prand` with an IRK, with keys and addresses least significant byte first as in the stack.
+    pub fn resolve_peer<A>(&self, addr_type: u8, addr: &[u8; 6], ah: A) -> Option<&Bond>
+    where
+        A: Fn(&[u8; 16], &[u8; 3]) -> Option<[u8; 3]>,
+    {
+        if let Some(bond) = self.find_peer(addr_type, addr) {
+            return Some(bond);
+        }
+        if addr_type != ADDR_TYPE_RANDOM_PRIVATE_RESOLVABLE {
+            return None;
+        }
+        let hash = [addr[0], addr[1], addr[2]];
+        let prand = [addr[3], addr[4], addr[5]];
+        self.bonds.iter().find(|b| ah(&b.irk, &prand) == Some(hash))
+    }
+
+    /// Keep the system attributes of a bonded peer.
+    pub fn set_sys_attrs(
+        &mut self,
+        addr_type: u8,
+        addr: &[u8; 6],
+        sys_attrs: &[u8],
+    ) -> Result<(), BondError> {
+        let bond = self
+            .bonds
+            .iter_mut()
+            .find(|b| b.addr_type == addr_type && &b.addr == addr)
+            .ok_or(BondError::NotFound)?;
+        if bond.sys_attrs != sys_attrs {
+            bond.sys_attrs = Vec::from_slice(sys_attrs).map_err(|_| BondError::TooLarge)?;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Load the bonds persisted in `flash`, skipping invalid records.
+    pub async fn load<F: AsyncReadNorFlash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        self.bonds.clear();
+        let mut buf = [0; BOND_RECORD_LEN];
+        for i in 0..N {
+            flash
+                .read(self.offset + (i * BOND_RECORD_LEN) as u32, &mut buf)
+                .await?;
+            if let Some(bond) = Bond::decode(&buf) {
+                let _ = self.bonds.push(bond);
+            }
+        }
+        debug!("Loaded {} bonds", self.bonds.len());
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Persist the bonds in `flash` if they changed, rewriting the whole region.
+    pub async fn save<F: AsyncNorFlash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let len = (N * BOND_RECORD_LEN) as u32;
+        let erase_size = F::ERASE_SIZE as u32;
+        let end = self.offset + (len + erase_size - 1) / erase_size * erase_size;
+        flash.erase(self.offset, end).await?;
+
+        let mut buf = [0; BOND_RECORD_LEN];
+        for (i, bond) in self.bonds.iter().enumerate() {
+            bond.encode(&mut buf);
+            flash
+                .write(self.offset + (i * BOND_RECORD_LEN) as u32, &buf)
+                .await?;
+        }
+        debug!("Saved {} bonds", self.bonds.len());
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        embassy_embedded_hal::adapter::BlockingAsync,
+        embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash},
+        futures::executor::block_on,
+    };
+
+    struct MockFlash([u8; 1024]);
+
+    impl ErrorType for MockFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for MockFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.0[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    impl NorFlash for MockFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 256;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            if from as usize % Self::ERASE_SIZE != 0 || to as usize % Self::ERASE_SIZE != 0 {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            self.0[from as usize..to as usize].fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            for (i, b) in bytes.iter().enumerate() {
+                // Flash can only clear bits
+                self.0[offset + i] &= b;
+            }
+            Ok(())
+        }
+    }
+
+    fn bond(id: u8) -> Bond {
+        Bond {
+            ediv: id as u16,
+            rand: [id; 8],
+            ltk: [id; 16],
+            ltk_flags: 0x42,
+            addr_type: 1,
+            addr: [id; 6],
+            irk: [!id; 16],
+            sys_attrs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_bonds() {
+        let mut store: BondStore<2> = BondStore::new(256);
+        store.add(bond(1));
+        store.add(bond(2));
+        assert_eq!(Some(&bond(1)), store.find_key(1, &[1; 8]));
+        assert_eq!(None, store.find_key(1, &[2; 8]));
+
+        // Bonding again replaces the bond of the peer
+        let mut rebonded = bond(1);
+        rebonded.ltk = [9; 16];
+        store.add(rebonded.clone());
+        assert_eq!(2, store.bonds().len());
+        assert_eq!(Some(&rebonded), store.find_peer(1, &[1; 6]));
+
+        // The least recently bonded peer is forgotten when full
+        store.add(bond(3));
+        assert_eq!(None, store.find_peer(1, &[2; 6]));
+        assert_eq!(Some(&rebonded), store.find_peer(1, &[1; 6]));
+
+        store.remove(1, &[1; 6]);
+        assert_eq!(&[bond(3)], store.bonds());
+
+        assert_eq!(
+            Err(BondError::NotFound),
+            store.set_sys_attrs(1, &[1; 6], &[1, 2])
+        );
+        assert_eq!(
+            Err(BondError::TooLarge),
+            store.set_sys_attrs(1, &[3; 6], &[0; MAX_SYS_ATTRS_LEN + 1])
+        );
+    }
+
+    #[test]
+    fn test_resolve_peer() {
+        let mut store: BondStore<2> = BondStore::new(256);
+        store.add(bond(1));
+        store.add(bond(2));
+        // Stand-in for the AES based hash of the stack
+        let ah = |irk: &[u8; 16], prand: &[u8; 3]| {
+            Some([irk[0] ^ prand[0], irk[1] ^ prand[1], irk[2] ^ prand[2]])
+        };
+
+        // The identity address is found as is
+        assert_eq!(Some(&bond(1)), store.resolve_peer(1, &[1; 6], ah));
+
+        // A resolvable private address generated from the IRK of the second peer
+        let prand = [0x11, 0x22, 0x63];
+        let irk = bond(2).irk;
+        let addr = [
+            irk[0] ^ prand[0],
+            irk[1] ^ prand[1],
+            irk[2] ^ prand[2],
+            prand[0],
+            prand[1],
+            prand[2],
+        ];
+        let bonded = store.resolve_peer(2, &addr, ah).unwrap();
+        let identity = (bonded.addr_type, bonded.addr);
+        assert_eq!((1, [2; 6]), identity);
+        assert_eq!(None, store.find_peer(2, &addr));
+
+        // Addresses of other peers and of other types are not resolved
+        let mut other = addr;
+        other[0] ^= 0xFF;
+        assert_eq!(None, store.resolve_peer(2, &other, ah));
+        assert_eq!(None, store.resolve_peer(3, &addr, ah));
+        assert_eq!(None, store.resolve_peer(2, &addr, |_, _| None));
+
+        // System attributes are kept with the identity address
+        store
+            .set_sys_attrs(identity.0, &identity.1, &[1, 2])
+            .unwrap();
+        assert_eq!(
+            &[1, 2],
+            &store.resolve_peer(2, &addr, ah).unwrap().sys_attrs[..]
+        );
+    }
+
+    #[test]
+    fn test_persistence() {
+        let mut flash = BlockingAsync::new(MockFlash([0xFF; 1024]));
+        block_on(async {
+            let mut store: BondStore<3> = BondStore::new(256);
+            store.load(&mut flash).await.unwrap();
+            assert!(store.bonds().is_empty());
+
+            store.add(bond(1));
+            store.add(bond(2));
+            store.set_sys_attrs(1, &[2; 6], &[1, 2, 3]).unwrap();
+            assert!(store.is_dirty());
+            store.save(&mut flash).await.unwrap();
+            assert!(!store.is_dirty());
+
+            let mut loaded: BondStore<3> = BondStore::new(256);
+            loaded.load(&mut flash).await.unwrap();
+            assert_eq!(store.bonds(), loaded.bonds());
+            assert_eq!(&[1, 2, 3], &loaded.bonds()[1].sys_attrs[..]);
+
+            // Removed bonds are erased
+            loaded.remove(1, &[1; 6]);
+            loaded.snapshot().save(&mut flash).await.unwrap();
+            assert!(!loaded.is_dirty());
+            store.load(&mut flash).await.unwrap();
+            assert_eq!(&[loaded.bonds()[0].clone()], store.bonds());
+        });
+    }
+}
@@ -0,0 +1,187 @@
+use nrf_softdevice::{raw, Softdevice};
+
+/// Source of the low frequency clock used by the softdevice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClockSource {
+    /// Internal RC oscillator, calibrated when the temperature changes and at least every 2 seconds.
+    Rc,
+    /// External 32.768 kHz crystal with an accuracy of 20 ppm.
+    Xtal,
+}
+
+/// Security required to write the GAP device name.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NameWritePermission {
+    /// The device name cannot be written by peers.
+    None,
+    /// Any peer can write the device name.
+    Open,
+    /// The device name can be written over encrypted links.
+    Encrypted,
+    /// The device name can be written over encrypted links with authenticated pairing.
+    Authenticated,
+}
+
+/// Configuration of the softdevice, built by chaining setters on [`SoftdeviceConfig::new`].
+///
+/// ```ignore
+/// let sd = SoftdeviceConfig::new("Drogue Low Energy")
+///     .clock(ClockSource::Xtal)
+///     .att_mtu(128)
+///     .enable();
+/// ```
+pub struct SoftdeviceConfig {
+    name: &'static str,
+    clock: ClockSource,
+    connections: u8,
+    event_length: u16,
+    att_mtu: u16,
+    attr_table_size: u32,
+    peripheral_roles: u8,
+    #[cfg(feature = "ble-central")]
+    central_roles: u8,
+    name_write: NameWritePermission,
+    name_max_len: u16,
+}
+
+impl SoftdeviceConfig {
+    /// Configuration for a device advertised as `name`, using the RC oscillator, with 2
+    /// connections, an ATT MTU of 256, a 32 KiB attribute table and 3 peripheral roles.
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            clock: ClockSource::Rc,
+            connections: 2,
+            event_length: 24,
+            att_mtu: 256,
+            attr_table_size: 32768,
+            peripheral_roles: 3,
+            #[cfg(feature = "ble-central")]
+            central_roles: 0,
+            name_write: NameWritePermission::None,
+            name_max_len: 0,
+        }
+    }
+
+    pub fn clock(mut self, clock: ClockSource) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Number of concurrent connections the softdevice allocates resources for.
+    pub fn connections(mut self, connections: u8) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Time reserved for each connection event, in 1.25 ms units.
+    pub fn event_length(mut self, event_length: u16) -> Self {
+        self.event_length = event_length;
+        self
+    }
+
+    /// Largest ATT MTU negotiated with peers.
+    pub fn att_mtu(mut self, att_mtu: u16) -> Self {
+        self.att_mtu = att_mtu;
+        self
+    }
+
+    /// Size of the attribute table in bytes, a multiple of 4.
+    pub fn attr_table_size(mut self, size: u32) -> Self {
+        self.attr_table_size = size;
+        self
+    }
+
+    pub fn peripheral_roles(mut self, count: u8) -> Self {
+        self.peripheral_roles = count;
+        self
+    }
+
+    #[cfg(feature = "ble-central")]
+    pub fn central_roles(mut self, count: u8) -> Self {
+        self.central_roles = count;
+        self
+    }
+
+    pub fn name_write(mut self, permission: NameWritePermission) -> Self {
+        self.name_write = permission;
+        self
+    }
+
+    /// Longest device name peers may write, up to `BLE_GAP_DEVNAME_MAX_LEN` bytes. Defaults to
+    /// the length of the initial name, and is never shorter than it.
+    pub fn name_max_len(mut self, len: u16) -> Self {
+        self.name_max_len = len;
+        self
+    }
+
+    /// Enable the softdevice with this configuration.
+    pub fn enable(&self) -> &'static mut Softdevice {
+        Softdevice::enable(&self.to_config())
+    }
+
+    fn to_config(&self) -> nrf_softdevice::Config {
+        let clock = match self.clock {
+            ClockSource::Rc => raw::nrf_clock_lf_cfg_t {
+                source: raw::NRF_CLOCK_LF_SRC_RC as u8,
+                rc_ctiv: 4,
+                rc_temp_ctiv: 2,
+                accuracy: raw::NRF_CLOCK_LF_ACCURACY_20_PPM as u8,
+            },
+            ClockSource::Xtal => raw::nrf_clock_lf_cfg_t {
+                source: raw::NRF_CLOCK_LF_SRC_XTAL as u8,
+                rc_ctiv: 0,
+                rc_temp_ctiv: 0,
+                accuracy: raw::NRF_CLOCK_LF_ACCURACY_20_PPM as u8,
+            },
+        };
+        // Security mode 1 with the level required for writing, or mode 0 for no access
+        let (sm, lv) = match self.name_write {
+            NameWritePermission::None => (0, 0),
+            NameWritePermission::Open => (1, 1),
+            NameWritePermission::Encrypted => (1, 2),
+            NameWritePermission::Authenticated => (1, 3),
+        };
+        let name_max_len = self
+            .name_max_len
+            .max(self.name.len() as u16)
+            .min(raw::BLE_GAP_DEVNAME_MAX_LEN as u16);
+        nrf_softdevice::Config {
+            clock: Some(clock),
+            conn_gap: Some(raw::ble_gap_conn_cfg_t {
+                conn_count: self.connections,
+                event_length: self.event_length,
+            }),
+            conn_gatt: Some(raw::ble_gatt_conn_cfg_t {
+                att_mtu: self.att_mtu,
+            }),
+            gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
+                attr_tab_size: self.attr_table_size,
+            }),
+            gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
+                adv_set_count: 1,
+                periph_role_count: self.peripheral_roles,
+                #[cfg(feature = "ble-central")]
+                central_role_count: self.central_roles,
+                #[cfg(feature = "ble-central")]
+                central_sec_count: 0,
+                #[cfg(feature = "ble-central")]
+                _bitfield_1: raw::ble_gap_cfg_role_count_t::new_bitfield_1(0),
+            }),
+            gap_device_name: Some(raw::ble_gap_cfg_device_name_t {
+                p_value: self.name.as_ptr() as *const u8 as _,
+                current_len: self.name.len().min(name_max_len as usize) as u16,
+                max_len: name_max_len,
+                write_perm: raw::ble_gap_conn_sec_mode_t {
+                    _bitfield_1: raw::ble_gap_conn_sec_mode_t::new_bitfield_1(sm, lv),
+                },
+                _bitfield_1: raw::ble_gap_cfg_device_name_t::new_bitfield_1(
+                    raw::BLE_GATTS_VLOC_STACK as u8,
+                ),
+            }),
+            ..Default::default()
+        }
+    }
+}
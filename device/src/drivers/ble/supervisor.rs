@@ -0,0 +1,106 @@
+use {
+    core::future::Future,
+    embassy_futures::select::select_array,
+    embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex},
+    nrf_softdevice::{
+        ble::{
+            peripheral::{self, AdvertiseError},
+            Connection,
+        },
+        Softdevice,
+    },
+};
+
+#[cfg(feature = "ble-security")]
+use nrf_softdevice::ble::security::SecurityHandler;
+
+/// Advertises and accepts up to `N` concurrent connections, advertising again whenever a
+/// connection is closed.
+///
+/// Each connection is handled by a future created by the handler passed to [`run`], which
+/// completes when the connection is lost, typically by running the GATT server. The softdevice
+/// must be configured with at least `N` connections and peripheral roles.
+///
+/// [`run`]: ConnectionSupervisor::run
+pub struct ConnectionSupervisor<'a, const N: usize> {
+    sd: &'a Softdevice,
+    adv_data: &'a [u8],
+    scan_data: &'a [u8],
+    config: peripheral::Config,
+    advertising: Mutex<NoopRawMutex, ()>,
+    #[cfg(feature = "ble-security")]
+    security: Option<&'static dyn SecurityHandler>,
+}
+
+impl<'a, const N: usize> ConnectionSupervisor<'a, N> {
+    pub fn new(sd: &'a Softdevice, adv_data: &'a [u8], scan_data: &'a [u8]) -> Self {
+        Self {
+            sd,
+            adv_data,
+            scan_data,
+            config: peripheral::Config::default(),
+            advertising: Mutex::new(()),
+            #[cfg(feature = "ble-security")]
+            security: None,
+        }
+    }
+
+    /// Advertising parameters such as the interval and TX power.
+    pub fn config(mut self, config: peripheral::Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Accept pairing and bonding requests of peers, handled by `handler`.
+    #[cfg(feature = "ble-security")]
+    pub fn security(mut self, handler: &'static dyn SecurityHandler) -> Self {
+        self.security.replace(handler);
+        self
+    }
+
+    /// Handle connections until advertising fails.
+    pub async fn run<F, Fut>(&self, handler: F) -> AdvertiseError
+    where
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let slots: [_; N] = core::array::from_fn(|_| self.slot(&handler));
+        select_array(slots).await.0
+    }
+
+    /// Handle one connection at a time, taking turns with the other slots to advertise.
+    async fn slot<F, Fut>(&self, handler: &F) -> AdvertiseError
+    where
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        loop {
+            let conn = {
+                let _advertising = self.advertising.lock().await;
+                let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
+                    adv_data: self.adv_data,
+                    scan_data: self.scan_data,
+                };
+                debug!("Advertising");
+                match self.advertise(adv).await {
+                    Ok(conn) => conn,
+                    Err(e) => return e,
+                }
+            };
+            debug!("Connection established");
+            handler(conn).await;
+            debug!("Connection closed");
+        }
+    }
+
+    async fn advertise(
+        &self,
+        adv: peripheral::ConnectableAdvertisement<'_>,
+    ) -> Result<Connection, AdvertiseError> {
+        #[cfg(feature = "ble-security")]
+        if let Some(security) = self.security {
+            return peripheral::advertise_pairable(self.sd, adv, &self.config, security).await;
+        }
+        peripheral::advertise_connectable(self.sd, adv, &self.config).await
+    }
+}
@@ -1,45 +1,75 @@
+use super::{LoraRadio, SharedRadio};
+use crate::lora::{DEVICE_TIME_CID, LINK_CHECK_CID};
 use crate::traits::lora::{LoraError, *};
 use core::future::Future;
 
 use embassy_lora::LoraTimer;
+use lorawan::{default_crypto::DefaultFactory as Crypto, parser::DevAddr as LDevAddr};
 use lorawan_device::async_device::{
-    radio, region, Device JoinMode as LoraJoinMode, Timings,
+    radio, region, Device as LorawanDevice, JoinMode as LoraJoinMode, Timings,
 };
-use lorawan_encoding::{default_crypto::DefaultFactory as Crypto, parser::DevAddr as LDevAddr};
 use rand_core::RngCore;
 
-pub struct LoraDevice<R, RNG>
+/// A LoRaWAN device running the LoRaWAN stack on top of a [`LoraRadio`].
+///
+/// Class A and class C are supported. A class C device listens for downlinks in the RX2 window
+/// while waiting in [`LoraDriver::receive`]. Downlinks received this way are never
+/// acknowledged, so the network should only send unconfirmed data outside of the receive
+/// windows following an uplink.
+pub struct LoraDevice<'a, R, RNG>
 where
     R: radio::PhyRxTx + Timings,
     RNG: RngCore,
 {
-    device: LorawanDevice<R, Crypto, LoraTimer, RNG>,
+    device: LorawanDevice<SharedRadio<'a, R>, Crypto, LoraTimer, RNG>,
+    radio: &'a LoraRadio<R>,
+    class: DeviceClass,
+    region: LoraRegion,
 }
 
-pub fn create(radio: R, rng: RNG, config: &LoraConfig) -> LorawanDevice<R, Crypto, LoraTimer, RNG> {
-
-
+#[cfg(not(test))]
 const RX_DELAY1: u32 = 5000;
-impl<R, RNG> LoraDevice<R, RNG>
+// The simulated network server answers immediately
+#[cfg(test)]
+const RX_DELAY1: u32 = 100;
+
+impl<'a, R, RNG> LoraDevice<'a, R, RNG>
 where
     R: radio::PhyRxTx + Timings,
     RNG: RngCore,
 {
-    pub fn new(config: &LoraConfig, radio: R, rng: RNG) -> Result<Self, LoraError> {
-        let data_rate = to_datarate(config.spreading_factor.unwrap_or(SpreadingFactor::SF7));
-        let region = to_region(config.region.unwrap_or(LoraRegion::EU868));
-        if let Err(e) = region {
-            return Err(e);
+    pub fn new(config: &LoraConfig, radio: &'a LoraRadio<R>, rng: RNG) -> Result<Self, LoraError> {
+        let class = config.device_class.unwrap_or(DeviceClass::A);
+        // Class B needs beacon tracking and ping slots, which are not implemented
+        if let DeviceClass::B = class {
+            return Err(LoraError::UnsupportedDeviceClass);
         }
-        let mut region = region.unwrap();
-        region.set_receive_delay1(RX_DELAY1);
-        let mut device = LorawanDevice::new(region, radio, LoraTimer::new(), rng);
+        let data_rate = to_datarate(config.spreading_factor.unwrap_or(SpreadingFactor::SF7));
+        let region = config.region.unwrap_or(LoraRegion::EU868);
+        let mut configuration = to_region(region)?;
+        configuration.set_receive_delay1(RX_DELAY1);
+        let mut device = LorawanDevice::new(configuration, radio.shared(), LoraTimer::new(), rng);
         device.set_datarate(data_rate);
-        Ok(Self { device })
+        Ok(Self {
+            device,
+            radio,
+            class,
+            region,
+        })
+    }
+
+    /// Send a MAC command request in a port 0 uplink. The answer is picked up by the radio in the
+    /// receive windows that follow.
+    async fn mac_request(&mut self, cid: u8) -> Result<(), LoraError> {
+        self.device
+            .send(&[cid], 0, false)
+            .await
+            .map_err(|_| LoraError::SendError)?;
+        Ok(())
     }
 }
 
-impl<R, RNG> LoraDriver for LoraDevice<R, RNG>
+impl<'a, R, RNG> LoraDriver for LoraDevice<'a, R, RNG>
 where
     R: radio::PhyRxTx + Timings,
     RNG: RngCore,
@@ -48,6 +78,14 @@ where
     where
         Self: 'm;
     fn join<'m>(&'m mut self, mode: JoinMode) -> Self::JoinFuture<'m> {
+        match mode {
+            JoinMode::OTAA { app_key, .. } => self.radio.join_otaa(app_key.0),
+            JoinMode::ABP {
+                news_key,
+                apps_key,
+                dev_addr,
+            } => self.radio.join_abp(dev_addr.0, news_key.0, apps_key.0),
+        }
         let join_mode = to_lorajoinmode(mode);
         async move {
             self.device
@@ -105,6 +143,61 @@ where
             Ok(len)
         }
     }
+
+    type ReceiveFuture<'m> = impl Future<Output = Result<(Port, usize), LoraError>> + 'm
+    where
+        Self: 'm;
+    fn receive<'m>(&'m mut self, rx: &'m mut [u8]) -> Self::ReceiveFuture<'m> {
+        async move {
+            if let DeviceClass::A = self.class {
+                return Err(LoraError::UnsupportedDeviceClass);
+            }
+            if !self.radio.is_joined() {
+                return Err(LoraError::NotInitialized);
+            }
+            loop {
+                if let Some(received) = self.radio.receive(rx2_config(self.region)?, rx).await? {
+                    return Ok(received);
+                }
+            }
+        }
+    }
+
+    type LinkQualityFuture<'m> = impl Future<Output = Result<Option<LinkQuality>, LoraError>> + 'm
+    where
+        Self: 'm;
+    fn link_quality<'m>(&'m mut self) -> Self::LinkQualityFuture<'m> {
+        async move { Ok(self.radio.link_quality()) }
+    }
+}
+
+impl<'a, R, RNG> LoraMacCommands for LoraDevice<'a, R, RNG>
+where
+    R: radio::PhyRxTx + Timings,
+    RNG: RngCore,
+{
+    type LinkCheckFuture<'m> = impl Future<Output = Result<LinkCheck, LoraError>> + 'm
+    where
+        Self: 'm;
+    fn link_check<'m>(&'m mut self) -> Self::LinkCheckFuture<'m> {
+        async move {
+            // Drop an answer left from an earlier request
+            self.radio.take_link_check();
+            self.mac_request(LINK_CHECK_CID).await?;
+            self.radio.take_link_check().ok_or(LoraError::RecvTimeout)
+        }
+    }
+
+    type DeviceTimeFuture<'m> = impl Future<Output = Result<NetworkTime, LoraError>> + 'm
+    where
+        Self: 'm;
+    fn device_time<'m>(&'m mut self) -> Self::DeviceTimeFuture<'m> {
+        async move {
+            self.radio.take_device_time();
+            self.mac_request(DEVICE_TIME_CID).await?;
+            self.radio.take_device_time().ok_or(LoraError::RecvTimeout)
+        }
+    }
 }
 
 fn to_region(region: LoraRegion) -> Result<region::Configuration, LoraError> {
@@ -116,6 +209,22 @@ fn to_region(region: LoraRegion) -> Result<region::Configuration, LoraError> {
     }
 }
 
+/// Default RX2 channel of the region, which class C devices listen on between uplinks.
+fn rx2_config(region: LoraRegion) -> Result<radio::RfConfig, LoraError> {
+    let (frequency, bandwidth) = match region {
+        LoraRegion::EU868 => (869_525_000, radio::Bandwidth::_125KHz),
+        LoraRegion::US915 => (923_300_000, radio::Bandwidth::_500KHz),
+        LoraRegion::CN470 => (505_300_000, radio::Bandwidth::_125KHz),
+        _ => return Err(LoraError::UnsupportedRegion),
+    };
+    Ok(radio::RfConfig {
+        frequency,
+        bandwidth,
+        spreading_factor: radio::SpreadingFactor::_12,
+        coding_rate: radio::CodingRate::_4_5,
+    })
+}
+
 fn to_datarate(spreading_factor: SpreadingFactor) -> region::DR {
     match spreading_factor {
         SpreadingFactor::SF7 => region::DR::_5,
@@ -149,3 +258,128 @@ fn to_lorajoinmode(join_mode: JoinMode) -> LoraJoinMode {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lora::simulator::{NetworkServer, SimulatedRadio, LINK_MARGIN};
+    use crate::testing::TestRng;
+
+    const DEV_EUI: EUI = EUI([0x70, 0xB3, 0xD5, 0x7E, 0xD0, 0x05, 0x00, 0x01]);
+    const APP_EUI: EUI = EUI([0x00; 8]);
+    const APP_KEY: AppKey = AppKey([
+        0x2B, 0x7E, 0x15, 0x16, 0x28, 0xAE, 0xD2, 0xA6, 0xAB, 0xF7, 0x15, 0x88, 0x09, 0xCF, 0x4F,
+        0x3C,
+    ]);
+
+    fn server() -> NetworkServer {
+        // The device sends its EUIs in little endian
+        NetworkServer::new(DEV_EUI.reverse().0, APP_EUI.reverse().0, APP_KEY.0)
+    }
+
+    const OTAA: JoinMode = JoinMode::OTAA {
+        dev_eui: DEV_EUI,
+        app_eui: APP_EUI,
+        app_key: APP_KEY,
+    };
+
+    #[test]
+    fn test_class_c_receive() {
+        let server = server();
+        let radio = LoraRadio::new(SimulatedRadio::new(&server));
+        let config = LoraConfig::new().device_class(DeviceClass::C);
+        let mut device = LoraDevice::new(&config, &radio, TestRng(0x1234_5678)).unwrap();
+        futures::executor::block_on(async {
+            let mut rx = [0; 16];
+            assert!(matches!(
+                device.receive(&mut rx).await,
+                Err(LoraError::NotInitialized)
+            ));
+
+            device.join(OTAA).await.unwrap();
+            assert!(server.is_joined());
+
+            server.push(3, b"hello").unwrap();
+            let (port, len) = device.receive(&mut rx).await.unwrap();
+            assert_eq!(3, port);
+            assert_eq!(b"hello", &rx[..len]);
+
+            server.push(4, b"again").unwrap();
+            let (port, len) = device.receive(&mut rx).await.unwrap();
+            assert_eq!(4, port);
+            assert_eq!(b"again", &rx[..len]);
+
+            let mut small = [0; 2];
+            server.push(3, b"hello").unwrap();
+            assert!(matches!(
+                device.receive(&mut small).await,
+                Err(LoraError::RecvBufferTooSmall)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_device_class() {
+        let server = server();
+        let radio = LoraRadio::new(SimulatedRadio::new(&server));
+        let config = LoraConfig::new().device_class(DeviceClass::B);
+        assert!(matches!(
+            LoraDevice::new(&config, &radio, TestRng(1)),
+            Err(LoraError::UnsupportedDeviceClass)
+        ));
+
+        let mut device = LoraDevice::new(&LoraConfig::new(), &radio, TestRng(1)).unwrap();
+        futures::executor::block_on(async {
+            device.join(OTAA).await.unwrap();
+            let mut rx = [0; 16];
+            assert!(matches!(
+                device.receive(&mut rx).await,
+                Err(LoraError::UnsupportedDeviceClass)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_mac_commands() {
+        let server = server();
+        server.set_gps_time(1_300_000_000);
+        let radio = LoraRadio::new(SimulatedRadio::new(&server));
+        let mut device = LoraDevice::new(&LoraConfig::new(), &radio, TestRng(0x1234_5678)).unwrap();
+        futures::executor::block_on(async {
+            assert_eq!(None, device.link_quality().await.unwrap());
+            device.join(OTAA).await.unwrap();
+            assert_eq!(
+                Some(LinkQuality { rssi: -60, snr: 10 }),
+                device.link_quality().await.unwrap()
+            );
+
+            let check = device.link_check().await.unwrap();
+            assert_eq!(
+                LinkCheck {
+                    margin: LINK_MARGIN,
+                    gateway_count: 1,
+                },
+                check
+            );
+            assert_eq!(Some(0), server.uplink().unwrap().port);
+
+            let time = device.device_time().await.unwrap();
+            assert_eq!(
+                NetworkTime {
+                    seconds: 1_300_000_000,
+                    fraction: 0,
+                },
+                time
+            );
+
+            // Application data queued meanwhile still reaches the device
+            server.enqueue(2, b"pong").unwrap();
+            let mut rx = [0; 16];
+            let len = device
+                .send_recv(QoS::Unconfirmed, 1, b"ping", &mut rx)
+                .await
+                .unwrap();
+            assert_eq!(b"pong", &rx[..len]);
+        });
+    }
+}
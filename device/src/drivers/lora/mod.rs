@@ -3,6 +3,14 @@ pub mod rak811;
 
 #[cfg(feature = "lora")]
 pub mod device;
+#[cfg(feature = "lora")]
+mod p2p;
+#[cfg(feature = "lora")]
+mod radio;
 
 #[cfg(feature = "lora")]
 pub use device::*;
+#[cfg(feature = "lora")]
+pub use p2p::*;
+#[cfg(feature = "lora")]
+pub use radio::*;
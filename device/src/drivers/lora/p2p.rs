@@ -0,0 +1,194 @@
+use crate::lora::{P2pConfig, PacketStatus};
+use crate::traits::lora::LoraP2p;
+use core::future::Future;
+use embassy_time::{with_timeout, Duration};
+use lorawan_device::{
+    async_device::radio::{PhyRxTx, RfConfig, TxConfig},
+    radio::{Bandwidth, CodingRate, SpreadingFactor},
+};
+
+/// Preamble length of the radio drivers, which is the one of LoRaWAN.
+const PREAMBLE_LEN: u16 = 8;
+
+/// A LoRaWAN radio receiving with the same IQ polarity as it transmits.
+///
+/// LoRaWAN radios usually invert IQ when receiving, as downlinks require, and then cannot
+/// receive the packets of a peer transmitting with the same radio. Only radios receiving
+/// without IQ inversion may implement this trait, so that two [`P2pRadio`] can talk.
+pub trait P2pPhy: PhyRxTx {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum P2pError<E> {
+    Radio(E),
+    /// No configuration has been applied yet.
+    NotConfigured,
+    /// The radio does not support the requested modulation.
+    UnsupportedModulation,
+    /// No packet was received in time.
+    Timeout,
+}
+
+/// Point-to-point communication using a LoRaWAN radio without IQ inversion on receive.
+///
+/// The preamble length is fixed to the 8 symbols of LoRaWAN by the radio driver, so other
+/// preamble lengths are rejected as unsupported.
+pub struct P2pRadio<R>
+where
+    R: P2pPhy,
+{
+    radio: R,
+    config: Option<P2pConfig>,
+}
+
+impl<R> P2pRadio<R>
+where
+    R: P2pPhy,
+{
+    pub fn new(radio: R) -> Self {
+        Self {
+            radio,
+            config: None,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.radio
+    }
+
+    fn config(&self) -> Result<&P2pConfig, P2pError<R::PhyError>> {
+        self.config.as_ref().ok_or(P2pError::NotConfigured)
+    }
+}
+
+impl<R> LoraP2p for P2pRadio<R>
+where
+    R: P2pPhy,
+{
+    type Error = P2pError<R::PhyError>;
+
+    type ConfigureFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+    fn configure_p2p<'m>(&'m mut self, config: &'m P2pConfig) -> Self::ConfigureFuture<'m> {
+        async move {
+            to_rf_config(config)?;
+            self.config.replace(*config);
+            Ok(())
+        }
+    }
+
+    type TransmitFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+    fn transmit_packet<'m>(&'m mut self, data: &'m [u8]) -> Self::TransmitFuture<'m> {
+        async move {
+            let config = self.config()?;
+            let tx = TxConfig {
+                pw: config.tx_power,
+                rf: to_rf_config(config)?,
+            };
+            self.radio.tx(tx, data).await.map_err(P2pError::Radio)?;
+            Ok(())
+        }
+    }
+
+    type ReceiveFuture<'m> = impl Future<Output = Result<(usize, Option<PacketStatus>), Self::Error>> + 'm
+    where
+        Self: 'm;
+    fn receive_packet<'m>(
+        &'m mut self,
+        rx: &'m mut [u8],
+        timeout: Duration,
+    ) -> Self::ReceiveFuture<'m> {
+        async move {
+            let rf = to_rf_config(self.config()?)?;
+            let (len, quality) = with_timeout(timeout, self.radio.rx(rf, rx))
+                .await
+                .map_err(|_| P2pError::Timeout)?
+                .map_err(P2pError::Radio)?;
+            Ok((
+                len,
+                Some(PacketStatus {
+                    rssi: quality.rssi(),
+                    snr: quality.snr(),
+                }),
+            ))
+        }
+    }
+}
+
+fn to_rf_config<E>(config: &P2pConfig) -> Result<RfConfig, P2pError<E>> {
+    // The radio driver always uses an explicit header, CRC and the LoRaWAN preamble
+    if !config.is_supported() || config.modulation.preamble_len != PREAMBLE_LEN {
+        return Err(P2pError::UnsupportedModulation);
+    }
+    let modulation = &config.modulation;
+    let spreading_factor = match modulation.spreading_factor {
+        7 => SpreadingFactor::_7,
+        8 => SpreadingFactor::_8,
+        9 => SpreadingFactor::_9,
+        10 => SpreadingFactor::_10,
+        11 => SpreadingFactor::_11,
+        12 => SpreadingFactor::_12,
+        _ => return Err(P2pError::UnsupportedModulation),
+    };
+    let bandwidth = match modulation.bandwidth {
+        125_000 => Bandwidth::_125KHz,
+        250_000 => Bandwidth::_250KHz,
+        500_000 => Bandwidth::_500KHz,
+        _ => return Err(P2pError::UnsupportedModulation),
+    };
+    let coding_rate = match modulation.coding_rate {
+        5 => CodingRate::_4_5,
+        6 => CodingRate::_4_6,
+        7 => CodingRate::_4_7,
+        8 => CodingRate::_4_8,
+        _ => return Err(P2pError::UnsupportedModulation),
+    };
+    Ok(RfConfig {
+        frequency: config.frequency,
+        bandwidth,
+        spreading_factor,
+        coding_rate,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lora::Modulation;
+
+    #[test]
+    fn test_rf_config() {
+        let config = P2pConfig::new(868_100_000, Modulation::lorawan(7, 125_000), 14);
+        assert!(matches!(
+            to_rf_config::<()>(&config),
+            Ok(RfConfig {
+                frequency: 868_100_000,
+                bandwidth: Bandwidth::_125KHz,
+                spreading_factor: SpreadingFactor::_7,
+                coding_rate: CodingRate::_4_5,
+            })
+        ));
+
+        let unsupported = [
+            Modulation::lorawan(13, 125_000),
+            Modulation {
+                crc: false,
+                ..Modulation::lorawan(7, 125_000)
+            },
+            Modulation {
+                preamble_len: 12,
+                ..Modulation::lorawan(7, 125_000)
+            },
+        ];
+        for modulation in unsupported {
+            let config = P2pConfig::new(868_100_000, modulation, 14);
+            assert!(matches!(
+                to_rf_config::<()>(&config),
+                Err(P2pError::UnsupportedModulation)
+            ));
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use crate::lora::{DataFrame, Direction, FrameType, MacCommands, DEVICE_TIME_CID, LINK_CHECK_CID};
+use crate::traits::lora::{LinkCheck, LinkQuality, LoraError, NetworkTime, Port};
+use core::{cell::RefCell, future::Future};
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use heapless::Vec;
+use lorawan::{
+    keys::AES128,
+    parser::{parse, DataHeader, DataPayload, DecryptedJoinAcceptPayload, PhyPayload},
+};
+use lorawan_device::async_device::{
+    radio::{PhyRxTx, RfConfig, RxQuality, TxConfig},
+    Timings,
+};
+
+/// Maximum length of a frame received outside of the LoRaWAN stack.
+const MAX_FRAME_LEN: usize = 256;
+/// Length of a join request frame.
+const JOIN_REQUEST_LEN: usize = 23;
+
+/// A radio shared by a [`LoraDevice`](super::LoraDevice) and its LoRaWAN stack.
+///
+/// The stack only listens for downlinks in the receive windows following an uplink and keeps
+/// its session to itself. The shared radio follows the join exchange to derive the same session
+/// keys, so that the device can receive class C downlinks between uplinks, and picks up the
+/// signal quality and the MAC command answers of the downlinks received by the stack.
+pub struct LoraRadio<R>
+where
+    R: PhyRxTx + Timings,
+{
+    radio: RefCell<R>,
+    state: Mutex<NoopRawMutex, RefCell<State>>,
+}
+
+impl<R> LoraRadio<R>
+where
+    R: PhyRxTx + Timings,
+{
+    pub fn new(radio: R) -> Self {
+        Self {
+            radio: RefCell::new(radio),
+            state: Mutex::new(RefCell::new(State {
+                join: None,
+                session: None,
+                quality: None,
+                link_check: None,
+                device_time: None,
+            })),
+        }
+    }
+
+    /// Handle on the radio for the LoRaWAN stack.
+    pub(crate) fn shared(&self) -> SharedRadio<'_, R> {
+        SharedRadio(self)
+    }
+
+    /// Expect an OTAA join using `app_key`, dropping the current session.
+    pub(crate) fn join_otaa(&self, app_key: [u8; 16]) {
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.join.replace((AES128(app_key), Vec::new()));
+            s.session = None;
+        });
+    }
+
+    /// Use the session of a device activated by personalization.
+    pub(crate) fn join_abp(&self, dev_addr: [u8; 4], nwk_skey: [u8; 16], app_skey: [u8; 16]) {
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            s.join = None;
+            s.session.replace(Session {
+                dev_addr,
+                nwk_skey: AES128(nwk_skey),
+                app_skey: AES128(app_skey),
+                fcnt_down: None,
+            });
+        });
+    }
+
+    pub(crate) fn is_joined(&self) -> bool {
+        self.state.lock(|s| s.borrow().session.is_some())
+    }
+
+    /// Signal quality of the last downlink received for the device.
+    pub(crate) fn link_quality(&self) -> Option<LinkQuality> {
+        self.state.lock(|s| s.borrow().quality)
+    }
+
+    /// Take the last LinkCheckAns received.
+    pub(crate) fn take_link_check(&self) -> Option<LinkCheck> {
+        self.state.lock(|s| s.borrow_mut().link_check.take())
+    }
+
+    /// Take the last DeviceTimeAns received.
+    pub(crate) fn take_device_time(&self) -> Option<NetworkTime> {
+        self.state.lock(|s| s.borrow_mut().device_time.take())
+    }
+
+    /// Receive a frame with `config`. If it is a downlink with application data for the device,
+    /// write the data into `rx` and return its port and length.
+    pub(crate) async fn receive(
+        &self,
+        config: RfConfig,
+        rx: &mut [u8],
+    ) -> Result<Option<(Port, usize)>, LoraError> {
+        let mut frame = [0; MAX_FRAME_LEN];
+        let (len, quality) = self
+            .radio
+            .borrow_mut()
+            .rx(config, &mut frame)
+            .await
+            .map_err(|_| LoraError::RecvError)?;
+        let frame = &mut frame[..len];
+        match self
+            .state
+            .lock(move |s| s.borrow_mut().received(frame, quality))
+        {
+            Some((port, data)) => {
+                let rx = rx
+                    .get_mut(..data.len())
+                    .ok_or(LoraError::RecvBufferTooSmall)?;
+                rx.copy_from_slice(data);
+                Ok(Some((port, data.len())))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Handle on a [`LoraRadio`] used by the LoRaWAN stack.
+pub(crate) struct SharedRadio<'a, R>(&'a LoraRadio<R>)
+where
+    R: PhyRxTx + Timings;
+
+impl<'a, R> PhyRxTx for SharedRadio<'a, R>
+where
+    R: PhyRxTx + Timings,
+{
+    type PhyError = R::PhyError;
+
+    type TxFuture<'m> = impl Future<Output = Result<u32, Self::PhyError>> + 'm
+    where
+        Self: 'm;
+    fn tx<'m>(&'m mut self, config: TxConfig, buf: &'m [u8]) -> Self::TxFuture<'m> {
+        async move {
+            self.0.state.lock(|s| s.borrow_mut().transmitted(buf));
+            self.0.radio.borrow_mut().tx(config, buf).await
+        }
+    }
+
+    type RxFuture<'m> = impl Future<Output = Result<(usize, RxQuality), Self::PhyError>> + 'm
+    where
+        Self: 'm;
+    fn rx<'m>(&'m mut self, config: RfConfig, rx_buf: &'m mut [u8]) -> Self::RxFuture<'m> {
+        async move {
+            let (len, quality) = self.0.radio.borrow_mut().rx(config, rx_buf).await?;
+            // Decryption happens in place, so follow the session on a copy of the frame
+            if let Ok(mut frame) = Vec::<u8, MAX_FRAME_LEN>::from_slice(&rx_buf[..len]) {
+                self.0.state.lock(|s| {
+                    s.borrow_mut().received(&mut frame, quality);
+                });
+            }
+            Ok((len, quality))
+        }
+    }
+}
+
+impl<'a, R> Timings for SharedRadio<'a, R>
+where
+    R: PhyRxTx + Timings,
+{
+    fn get_rx_window_offset_ms(&self) -> i32 {
+        self.0.radio.borrow().get_rx_window_offset_ms()
+    }
+
+    fn get_rx_window_duration_ms(&self) -> u32 {
+        self.0.radio.borrow().get_rx_window_duration_ms()
+    }
+}
+
+struct Session {
+    dev_addr: [u8; 4],
+    nwk_skey: AES128,
+    app_skey: AES128,
+    fcnt_down: Option<u32>,
+}
+
+impl Session {
+    /// Restore the upper 16 bits of the frame counter of a downlink. Returns `None` if the
+    /// counter would wrap around.
+    fn next_fcnt_down(&self, fcnt16: u16) -> Option<u32> {
+        match self.fcnt_down {
+            None => Some(fcnt16 as u32),
+            Some(last) => {
+                let fcnt = (last & 0xFFFF_0000) | fcnt16 as u32;
+                if fcnt > last {
+                    Some(fcnt)
+                } else {
+                    fcnt.checked_add(0x1_0000)
+                }
+            }
+        }
+    }
+}
+
+struct State {
+    /// AppKey and join request of an OTAA join in progress.
+    join: Option<(AES128, Vec<u8, JOIN_REQUEST_LEN>)>,
+    session: Option<Session>,
+    quality: Option<LinkQuality>,
+    link_check: Option<LinkCheck>,
+    device_time: Option<NetworkTime>,
+}
+
+impl State {
+    fn transmitted(&mut self, frame: &[u8]) {
+        if let Some((_, request)) = self.join.as_mut() {
+            if FrameType::of(frame) == Some(FrameType::JoinRequest) {
+                if let Ok(frame) = Vec::from_slice(frame) {
+                    *request = frame;
+                }
+            }
+        }
+    }
+
+    /// Follow the session with a frame received from the network, returning the port and
+    /// application data of data downlinks for the device.
+    fn received<'f>(
+        &mut self,
+        frame: &'f mut [u8],
+        quality: RxQuality,
+    ) -> Option<(Port, &'f [u8])> {
+        let quality = LinkQuality {
+            rssi: quality.rssi(),
+            snr: quality.snr(),
+        };
+        match FrameType::of(frame)? {
+            FrameType::JoinAccept => {
+                if self.join_accept(frame) {
+                    self.quality.replace(quality);
+                }
+                None
+            }
+            mtype if mtype.is_data_downlink() => {
+                let data = self.data_downlink(frame)?;
+                self.quality.replace(quality);
+                self.mac_commands(data.fopts);
+                match data.port {
+                    Some(0) => {
+                        self.mac_commands(data.payload);
+                        None
+                    }
+                    Some(port) => Some((port, data.payload)),
+                    None => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Keep the answers to the MAC commands requested by the application.
+    fn mac_commands(&mut self, commands: &[u8]) {
+        for (cid, payload) in MacCommands::new(commands, Direction::Downlink) {
+            match cid {
+                LINK_CHECK_CID => {
+                    self.link_check.replace(LinkCheck {
+                        margin: payload[0],
+                        gateway_count: payload[1],
+                    });
+                }
+                DEVICE_TIME_CID => {
+                    self.device_time.replace(NetworkTime {
+                        seconds: u32::from_le_bytes([
+                            payload[0], payload[1], payload[2], payload[3],
+                        ]),
+                        fraction: payload[4],
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn join_accept(&mut self, frame: &mut [u8]) -> bool {
+        let (app_key, request) = match self.join.as_ref() {
+            Some(join) => join,
+            None => return false,
+        };
+        let mut buf = request.clone();
+        let request = match parse(&mut buf[..]) {
+            Ok(PhyPayload::JoinRequest(request)) => request,
+            _ => {
+                warn!("Join accept without a join request");
+                return false;
+            }
+        };
+        let dev_nonce = request.dev_nonce();
+        let decrypted = match DecryptedJoinAcceptPayload::new(frame, app_key) {
+            Ok(decrypted) => decrypted,
+            Err(_) => return false,
+        };
+        if !decrypted.validate_mic(app_key) {
+            warn!("Join accept with invalid MIC");
+            return false;
+        }
+        let mut dev_addr = [0; 4];
+        dev_addr.copy_from_slice(decrypted.dev_addr().as_ref());
+        let session = Session {
+            dev_addr,
+            nwk_skey: decrypted.derive_newskey(&dev_nonce, app_key),
+            app_skey: decrypted.derive_appskey(&dev_nonce, app_key),
+            fcnt_down: None,
+        };
+        self.session.replace(session);
+        self.join = None;
+        true
+    }
+
+    /// Validate and decrypt a data downlink for the device.
+    fn data_downlink<'f>(&mut self, frame: &'f mut [u8]) -> Option<DataFrame<'f>> {
+        let session = self.session.as_mut()?;
+        let fcnt = match parse(&mut *frame) {
+            Ok(PhyPayload::Data(DataPayload::Encrypted(payload))) => {
+                if payload.fhdr().dev_addr().as_ref() != session.dev_addr {
+                    return None;
+                }
+                let fcnt = session.next_fcnt_down(payload.fhdr().fcnt())?;
+                if !payload.validate_mic(&session.nwk_skey, fcnt) {
+                    warn!("Downlink with invalid MIC or replayed frame counter");
+                    return None;
+                }
+                payload
+                    .decrypt(Some(&session.nwk_skey), Some(&session.app_skey), fcnt)
+                    .ok()?;
+                fcnt
+            }
+            _ => return None,
+        };
+        session.fcnt_down.replace(fcnt);
+
+        let frame: &'f [u8] = frame;
+        DataFrame::parse(frame)
+    }
+}
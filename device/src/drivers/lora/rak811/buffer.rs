@@ -1,6 +1,7 @@
-use super::{parser, protocol::Response};
-use core::str::from_utf8;
-use moveslice::Moveslice;
+use super::{
+    parser::{self, ParseError},
+    protocol::Response,
+};
 
 pub struct Buffer {
     buffer: [u8; 512],
@@ -28,6 +29,19 @@ impl Buffer {
         }
     }
 
+    /// Discard all buffered data.
+    pub fn clear(&mut self) {
+        self.pos = 0;
+        self.needs_parse = false;
+    }
+
+    /// Drop the first `len` bytes of the buffer, keeping the remainder for parsing.
+    fn consume(&mut self, len: usize) {
+        self.buffer.copy_within(len..self.pos, 0);
+        self.pos -= len;
+        self.needs_parse = self.pos > 0;
+    }
+
     pub fn parse(&mut self) -> Result<Response, ()> {
         if self.pos == 0 {
             return Ok(Response::None);
@@ -37,30 +51,22 @@ impl Buffer {
         }
         self.needs_parse = false;
 
-        let str = from_utf8(&self.buffer[0..self.pos]);
-        match str {
-            Ok(_) => {
-                //               trace!("parsing {} [{}]", self.pos, s);
-            }
-            Err(e) => {
-                let _ = from_utf8(&self.buffer[0..e.valid_up_to()]).unwrap();
-                //                trace!("parsing {} [{}<truncated>] ({})", self.pos, s, e);
-            }
-        }
-
-        let mut ret = Ok(Response::None);
-
-        if let Ok((remainder, response)) = parser::parse(&self.buffer[0..self.pos]) {
-            let len = remainder.len();
-            if len > 0 {
-                let start = self.pos - len;
-                (&mut self.buffer[..]).moveslice(start..start + len, 0);
-                self.pos = len;
-                self.needs_parse = true;
-            } else {
-                self.pos = 0;
+        loop {
+            match parser::parse(&self.buffer[0..self.pos]) {
+                Ok((response, len)) => {
+                    self.consume(len);
+                    return Ok(response);
+                }
+                Err(ParseError::Incomplete) => return Ok(Response::None),
+                Err(ParseError::Invalid(len)) => {
+                    // Skip past the offending line so that it does not block later responses
+                    warn!("Discarding {} bytes of unparseable data", len);
+                    self.consume(len);
+                    if self.pos == 0 {
+                        return Ok(Response::None);
+                    }
+                }
             }
-            ret = Ok(response);
         }
 
         /*
@@ -100,7 +106,5 @@ impl Buffer {
 
 
          */
-        //Ok(Response::None)
-        ret
     }
 }
@@ -5,15 +5,30 @@
 mod buffer;
 mod parser;
 mod protocol;
+use crate::lora::{P2pConfig, PacketStatus};
 use crate::traits::lora::*;
 
 pub use buffer::*;
 use core::future::Future;
-use embedded_hal::digital::v2::OutputPin;
+use embassy_time::{with_timeout, Duration};
+use embedded_hal::digital::OutputPin;
 use embedded_io::asynch::{Read, Write};
+use heapless::{Deque, Vec};
 pub use protocol::*;
 
 const RECV_BUFFER_LEN: usize = 256;
+const READ_CHUNK_LEN: usize = 32;
+const DOWNLINK_QUEUE_LEN: usize = 4;
+const EVENT_QUEUE_LEN: usize = 4;
+
+/// Time to wait for the modem to boot after a reset.
+const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait for the modem to respond to a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait for the outcome of a join request.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time to wait for the outcome of an uplink, including both receive windows.
+const TX_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct Rak811Modem<T, RESET>
 where
@@ -24,6 +39,12 @@ where
     reset: RESET,
     parse_buffer: Buffer,
     config: LoraConfig,
+    downlinks: Deque<Downlink, DOWNLINK_QUEUE_LEN>,
+    /// Number of downlinks queued so far, wrapping around.
+    downlink_count: u32,
+    events: Deque<EventCode, EVENT_QUEUE_LEN>,
+    /// Whether the modem reports received P2P packets.
+    p2p_receiving: bool,
 }
 
 impl<T, RESET> Rak811Modem<T, RESET>
@@ -37,41 +58,40 @@ where
             reset,
             config: LoraConfig::new(),
             parse_buffer: Buffer::new(),
+            downlinks: Deque::new(),
+            downlink_count: 0,
+            events: Deque::new(),
+            p2p_receiving: false,
         }
     }
 
     pub async fn initialize(&mut self) -> Result<(), LoraError> {
         self.reset.set_high().ok();
         self.reset.set_low().ok();
-        loop {
-            // Run processing to increase likelyhood we have something to parse.
-            self.process().await?;
-            if let Some(response) = self.parse() {
-                match response {
-                    Response::Initialized(region) => {
-                        info!("Got initialize response with region {:?}", region);
-                        self.config.region.replace(region);
-                        return Ok(());
-                    }
-                    e => {
-                        error!("Got unexpected repsonse: {:?}", e);
-                        return Err(LoraError::NotInitialized);
-                    }
-                }
+        self.parse_buffer.clear();
+        self.downlinks.clear();
+        self.events.clear();
+        self.p2p_receiving = false;
+
+        let response = with_timeout(INIT_TIMEOUT, self.read_response())
+            .await
+            .map_err(|_| LoraError::NotInitialized)??;
+        match response {
+            Response::Initialized(region) => {
+                info!("Got initialize response with region {:?}", region);
+                self.config.region.replace(region);
+                Ok(())
+            }
+            e => {
+                error!("Got unexpected repsonse: {:?}", e);
+                Err(LoraError::NotInitialized)
             }
         }
     }
 
-    async fn process(&mut self) -> Result<(), LoraError> {
-        let mut buf = [0; 1];
-        self.transport
-            .read(&mut buf[..])
-            .await
-            .map_err(|_| LoraError::RecvError)?;
-        self.parse_buffer
-            .write(buf[0])
-            .map_err(|_| LoraError::RecvError)?;
-        Ok(())
+    /// Return the oldest downlink received from the network, if any.
+    pub fn try_receive(&mut self) -> Option<Downlink> {
+        self.downlinks.pop_front()
     }
 
     fn parse(&mut self) -> Option<Response> {
@@ -85,36 +105,125 @@ where
         None
     }
 
-    async fn recv(&mut self) -> Result<Response, LoraError> {
-        let mut buf = [0; 1];
+    /// Read from the transport until the parser yields a response.
+    async fn read_response(&mut self) -> Result<Response, LoraError> {
+        let mut buf = [0; READ_CHUNK_LEN];
         loop {
-            match self.transport.read(&mut buf[..]).await {
-                Ok(len) => {
-                    for b in &buf[..len] {
-                        self.parse_buffer.write(*b).unwrap();
+            if let Some(response) = self.parse() {
+                return Ok(response);
+            }
+            let len = self
+                .transport
+                .read(&mut buf[..])
+                .await
+                .map_err(|_| LoraError::RecvError)?;
+            for b in &buf[..len] {
+                if self.parse_buffer.write(*b).is_err() {
+                    // Whatever is buffered could not be parsed, so it will never form a valid response
+                    warn!("Parse buffer overflow, discarding buffered data");
+                    self.parse_buffer.clear();
+                    self.parse_buffer.write(*b).ok();
+                }
+            }
+        }
+    }
+
+    /// Queue unsolicited result codes, returning any other response.
+    fn dispatch(&mut self, response: Response) -> Option<Response> {
+        match response {
+            Response::Recv(code, port, quality, len, data) => {
+                if let Some(data) = data {
+                    let len = core::cmp::min(len, data.len());
+                    if let Ok(data) = Vec::from_slice(&data[..len]) {
+                        if self.downlinks.is_full() {
+                            warn!("Downlink queue full, dropping oldest downlink");
+                            self.downlinks.pop_front();
+                        }
+                        self.downlinks
+                            .push_back(Downlink {
+                                port,
+                                quality,
+                                data,
+                            })
+                            .ok();
+                        self.downlink_count = self.downlink_count.wrapping_add(1);
                     }
-                    if let Some(response) = self.parse() {
-                        return Ok(response);
+                }
+                if code != EventCode::RecvData {
+                    if self.events.is_full() {
+                        warn!("Event queue full, dropping oldest event");
+                        self.events.pop_front();
                     }
+                    self.events.push_back(code).ok();
                 }
-                Err(_) => {
-                    error!("Error reading from uart");
+                None
+            }
+            Response::Initialized(region) => {
+                warn!("Modem restarted with region {:?}", region);
+                self.config.region.replace(region);
+                self.p2p_receiving = false;
+                None
+            }
+            r => Some(r),
+        }
+    }
+
+    /// Wait for a command response, queueing any unsolicited result codes received meanwhile.
+    async fn recv(&mut self) -> Result<Response, LoraError> {
+        loop {
+            let response = self.read_response().await?;
+            if let Some(response) = self.dispatch(response) {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Wait for the next event reported by the modem.
+    async fn recv_event(&mut self, timeout: Duration) -> Result<EventCode, LoraError> {
+        with_timeout(timeout, async {
+            loop {
+                if let Some(event) = self.events.pop_front() {
+                    return Ok(event);
+                }
+                let response = self.read_response().await?;
+                if let Some(r) = self.dispatch(response) {
+                    warn!("Ignoring response while waiting for event: {:?}", r);
                 }
             }
+        })
+        .await
+        .map_err(|_| LoraError::RecvTimeout)?
+    }
+
+    /// Wait for the next downlink, queueing any events received meanwhile.
+    async fn recv_downlink(&mut self) -> Result<Downlink, LoraError> {
+        loop {
+            if let Some(downlink) = self.downlinks.pop_front() {
+                return Ok(downlink);
+            }
+            let response = self.read_response().await?;
+            if let Some(r) = self.dispatch(response) {
+                warn!("Ignoring response while waiting for downlink: {:?}", r);
+            }
         }
     }
 
     async fn send_command<'m>(&mut self, command: Command<'m>) -> Result<Response, LoraError> {
         let mut s = Command::buffer();
-        command.encode(&mut s);
+        command.encode(&mut s).map_err(|e| {
+            warn!("Error encoding command {:?}: {:?}", command, e);
+            LoraError::SendError
+        })?;
         debug!("Sending command {}", s.as_str());
-        s.push_str("\r\n").unwrap();
+        s.push_str("\r\n").map_err(|_| LoraError::SendError)?;
         self.transport
             .write(s.as_bytes())
             .await
             .map_err(|_| LoraError::SendError)?;
 
-        self.recv().await
+        with_timeout(COMMAND_TIMEOUT, self.recv())
+            .await
+            .map_err(|_| LoraError::RecvTimeout)?
     }
 
     async fn send_command_ok<'m>(&mut self, command: Command<'m>) -> Result<(), LoraError> {
@@ -124,6 +233,37 @@ where
         }
     }
 
+    async fn transmit(&mut self, qos: QoS, port: Port, data: &[u8]) -> Result<(), LoraError> {
+        // Stale events from a previous timed out transmission must not be mistaken for ours
+        self.events.clear();
+        let response = self.send_command(Command::Send(qos, port, data)).await?;
+        match response {
+            Response::Ok => {
+                let expected_code = match qos {
+                    QoS::Unconfirmed => EventCode::TxUnconfirmed,
+                    QoS::Confirmed => EventCode::TxConfirmed,
+                };
+                match self.recv_event(TX_TIMEOUT).await? {
+                    c if c == expected_code => Ok(()),
+                    EventCode::TxTimeout => Err(LoraError::AckTimeout),
+                    e => log_unexpected_event(e),
+                }
+            }
+            r => log_unexpected(r),
+        }
+    }
+
+    /// Read the current value of a configuration key.
+    pub async fn get_config(&mut self, key: ConfigKey) -> Result<ConfigValue, LoraError> {
+        match self.send_command(Command::GetConfig(key)).await? {
+            Response::Config(value) => Ok(value),
+            r => {
+                error!("Unexpected response: {:?}", r);
+                Err(LoraError::OtherError)
+            }
+        }
+    }
+
     pub async fn configure(&mut self, config: &LoraConfig) -> Result<(), LoraError> {
         info!("Applying config: {:?}", config);
         if let Some(region) = config.region {
@@ -138,6 +278,13 @@ where
                 self.config.lora_mode.replace(lora_mode);
             }
         }
+        if let Some(device_class) = config.device_class {
+            if self.config.device_class != config.device_class {
+                self.send_command_ok(Command::SetConfig(ConfigOption::Class(device_class)))
+                    .await?;
+                self.config.device_class.replace(device_class);
+            }
+        }
         debug!("Config applied");
         Ok(())
     }
@@ -181,15 +328,17 @@ where
                     ConnectMode::ABP
                 }
             };
+            self.events.clear();
             let response = self.send_command(Command::Join(mode)).await?;
             match response {
-                Response::Ok => {
-                    let response = self.recv().await?;
-                    match response {
-                        Response::Recv(EventCode::JoinedSuccess, _, _, _) => Ok(()),
-                        r => log_unexpected(r),
+                Response::Ok => match self.recv_event(JOIN_TIMEOUT).await {
+                    Ok(EventCode::JoinedSuccess) => Ok(()),
+                    Ok(EventCode::JoinedFailed) | Err(LoraError::RecvTimeout) => {
+                        Err(LoraError::JoinError)
                     }
-                }
+                    Ok(e) => log_unexpected_event(e),
+                    Err(e) => Err(e),
+                },
                 r => log_unexpected(r),
             }
         }
@@ -199,36 +348,149 @@ where
     where
         Self: 'm;
     fn send<'m>(&'m mut self, qos: QoS, port: Port, data: &'m [u8]) -> Self::SendFuture<'m> {
+        async move { self.transmit(qos, port, data).await }
+    }
+
+    type SendRecvFuture<'m> = impl Future<Output = Result<usize, LoraError>> + 'm
+    where
+        Self: 'm;
+    fn send_recv<'m>(
+        &'m mut self,
+        qos: QoS,
+        port: Port,
+        data: &'m [u8],
+        rx: &'m mut [u8],
+    ) -> Self::SendRecvFuture<'m> {
         async move {
-            let response = self.send_command(Command::Send(qos, port, data)).await?;
-            match response {
-                Response::Ok => {
-                    let response = self.recv().await?;
-                    let expected_code = match qos {
-                        QoS::Unconfirmed => EventCode::TxUnconfirmed,
-                        QoS::Confirmed => EventCode::TxConfirmed,
-                    };
-                    match response {
-                        Response::Recv(c, 0, _, _) if expected_code == c => Ok(()),
-                        r => log_unexpected(r),
+            // Downlinks queued before sending are left for receive, and only the one received
+            // in the windows of this uplink is returned
+            let count = self.downlink_count;
+            self.transmit(qos, port, data).await?;
+            let downlink = if self.downlink_count != count {
+                self.downlinks.pop_back()
+            } else {
+                None
+            };
+            match downlink {
+                Some(downlink) => {
+                    let len = downlink.data.len();
+                    if len > rx.len() {
+                        return Err(LoraError::RecvBufferTooSmall);
                     }
+                    rx[..len].copy_from_slice(&downlink.data);
+                    Ok(len)
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    type ReceiveFuture<'m> = impl Future<Output = Result<(Port, usize), LoraError>> + 'm
+    where
+        Self: 'm;
+    fn receive<'m>(&'m mut self, rx: &'m mut [u8]) -> Self::ReceiveFuture<'m> {
+        async move {
+            let downlink = self.recv_downlink().await?;
+            let len = downlink.data.len();
+            if len > rx.len() {
+                return Err(LoraError::RecvBufferTooSmall);
+            }
+            rx[..len].copy_from_slice(&downlink.data);
+            Ok((downlink.port, len))
+        }
+    }
+
+    type LinkQualityFuture<'m> = impl Future<Output = Result<Option<LinkQuality>, LoraError>> + 'm
+    where
+        Self: 'm;
+    fn link_quality<'m>(&'m mut self) -> Self::LinkQualityFuture<'m> {
+        async move {
+            match self.send_command(Command::GetStatus).await? {
+                Response::Status { rx_ok: 0, .. } => Ok(None),
+                Response::Status { rssi, snr, .. } => Ok(Some(LinkQuality { rssi, snr })),
+                r => {
+                    error!("Unexpected response: {:?}", r);
+                    Err(LoraError::OtherError)
                 }
+            }
+        }
+    }
+}
+
+impl<T, RESET> LoraP2p for Rak811Modem<T, RESET>
+where
+    T: Read + Write + Unpin,
+    RESET: OutputPin,
+{
+    type Error = LoraError;
+
+    type ConfigureFuture<'m> = impl Future<Output = Result<(), LoraError>> + 'm
+    where
+        Self: 'm;
+    fn configure_p2p<'m>(&'m mut self, config: &'m P2pConfig) -> Self::ConfigureFuture<'m> {
+        async move {
+            if !config.is_supported() {
+                return Err(LoraError::UnsupportedModulation);
+            }
+            self.configure(&LoraConfig::new().lora_mode(LoraMode::P2P))
+                .await?;
+            self.send_command_ok(Command::SetRfConfig(config)).await?;
+            self.p2p_receiving = false;
+            Ok(())
+        }
+    }
+
+    type TransmitFuture<'m> = impl Future<Output = Result<(), LoraError>> + 'm
+    where
+        Self: 'm;
+    fn transmit_packet<'m>(&'m mut self, data: &'m [u8]) -> Self::TransmitFuture<'m> {
+        async move {
+            if self.config.lora_mode != Some(LoraMode::P2P) {
+                return Err(LoraError::NotReady);
+            }
+            self.events.clear();
+            // The modem leaves receive mode to transmit
+            self.p2p_receiving = false;
+            match self.send_command(Command::SendP2p(data)).await? {
+                Response::Ok => match self.recv_event(TX_TIMEOUT).await? {
+                    EventCode::P2PTxComplete => Ok(()),
+                    e => log_unexpected_event(e),
+                },
                 r => log_unexpected(r),
             }
         }
     }
 
-    type SendRecvFuture<'m> = impl Future<Output = Result<usize, LoraError>> + 'm
+    type ReceiveFuture<'m> = impl Future<Output = Result<(usize, Option<PacketStatus>), LoraError>> + 'm
     where
         Self: 'm;
-    fn send_recv<'m>(
+    fn receive_packet<'m>(
         &'m mut self,
-        _qos: QoS,
-        _port: Port,
-        _data: &'m [u8],
-        _rx: &'m mut [u8],
-    ) -> Self::SendRecvFuture<'m> {
-        async move { todo!() }
+        rx: &'m mut [u8],
+        timeout: Duration,
+    ) -> Self::ReceiveFuture<'m> {
+        async move {
+            if self.config.lora_mode != Some(LoraMode::P2P) {
+                return Err(LoraError::NotReady);
+            }
+            if !self.p2p_receiving {
+                self.send_command_ok(Command::ReceiveP2p).await?;
+                self.p2p_receiving = true;
+            }
+            let downlink = with_timeout(timeout, self.recv_downlink())
+                .await
+                .map_err(|_| LoraError::RecvTimeout)??;
+            let len = downlink.data.len();
+            if len > rx.len() {
+                return Err(LoraError::RecvBufferTooSmall);
+            }
+            rx[..len].copy_from_slice(&downlink.data);
+            let status = downlink.quality.map(|quality| PacketStatus {
+                rssi: quality.rssi,
+                snr: quality.snr,
+            });
+            Ok((len, status))
+        }
     }
 }
 
@@ -236,3 +498,8 @@ fn log_unexpected(r: Response) -> Result<(), LoraError> {
     error!("Unexpected response: {:?}", r);
     Err(LoraError::OtherError)
 }
+
+fn log_unexpected_event(e: EventCode) -> Result<(), LoraError> {
+    error!("Unexpected event: {:?}", e);
+    Err(LoraError::OtherError)
+}
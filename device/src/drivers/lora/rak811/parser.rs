@@ -1,279 +1,406 @@
-use nom::{alt, char, character::streaming::digit1, do_parse, named, opt, tag, take, IResult};
+use heapless::String;
 
-use super::{protocol::Decoder, EventCode, FirmwareInfo, LoraRegion, Response};
+use super::{
+    protocol::Decoder, EventCode, FirmwareInfo, LinkQuality, LoraRegion, Response, RECV_BUFFER_LEN,
+};
 
-fn ascii_to_digit(character: u8) -> Option<u8> {
-    match character {
-        b'0' => Some(0),
-        b'1' => Some(1),
-        b'2' => Some(2),
-        b'3' => Some(3),
-        b'4' => Some(4),
-        b'5' => Some(5),
-        b'6' => Some(6),
-        b'7' => Some(7),
-        b'8' => Some(8),
-        b'9' => Some(9),
-        _ => None,
-    }
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParseError {
+    /// More data is needed to parse a response.
+    Incomplete,
+    /// The input does not start with a valid response. The given number of bytes, up to and
+    /// including the offending line, should be discarded.
+    Invalid(usize),
 }
 
-fn atoi_u32(digits: &[u8]) -> Option<u32> {
-    let mut num: u32 = 0;
-    let len = digits.len();
-    for (i, digit) in digits.iter().enumerate() {
-        let digit = ascii_to_digit(*digit as u8)? as u32;
-        let mut exp: u32 = 1;
-        for _ in 0..(len - i - 1) {
-            exp *= 10;
+/// Parse the first response in `input`, returning it along with the number of bytes consumed.
+pub fn parse(input: &[u8]) -> Result<(Response, usize), ParseError> {
+    let (line, end) = next_line(input, 0).ok_or(ParseError::Incomplete)?;
+    let invalid = ParseError::Invalid(end);
+
+    if line == b"OK" {
+        Ok((Response::Ok, end))
+    } else if let Some(code) = line.strip_prefix(b"ERROR") {
+        let code = parse_int(code).ok_or(invalid)?;
+        Ok((Response::Error(code), end))
+    } else if let Some(event) = line.strip_prefix(b"at+recv=") {
+        Ok((recv(event).ok_or(invalid)?, end))
+    } else if line == b"Welcome to RAK811" {
+        // Printed after a reset, followed by the selected region
+        let (mode, end) = next_line(input, end).ok_or(ParseError::Incomplete)?;
+        let region = mode_info(mode).ok_or(ParseError::Invalid(end))?;
+        Ok((Response::Initialized(region), end))
+    } else if line.starts_with(b"Selected LoraWAN ") {
+        // Printed when switching mode, followed by the command result
+        mode_info(line).ok_or(invalid)?;
+        let (result, end) = next_line(input, end).ok_or(ParseError::Incomplete)?;
+        if result == b"OK" {
+            Ok((Response::Ok, end))
+        } else {
+            Err(ParseError::Invalid(end))
         }
-        num += exp * digit;
+    } else if let Some(value) = line.strip_prefix(b"OK") {
+        Ok((ok_value(value).ok_or(invalid)?, end))
+    } else {
+        Err(invalid)
     }
-    Some(num)
 }
 
-fn atoi_u8(digits: &[u8]) -> Option<u8> {
-    let mut num: u8 = 0;
-    let len = digits.len();
-    for (i, digit) in digits.iter().enumerate() {
-        let digit = ascii_to_digit(*digit)?;
-        let mut exp = 1;
-        for _ in 0..(len - i - 1) {
-            exp *= 10;
+/// Find the first non-empty line at or after `start`, returning it without its terminator
+/// along with the offset following the terminator.
+fn next_line(input: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let start = start
+        + input[start..]
+            .iter()
+            .take_while(|b| is_newline(**b))
+            .count();
+    let len = input[start..].windows(2).position(|w| w == b"\r\n")?;
+    Some((&input[start..start + len], start + len + 2))
+}
+
+fn is_newline(b: u8) -> bool {
+    b == b'\r' || b == b'\n'
+}
+
+/// Parse an `at+recv=<event>,<port>[,<rssi>,<snr>],<len>[:<hex data>]` notification.
+fn recv(event: &[u8]) -> Option<Response> {
+    let (fields, data) = match event.iter().position(|b| *b == b':') {
+        Some(pos) => (&event[..pos], Some(&event[pos + 1..])),
+        None => (event, None),
+    };
+
+    let mut values = [0i32; 5];
+    let mut count = 0;
+    for field in fields.split(|b| *b == b',') {
+        *values.get_mut(count)? = parse_int(field)?;
+        count += 1;
+    }
+    let (code, port, quality, len) = match values[..count] {
+        [code, port, len] => (code, port, None, len),
+        [code, port, rssi, snr, len] => (
+            code,
+            port,
+            Some(LinkQuality {
+                rssi: rssi.try_into().ok()?,
+                snr: snr.try_into().ok()?,
+            }),
+            len,
+        ),
+        _ => return None,
+    };
+    let code = EventCode::parse(code.try_into().ok()?);
+    let port = port.try_into().ok()?;
+    let len: usize = len.try_into().ok()?;
+
+    let data = match data {
+        Some(hex) if hex.len() == 2 * len && len <= RECV_BUFFER_LEN => {
+            let mut buf = [0; RECV_BUFFER_LEN];
+            for (b, digits) in buf.iter_mut().zip(hex.chunks(2)) {
+                *b = hex_digit(digits[0])? << 4 | hex_digit(digits[1])?;
+            }
+            Some(buf)
         }
-        num += exp * digit;
+        None if len == 0 => None,
+        _ => return None,
+    };
+    Some(Response::Recv(code, port, quality, len, data))
+}
+
+/// Parse `Selected LoraWAN <version> Region: <region> `, returning the region.
+fn mode_info(line: &[u8]) -> Option<LoraRegion> {
+    let info = line.strip_prefix(b"Selected LoraWAN ")?;
+    let pos = info.windows(9).position(|w| w == b" Region: ")?;
+    let version = &info[..pos];
+    if version.is_empty() || !version.iter().all(|b| b.is_ascii_digit() || *b == b'.') {
+        return None;
     }
-    Some(num)
+    region(trim(&info[pos + 9..]))
 }
 
-fn parse_u8(input: &[u8]) -> IResult<&[u8], u8> {
-    let (input, digits) = digit1(input)?;
-    IResult::Ok((input, atoi_u8(digits).unwrap()))
+/// Parse the value following `OK` in response to a query.
+fn ok_value(value: &[u8]) -> Option<Response> {
+    if let Some(region) = region(value) {
+        return Some(Response::LoraBand(region));
+    }
+    if let Some(info) = firmware_info(value) {
+        return Some(Response::FirmwareInfo(info));
+    }
+    if let Some(status) = status(value) {
+        return Some(status);
+    }
+    // Anything else is the value of a configuration key
+    let mut config = String::new();
+    config.push_str(core::str::from_utf8(value).ok()?).ok()?;
+    Some(Response::Config(config))
 }
 
-fn parse_u32(input: &[u8]) -> IResult<&[u8], u32> {
-    let (input, digits) = digit1(input)?;
-    IResult::Ok((input, atoi_u32(digits).unwrap()))
+fn firmware_info(value: &[u8]) -> Option<FirmwareInfo> {
+    let mut parts = [0u8; 4];
+    let mut count = 0;
+    for part in value.split(|b| *b == b'.') {
+        *parts.get_mut(count)? = parse_uint(part)?.try_into().ok()?;
+        count += 1;
+    }
+    match parts {
+        [major, minor, patch, build] if count == 4 => Some(FirmwareInfo {
+            major,
+            minor,
+            patch,
+            build,
+        }),
+        _ => None,
+    }
 }
 
-#[rustfmt::skip]
-named!(
-    crlf,
-    tag!("\r\n")
-);
+/// Parse `<tx_ok>,<tx_err>,<rx_ok>,<rx_timeout>,<rx_err>,<rssi>,<snr>` as reported by
+/// `at+status`.
+fn status(value: &[u8]) -> Option<Response> {
+    let mut values = [0i32; 7];
+    let mut count = 0;
+    for field in value.split(|b| *b == b',') {
+        *values.get_mut(count)? = parse_int(field)?;
+        count += 1;
+    }
+    if count != values.len() {
+        return None;
+    }
+    Some(Response::Status {
+        tx_ok: values[0].try_into().ok()?,
+        tx_err: values[1].try_into().ok()?,
+        rx_ok: values[2].try_into().ok()?,
+        rx_timeout: values[3].try_into().ok()?,
+        rx_err: values[4].try_into().ok()?,
+        rssi: values[5].try_into().ok()?,
+        snr: values[6].try_into().ok()?,
+    })
+}
 
-#[rustfmt::skip]
-named!(
-    pub mode_info<Response>,
-    do_parse!(
-        opt!(crlf) >>
-        opt!(crlf) >>
-        tag!("Selected LoraWAN ") >>
-        _major: parse_u8 >>
-        tag!(".") >>
-        _minor: parse_u8 >>
-        tag!(".") >>
-        _patch: parse_u8 >>
-        tag!(" Region: ") >>
-        _region: lora_region >>
-        tag!(" ") >>
-        crlf >>
-        crlf >>
-        tag!("OK") >>
-        crlf >>
-        (
-            Response::Ok
-        )
-    )
-);
+fn region(value: &[u8]) -> Option<LoraRegion> {
+    match LoraRegion::decode(value) {
+        LoraRegion::UNKNOWN => None,
+        region => Some(region),
+    }
+}
 
-#[rustfmt::skip]
-named!(
-    pub ok<Response>,
-    do_parse!(
-        opt!(crlf) >>
-        opt!(crlf) >>
-        tag!("OK") >>
-        crlf >>
-        (
-            Response::Ok
-        )
-    )
-);
+fn trim(value: &[u8]) -> &[u8] {
+    let start = value.iter().take_while(|b| **b == b' ').count();
+    let end = value.len() - value.iter().rev().take_while(|b| **b == b' ').count();
+    &value[start..end.max(start)]
+}
 
-#[rustfmt::skip]
-named!(
-    pub error<Response>,
-    do_parse!(
-        opt!(crlf) >>
-        opt!(crlf) >>
-        tag!("ERROR") >>
-        sign: opt!(char!('-')) >>
-        code: parse_u8 >>
-        crlf >>
-        (
-            Response::Error(sign.map(|s| if s == '-' { - (code as i8) } else {code as i8}).unwrap_or(code as i8))
-        )
-    )
-);
+fn parse_uint(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |num, digit| {
+        let digit = (*digit as char).to_digit(10)?;
+        num.checked_mul(10)?.checked_add(digit)
+    })
+}
 
-#[rustfmt::skip]
-named!(
-    pub firmware_info<Response>,
-    do_parse!(
-        opt!(crlf) >>
-        opt!(crlf) >>
-        tag!("OK") >>
-        major: parse_u8 >>
-        tag!(".") >>
-        minor: parse_u8 >>
-        tag!(".") >>
-        patch: parse_u8 >>
-        tag!(".") >>
-        build: parse_u8 >>
-        crlf >>
-        (
-            Response::FirmwareInfo(FirmwareInfo{major, minor, patch, build})
-        )
-    )
-);
+fn parse_int<T: TryFrom<i32>>(digits: &[u8]) -> Option<T> {
+    let value = match digits.strip_prefix(b"-") {
+        Some(digits) => -i32::try_from(parse_uint(digits)?).ok()?,
+        None => i32::try_from(parse_uint(digits)?).ok()?,
+    };
+    value.try_into().ok()
+}
 
-#[rustfmt::skip]
-named!(
-    pub lora_region<LoraRegion>,
-    do_parse!(
-        region: alt!(
-            tag!("EU868") |
-            tag!("US915") |
-            tag!("AU915") |
-            tag!("KR920") |
-            tag!("AS923") |
-            tag!("IN865")
-        ) >>
-        (
-                LoraRegion::decode(region)
-        )
-    )
-);
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
 
-#[rustfmt::skip]
-named!(
-    pub lora_band<Response>,
-    do_parse!(
-        tag!("OK") >>
-        region: lora_region >>
-        crlf >>
-        (
-            Response::LoraBand(region)
-        )
-    )
-);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-#[rustfmt::skip]
-named!(
-    pub status<Response>,
-    do_parse!(
-        tag!("OK") >>
-        tx_ok: parse_u8 >>
-        char!(',') >>
-        tx_err: parse_u8 >>
-        char!(',') >>
-        rx_ok: parse_u8 >>
-        char!(',') >>
-        rx_timeout: parse_u8 >>
-        char!(',') >>
-        rx_err: parse_u8 >>
-        char!(',') >>
-        rssi_sign: opt!(char!('-')) >>
-        rssi: parse_u8 >>
-        char!(',') >>
-        snr: parse_u32 >>
-        crlf >>
-        ( {
-            Response::Status {
-                tx_ok,
-                tx_err,
-                rx_ok,
-                rx_timeout,
-                rx_err,
-                rssi: rssi_sign.map(|s| if s == '-' { - (rssi as i8) } else {rssi as i8}).unwrap_or(rssi as i8),
-                snr,
+    const P2P_DATA: [u8; 5] = *b"hello";
+
+    fn config(value: &str) -> Response {
+        Response::Config(String::from(value))
+    }
+
+    fn responses() -> [(&'static [u8], Response); 16] {
+        [
+            (b"OK\r\n", Response::Ok),
+            (b"\r\nOK\r\n", Response::Ok),
+            (b"ERROR-3\r\n", Response::Error(-3)),
+            (b"ERROR5\r\n", Response::Error(5)),
+            (
+                b"OK2.0.3.0\r\n",
+                Response::FirmwareInfo(FirmwareInfo {
+                    major: 2,
+                    minor: 0,
+                    patch: 3,
+                    build: 0,
+                }),
+            ),
+            (b"OKEU868\r\n", Response::LoraBand(LoraRegion::EU868)),
+            (b"OKCN470\r\n", Response::LoraBand(LoraRegion::CN470)),
+            (
+                b"OK12,0,3,9,0,-97,-5\r\n",
+                Response::Status {
+                    tx_ok: 12,
+                    tx_err: 0,
+                    rx_ok: 3,
+                    rx_timeout: 9,
+                    rx_err: 0,
+                    rssi: -97,
+                    snr: -5,
+                },
+            ),
+            (b"OK60c5a8fffe000001\r\n", config("60c5a8fffe000001")),
+            (b"OK0\r\n", config("0")),
+            (b"OK0,00ff\r\n", config("0,00ff")),
+            (
+                b"OK0,on,868100000,0,5;1,off\r\n",
+                config("0,on,868100000,0,5;1,off"),
+            ),
+            (
+                b"Welcome to RAK811\r\n\r\nSelected LoraWAN 2.0.3.0 Region: EU868 \r\n\r\n",
+                Response::Initialized(LoraRegion::EU868),
+            ),
+            (
+                b"\r\nSelected LoraWAN 2.0.3.0 Region: US915 \r\n\r\nOK\r\n",
+                Response::Ok,
+            ),
+            (
+                b"at+recv=3,0,0\r\n",
+                Response::Recv(EventCode::JoinedSuccess, 0, None, 0, None),
+            ),
+            (
+                b"at+recv=0,0,-100,-12,5:68656c6c6f\r\n",
+                Response::Recv(
+                    EventCode::RecvData,
+                    0,
+                    Some(LinkQuality {
+                        rssi: -100,
+                        snr: -12,
+                    }),
+                    5,
+                    Some(data(&P2P_DATA)),
+                ),
+            ),
+        ]
+    }
+
+    fn data(d: &[u8]) -> [u8; RECV_BUFFER_LEN] {
+        let mut buf = [0; RECV_BUFFER_LEN];
+        buf[..d.len()].copy_from_slice(d);
+        buf
+    }
+
+    #[test]
+    fn test_parse_responses() {
+        for (input, expected) in responses() {
+            let (response, len) = parse(input).unwrap();
+            assert_eq!(expected, response, "{:?}", input);
+            // Only trailing line breaks may be left
+            assert!(input[len..].iter().all(|b| is_newline(*b)), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_partial_responses() {
+        for (input, _) in responses() {
+            let complete = input.len() - input.iter().rev().take_while(|b| is_newline(**b)).count();
+            for end in 0..complete + 2 {
+                assert_eq!(
+                    Err(ParseError::Incomplete),
+                    parse(&input[..end]),
+                    "{:?}",
+                    &input[..end]
+                );
             }
-          }
-        )
-    )
-);
+        }
+    }
+
+    #[test]
+    fn test_parse_recv_events() {
+        let events = [
+            (0, EventCode::RecvData),
+            (1, EventCode::TxConfirmed),
+            (2, EventCode::TxUnconfirmed),
+            (3, EventCode::JoinedSuccess),
+            (4, EventCode::JoinedFailed),
+            (5, EventCode::TxTimeout),
+            (6, EventCode::Rx2Timeout),
+            (7, EventCode::DownlinkRepeated),
+            (8, EventCode::WakeUp),
+            (9, EventCode::P2PTxComplete),
+            (42, EventCode::Unknown),
+        ];
+        for (code, expected) in events {
+            let mut input: String<32> = String::new();
+            core::fmt::Write::write_fmt(&mut input, format_args!("at+recv={},0,0\r\n", code))
+                .unwrap();
+            let (response, _) = parse(input.as_bytes()).unwrap();
+            assert_eq!(Response::Recv(expected, 0, None, 0, None), response);
+        }
+    }
 
-#[rustfmt::skip]
-named!(
-    pub welcome<Response>,
-    do_parse!(
-        tag!("Welcome to RAK811") >>
-        crlf >>
-        crlf >>
-        tag!("Selected LoraWAN ") >>
-        _major: parse_u8 >>
-        tag!(".") >>
-        _minor: parse_u8 >>
-        tag!(".") >>
-        _patch: parse_u8 >>
-        tag!(" Region: ") >>
-        region: lora_region >>
-        tag!(" ") >>
-        crlf >>
-        crlf >>
-        (
-            Response::Initialized(region)
-        )
-    )
-);
+    /// Input, port, signal quality and data of a notification.
+    type RecvCase = (&'static [u8], u8, Option<LinkQuality>, &'static [u8]);
 
-#[rustfmt::skip]
-named!(
-    pub recv<Response>,
-    do_parse!(
-        tag!("at+recv=") >>
-        status: parse_u8 >>
-        char!(',') >>
-        port: parse_u8 >>
-        char!(',') >>
-        len: parse_u8 >>
-        data: take!(len) >>
-        crlf >>
-        ( {
-            let rx = if len > 0 {
-                let mut buf: [u8; super::RECV_BUFFER_LEN] = [0; super::RECV_BUFFER_LEN];
-                for (i, b) in data.iter().enumerate() {
-                    buf[i] = *b;
+    #[test]
+    fn test_parse_recv_data() {
+        let cases: [RecvCase; 3] = [
+            (
+                b"at+recv=0,2,4:deadBEEF\r\n",
+                2,
+                None,
+                &[0xde, 0xad, 0xbe, 0xef],
+            ),
+            (
+                b"at+recv=0,1,-45,7,2:0a0b\r\n",
+                1,
+                Some(LinkQuality { rssi: -45, snr: 7 }),
+                &[0x0a, 0x0b],
+            ),
+            (
+                b"at+recv=1,223,-120,-20,1:00\r\n",
+                223,
+                Some(LinkQuality {
+                    rssi: -120,
+                    snr: -20,
+                }),
+                &[0x00],
+            ),
+        ];
+        for (input, port, quality, expected) in cases {
+            match parse(input).unwrap().0 {
+                Response::Recv(_, p, q, len, Some(buf)) => {
+                    assert_eq!(port, p);
+                    assert_eq!(quality, q);
+                    assert_eq!(expected, &buf[..len]);
                 }
-                Some(buf)
-            } else {
-                None
-            };
-            Response::Recv(EventCode::parse(status), port, len as usize, rx)
-          }
-        )
-    )
-);
-
-named!(
-    pub parse<Response>,
-    alt!(
-          ok
-        | error
-        | firmware_info
-        | lora_band
-        | mode_info
-        | recv
-        | status
-        | welcome
-    )
-);
+                r => panic!("Unexpected response {:?}", r),
+            }
+        }
+    }
 
-#[cfg(test)]
-mod tests {
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn test_parse_invalid() {
+        let cases: [&[u8]; 9] = [
+            b"garbage\r\nOK\r\n",
+            b"ERROR\r\n",
+            b"ERRORx\r\n",
+            b"at+recv=0,1,3:abcd\r\n",
+            b"at+recv=0,1,2:zz11\r\n",
+            b"at+recv=0,1,2\r\n",
+            b"at+recv=0,1\r\n",
+            b"at+recv=0,300,0\r\n",
+            b"Welcome to RAK811\r\nSelected LoraWAN 2.0 Region: MARS \r\n",
+        ];
+        for input in cases {
+            // The whole response is discarded if a continuation line is invalid
+            let end = if input.starts_with(b"Welcome") {
+                input.len()
+            } else {
+                input.windows(2).position(|w| w == b"\r\n").unwrap() + 2
+            };
+            assert_eq!(Err(ParseError::Invalid(end)), parse(input), "{:?}", input);
+        }
     }
 }
@@ -1,6 +1,7 @@
+use crate::lora::P2pConfig;
 use crate::traits::lora::*;
 use core::fmt::Write;
-use heapless::String;
+use heapless::{String, Vec};
 
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -20,6 +21,7 @@ pub enum ConfigKey {
     AppsKey,
     ChMask,
     ChList,
+    Class,
 }
 
 #[derive(Debug)]
@@ -35,6 +37,9 @@ pub enum Command<'a> {
     GetConfig(ConfigKey),
     Send(QoS, Port, &'a [u8]),
     GetStatus,
+    SetRfConfig(&'a P2pConfig),
+    SendP2p(&'a [u8]),
+    ReceiveP2p,
 }
 
 #[derive(Debug)]
@@ -47,6 +52,7 @@ pub enum ConfigOption<'a> {
     NwksKey(&'a NwksKey),
     AppsKey(&'a AppsKey),
     ChMask(u8, u16),
+    Class(DeviceClass),
     /*
     PwrLevel,
     Adr,
@@ -59,12 +65,13 @@ pub enum ConfigOption<'a> {
     MaxChs,
     JoinCnt,
     Nbtrans,
-    Class,
     Duty,*/
 }
 
+pub type ConfigValue = String<{ super::RECV_BUFFER_LEN }>;
+
 #[allow(clippy::large_enum_variant)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Response {
     None,
@@ -72,20 +79,28 @@ pub enum Response {
     Error(i8),
     FirmwareInfo(FirmwareInfo),
     LoraBand(LoraRegion),
-    Recv(EventCode, Port, usize, Option<[u8; super::RECV_BUFFER_LEN]>),
+    Recv(
+        EventCode,
+        Port,
+        Option<LinkQuality>,
+        usize,
+        Option<[u8; super::RECV_BUFFER_LEN]>,
+    ),
     Status {
-        tx_ok: u8,
-        tx_err: u8,
-        rx_ok: u8,
-        rx_timeout: u8,
-        rx_err: u8,
-        rssi: i8,
-        snr: u32,
+        tx_ok: u16,
+        tx_err: u16,
+        rx_ok: u16,
+        rx_timeout: u16,
+        rx_err: u16,
+        rssi: i16,
+        snr: i8,
     },
+    /// Value of a configuration key queried with `at+get_config`.
+    Config(ConfigValue),
     Initialized(LoraRegion),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(u8)]
 pub enum EventCode {
@@ -102,8 +117,17 @@ pub enum EventCode {
     Unknown,
 }
 
+/// Data received from the network on a given port, or from a peer in P2P mode.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Downlink {
+    pub port: Port,
+    pub quality: Option<LinkQuality>,
+    pub data: Vec<u8, { super::RECV_BUFFER_LEN }>,
+}
+
 /// Version information for the RAK811 board
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct FirmwareInfo {
     pub major: u8,
@@ -114,38 +138,56 @@ pub struct FirmwareInfo {
 
 pub type CommandBuffer = String<128>;
 
+/// Length of the line terminator appended to an encoded command when sending it.
+const TERMINATOR_LEN: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EncodeError {
+    /// The data to send does not fit in a command.
+    PayloadTooLarge,
+    /// The command does not fit in the buffer.
+    BufferTooSmall,
+}
+
+impl From<core::fmt::Error> for EncodeError {
+    fn from(_: core::fmt::Error) -> Self {
+        EncodeError::BufferTooSmall
+    }
+}
+
 impl<'a> Command<'a> {
     pub fn buffer() -> CommandBuffer {
         String::new()
     }
 
-    pub fn encode(&self, s: &mut CommandBuffer) {
+    pub fn encode(&self, s: &mut CommandBuffer) -> Result<(), EncodeError> {
         match self {
             Command::QueryFirmwareInfo => {
-                write!(s, "at+version").unwrap();
+                write!(s, "at+version")?;
             }
             Command::SetBand(region) => {
-                write!(s, "at+band=").unwrap();
-                region.encode(s);
+                write!(s, "at+band=")?;
+                region.encode(s)?;
             }
             Command::GetBand => {
-                write!(s, "at+band").unwrap();
+                write!(s, "at+band")?;
             }
             Command::SetMode(mode) => {
-                write!(s, "at+mode=").unwrap();
-                mode.encode(s);
+                write!(s, "at+mode=")?;
+                mode.encode(s)?;
             }
             Command::Join(mode) => {
-                write!(s, "at+join=").unwrap();
-                mode.encode(s);
+                write!(s, "at+join=")?;
+                mode.encode(s)?;
             }
             Command::SetConfig(opt) => {
-                write!(s, "at+set_config=").unwrap();
-                opt.encode(s);
+                write!(s, "at+set_config=")?;
+                opt.encode(s)?;
             }
             Command::GetConfig(key) => {
-                write!(s, "at+get_config=").unwrap();
-                key.encode(s);
+                write!(s, "at+get_config=")?;
+                key.encode(s)?;
             }
             Command::Reset(mode) => {
                 write!(
@@ -155,10 +197,10 @@ impl<'a> Command<'a> {
                         ResetMode::Restart => 0,
                         ResetMode::Reload => 1,
                     }
-                )
-                .unwrap();
+                )?;
             }
             Command::Send(qos, port, data) => {
+                check_len(s, "at+send=1,255,".len() + 2 * data.len())?;
                 write!(
                     s,
                     "at+send={},{},{}",
@@ -168,88 +210,117 @@ impl<'a> Command<'a> {
                     },
                     port,
                     HexSlice(data),
-                )
-                .unwrap();
+                )?;
             }
             Command::GetStatus => {
-                write!(s, "at+status").unwrap();
+                write!(s, "at+status")?;
+            }
+            Command::SetRfConfig(config) => {
+                let modulation = &config.modulation;
+                write!(
+                    s,
+                    "at+rf_config={},{},{},{},{},{}",
+                    config.frequency,
+                    modulation.spreading_factor,
+                    match modulation.bandwidth {
+                        250_000 => 1,
+                        500_000 => 2,
+                        _ => 0,
+                    },
+                    modulation.coding_rate.saturating_sub(4),
+                    modulation.preamble_len,
+                    config.tx_power,
+                )?;
+            }
+            Command::SendP2p(data) => {
+                check_len(s, "at+txc=1,0,".len() + 2 * data.len())?;
+                write!(s, "at+txc=1,0,{}", HexSlice(data))?;
+            }
+            Command::ReceiveP2p => {
+                write!(s, "at+rxc=1")?;
             }
         }
+        Ok(())
     }
 }
 
+/// Check that `len` more bytes and the terminator fit in the command buffer, so that oversized
+/// data is rejected before anything is written.
+fn check_len(s: &CommandBuffer, len: usize) -> Result<(), EncodeError> {
+    if s.len() + len + TERMINATOR_LEN > s.capacity() {
+        Err(EncodeError::PayloadTooLarge)
+    } else {
+        Ok(())
+    }
+}
+
+fn push_str(s: &mut CommandBuffer, val: &str) -> Result<(), EncodeError> {
+    s.push_str(val).map_err(|_| EncodeError::BufferTooSmall)
+}
+
 struct HexSlice<'a>(&'a [u8]);
 
 impl<'a> core::fmt::Display for HexSlice<'a> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::result::Result<(), core::fmt::Error> {
         for b in self.0.iter() {
-            write!(f, "{:x}", b)?;
+            write!(f, "{:02x}", b)?;
         }
         Ok(())
     }
 }
 
 impl ConfigKey {
-    pub fn encode(&self, s: &mut CommandBuffer) {
-        match self {
-            ConfigKey::DevAddr => {
-                s.push_str("dev_addr").unwrap();
-            }
-            ConfigKey::DevEui => {
-                s.push_str("dev_eui").unwrap();
-            }
-            ConfigKey::AppEui => {
-                s.push_str("app_eui").unwrap();
-            }
-            ConfigKey::AppKey => {
-                s.push_str("app_key").unwrap();
-            }
-            ConfigKey::NwksKey => {
-                s.push_str("nwks_key").unwrap();
-            }
-            ConfigKey::AppsKey => {
-                s.push_str("apps_key").unwrap();
-            }
-            ConfigKey::ChMask => {
-                s.push_str("ch_mask").unwrap();
-            }
-            ConfigKey::ChList => {
-                s.push_str("ch_list").unwrap();
-            }
-        }
+    pub fn encode(&self, s: &mut CommandBuffer) -> Result<(), EncodeError> {
+        let val = match self {
+            ConfigKey::DevAddr => "dev_addr",
+            ConfigKey::DevEui => "dev_eui",
+            ConfigKey::AppEui => "app_eui",
+            ConfigKey::AppKey => "app_key",
+            ConfigKey::NwksKey => "nwks_key",
+            ConfigKey::AppsKey => "apps_key",
+            ConfigKey::ChMask => "ch_mask",
+            ConfigKey::ChList => "ch_list",
+            ConfigKey::Class => "class",
+        };
+        push_str(s, val)
     }
 }
 
 impl<'a> ConfigOption<'a> {
-    pub fn encode(&self, s: &mut CommandBuffer) {
+    pub fn encode(&self, s: &mut CommandBuffer) -> Result<(), EncodeError> {
         match self {
             ConfigOption::DevAddr(addr) => {
-                write!(s, "dev_addr:{}", addr).unwrap();
+                write!(s, "dev_addr:{}", addr)?;
             }
             ConfigOption::DevEui(eui) => {
-                write!(s, "dev_eui:{}", eui,).unwrap();
+                write!(s, "dev_eui:{}", eui,)?;
             }
             ConfigOption::AppEui(eui) => {
-                write!(s, "app_eui:{}", eui,).unwrap();
+                write!(s, "app_eui:{}", eui,)?;
             }
             ConfigOption::AppKey(key) => {
-                write!(s, "app_key:{}", key).unwrap();
+                write!(s, "app_key:{}", key)?;
             }
             ConfigOption::NwksKey(key) => {
-                write!(s, "nwks_key:{}", key,).unwrap();
+                write!(s, "nwks_key:{}", key,)?;
             }
             ConfigOption::AppsKey(key) => {
-                write!(s, "apps_key:{}", key,).unwrap();
+                write!(s, "apps_key:{}", key,)?;
             }
             ConfigOption::ChMask(id, mask) => {
-                write!(s, "ch_mask:{},{:04x}", id, mask).unwrap();
+                write!(s, "ch_mask:{},{:04x}", id, mask)?;
+            }
+            ConfigOption::Class(class) => {
+                write!(s, "class:")?;
+                class.encode(s)?;
             }
         }
+        Ok(())
     }
 }
 
 pub trait Encoder {
-    fn encode(&self, s: &mut CommandBuffer);
+    fn encode(&self, s: &mut CommandBuffer) -> Result<(), EncodeError>;
 }
 
 pub trait Decoder {
@@ -257,12 +328,12 @@ pub trait Decoder {
 }
 
 impl Encoder for ConnectMode {
-    fn encode(&self, s: &mut CommandBuffer) {
+    fn encode(&self, s: &mut CommandBuffer) -> Result<(), EncodeError> {
         let val = match self {
             ConnectMode::OTAA => "otaa",
             ConnectMode::ABP => "abp",
         };
-        s.push_str(val).unwrap();
+        push_str(s, val)
     }
 }
 
@@ -280,12 +351,12 @@ impl Decoder for ConnectMode {
 }
 
 impl Encoder for LoraMode {
-    fn encode(&self, s: &mut CommandBuffer) {
+    fn encode(&self, s: &mut CommandBuffer) -> Result<(), EncodeError> {
         let val = match self {
             LoraMode::WAN => "0",
             LoraMode::P2P => "1",
         };
-        s.push_str(val).unwrap();
+        push_str(s, val)
     }
 }
 
@@ -302,8 +373,29 @@ impl Decoder for LoraMode {
     }
 }
 
+impl Encoder for DeviceClass {
+    fn encode(&self, s: &mut CommandBuffer) -> Result<(), EncodeError> {
+        let val = match self {
+            DeviceClass::A => "0",
+            DeviceClass::B => "1",
+            DeviceClass::C => "2",
+        };
+        push_str(s, val)
+    }
+}
+
+impl Decoder for DeviceClass {
+    fn decode(d: &[u8]) -> DeviceClass {
+        match d {
+            b"1" => DeviceClass::B,
+            b"2" => DeviceClass::C,
+            _ => DeviceClass::A,
+        }
+    }
+}
+
 impl Encoder for LoraRegion {
-    fn encode(&self, s: &mut CommandBuffer) {
+    fn encode(&self, s: &mut CommandBuffer) -> Result<(), EncodeError> {
         let val = match self {
             LoraRegion::EU868 => "EU868",
             LoraRegion::CN470 => "CN470",
@@ -314,7 +406,7 @@ impl Encoder for LoraRegion {
             LoraRegion::IN865 => "IN865",
             LoraRegion::UNKNOWN => "UNKNOWN",
         };
-        s.push_str(val).unwrap();
+        push_str(s, val)
     }
 }
 
@@ -328,6 +420,7 @@ impl Decoder for LoraRegion {
                 "KR920" => LoraRegion::KR920,
                 "AS923" => LoraRegion::AS923,
                 "IN865" => LoraRegion::IN865,
+                "CN470" => LoraRegion::CN470,
                 _ => LoraRegion::UNKNOWN,
             }
         } else {
@@ -356,8 +449,53 @@ impl EventCode {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_encode_send() {
+        let mut s = Command::buffer();
+        Command::Send(QoS::Confirmed, 2, &[0xde, 0xad])
+            .encode(&mut s)
+            .unwrap();
+        assert_eq!("at+send=1,2,dead", s.as_str());
+
+        // The largest payload leaves room for the terminator whatever the port
+        let data = [0xab; 56];
+        let mut s = Command::buffer();
+        Command::Send(QoS::Unconfirmed, 223, &data)
+            .encode(&mut s)
+            .unwrap();
+        s.push_str("\r\n").unwrap();
+
+        let data = [0xab; 57];
+        let mut s = Command::buffer();
+        assert_eq!(
+            Err(EncodeError::PayloadTooLarge),
+            Command::Send(QoS::Unconfirmed, 1, &data).encode(&mut s)
+        );
+        assert!(s.is_empty());
+    }
+
+    #[test]
+    fn test_encode_send_p2p() {
+        let mut s = Command::buffer();
+        Command::SendP2p(b"hello").encode(&mut s).unwrap();
+        assert_eq!("at+txc=1,0,68656c6c6f", s.as_str());
+
+        let data = [0xab; 57];
+        let mut s = Command::buffer();
+        Command::SendP2p(&data).encode(&mut s).unwrap();
+
+        let data = [0xab; 58];
+        let mut s = Command::buffer();
+        assert_eq!(
+            Err(EncodeError::PayloadTooLarge),
+            Command::SendP2p(&data).encode(&mut s)
+        );
+    }
 }
@@ -1,10 +1,12 @@
-#[cfg(feature = "ble+softdevice")]
 pub mod ble;
 
 pub mod led;
 
 pub mod button;
 
+#[cfg(feature = "lora")]
+pub mod lora;
+
 pub trait ActiveLevel {}
 
 /// Discriminator for inputs/outputs that are active on high state.
@@ -3,7 +3,8 @@ use embassy_boot::FirmwareUpdaterError;
 use {
     embassy_boot::{AlignedBuffer, FirmwareUpdater, FirmwareWriter},
     embassy_embedded_hal::adapter::BlockingAsync,
-    embedded_storage::nor_flash::{NorFlash, NorFlashErrorKind, ReadNorFlash},
+    embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex},
+    embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash},
     embedded_storage_async::nor_flash::{AsyncNorFlash, AsyncReadNorFlash},
     embedded_update::{FirmwareDevice, FirmwareStatus},
     heapless::Vec,
@@ -223,3 +224,80 @@ impl<F: NorFlash + ReadNorFlash> FirmwareConfig for BlockingFlash<F> {
         &mut self.flash
     }
 }
+
+/// A flash shared by the firmware manager and other users of the same flash, e.g. a bond store,
+/// for flash drivers that can only be taken once like the one of the softdevice.
+///
+/// Every operation of a [`SharedFlashHandle`] locks the flash for its duration.
+pub struct SharedFlash<M: RawMutex, F: AsyncNorFlash + AsyncReadNorFlash> {
+    flash: Mutex<M, F>,
+    capacity: usize,
+}
+
+impl<M: RawMutex, F: AsyncNorFlash + AsyncReadNorFlash> SharedFlash<M, F> {
+    pub fn new(flash: F) -> Self {
+        Self {
+            capacity: flash.capacity(),
+            flash: Mutex::new(flash),
+        }
+    }
+
+    /// Create a handle to the flash, to be passed to a [`FirmwareManager`] or used as flash.
+    pub fn handle(&self) -> SharedFlashHandle<'_, M, F> {
+        SharedFlashHandle { shared: self }
+    }
+}
+
+pub struct SharedFlashHandle<'a, M: RawMutex, F: AsyncNorFlash + AsyncReadNorFlash> {
+    shared: &'a SharedFlash<M, F>,
+}
+
+impl<'a, M: RawMutex, F: AsyncNorFlash + AsyncReadNorFlash> ErrorType
+    for SharedFlashHandle<'a, M, F>
+{
+    type Error = F::Error;
+}
+
+impl<'a, M: RawMutex, F: AsyncNorFlash + AsyncReadNorFlash> AsyncReadNorFlash
+    for SharedFlashHandle<'a, M, F>
+{
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.shared.flash.lock().await.read(offset, bytes).await
+    }
+
+    fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+}
+
+impl<'a, M: RawMutex, F: AsyncNorFlash + AsyncReadNorFlash> AsyncNorFlash
+    for SharedFlashHandle<'a, M, F>
+{
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.shared.flash.lock().await.erase(from, to).await
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.shared.flash.lock().await.write(offset, bytes).await
+    }
+}
+
+impl<'a, M: RawMutex, F: AsyncNorFlash + AsyncReadNorFlash> FirmwareConfig
+    for SharedFlashHandle<'a, M, F>
+{
+    type STATE = Self;
+    type DFU = Self;
+
+    fn state(&mut self) -> &mut Self::STATE {
+        self
+    }
+
+    fn dfu(&mut self) -> &mut Self::DFU {
+        self
+    }
+}
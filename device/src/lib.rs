@@ -19,6 +19,11 @@ pub mod net;
 
 pub mod ota;
 
+pub mod traits;
+
+#[cfg(test)]
+mod testing;
+
 #[doc(hidden)]
 pub use drogue_device_macros::{self as drogue, config, test as drogue_test};
 
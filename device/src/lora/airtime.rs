@@ -0,0 +1,118 @@
+use embassy_time::Duration;
+
+/// Modulation parameters determining the time on air of a LoRa frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Modulation {
+    /// Spreading factor, 7 to 12.
+    pub spreading_factor: u8,
+    /// Bandwidth in Hz.
+    pub bandwidth: u32,
+    /// Coding rate denominator, 5 for 4/5 up to 8 for 4/8.
+    pub coding_rate: u8,
+    /// Number of preamble symbols.
+    pub preamble_len: u16,
+    pub explicit_header: bool,
+    pub crc: bool,
+}
+
+impl Modulation {
+    /// Modulation used for LoRaWAN uplinks: 8 symbol preamble, explicit header, CRC enabled and
+    /// coding rate 4/5.
+    pub const fn lorawan(spreading_factor: u8, bandwidth: u32) -> Self {
+        Self {
+            spreading_factor,
+            bandwidth,
+            coding_rate: 5,
+            preamble_len: 8,
+            explicit_header: true,
+            crc: true,
+        }
+    }
+
+    /// Low data rate optimization is mandated when the symbol time exceeds 16 ms.
+    fn low_data_rate_optimize(&self) -> bool {
+        (1u64 << self.spreading_factor) * 1_000 / self.bandwidth as u64 >= 16
+    }
+
+    /// Time on air of a frame with a PHY payload of `payload_len` bytes, as specified by
+    /// Semtech AN1200.13.
+    pub fn time_on_air(&self, payload_len: usize) -> Duration {
+        let sf = self.spreading_factor as i64;
+        let de = self.low_data_rate_optimize() as i64;
+        let ih = !self.explicit_header as i64;
+        let crc = self.crc as i64;
+        let cr = self.coding_rate as i64 - 4;
+
+        let numerator = 8 * payload_len as i64 - 4 * sf + 28 + 16 * crc - 20 * ih;
+        let denominator = 4 * (sf - 2 * de);
+        let payload_symbols = if numerator > 0 {
+            8 + (numerator + denominator - 1) / denominator * (cr + 4)
+        } else {
+            8
+        };
+
+        // Count in quarter symbols, as the preamble lasts preamble_len + 4.25 symbols
+        let quarter_symbols = (4 * self.preamble_len as u64 + 17) + 4 * payload_symbols as u64;
+        let micros = quarter_symbols * (1u64 << sf) * 1_000_000 / (4 * self.bandwidth as u64);
+        Duration::from_micros(micros)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference values from the Semtech LoRa calculator for a 23 byte PHY payload
+    // (10 bytes of application data), 125 kHz bandwidth
+    #[test]
+    fn test_time_on_air_lorawan_125khz() {
+        let expected = [
+            (7, 61_696),
+            (8, 113_152),
+            (9, 205_824),
+            (10, 370_688),
+            (11, 823_296),
+            (12, 1_482_752),
+        ];
+        for (sf, micros) in expected {
+            let modulation = Modulation::lorawan(sf, 125_000);
+            assert_eq!(
+                Duration::from_micros(micros),
+                modulation.time_on_air(23),
+                "SF{}",
+                sf
+            );
+        }
+    }
+
+    #[test]
+    fn test_time_on_air_lorawan_250khz() {
+        let modulation = Modulation::lorawan(7, 250_000);
+        assert_eq!(Duration::from_micros(30_848), modulation.time_on_air(23));
+    }
+
+    #[test]
+    fn test_time_on_air_payload_len() {
+        let modulation = Modulation::lorawan(7, 125_000);
+        assert_eq!(Duration::from_micros(46_336), modulation.time_on_air(13));
+        assert_eq!(Duration::from_micros(102_656), modulation.time_on_air(51));
+
+        let modulation = Modulation::lorawan(12, 125_000);
+        assert_eq!(Duration::from_micros(2_465_792), modulation.time_on_air(51));
+    }
+
+    #[test]
+    fn test_time_on_air_empty_payload() {
+        // Only the minimum of 8 payload symbols plus the preamble
+        let modulation = Modulation {
+            spreading_factor: 12,
+            bandwidth: 125_000,
+            coding_rate: 5,
+            preamble_len: 8,
+            explicit_header: false,
+            crc: false,
+        };
+        assert_eq!(Duration::from_micros(663_552), modulation.time_on_air(0));
+    }
+}
@@ -0,0 +1,432 @@
+use {
+    super::Modulation,
+    core::{cell::RefCell, future::Future},
+    embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex},
+    embassy_time::{Duration, Instant, Timer},
+    heapless::{Deque, Vec},
+    lorawan_device::{
+        async_device::{
+            radio::{PhyRxTx, RfConfig, RxQuality, TxConfig},
+            Timings,
+        },
+        radio::{Bandwidth, CodingRate, SpreadingFactor},
+    },
+};
+
+/// Maximum number of sub-bands tracked by an [`AirtimeLimiter`].
+pub const MAX_SUB_BANDS: usize = 8;
+
+/// Window over which the daily airtime budget is accounted.
+const BUDGET_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Fair use policy of The Things Network: 30 seconds of uplink airtime per device per day.
+pub const TTN_FAIR_USE_BUDGET: Duration = Duration::from_secs(30);
+
+/// A frequency range with a regulatory duty cycle limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SubBand {
+    /// Lowest frequency in Hz (inclusive).
+    pub min_frequency: u32,
+    /// Highest frequency in Hz (inclusive).
+    pub max_frequency: u32,
+    /// Permitted duty cycle expressed as 1/`duty_cycle`, e.g. 100 for 1%. Both 0 and 1 mean
+    /// no limit.
+    pub duty_cycle: u16,
+}
+
+impl SubBand {
+    pub const fn new(min_frequency: u32, max_frequency: u32, duty_cycle: u16) -> Self {
+        Self {
+            min_frequency,
+            max_frequency,
+            duty_cycle,
+        }
+    }
+
+    fn contains(&self, frequency: u32) -> bool {
+        frequency >= self.min_frequency && frequency <= self.max_frequency
+    }
+}
+
+/// ETSI EN 300 220 sub-bands used by the EU868 region.
+pub const EU868_SUB_BANDS: [SubBand; 5] = [
+    SubBand::new(863_000_000, 867_999_999, 100),
+    SubBand::new(868_000_000, 868_600_000, 100),
+    SubBand::new(868_700_000, 869_200_000, 1000),
+    SubBand::new(869_400_000, 869_650_000, 10),
+    SubBand::new(869_700_000, 870_000_000, 100),
+];
+
+/// Reasons for not allowing a transmission.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AirtimeError {
+    /// The transmission is allowed once the given time has passed.
+    Unavailable(Duration),
+    /// The transmission is longer than the entire daily budget.
+    ExceedsBudget,
+}
+
+struct State<const N: usize> {
+    band_available: Vec<Instant, MAX_SUB_BANDS>,
+    history: Deque<(Instant, Duration), N>,
+}
+
+/// Tracks the airtime used for transmissions, enforcing per sub-band duty cycles and an
+/// optional daily airtime budget over a rolling 24 hour window.
+///
+/// The budget is accounted using up to `N` transmissions. When more are recorded, the oldest
+/// ones are merged, which keeps their airtime accounted for slightly longer than needed.
+pub struct AirtimeLimiter<const N: usize = 16> {
+    bands: Vec<SubBand, MAX_SUB_BANDS>,
+    daily_budget: Option<Duration>,
+    state: Mutex<NoopRawMutex, RefCell<State<N>>>,
+}
+
+impl<const N: usize> AirtimeLimiter<N> {
+    /// Create a limiter for the given sub-bands. At most [`MAX_SUB_BANDS`] are used.
+    pub fn new(bands: &[SubBand], daily_budget: Option<Duration>) -> Self {
+        let bands: Vec<SubBand, MAX_SUB_BANDS> =
+            bands.iter().take(MAX_SUB_BANDS).copied().collect();
+        let band_available = bands.iter().map(|_| Instant::from_ticks(0)).collect();
+        Self {
+            bands,
+            daily_budget,
+            state: Mutex::new(RefCell::new(State {
+                band_available,
+                history: Deque::new(),
+            })),
+        }
+    }
+
+    /// Create a limiter for the EU868 region with the fair use budget of The Things Network.
+    pub fn eu868_ttn() -> Self {
+        Self::new(&EU868_SUB_BANDS, Some(TTN_FAIR_USE_BUDGET))
+    }
+
+    /// Check if a transmission with the given airtime is allowed on a frequency.
+    pub fn check(
+        &self,
+        now: Instant,
+        frequency: u32,
+        airtime: Duration,
+    ) -> Result<(), AirtimeError> {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            Self::expire(&mut state, now);
+
+            let mut wait = Duration::from_ticks(0);
+            if let Some(idx) = self.band(frequency) {
+                let available = state.band_available[idx];
+                if available > now {
+                    wait = available - now;
+                }
+            }
+
+            if let Some(budget) = self.daily_budget {
+                if airtime > budget {
+                    return Err(AirtimeError::ExceedsBudget);
+                }
+                let mut used = Self::used(&state);
+                // Find when enough airtime has left the window for this transmission to fit
+                for (at, duration) in state.history.iter() {
+                    if used + airtime <= budget {
+                        break;
+                    }
+                    used = used - *duration;
+                    let expires = *at + BUDGET_WINDOW - now;
+                    if expires > wait {
+                        wait = expires;
+                    }
+                }
+            }
+
+            if wait.as_ticks() > 0 {
+                Err(AirtimeError::Unavailable(wait))
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// Record a transmission started at `now`.
+    pub fn record(&self, now: Instant, frequency: u32, airtime: Duration) {
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            Self::expire(&mut state, now);
+
+            if let Some(idx) = self.band(frequency) {
+                let off_time = airtime * (self.bands[idx].duty_cycle as u32).saturating_sub(1);
+                state.band_available[idx] = now + airtime + off_time;
+            }
+
+            if self.daily_budget.is_some() {
+                if state.history.is_full() {
+                    if let (Some((_, first)), Some((at, second))) =
+                        (state.history.pop_front(), state.history.pop_front())
+                    {
+                        state.history.push_front((at, first + second)).ok();
+                    }
+                }
+                state.history.push_back((now, airtime)).ok();
+            }
+        })
+    }
+
+    /// Airtime left of the daily budget, or `None` if no budget is enforced.
+    pub fn remaining(&self, now: Instant) -> Option<Duration> {
+        let budget = self.daily_budget?;
+        self.state.lock(|state| {
+            let mut state = state.borrow_mut();
+            Self::expire(&mut state, now);
+            let used = Self::used(&state);
+            Some(if used < budget {
+                budget - used
+            } else {
+                Duration::from_ticks(0)
+            })
+        })
+    }
+
+    fn band(&self, frequency: u32) -> Option<usize> {
+        self.bands.iter().position(|b| b.contains(frequency))
+    }
+
+    fn expire(state: &mut State<N>, now: Instant) {
+        while let Some((at, _)) = state.history.front() {
+            if *at + BUDGET_WINDOW <= now {
+                state.history.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn used(state: &State<N>) -> Duration {
+        state
+            .history
+            .iter()
+            .fold(Duration::from_ticks(0), |used, (_, d)| used + *d)
+    }
+}
+
+/// What to do with a transmission that is not allowed yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DutyCyclePolicy {
+    /// Wait until the transmission is allowed.
+    Delay,
+    /// Fail the transmission.
+    Reject,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DutyCycleError<E> {
+    Radio(E),
+    Airtime(AirtimeError),
+}
+
+/// A radio wrapper that accounts the airtime of every transmission in an [`AirtimeLimiter`].
+pub struct DutyCycleRadio<'a, R, const N: usize = 16>
+where
+    R: PhyRxTx + Timings,
+{
+    radio: R,
+    limiter: &'a AirtimeLimiter<N>,
+    policy: DutyCyclePolicy,
+}
+
+impl<'a, R, const N: usize> DutyCycleRadio<'a, R, N>
+where
+    R: PhyRxTx + Timings,
+{
+    pub fn new(radio: R, limiter: &'a AirtimeLimiter<N>, policy: DutyCyclePolicy) -> Self {
+        Self {
+            radio,
+            limiter,
+            policy,
+        }
+    }
+}
+
+impl<'a, R, const N: usize> PhyRxTx for DutyCycleRadio<'a, R, N>
+where
+    R: PhyRxTx + Timings,
+{
+    type PhyError = DutyCycleError<R::PhyError>;
+
+    type TxFuture<'m> = impl Future<Output = Result<u32, Self::PhyError>> + 'm
+    where
+        Self: 'm;
+    fn tx<'m>(&'m mut self, config: TxConfig, buf: &'m [u8]) -> Self::TxFuture<'m> {
+        async move {
+            let frequency = config.rf.frequency;
+            let airtime = to_modulation(&config.rf).time_on_air(buf.len());
+            loop {
+                match self.limiter.check(Instant::now(), frequency, airtime) {
+                    Ok(()) => break,
+                    Err(AirtimeError::Unavailable(wait))
+                        if self.policy == DutyCyclePolicy::Delay =>
+                    {
+                        debug!("Delaying transmission by {} ms", wait.as_millis());
+                        Timer::after(wait).await;
+                    }
+                    Err(e) => {
+                        warn!("Transmission not allowed: {:?}", e);
+                        return Err(DutyCycleError::Airtime(e));
+                    }
+                }
+            }
+            let start = Instant::now();
+            let result = self
+                .radio
+                .tx(config, buf)
+                .await
+                .map_err(DutyCycleError::Radio)?;
+            self.limiter.record(start, frequency, airtime);
+            Ok(result)
+        }
+    }
+
+    type RxFuture<'m> = impl Future<Output = Result<(usize, RxQuality), Self::PhyError>> + 'm
+    where
+        Self: 'm;
+    fn rx<'m>(&'m mut self, config: RfConfig, rx_buf: &'m mut [u8]) -> Self::RxFuture<'m> {
+        async move {
+            self.radio
+                .rx(config, rx_buf)
+                .await
+                .map_err(DutyCycleError::Radio)
+        }
+    }
+}
+
+impl<'a, R, const N: usize> Timings for DutyCycleRadio<'a, R, N>
+where
+    R: PhyRxTx + Timings,
+{
+    fn get_rx_window_offset_ms(&self) -> i32 {
+        self.radio.get_rx_window_offset_ms()
+    }
+
+    fn get_rx_window_duration_ms(&self) -> u32 {
+        self.radio.get_rx_window_duration_ms()
+    }
+}
+
+fn to_modulation(config: &RfConfig) -> Modulation {
+    let spreading_factor = match config.spreading_factor {
+        SpreadingFactor::_7 => 7,
+        SpreadingFactor::_8 => 8,
+        SpreadingFactor::_9 => 9,
+        SpreadingFactor::_10 => 10,
+        SpreadingFactor::_11 => 11,
+        SpreadingFactor::_12 => 12,
+    };
+    let bandwidth = match config.bandwidth {
+        Bandwidth::_125KHz => 125_000,
+        Bandwidth::_250KHz => 250_000,
+        Bandwidth::_500KHz => 500_000,
+    };
+    let mut modulation = Modulation::lorawan(spreading_factor, bandwidth);
+    modulation.coding_rate = match config.coding_rate {
+        CodingRate::_4_5 => 5,
+        CodingRate::_4_6 => 6,
+        CodingRate::_4_7 => 7,
+        CodingRate::_4_8 => 8,
+    };
+    modulation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL: u32 = 868_100_000;
+
+    #[test]
+    fn test_sub_band_duty_cycle() {
+        let limiter: AirtimeLimiter = AirtimeLimiter::new(&EU868_SUB_BANDS, None);
+        let now = Instant::from_secs(10);
+        let airtime = Duration::from_millis(100);
+
+        assert_eq!(Ok(()), limiter.check(now, CHANNEL, airtime));
+        limiter.record(now, CHANNEL, airtime);
+
+        // 1% duty cycle: blocked for 100 times the airtime
+        assert_eq!(
+            Err(AirtimeError::Unavailable(Duration::from_secs(10))),
+            limiter.check(now, CHANNEL, airtime)
+        );
+        assert_eq!(
+            Err(AirtimeError::Unavailable(Duration::from_secs(1))),
+            limiter.check(now + Duration::from_secs(9), 868_500_000, airtime)
+        );
+        assert_eq!(
+            Ok(()),
+            limiter.check(now + Duration::from_secs(10), CHANNEL, airtime)
+        );
+
+        // Other sub-bands are unaffected
+        assert_eq!(Ok(()), limiter.check(now, 869_525_000, airtime));
+        assert_eq!(None, limiter.remaining(now));
+
+        // Sub-bands without a limit
+        let bands = [SubBand::new(863_000_000, 870_000_000, 0)];
+        let limiter: AirtimeLimiter = AirtimeLimiter::new(&bands, None);
+        limiter.record(now, CHANNEL, airtime);
+        assert_eq!(Ok(()), limiter.check(now + airtime, CHANNEL, airtime));
+    }
+
+    #[test]
+    fn test_daily_budget() {
+        let limiter: AirtimeLimiter = AirtimeLimiter::new(&[], Some(Duration::from_secs(3)));
+        let start = Instant::from_secs(0);
+        let airtime = Duration::from_secs(1);
+
+        for i in 0..3 {
+            let now = start + Duration::from_secs(i * 60);
+            assert_eq!(Ok(()), limiter.check(now, CHANNEL, airtime));
+            limiter.record(now, CHANNEL, airtime);
+        }
+
+        let now = start + Duration::from_secs(3600);
+        assert_eq!(Some(Duration::from_secs(0)), limiter.remaining(now));
+        assert_eq!(
+            Err(AirtimeError::Unavailable(
+                BUDGET_WINDOW - Duration::from_secs(3600)
+            )),
+            limiter.check(now, CHANNEL, airtime)
+        );
+        assert_eq!(
+            Err(AirtimeError::ExceedsBudget),
+            limiter.check(now, CHANNEL, Duration::from_secs(4))
+        );
+
+        // The first transmission leaves the window
+        let now = start + BUDGET_WINDOW;
+        assert_eq!(Some(Duration::from_secs(1)), limiter.remaining(now));
+        assert_eq!(Ok(()), limiter.check(now, CHANNEL, airtime));
+    }
+
+    #[test]
+    fn test_history_merge() {
+        let limiter: AirtimeLimiter<2> = AirtimeLimiter::new(&[], Some(Duration::from_secs(10)));
+        let airtime = Duration::from_secs(1);
+        for i in 0..3 {
+            limiter.record(Instant::from_secs(i), CHANNEL, airtime);
+        }
+        assert_eq!(
+            Some(Duration::from_secs(7)),
+            limiter.remaining(Instant::from_secs(3))
+        );
+
+        // Merged transmissions expire together with the newest of them
+        assert_eq!(
+            Some(Duration::from_secs(9)),
+            limiter.remaining(Instant::from_secs(0) + BUDGET_WINDOW + Duration::from_secs(1))
+        );
+    }
+}
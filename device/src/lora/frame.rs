@@ -0,0 +1,113 @@
+/// Length of the MAC header.
+const MHDR_LEN: usize = 1;
+/// Length of the frame header without FOpts: DevAddr, FCtrl and FCnt.
+const FHDR_LEN: usize = 7;
+/// Length of the message integrity code.
+const MIC_LEN: usize = 4;
+
+/// Message type of a frame, from the upper bits of its MAC header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameType {
+    JoinRequest,
+    JoinAccept,
+    UnconfirmedDataUp,
+    UnconfirmedDataDown,
+    ConfirmedDataUp,
+    ConfirmedDataDown,
+    Other,
+}
+
+impl FrameType {
+    pub fn of(frame: &[u8]) -> Option<Self> {
+        let mtype = match frame.first()? >> 5 {
+            0 => FrameType::JoinRequest,
+            1 => FrameType::JoinAccept,
+            2 => FrameType::UnconfirmedDataUp,
+            3 => FrameType::UnconfirmedDataDown,
+            4 => FrameType::ConfirmedDataUp,
+            5 => FrameType::ConfirmedDataDown,
+            _ => FrameType::Other,
+        };
+        Some(mtype)
+    }
+
+    pub fn is_data_downlink(&self) -> bool {
+        matches!(
+            self,
+            FrameType::UnconfirmedDataDown | FrameType::ConfirmedDataDown
+        )
+    }
+}
+
+/// Options and payload of a data frame. The payload is only readable once the frame has been
+/// decrypted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataFrame<'a> {
+    /// MAC commands piggybacked in the frame header.
+    pub fopts: &'a [u8],
+    pub port: Option<u8>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> DataFrame<'a> {
+    /// Split a data frame into its fields, or return `None` if it is truncated.
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        let end = frame.len().checked_sub(MIC_LEN)?;
+        let body = frame.get(MHDR_LEN..end)?;
+        let fopts_len = (*body.get(4)? & 0x0F) as usize;
+        let fopts = body.get(FHDR_LEN..FHDR_LEN + fopts_len)?;
+        let (port, payload) = match body[FHDR_LEN + fopts_len..].split_first() {
+            Some((port, payload)) => (Some(*port), payload),
+            None => (None, &[][..]),
+        };
+        Some(Self {
+            fopts,
+            port,
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_frame() {
+        // Unconfirmed downlink with one FOpts byte, port 3 and a 2 byte payload
+        let frame = [
+            0x60, 0x04, 0x03, 0x02, 0x01, 0x01, 0x05, 0x00, 0x06, 0x03, 0xaa, 0xbb, 0x11, 0x22,
+            0x33, 0x44,
+        ];
+        assert_eq!(Some(FrameType::UnconfirmedDataDown), FrameType::of(&frame));
+        assert_eq!(
+            Some(DataFrame {
+                fopts: &[0x06],
+                port: Some(3),
+                payload: &[0xaa, 0xbb],
+            }),
+            DataFrame::parse(&frame)
+        );
+
+        // Frame without port and payload
+        let frame = [0xa0, 0x04, 0x03, 0x02, 0x01, 0x20, 0x05, 0x00, 1, 2, 3, 4];
+        assert_eq!(Some(FrameType::ConfirmedDataDown), FrameType::of(&frame));
+        assert_eq!(
+            Some(DataFrame {
+                fopts: &[],
+                port: None,
+                payload: &[],
+            }),
+            DataFrame::parse(&frame)
+        );
+
+        // FOpts exceeding the frame
+        let frame = [
+            0x60, 0x04, 0x03, 0x02, 0x01, 0x03, 0x05, 0x00, 0x06, 1, 2, 3, 4,
+        ];
+        assert_eq!(None, DataFrame::parse(&frame));
+        assert_eq!(None, DataFrame::parse(&[0x60, 1, 2, 3]));
+        assert_eq!(None, FrameType::of(&[]));
+    }
+}
@@ -0,0 +1,110 @@
+/// Command identifier of LinkCheckReq and LinkCheckAns.
+pub const LINK_CHECK_CID: u8 = 0x02;
+/// Command identifier of DeviceTimeReq and DeviceTimeAns.
+pub const DEVICE_TIME_CID: u8 = 0x0D;
+
+/// Direction of a frame, which determines the payload length of the MAC commands it carries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    Uplink,
+    Downlink,
+}
+
+/// Iterator over the MAC commands in FOpts or in the payload of a port 0 frame, yielding the
+/// identifier and payload of each command.
+///
+/// Iteration stops at a command with an unknown identifier or a truncated payload, since the
+/// length of the commands that follow it cannot be known.
+#[derive(Debug, Clone)]
+pub struct MacCommands<'a> {
+    data: &'a [u8],
+    direction: Direction,
+}
+
+impl<'a> MacCommands<'a> {
+    pub fn new(data: &'a [u8], direction: Direction) -> Self {
+        Self { data, direction }
+    }
+}
+
+impl<'a> Iterator for MacCommands<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (cid, rest) = self.data.split_first()?;
+        let len = match payload_len(*cid, self.direction) {
+            Some(len) if len <= rest.len() => len,
+            _ => {
+                self.data = &[];
+                return None;
+            }
+        };
+        let (payload, rest) = rest.split_at(len);
+        self.data = rest;
+        Some((*cid, payload))
+    }
+}
+
+/// Payload length of the LoRaWAN 1.0.3 MAC commands.
+fn payload_len(cid: u8, direction: Direction) -> Option<usize> {
+    let (uplink, downlink) = match cid {
+        0x02 => (0, 2),
+        0x03 => (1, 4),
+        0x04 => (0, 1),
+        0x05 => (1, 4),
+        0x06 => (2, 0),
+        0x07 => (1, 5),
+        0x08 => (0, 1),
+        0x09 => (0, 1),
+        0x0A => (1, 4),
+        0x0D => (0, 5),
+        _ => return None,
+    };
+    match direction {
+        Direction::Uplink => Some(uplink),
+        Direction::Downlink => Some(downlink),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mac_commands() {
+        // LinkCheckAns, DevStatusReq and DeviceTimeAns
+        let data = [0x02, 20, 1, 0x06, 0x0D, 0x10, 0x20, 0x30, 0x40, 0x80];
+        let mut commands = MacCommands::new(&data, Direction::Downlink);
+        assert_eq!(Some((LINK_CHECK_CID, &[20, 1][..])), commands.next());
+        assert_eq!(Some((0x06, &[][..])), commands.next());
+        assert_eq!(
+            Some((DEVICE_TIME_CID, &[0x10, 0x20, 0x30, 0x40, 0x80][..])),
+            commands.next()
+        );
+        assert_eq!(None, commands.next());
+
+        // LinkCheckReq and DevStatusAns
+        let data = [0x02, 0x06, 0xFF, 0x20];
+        let commands: Vec<_> = MacCommands::new(&data, Direction::Uplink).collect();
+        assert_eq!(
+            vec![(LINK_CHECK_CID, &[][..]), (0x06, &[0xFF, 0x20][..])],
+            commands
+        );
+
+        // Unknown and truncated commands
+        let data = [0x02, 0x80, 0x02];
+        let mut commands = MacCommands::new(&data, Direction::Uplink);
+        assert_eq!(Some((LINK_CHECK_CID, &[][..])), commands.next());
+        assert_eq!(None, commands.next());
+        assert_eq!(None, commands.next());
+        assert_eq!(
+            0,
+            MacCommands::new(&[0x02, 20], Direction::Downlink).count()
+        );
+        assert_eq!(
+            None,
+            MacCommands::new(&[0x0D, 1, 2], Direction::Downlink).next()
+        );
+    }
+}
@@ -1,3 +1,13 @@
+mod airtime;
+mod duty_cycle;
+mod frame;
+mod mac;
+mod p2p;
+#[cfg(feature = "std")]
+pub mod simulator;
+
+pub use {airtime::*, duty_cycle::*, frame::*, mac::*, p2p::*};
+
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DevAddr(pub [u8; 4]);
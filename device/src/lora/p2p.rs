@@ -0,0 +1,77 @@
+use super::Modulation;
+
+/// Radio settings for point-to-point communication. Both ends must use the same settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct P2pConfig {
+    /// Carrier frequency in Hz.
+    pub frequency: u32,
+    pub modulation: Modulation,
+    /// Transmit power in dBm.
+    pub tx_power: i8,
+}
+
+impl P2pConfig {
+    pub const fn new(frequency: u32, modulation: Modulation, tx_power: i8) -> Self {
+        Self {
+            frequency,
+            modulation,
+            tx_power,
+        }
+    }
+
+    /// Whether the settings are supported for point-to-point communication: spreading factor 7
+    /// to 12, a bandwidth of 125, 250 or 500 kHz, coding rate 4/5 to 4/8, an explicit header and
+    /// CRC.
+    pub fn is_supported(&self) -> bool {
+        let modulation = &self.modulation;
+        (7..=12).contains(&modulation.spreading_factor)
+            && matches!(modulation.bandwidth, 125_000 | 250_000 | 500_000)
+            && (5..=8).contains(&modulation.coding_rate)
+            && modulation.explicit_header
+            && modulation.crc
+    }
+}
+
+/// Signal quality of a received packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PacketStatus {
+    /// Received signal strength in dBm.
+    pub rssi: i16,
+    /// Signal to noise ratio in dB.
+    pub snr: i8,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supported_config() {
+        let config = P2pConfig::new(868_100_000, Modulation::lorawan(7, 125_000), 14);
+        assert!(config.is_supported());
+
+        let unsupported = [
+            Modulation::lorawan(6, 125_000),
+            Modulation::lorawan(13, 125_000),
+            Modulation::lorawan(12, 62_500),
+            Modulation {
+                coding_rate: 4,
+                ..Modulation::lorawan(7, 125_000)
+            },
+            Modulation {
+                explicit_header: false,
+                ..Modulation::lorawan(7, 125_000)
+            },
+            Modulation {
+                crc: false,
+                ..Modulation::lorawan(7, 125_000)
+            },
+        ];
+        for modulation in unsupported {
+            let config = P2pConfig::new(868_100_000, modulation, 14);
+            assert!(!config.is_supported(), "{:?}", modulation);
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! A simulated LoRa radio connected to an in-process network server, for running LoRaWAN flows
+//! on the host.
+use {
+    super::{DataFrame, Direction, MacCommands, DEVICE_TIME_CID, LINK_CHECK_CID},
+    core::{cell::RefCell, future::Future},
+    embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex},
+    heapless::{Deque, Vec},
+    lorawan::{
+        creator::{DataPayloadCreator, JoinAcceptCreator},
+        keys::AES128,
+        parser::{
+            parse, DataHeader, DataPayload, DecryptedJoinAcceptPayload, DevNonce, FCtrl,
+            FRMPayload, PhyPayload,
+        },
+    },
+    lorawan_device::async_device::{
+        radio::{PhyRxTx, RfConfig, RxQuality, TxConfig},
+        Timings,
+    },
+};
+
+/// Default RX2 frequency of the EU868 region.
+pub const RX2_FREQUENCY: u32 = 869_525_000;
+
+const MTU: usize = 256;
+const QUEUE_LEN: usize = 8;
+
+const NET_ID: [u8; 3] = [0x00, 0x00, 0x13];
+const DEV_ADDR: [u8; 4] = [0x01, 0x02, 0x03, 0x04];
+
+/// Demodulation margin reported in link check answers.
+pub const LINK_MARGIN: u8 = 20;
+
+pub type Payload = Vec<u8, MTU>;
+
+/// Receive window in which the network server sends downlinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RxWindow {
+    Rx1,
+    Rx2,
+}
+
+/// An uplink accepted by the network server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uplink {
+    pub frequency: u32,
+    pub fcnt: u32,
+    pub port: Option<u8>,
+    pub confirmed: bool,
+    pub data: Payload,
+}
+
+/// Errors of the simulated radio.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SimulatorError {
+    BufferTooSmall,
+}
+
+struct Session {
+    dev_addr: [u8; 4],
+    nwk_skey: AES128,
+    app_skey: AES128,
+    fcnt_up: Option<u32>,
+    fcnt_down: u32,
+}
+
+struct Downlink {
+    window: RxWindow,
+    data: Payload,
+}
+
+struct State {
+    dev_eui: [u8; 8],
+    app_eui: [u8; 8],
+    app_key: AES128,
+    app_nonce: u32,
+    dev_nonces: Deque<[u8; 2], QUEUE_LEN>,
+    session: Option<Session>,
+    window: RxWindow,
+    pending: Option<Downlink>,
+    downlinks: Deque<(u8, Payload), QUEUE_LEN>,
+    uplinks: Deque<Uplink, QUEUE_LEN>,
+    gps_time: u32,
+}
+
+/// A network server for a single device using OTAA.
+///
+/// It validates join requests and data uplinks, including MIC and frame counters, answers
+/// joins with a join accept and schedules queued downlinks and acknowledgements in the
+/// configured receive window. LinkCheckReq and DeviceTimeReq are answered in a port 0 downlink,
+/// ahead of queued data.
+pub struct NetworkServer {
+    state: Mutex<NoopRawMutex, RefCell<State>>,
+}
+
+impl NetworkServer {
+    /// Create a network server knowing the device with the given OTAA credentials, as passed to
+    /// the device when joining.
+    pub fn new(dev_eui: [u8; 8], app_eui: [u8; 8], app_key: [u8; 16]) -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(State {
+                dev_eui,
+                app_eui,
+                app_key: AES128(app_key),
+                app_nonce: 0,
+                dev_nonces: Deque::new(),
+                session: None,
+                window: RxWindow::Rx1,
+                pending: None,
+                downlinks: Deque::new(),
+                uplinks: Deque::new(),
+                gps_time: 0,
+            })),
+        }
+    }
+
+    /// Select the receive window used for subsequent downlinks.
+    pub fn set_rx_window(&self, window: RxWindow) {
+        self.state.lock(|s| s.borrow_mut().window = window);
+    }
+
+    /// Queue data to be sent to the device after its next uplink.
+    pub fn enqueue(&self, port: u8, data: &[u8]) -> Result<(), SimulatorError> {
+        let data = Vec::from_slice(data).map_err(|_| SimulatorError::BufferTooSmall)?;
+        self.state.lock(|s| {
+            s.borrow_mut()
+                .downlinks
+                .push_back((port, data))
+                .map_err(|_| SimulatorError::BufferTooSmall)
+        })
+    }
+
+    /// Set the time reported in DeviceTimeAns, in seconds since the GPS epoch.
+    pub fn set_gps_time(&self, seconds: u32) {
+        self.state.lock(|s| s.borrow_mut().gps_time = seconds);
+    }
+
+    /// Send data to a class C device right away, in the RX2 window it keeps listening in.
+    pub fn push(&self, port: u8, data: &[u8]) -> Result<(), SimulatorError> {
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            let data = s
+                .data_frame(Some((port, data)), false)
+                .ok_or(SimulatorError::BufferTooSmall)?;
+            s.pending.replace(Downlink {
+                window: RxWindow::Rx2,
+                data,
+            });
+            Ok(())
+        })
+    }
+
+    /// Take the oldest uplink received from the device.
+    pub fn uplink(&self) -> Option<Uplink> {
+        self.state.lock(|s| s.borrow_mut().uplinks.pop_front())
+    }
+
+    pub fn is_joined(&self) -> bool {
+        self.state.lock(|s| s.borrow().session.is_some())
+    }
+
+    fn handle_uplink(&self, frequency: u32, data: &[u8]) {
+        self.state.lock(|s| {
+            let s = &mut *s.borrow_mut();
+            // A downlink not picked up before the next uplink is lost
+            s.pending = None;
+            let mut buf: Payload = match Vec::from_slice(data) {
+                Ok(buf) => buf,
+                Err(_) => return,
+            };
+            let confirmed = match parse(&mut buf[..]) {
+                Ok(PhyPayload::JoinRequest(request)) => {
+                    if !request.validate_mic(&s.app_key) {
+                        warn!("Join request with invalid MIC");
+                        return;
+                    }
+                    if request.dev_eui().as_ref() != s.dev_eui
+                        || request.app_eui().as_ref() != s.app_eui
+                    {
+                        warn!("Join request from unknown device");
+                        return;
+                    }
+                    let mut dev_nonce = [0; 2];
+                    dev_nonce.copy_from_slice(request.dev_nonce().as_ref());
+                    if s.dev_nonces.iter().any(|n| *n == dev_nonce) {
+                        warn!("Join request with reused DevNonce");
+                        return;
+                    }
+                    if s.dev_nonces.is_full() {
+                        s.dev_nonces.pop_front();
+                    }
+                    s.dev_nonces.push_back(dev_nonce).ok();
+                    s.join_accept(&request.dev_nonce());
+                    return;
+                }
+                Ok(PhyPayload::Data(DataPayload::Encrypted(payload))) => {
+                    if !payload.is_uplink() {
+                        return;
+                    }
+                    let confirmed = payload.is_confirmed();
+                    let session = match s.session.as_mut() {
+                        Some(session) if payload.fhdr().dev_addr().as_ref() == session.dev_addr => {
+                            session
+                        }
+                        _ => {
+                            warn!("Uplink from unknown device");
+                            return;
+                        }
+                    };
+
+                    // Restore the upper 16 bits of the frame counter and reject replays
+                    let fcnt16 = payload.fhdr().fcnt() as u32;
+                    let fcnt = match session.fcnt_up {
+                        None => fcnt16,
+                        Some(last) => {
+                            let fcnt = (last & 0xFFFF_0000) | fcnt16;
+                            if fcnt > last {
+                                fcnt
+                            } else {
+                                fcnt + 0x1_0000
+                            }
+                        }
+                    };
+                    if !payload.validate_mic(&session.nwk_skey, fcnt) {
+                        warn!("Uplink with invalid MIC or replayed frame counter");
+                        return;
+                    }
+                    let decrypted = match payload.decrypt(
+                        Some(&session.nwk_skey),
+                        Some(&session.app_skey),
+                        fcnt,
+                    ) {
+                        Ok(decrypted) => decrypted,
+                        Err(_) => return,
+                    };
+                    session.fcnt_up.replace(fcnt);
+
+                    let port = decrypted.f_port();
+                    let data = match decrypted.frm_payload() {
+                        Ok(FRMPayload::Data(data)) => Vec::from_slice(data).unwrap_or_default(),
+                        _ => Vec::new(),
+                    };
+                    if s.uplinks.is_full() {
+                        s.uplinks.pop_front();
+                    }
+                    s.uplinks
+                        .push_back(Uplink {
+                            frequency,
+                            fcnt,
+                            port,
+                            confirmed,
+                            data,
+                        })
+                        .ok();
+                    confirmed
+                }
+                _ => {
+                    warn!("Ignoring unexpected frame");
+                    return;
+                }
+            };
+
+            // MAC commands are in FOpts or in the payload of a port 0 uplink, decrypted by now
+            let mut answers = Payload::new();
+            if let Some(frame) = DataFrame::parse(&buf) {
+                let payload = match frame.port {
+                    Some(0) => frame.payload,
+                    _ => &[],
+                };
+                let commands = MacCommands::new(frame.fopts, Direction::Uplink)
+                    .chain(MacCommands::new(payload, Direction::Uplink));
+                for (cid, _) in commands {
+                    let answer = match cid {
+                        LINK_CHECK_CID => answers.extend_from_slice(&[cid, LINK_MARGIN, 1]),
+                        DEVICE_TIME_CID => {
+                            let time = s.gps_time.to_le_bytes();
+                            answers.extend_from_slice(&[cid, time[0], time[1], time[2], time[3], 0])
+                        }
+                        _ => Ok(()),
+                    };
+                    answer.ok();
+                }
+            }
+            s.data_downlink(confirmed, &answers);
+        })
+    }
+
+    /// Take the downlink scheduled for the receive window listening on `frequency`.
+    fn downlink(&self, frequency: u32) -> Option<Payload> {
+        let window = if frequency == RX2_FREQUENCY {
+            RxWindow::Rx2
+        } else {
+            RxWindow::Rx1
+        };
+        self.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            match &s.pending {
+                Some(downlink) if downlink.window == window => s.pending.take().map(|d| d.data),
+                _ => None,
+            }
+        })
+    }
+}
+
+impl State {
+    fn join_accept<T: AsRef<[u8]>>(&mut self, dev_nonce: &DevNonce<T>) {
+        self.app_nonce += 1;
+        let app_nonce = self.app_nonce.to_le_bytes();
+
+        let mut creator = JoinAcceptCreator::new();
+        creator
+            .set_app_nonce(&[app_nonce[0], app_nonce[1], app_nonce[2]])
+            .set_net_id(&NET_ID)
+            .set_dev_addr(&DEV_ADDR)
+            .set_dl_settings(0)
+            .set_rx_delay(1);
+        let mut accept: Payload = match creator.build(&self.app_key) {
+            Ok(data) => Vec::from_slice(data).unwrap_or_default(),
+            Err(_) => return,
+        };
+        let data = accept.clone();
+
+        // Derive the session keys the same way the device does
+        let decrypted = match DecryptedJoinAcceptPayload::new(&mut accept[..], &self.app_key) {
+            Ok(decrypted) => decrypted,
+            Err(_) => return,
+        };
+        self.session.replace(Session {
+            dev_addr: DEV_ADDR,
+            nwk_skey: decrypted.derive_newskey(dev_nonce, &self.app_key),
+            app_skey: decrypted.derive_appskey(dev_nonce, &self.app_key),
+            fcnt_up: None,
+            fcnt_down: 0,
+        });
+        self.pending.replace(Downlink {
+            window: self.window,
+            data,
+        });
+    }
+
+    fn data_downlink(&mut self, ack: bool, answers: &[u8]) {
+        // Queued data waits for the next uplink when there are MAC command answers to send
+        let queued = if answers.is_empty() {
+            self.downlinks.pop_front()
+        } else {
+            None
+        };
+        let data = match &queued {
+            Some((port, data)) => Some((*port, &data[..])),
+            None if !answers.is_empty() => Some((0, answers)),
+            None if ack => None,
+            None => return,
+        };
+        if let Some(data) = self.data_frame(data, ack) {
+            self.pending.replace(Downlink {
+                window: self.window,
+                data,
+            });
+        }
+    }
+
+    /// Build the next data downlink of the session.
+    fn data_frame(&mut self, data: Option<(u8, &[u8])>, ack: bool) -> Option<Payload> {
+        let session = self.session.as_mut()?;
+
+        let mut creator = DataPayloadCreator::new();
+        creator
+            .set_confirmed(false)
+            .set_uplink(false)
+            .set_dev_addr(&session.dev_addr)
+            .set_fctrl(&FCtrl::new(if ack { 0x20 } else { 0x00 }, false))
+            .set_fcnt(session.fcnt_down);
+        let data = match data {
+            Some((port, data)) => {
+                creator.set_f_port(port);
+                data
+            }
+            None => &[],
+        };
+        let data = match creator.build(data, &[], &session.nwk_skey, &session.app_skey) {
+            Ok(data) => Vec::from_slice(data).ok()?,
+            Err(_) => return None,
+        };
+        session.fcnt_down += 1;
+        Some(data)
+    }
+}
+
+/// A radio exchanging frames with a [`NetworkServer`] instead of over the air.
+pub struct SimulatedRadio<'a> {
+    server: &'a NetworkServer,
+}
+
+impl<'a> SimulatedRadio<'a> {
+    pub fn new(server: &'a NetworkServer) -> Self {
+        Self { server }
+    }
+}
+
+impl<'a> PhyRxTx for SimulatedRadio<'a> {
+    type PhyError = SimulatorError;
+
+    type TxFuture<'m> = impl Future<Output = Result<u32, Self::PhyError>> + 'm
+    where
+        Self: 'm;
+    fn tx<'m>(&'m mut self, config: TxConfig, buf: &'m [u8]) -> Self::TxFuture<'m> {
+        async move {
+            self.server.handle_uplink(config.rf.frequency, buf);
+            Ok(0)
+        }
+    }
+
+    type RxFuture<'m> = impl Future<Output = Result<(usize, RxQuality), Self::PhyError>> + 'm
+    where
+        Self: 'm;
+    fn rx<'m>(&'m mut self, config: RfConfig, rx_buf: &'m mut [u8]) -> Self::RxFuture<'m> {
+        async move {
+            match self.server.downlink(config.frequency) {
+                Some(data) => {
+                    if data.len() > rx_buf.len() {
+                        return Err(SimulatorError::BufferTooSmall);
+                    }
+                    rx_buf[..data.len()].copy_from_slice(&data);
+                    Ok((data.len(), RxQuality::new(-60, 10)))
+                }
+                // Nothing to receive in this window, let the device time out
+                None => core::future::pending().await,
+            }
+        }
+    }
+}
+
+impl<'a> Timings for SimulatedRadio<'a> {
+    fn get_rx_window_offset_ms(&self) -> i32 {
+        0
+    }
+
+    fn get_rx_window_duration_ms(&self) -> u32 {
+        100
+    }
+}
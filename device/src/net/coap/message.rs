@@ -0,0 +1,692 @@
+//! Encoding and decoding of CoAP messages (RFC 7252) and their options
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xFF;
+const HEADER_LEN: usize = 4;
+const MAX_TOKEN_LEN: usize = 8;
+
+const OBSERVE: u16 = 6;
+const URI_PATH: u16 = 11;
+const CONTENT_FORMAT: u16 = 12;
+const URI_QUERY: u16 = 15;
+const ACCEPT: u16 = 17;
+const BLOCK2: u16 = 23;
+const BLOCK1: u16 = 27;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MessageError {
+    BufferTooSmall,
+    Malformed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Method {
+    Get = 1,
+    Post = 2,
+    Put = 3,
+    Delete = 4,
+}
+
+/// Code of a response, made of a class and a detail, like 2.05 Content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ResponseCode(pub u8);
+
+impl ResponseCode {
+    pub const CREATED: Self = Self::new(2, 1);
+    pub const DELETED: Self = Self::new(2, 2);
+    pub const VALID: Self = Self::new(2, 3);
+    pub const CHANGED: Self = Self::new(2, 4);
+    pub const CONTENT: Self = Self::new(2, 5);
+    pub const CONTINUE: Self = Self::new(2, 31);
+    pub const BAD_REQUEST: Self = Self::new(4, 0);
+    pub const UNAUTHORIZED: Self = Self::new(4, 1);
+    pub const NOT_FOUND: Self = Self::new(4, 4);
+    pub const REQUEST_ENTITY_INCOMPLETE: Self = Self::new(4, 8);
+    pub const REQUEST_ENTITY_TOO_LARGE: Self = Self::new(4, 13);
+    pub const INTERNAL_SERVER_ERROR: Self = Self::new(5, 0);
+
+    pub const fn new(class: u8, detail: u8) -> Self {
+        Self(class << 5 | detail)
+    }
+
+    pub fn class(&self) -> u8 {
+        self.0 >> 5
+    }
+
+    pub fn detail(&self) -> u8 {
+        self.0 & 0x1F
+    }
+
+    /// Whether the code is of class 2.
+    pub fn is_success(&self) -> bool {
+        self.class() == 2
+    }
+}
+
+/// Format of a payload, as registered in the CoAP Content-Formats registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ContentFormat(pub u16);
+
+impl ContentFormat {
+    pub const TEXT_PLAIN: Self = Self(0);
+    pub const LINK_FORMAT: Self = Self(40);
+    pub const OCTET_STREAM: Self = Self(42);
+    pub const JSON: Self = Self(50);
+    pub const CBOR: Self = Self(60);
+}
+
+/// Block1 or Block2 option of a block-wise transfer (RFC 7959).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Block {
+    pub num: u32,
+    /// Whether more blocks follow this one.
+    pub more: bool,
+    /// Size exponent, the block size being `16 << szx`.
+    pub szx: u8,
+}
+
+impl Block {
+    pub const MAX_SZX: u8 = 6;
+
+    pub fn new(num: u32, more: bool, szx: u8) -> Self {
+        Self {
+            num,
+            more,
+            szx: szx.min(Self::MAX_SZX),
+        }
+    }
+
+    /// Size exponent of the largest block size not exceeding `size`, at least 16 and at most
+    /// 1024 bytes.
+    pub fn szx(size: usize) -> u8 {
+        let mut szx = 0;
+        while szx < Self::MAX_SZX && 16 << (szx + 1) <= size {
+            szx += 1;
+        }
+        szx
+    }
+
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    /// Offset of the block in the transferred representation.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    fn encode(&self) -> u32 {
+        self.num << 4 | (self.more as u32) << 3 | self.szx as u32
+    }
+
+    fn decode(value: u32) -> Option<Self> {
+        let szx = (value & 0x07) as u8;
+        // The size exponent 7 is reserved
+        if szx > Self::MAX_SZX || value > 0xFF_FFFF {
+            return None;
+        }
+        Some(Self {
+            num: value >> 4,
+            more: value & 0x08 != 0,
+            szx,
+        })
+    }
+}
+
+/// A request to send to a server.
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'r> {
+    method: Method,
+    path: &'r str,
+    query: Option<&'r str>,
+    content_format: Option<ContentFormat>,
+    accept: Option<ContentFormat>,
+    payload: &'r [u8],
+    confirmable: bool,
+    pub(crate) observe: Option<u32>,
+    pub(crate) block1: Option<Block>,
+    pub(crate) block2: Option<Block>,
+}
+
+impl<'r> Request<'r> {
+    /// A confirmable request for the resource at `path`, with segments separated by `/`.
+    pub fn new(method: Method, path: &'r str) -> Self {
+        Self {
+            method,
+            path,
+            query: None,
+            content_format: None,
+            accept: None,
+            payload: &[],
+            confirmable: true,
+            observe: None,
+            block1: None,
+            block2: None,
+        }
+    }
+
+    pub fn get(path: &'r str) -> Self {
+        Self::new(Method::Get, path)
+    }
+
+    pub fn post(path: &'r str, payload: &'r [u8]) -> Self {
+        Self::new(Method::Post, path).payload(payload)
+    }
+
+    pub fn put(path: &'r str, payload: &'r [u8]) -> Self {
+        Self::new(Method::Put, path).payload(payload)
+    }
+
+    pub fn delete(path: &'r str) -> Self {
+        Self::new(Method::Delete, path)
+    }
+
+    /// Query parameters separated by `&`, like `ct=50&wait=10`.
+    pub fn query(mut self, query: &'r str) -> Self {
+        self.query = Some(query);
+        self
+    }
+
+    pub fn content_format(mut self, content_format: ContentFormat) -> Self {
+        self.content_format = Some(content_format);
+        self
+    }
+
+    /// The format of the response accepted by the client.
+    pub fn accept(mut self, content_format: ContentFormat) -> Self {
+        self.accept = Some(content_format);
+        self
+    }
+
+    pub fn payload(mut self, payload: &'r [u8]) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Whether the request is acknowledged and retransmitted until acknowledged. Defaults to
+    /// true.
+    pub fn confirmable(mut self, confirmable: bool) -> Self {
+        self.confirmable = confirmable;
+        self
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    pub fn is_confirmable(&self) -> bool {
+        self.confirmable
+    }
+
+    pub(crate) fn full_payload(&self) -> &'r [u8] {
+        self.payload
+    }
+
+    fn segments(value: Option<&'r str>, separator: char) -> impl Iterator<Item = &'r str> {
+        value
+            .unwrap_or("")
+            .split(separator)
+            .filter(|segment| !segment.is_empty())
+    }
+
+    /// Encode the request as a message with `payload`, which is the request payload or a block
+    /// of it.
+    pub(crate) fn encode(
+        &self,
+        buf: &mut [u8],
+        id: u16,
+        token: &[u8],
+        payload: &[u8],
+    ) -> Result<usize, MessageError> {
+        let mtype = if self.confirmable {
+            MessageType::Confirmable
+        } else {
+            MessageType::NonConfirmable
+        };
+        let mut w = Writer::new(buf);
+        w.header(mtype, self.method as u8, id, token)?;
+        if let Some(observe) = self.observe {
+            w.uint_option(OBSERVE, observe)?;
+        }
+        for segment in Self::segments(Some(self.path), '/') {
+            w.option(URI_PATH, segment.as_bytes())?;
+        }
+        if let Some(ContentFormat(format)) = self.content_format {
+            w.uint_option(CONTENT_FORMAT, format as u32)?;
+        }
+        for param in Self::segments(self.query, '&') {
+            w.option(URI_QUERY, param.as_bytes())?;
+        }
+        if let Some(ContentFormat(format)) = self.accept {
+            w.uint_option(ACCEPT, format as u32)?;
+        }
+        if let Some(block) = self.block2 {
+            w.uint_option(BLOCK2, block.encode())?;
+        }
+        if let Some(block) = self.block1 {
+            w.uint_option(BLOCK1, block.encode())?;
+        }
+        if !payload.is_empty() {
+            w.bytes(&[PAYLOAD_MARKER])?;
+            w.bytes(payload)?;
+        }
+        Ok(w.pos)
+    }
+}
+
+/// Encode an empty acknowledgement or reset of the message with the given id.
+pub(crate) fn encode_empty(
+    buf: &mut [u8],
+    mtype: MessageType,
+    id: u16,
+) -> Result<usize, MessageError> {
+    let mut w = Writer::new(buf);
+    w.header(mtype, 0, id, &[])?;
+    Ok(w.pos)
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+    last_option: u16,
+}
+
+impl<'b> Writer<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            last_option: 0,
+        }
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> Result<(), MessageError> {
+        let end = self.pos + value.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(MessageError::BufferTooSmall)?
+            .copy_from_slice(value);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn header(
+        &mut self,
+        mtype: MessageType,
+        code: u8,
+        id: u16,
+        token: &[u8],
+    ) -> Result<(), MessageError> {
+        if token.len() > MAX_TOKEN_LEN {
+            return Err(MessageError::Malformed);
+        }
+        let [id_high, id_low] = id.to_be_bytes();
+        self.bytes(&[
+            VERSION << 6 | (mtype as u8) << 4 | token.len() as u8,
+            code,
+            id_high,
+            id_low,
+        ])?;
+        self.bytes(token)
+    }
+
+    /// Write an option, which must not precede the previously written option.
+    fn option(&mut self, number: u16, value: &[u8]) -> Result<(), MessageError> {
+        debug_assert!(number >= self.last_option);
+        let (delta, delta_ext) = Self::nibble(number - self.last_option);
+        let (len, len_ext) = Self::nibble(
+            value
+                .len()
+                .try_into()
+                .map_err(|_| MessageError::Malformed)?,
+        );
+        self.bytes(&[delta << 4 | len])?;
+        self.bytes(delta_ext.as_ref())?;
+        self.bytes(len_ext.as_ref())?;
+        self.bytes(value)?;
+        self.last_option = number;
+        Ok(())
+    }
+
+    /// An unsigned integer option, encoded in as few bytes as possible.
+    fn uint_option(&mut self, number: u16, value: u32) -> Result<(), MessageError> {
+        let bytes = value.to_be_bytes();
+        let skip = (value.leading_zeros() / 8) as usize;
+        self.option(number, &bytes[skip..])
+    }
+
+    /// Option delta or length nibble and its extended bytes.
+    fn nibble(value: u16) -> (u8, Extended) {
+        match value {
+            0..=12 => (value as u8, Extended::None),
+            13..=268 => (13, Extended::One([(value - 13) as u8])),
+            _ => (14, Extended::Two((value - 269).to_be_bytes())),
+        }
+    }
+}
+
+enum Extended {
+    None,
+    One([u8; 1]),
+    Two([u8; 2]),
+}
+
+impl AsRef<[u8]> for Extended {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Extended::None => &[],
+            Extended::One(b) => b,
+            Extended::Two(b) => b,
+        }
+    }
+}
+
+/// A received message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Message<'m> {
+    pub mtype: MessageType,
+    pub code: u8,
+    pub id: u16,
+    pub token: &'m [u8],
+    options: &'m [u8],
+    pub payload: &'m [u8],
+}
+
+impl<'m> Message<'m> {
+    pub fn decode(msg: &'m [u8]) -> Result<Self, MessageError> {
+        if msg.len() < HEADER_LEN || msg[0] >> 6 != VERSION {
+            return Err(MessageError::Malformed);
+        }
+        let mtype = match (msg[0] >> 4) & 0x03 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        };
+        let token_len = (msg[0] & 0x0F) as usize;
+        if token_len > MAX_TOKEN_LEN {
+            return Err(MessageError::Malformed);
+        }
+        let code = msg[1];
+        let id = u16::from_be_bytes([msg[2], msg[3]]);
+        let token = msg
+            .get(HEADER_LEN..HEADER_LEN + token_len)
+            .ok_or(MessageError::Malformed)?;
+        let rest = &msg[HEADER_LEN + token_len..];
+
+        // Walk the options to find the payload, validating them along the way
+        let mut options = Options {
+            data: rest,
+            number: 0,
+        };
+        for option in &mut options {
+            option?;
+        }
+        let (options, payload) = match options.data.split_first() {
+            Some((&PAYLOAD_MARKER, [])) => return Err(MessageError::Malformed),
+            Some((&PAYLOAD_MARKER, payload)) => (&rest[..rest.len() - payload.len() - 1], payload),
+            _ => (rest, &[][..]),
+        };
+        if code == 0 && (!token.is_empty() || !rest.is_empty()) {
+            return Err(MessageError::Malformed);
+        }
+        Ok(Self {
+            mtype,
+            code,
+            id,
+            token,
+            options,
+            payload,
+        })
+    }
+
+    /// Whether the message is an empty acknowledgement or reset.
+    pub fn is_empty(&self) -> bool {
+        self.code == 0
+    }
+
+    /// Whether the message is a response, as opposed to a request or an empty message.
+    pub fn is_response(&self) -> bool {
+        self.code >> 5 >= 2
+    }
+
+    fn options(&self) -> Options<'m> {
+        Options {
+            data: self.options,
+            number: 0,
+        }
+    }
+
+    fn option(&self, number: u16) -> Option<&'m [u8]> {
+        self.options()
+            .filter_map(Result::ok)
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| value)
+    }
+
+    fn uint_option(&self, number: u16) -> Option<u32> {
+        let value = self.option(number)?;
+        if value.len() > 4 {
+            return None;
+        }
+        Some(value.iter().fold(0, |acc, b| acc << 8 | *b as u32))
+    }
+
+    pub fn observe(&self) -> Option<u32> {
+        self.uint_option(OBSERVE)
+    }
+
+    pub fn content_format(&self) -> Option<ContentFormat> {
+        self.uint_option(CONTENT_FORMAT)
+            .map(|format| ContentFormat(format as u16))
+    }
+
+    pub fn block1(&self) -> Option<Block> {
+        self.uint_option(BLOCK1).and_then(Block::decode)
+    }
+
+    pub fn block2(&self) -> Option<Block> {
+        self.uint_option(BLOCK2).and_then(Block::decode)
+    }
+}
+
+/// Iterator over the options of a message, yielding option numbers and values.
+struct Options<'m> {
+    data: &'m [u8],
+    number: u16,
+}
+
+impl<'m> Options<'m> {
+    fn extended(&mut self, nibble: u8) -> Result<u16, MessageError> {
+        let (value, len) = match nibble {
+            0..=12 => (nibble as u16, 0),
+            13 => (
+                *self.data.first().ok_or(MessageError::Malformed)? as u16 + 13,
+                1,
+            ),
+            14 => match self.data {
+                [high, low, ..] => (
+                    u16::from_be_bytes([*high, *low])
+                        .checked_add(269)
+                        .ok_or(MessageError::Malformed)?,
+                    2,
+                ),
+                _ => return Err(MessageError::Malformed),
+            },
+            _ => return Err(MessageError::Malformed),
+        };
+        self.data = &self.data[len..];
+        Ok(value)
+    }
+
+    fn read(&mut self) -> Result<(u16, &'m [u8]), MessageError> {
+        let first = self.data[0];
+        self.data = &self.data[1..];
+        let delta = self.extended(first >> 4)?;
+        let len = self.extended(first & 0x0F)? as usize;
+        self.number = self
+            .number
+            .checked_add(delta)
+            .ok_or(MessageError::Malformed)?;
+        let value = self.data.get(..len).ok_or(MessageError::Malformed)?;
+        self.data = &self.data[len..];
+        Ok((self.number, value))
+    }
+}
+
+impl<'m> Iterator for Options<'m> {
+    type Item = Result<(u16, &'m [u8]), MessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.data.first() {
+            None | Some(&PAYLOAD_MARKER) => None,
+            Some(_) => {
+                let result = self.read();
+                if result.is_err() {
+                    self.data = &[];
+                }
+                Some(result)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_request() {
+        let mut buf = [0; 64];
+        let request = Request::post("/v1/sensor", b"{}")
+            .content_format(ContentFormat::JSON)
+            .query("ct=30");
+        let len = request
+            .encode(&mut buf, 0x1234, &[1, 2], request.full_payload())
+            .unwrap();
+        let expected: &[u8] = &[
+            0x42, 0x02, 0x12, 0x34, 1, 2, // Header and token
+            0xB2, b'v', b'1', // Uri-Path 11
+            0x06, b's', b'e', b'n', b's', b'o', b'r', // Uri-Path 11
+            0x11, 50, // Content-Format 12
+            0x35, b'c', b't', b'=', b'3', b'0', // Uri-Query 15
+            0xFF, b'{', b'}',
+        ];
+        assert_eq!(expected, &buf[..len]);
+
+        let mut request = Request::get("temperature").confirmable(false);
+        request.observe = Some(0);
+        request.block2 = Some(Block::new(2, false, 6));
+        let len = request.encode(&mut buf, 1, &[7], &[]).unwrap();
+        let expected: &[u8] = &[
+            0x51, 0x01, 0x00, 0x01, 7,    // Header and token
+            0x60, // Observe 6 with empty value
+            0x5B, b't', b'e', b'm', b'p', b'e', b'r', b'a', b't', b'u', b'r',
+            b'e', // Uri-Path
+            0xC1, 0x26, // Block2 23
+        ];
+        assert_eq!(expected, &buf[..len]);
+
+        assert_eq!(
+            Err(MessageError::BufferTooSmall),
+            request.encode(&mut buf[..8], 1, &[7], &[])
+        );
+    }
+
+    #[test]
+    fn test_extended_options() {
+        let mut buf = [0; 512];
+        let mut w = Writer::new(&mut buf);
+        let long = [b'x'; 300];
+        w.option(1, &[]).unwrap();
+        w.option(20, &long[..20]).unwrap();
+        w.option(400, &long).unwrap();
+        let len = w.pos;
+        assert_eq!(&[0x10, 0xD0 | 13, 6, 7], &buf[..4]);
+
+        let mut options = Options {
+            data: &buf[..len],
+            number: 0,
+        };
+        assert_eq!(Some(Ok((1, &[][..]))), options.next());
+        assert_eq!(Some(Ok((20, &long[..20]))), options.next());
+        assert_eq!(Some(Ok((400, &long[..]))), options.next());
+        assert_eq!(None, options.next());
+    }
+
+    #[test]
+    fn test_decode() {
+        let msg: &[u8] = &[
+            0x61, 0x45, 0x00, 0x01, 9, // Piggybacked 2.05 with token
+            0x62, 0x12, 0x34, // Observe 6
+            0x61, 50, // Content-Format 12
+            0xB1, 0x2E, // Block2 23, num 2 with more
+            0xFF, b'{', b'}',
+        ];
+        let msg = Message::decode(msg).unwrap();
+        assert_eq!(MessageType::Acknowledgement, msg.mtype);
+        assert_eq!(ResponseCode::CONTENT, ResponseCode(msg.code));
+        assert_eq!(1, msg.id);
+        assert_eq!(&[9], msg.token);
+        assert_eq!(Some(0x1234), msg.observe());
+        assert_eq!(Some(ContentFormat::JSON), msg.content_format());
+        assert_eq!(Some(Block::new(2, true, 6)), msg.block2());
+        assert_eq!(1024 * 2, msg.block2().unwrap().offset());
+        assert_eq!(None, msg.block1());
+        assert_eq!(b"{}", msg.payload);
+        assert!(msg.is_response());
+
+        let empty = Message::decode(&[0x60, 0x00, 0x12, 0x34]).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(0x1234, empty.id);
+
+        // Bad version, token too long, payload marker without payload, truncated option,
+        // reserved nibble and empty messages with a token
+        assert!(Message::decode(&[0x80, 0x45, 0, 1]).is_err());
+        assert!(Message::decode(&[0x49, 0x45, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(Message::decode(&[0x40, 0x45, 0, 1, 0xFF]).is_err());
+        assert!(Message::decode(&[0x40, 0x45, 0, 1, 0x13, 1]).is_err());
+        assert!(Message::decode(&[0x40, 0x45, 0, 1, 0xF0]).is_err());
+        assert!(Message::decode(&[0x61, 0x00, 0, 1, 9]).is_err());
+    }
+
+    #[test]
+    fn test_block() {
+        assert_eq!(0, Block::szx(10));
+        assert_eq!(0, Block::szx(31));
+        assert_eq!(5, Block::szx(512));
+        assert_eq!(5, Block::szx(1000));
+        assert_eq!(6, Block::szx(4096));
+
+        let block = Block::new(5, true, 2);
+        assert_eq!(64, block.size());
+        assert_eq!(320, block.offset());
+        assert_eq!(Some(block), Block::decode(block.encode()));
+        assert_eq!(None, Block::decode(0x07));
+    }
+
+    #[test]
+    fn test_response_code() {
+        assert_eq!(0x44, ResponseCode::CHANGED.0);
+        assert_eq!(4, ResponseCode::NOT_FOUND.class());
+        assert_eq!(4, ResponseCode::NOT_FOUND.detail());
+        assert!(ResponseCode::CONTINUE.is_success());
+        assert!(!ResponseCode::UNAUTHORIZED.is_success());
+    }
+}
@@ -0,0 +1,877 @@
+//! CoAP client for exchanging messages with a server
+//!
+//! The client sends confirmable requests, retransmitting them with exponential back-off as
+//! specified by RFC 7252, and matches responses by token, whether piggybacked on the
+//! acknowledgement or sent separately. It also supports observing a resource (RFC 7641) and
+//! block-wise transfers of large payloads (RFC 7959).
+//!
+//! The client runs over any connected UDP socket, like those of an
+//! [`embedded_nal_async::UdpStack`]. For CoAP over DTLS, wrap the DTLS socket in a
+//! [`ConnectedUdp`] implementation.
+use {
+    embassy_futures::select::{select, Either},
+    embassy_time::{Duration, Instant, Timer},
+    embedded_nal_async::ConnectedUdp,
+    message::{encode_empty, Message, MessageType},
+    rand_core::RngCore,
+};
+
+mod message;
+
+pub use message::{Block, ContentFormat, MessageError, Method, Request, ResponseCode};
+
+/// The default COAP port.
+pub const COAP_PORT: u16 = 5683;
+/// The default port for CoAP over DTLS.
+pub const COAPS_PORT: u16 = 5684;
+
+/// The maximum message size recommended by RFC 7252 when the path MTU is unknown.
+pub const DEFAULT_MESSAGE_SIZE: usize = 1152;
+
+const TOKEN_LEN: usize = 4;
+/// Time after which any notification is considered fresher than the last one (RFC 7641).
+const NOTIFICATION_MAX_AGE: Duration = Duration::from_secs(128);
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CoapError<E> {
+    Network(E),
+    Message(MessageError),
+    /// No response was received in time.
+    Timeout,
+    /// The server rejected the request with a reset message.
+    Reset,
+    /// A block-wise transfer did not continue with the expected block.
+    UnexpectedBlock,
+    /// No resource is being observed.
+    NotObserving,
+}
+
+impl<E> From<MessageError> for CoapError<E> {
+    fn from(e: MessageError) -> Self {
+        Self::Message(e)
+    }
+}
+
+/// A response received from the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Response<'m> {
+    pub code: ResponseCode,
+    pub content_format: Option<ContentFormat>,
+    /// Sequence number of a notification of an observed resource.
+    pub observe: Option<u32>,
+    pub payload: &'m [u8],
+}
+
+impl<'m> Response<'m> {
+    fn new(msg: &Message<'m>) -> Self {
+        Self {
+            code: ResponseCode(msg.code),
+            content_format: msg.content_format(),
+            observe: msg.observe(),
+            payload: msg.payload,
+        }
+    }
+}
+
+/// An observed resource.
+struct Observation {
+    token: [u8; TOKEN_LEN],
+    sequence: Option<u32>,
+    received_at: Instant,
+}
+
+/// A client exchanging messages of up to `N` bytes with a single server.
+///
+/// Message ids and tokens are drawn from `RNG`, making them harder to guess and avoiding
+/// collisions with those used before a restart.
+pub struct CoapClient<S, RNG, const N: usize = DEFAULT_MESSAGE_SIZE>
+where
+    S: ConnectedUdp,
+    RNG: RngCore,
+{
+    socket: S,
+    ack_timeout: Duration,
+    max_retransmit: u8,
+    response_timeout: Duration,
+    block_szx: u8,
+    message_id: u16,
+    rng: RNG,
+    observation: Option<Observation>,
+    tx: [u8; N],
+    rx: [u8; N],
+}
+
+impl<S, RNG, const N: usize> CoapClient<S, RNG, N>
+where
+    S: ConnectedUdp,
+    RNG: RngCore,
+{
+    /// Create a client using the transmission parameters of RFC 7252: an initial acknowledgement
+    /// timeout of 2 to 3 seconds, up to 4 retransmissions, and 93 seconds to wait for a separate
+    /// response. Block-wise transfers use 512 byte blocks.
+    pub fn new(socket: S, mut rng: RNG) -> Self {
+        Self {
+            socket,
+            ack_timeout: Duration::from_secs(2),
+            max_retransmit: 4,
+            response_timeout: Duration::from_secs(93),
+            block_szx: Block::szx(512),
+            message_id: rng.next_u32() as u16,
+            rng,
+            observation: None,
+            tx: [0; N],
+            rx: [0; N],
+        }
+    }
+
+    /// Time to wait for an acknowledgement before the first retransmission. The actual timeout
+    /// is randomized between 1 and 1.5 times this value, and doubled for every retransmission.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    /// Number of retransmissions of a confirmable message before giving up.
+    pub fn retries(mut self, retries: u8) -> Self {
+        self.max_retransmit = retries;
+        self
+    }
+
+    /// Time to wait for a response once a request has been acknowledged, or for a response to a
+    /// non-confirmable request.
+    pub fn response_timeout(mut self, timeout: Duration) -> Self {
+        self.response_timeout = timeout;
+        self
+    }
+
+    /// Preferred block size of block-wise transfers, rounded down to a power of two between 16
+    /// and 1024 bytes. Blocks must fit in a message along with the request options.
+    pub fn block_size(mut self, size: usize) -> Self {
+        self.block_szx = Block::szx(size);
+        self
+    }
+
+    /// Send a request and wait for its response.
+    pub async fn request(
+        &mut self,
+        request: &Request<'_>,
+    ) -> Result<Response<'_>, CoapError<S::Error>> {
+        let token = self.next_token();
+        let len = self
+            .exchange(request, &token, request.full_payload())
+            .await?;
+        Ok(Response::new(&Message::decode(&self.rx[..len])?))
+    }
+
+    /// Send a request with a payload in blocks of the configured size, returning the final
+    /// response. Payloads fitting in a single block are sent as a normal request.
+    pub async fn upload(
+        &mut self,
+        request: &Request<'_>,
+    ) -> Result<Response<'_>, CoapError<S::Error>> {
+        let payload = request.full_payload();
+        let mut szx = self.block_szx;
+        if payload.len() <= Block::new(0, false, szx).size() {
+            return self.request(request).await;
+        }
+
+        let token = self.next_token();
+        let mut offset = 0;
+        let len = loop {
+            let size = Block::new(0, false, szx).size();
+            let block = Block::new((offset / size) as u32, offset + size < payload.len(), szx);
+            let end = payload.len().min(offset + size);
+            let mut request = *request;
+            request.block1 = Some(block);
+            let len = self
+                .exchange(&request, &token, &payload[offset..end])
+                .await?;
+            let msg = Message::decode(&self.rx[..len])?;
+            if !block.more {
+                break len;
+            }
+            if ResponseCode(msg.code) != ResponseCode::CONTINUE {
+                warn!("Block-wise upload interrupted with code {}", msg.code);
+                break len;
+            }
+            match msg.block1() {
+                Some(ack) if ack.num == block.num => {
+                    // The server may ask for smaller blocks
+                    szx = szx.min(ack.szx);
+                }
+                _ => return Err(CoapError::UnexpectedBlock),
+            }
+            offset = end;
+        };
+        Ok(Response::new(&Message::decode(&self.rx[..len])?))
+    }
+
+    /// Send a request and receive a response payload transferred in blocks into `payload`. The
+    /// payload of the returned response refers to the received part of `payload`.
+    pub async fn download<'p>(
+        &mut self,
+        request: &Request<'_>,
+        payload: &'p mut [u8],
+    ) -> Result<Response<'p>, CoapError<S::Error>> {
+        let token = self.next_token();
+        let mut szx = self.block_szx;
+        let mut received = 0;
+        loop {
+            let mut request = *request;
+            request.block2 = Some(Block::new((received / (16 << szx)) as u32, false, szx));
+            let len = self.exchange(&request, &token, &[]).await?;
+            let msg = Message::decode(&self.rx[..len])?;
+
+            let more = match msg.block2() {
+                Some(block) if block.offset() != received => {
+                    return Err(CoapError::UnexpectedBlock)
+                }
+                Some(block) => {
+                    // The server may send smaller blocks than requested
+                    szx = block.szx;
+                    block.more
+                }
+                None if received == 0 => false,
+                None => return Err(CoapError::UnexpectedBlock),
+            };
+            let end = received + msg.payload.len();
+            payload
+                .get_mut(received..end)
+                .ok_or(MessageError::BufferTooSmall)?
+                .copy_from_slice(msg.payload);
+            received = end;
+
+            if !more || !ResponseCode(msg.code).is_success() {
+                return Ok(Response {
+                    code: ResponseCode(msg.code),
+                    content_format: msg.content_format(),
+                    observe: msg.observe(),
+                    payload: &payload[..received],
+                });
+            }
+        }
+    }
+
+    /// Observe a resource, returning the response to the registration. Notifications are
+    /// received with [`notification`](Self::notification).
+    ///
+    /// Only one resource is observed at a time, a new registration replaces the previous one.
+    /// When the response has no `observe` sequence number, the server did not accept the
+    /// registration.
+    pub async fn observe(
+        &mut self,
+        request: &Request<'_>,
+    ) -> Result<Response<'_>, CoapError<S::Error>> {
+        let token = self.next_token();
+        let mut request = *request;
+        request.observe = Some(0);
+        self.observation = None;
+        let len = self.exchange(&request, &token, &[]).await?;
+        let msg = Message::decode(&self.rx[..len])?;
+        if msg.observe().is_some() && ResponseCode(msg.code).is_success() {
+            self.observation = Some(Observation {
+                token,
+                sequence: msg.observe(),
+                received_at: Instant::now(),
+            });
+        }
+        Ok(Response::new(&msg))
+    }
+
+    /// Wait for the next notification of the observed resource, skipping any notification
+    /// older than the last one.
+    pub async fn notification(&mut self) -> Result<Response<'_>, CoapError<S::Error>> {
+        let len = loop {
+            let token = match &self.observation {
+                Some(observation) => observation.token,
+                None => return Err(CoapError::NotObserving),
+            };
+            let len = self
+                .socket
+                .receive_into(&mut self.rx)
+                .await
+                .map_err(CoapError::Network)?;
+            let msg = match Message::decode(&self.rx[..len]) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    warn!("Ignoring malformed CoAP message");
+                    continue;
+                }
+            };
+            if msg.token != token || !msg.is_response() {
+                reject(&mut self.socket, &msg, Some(&token)).await?;
+                continue;
+            }
+            if msg.mtype == MessageType::Confirmable {
+                send_empty(&mut self.socket, MessageType::Acknowledgement, msg.id).await?;
+            }
+
+            let now = Instant::now();
+            match (&mut self.observation, msg.observe()) {
+                (Some(observation), Some(sequence)) => {
+                    let fresh = observation.sequence.map_or(true, |last| {
+                        is_fresh(last, observation.received_at, sequence, now)
+                    });
+                    if !fresh {
+                        debug!("Ignoring outdated notification {}", sequence);
+                        continue;
+                    }
+                    observation.sequence = Some(sequence);
+                    observation.received_at = now;
+                }
+                // A notification without sequence number ends the observation
+                (observation, _) => *observation = None,
+            }
+            break len;
+        };
+        Ok(Response::new(&Message::decode(&self.rx[..len])?))
+    }
+
+    /// Stop observing the resource, deregistering from the server with `request`, which should
+    /// be the request used to register.
+    pub async fn cancel(
+        &mut self,
+        request: &Request<'_>,
+    ) -> Result<Response<'_>, CoapError<S::Error>> {
+        let observation = self.observation.take().ok_or(CoapError::NotObserving)?;
+        let mut request = *request;
+        request.observe = Some(1);
+        let len = self.exchange(&request, &observation.token, &[]).await?;
+        Ok(Response::new(&Message::decode(&self.rx[..len])?))
+    }
+
+    /// Whether a resource is being observed.
+    pub fn is_observing(&self) -> bool {
+        self.observation.is_some()
+    }
+
+    /// Send a request with the given token and payload, returning the length of the response
+    /// received into the receive buffer.
+    async fn exchange(
+        &mut self,
+        request: &Request<'_>,
+        token: &[u8],
+        payload: &[u8],
+    ) -> Result<usize, CoapError<S::Error>> {
+        let id = self.next_message_id();
+        let sent = request.encode(&mut self.tx, id, token, payload)?;
+        self.socket
+            .send(&self.tx[..sent])
+            .await
+            .map_err(CoapError::Network)?;
+
+        let mut acknowledged = !request.is_confirmable();
+        let mut timeout = self.initial_timeout();
+        let mut deadline = Instant::now()
+            + if acknowledged {
+                self.response_timeout
+            } else {
+                timeout
+            };
+        let mut retransmissions = 0;
+        let observed = self.observation.as_ref().map(|o| o.token);
+        loop {
+            let len =
+                match select(self.socket.receive_into(&mut self.rx), Timer::at(deadline)).await {
+                    Either::First(result) => result.map_err(CoapError::Network)?,
+                    Either::Second(_) if !acknowledged && retransmissions < self.max_retransmit => {
+                        retransmissions += 1;
+                        timeout = timeout + timeout;
+                        deadline = Instant::now() + timeout;
+                        debug!("Retransmitting CoAP message {} ({})", id, retransmissions);
+                        self.socket
+                            .send(&self.tx[..sent])
+                            .await
+                            .map_err(CoapError::Network)?;
+                        continue;
+                    }
+                    Either::Second(_) => return Err(CoapError::Timeout),
+                };
+
+            let msg = match Message::decode(&self.rx[..len]) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    warn!("Ignoring malformed CoAP message");
+                    continue;
+                }
+            };
+            match msg.mtype {
+                MessageType::Reset if msg.id == id => return Err(CoapError::Reset),
+                MessageType::Acknowledgement if msg.id == id && msg.is_empty() => {
+                    // The response will be sent separately
+                    trace!("CoAP message {} acknowledged", id);
+                    acknowledged = true;
+                    deadline = Instant::now() + self.response_timeout;
+                }
+                MessageType::Acknowledgement if msg.id == id && msg.token == token => {
+                    return Ok(len);
+                }
+                MessageType::Confirmable | MessageType::NonConfirmable
+                    if msg.token == token && msg.is_response() =>
+                {
+                    if msg.mtype == MessageType::Confirmable {
+                        send_empty(&mut self.socket, MessageType::Acknowledgement, msg.id).await?;
+                    }
+                    return Ok(len);
+                }
+                _ => reject(&mut self.socket, &msg, observed.as_ref().map(|t| &t[..])).await?,
+            }
+        }
+    }
+
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+
+    fn next_token(&mut self) -> [u8; TOKEN_LEN] {
+        self.rng.next_u32().to_be_bytes()
+    }
+
+    /// Acknowledgement timeout randomized between 1 and 1.5 times the configured timeout.
+    fn initial_timeout(&mut self) -> Duration {
+        let factor = self.rng.next_u32() % 1000;
+        self.ack_timeout + self.ack_timeout * factor / 2000
+    }
+}
+
+/// Handle an unexpected message: confirmable notifications of the observed resource with the
+/// `observed` token are acknowledged and dropped, other confirmable messages are rejected with a
+/// reset, which also cancels unwanted observations. Other messages are ignored.
+async fn reject<S: ConnectedUdp>(
+    socket: &mut S,
+    msg: &Message<'_>,
+    observed: Option<&[u8]>,
+) -> Result<(), CoapError<S::Error>> {
+    if msg.mtype != MessageType::Confirmable {
+        trace!("Ignoring unexpected CoAP message {}", msg.id);
+        return Ok(());
+    }
+    if observed == Some(msg.token) {
+        debug!("Dropping notification received during another exchange");
+        send_empty(socket, MessageType::Acknowledgement, msg.id).await
+    } else {
+        debug!("Rejecting unexpected CoAP message {}", msg.id);
+        send_empty(socket, MessageType::Reset, msg.id).await
+    }
+}
+
+async fn send_empty<S: ConnectedUdp>(
+    socket: &mut S,
+    mtype: MessageType,
+    id: u16,
+) -> Result<(), CoapError<S::Error>> {
+    let mut buf = [0; 4];
+    let len = encode_empty(&mut buf, mtype, id)?;
+    socket.send(&buf[..len]).await.map_err(CoapError::Network)
+}
+
+/// Whether a notification with sequence number `sequence` received at `now` is newer than the
+/// last one with `last` received at `last_at`, as defined in RFC 7641 section 3.4.
+fn is_fresh(last: u32, last_at: Instant, sequence: u32, now: Instant) -> bool {
+    const HALF: u32 = 1 << 23;
+    (last < sequence && sequence - last < HALF)
+        || (last > sequence && last - sequence > HALF)
+        || now > last_at + NOTIFICATION_MAX_AGE
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::testing::TestRng, core::cell::RefCell, heapless::Vec};
+
+    type Datagram = Vec<u8, 128>;
+    type Script<'s> = &'s mut dyn FnMut(&Message<'_>, &mut Vec<Datagram, 4>);
+
+    #[derive(Debug)]
+    struct MockError;
+
+    impl embedded_io::Error for MockError {
+        fn kind(&self) -> embedded_io::ErrorKind {
+            embedded_io::ErrorKind::Other
+        }
+    }
+
+    /// A server answering the messages of the client with a script, recording the messages.
+    struct MockServer<'s> {
+        script: Script<'s>,
+        sent: Vec<Datagram, 16>,
+        pending: Vec<Datagram, 4>,
+    }
+
+    impl<'s> MockServer<'s> {
+        fn new(script: Script<'s>) -> RefCell<Self> {
+            RefCell::new(Self {
+                script,
+                sent: Vec::new(),
+                pending: Vec::new(),
+            })
+        }
+
+        fn sent(&self) -> impl Iterator<Item = Message<'_>> {
+            self.sent.iter().map(|msg| Message::decode(msg).unwrap())
+        }
+    }
+
+    struct MockSocket<'t, 's>(&'t RefCell<MockServer<'s>>);
+
+    impl ConnectedUdp for MockSocket<'_, '_> {
+        type Error = MockError;
+
+        async fn send(&mut self, data: &[u8]) -> Result<(), MockError> {
+            let mut server = self.0.borrow_mut();
+            let server = &mut *server;
+            server
+                .sent
+                .push(Datagram::from_slice(data).unwrap())
+                .unwrap();
+            (server.script)(&Message::decode(data).unwrap(), &mut server.pending);
+            Ok(())
+        }
+
+        async fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, MockError> {
+            let next = {
+                let mut server = self.0.borrow_mut();
+                if server.pending.is_empty() {
+                    None
+                } else {
+                    Some(server.pending.remove(0))
+                }
+            };
+            match next {
+                Some(msg) => {
+                    buffer[..msg.len()].copy_from_slice(&msg);
+                    Ok(msg.len())
+                }
+                None => core::future::pending().await,
+            }
+        }
+    }
+
+    /// Encode a message with options of (number, value), in ascending order.
+    fn message(
+        mtype: MessageType,
+        code: ResponseCode,
+        id: u16,
+        token: &[u8],
+        options: &[(u8, &[u8])],
+        payload: &[u8],
+    ) -> Datagram {
+        let mut msg = Datagram::new();
+        msg.extend_from_slice(&[0x40 | (mtype as u8) << 4 | token.len() as u8, code.0])
+            .unwrap();
+        msg.extend_from_slice(&id.to_be_bytes()).unwrap();
+        msg.extend_from_slice(token).unwrap();
+        let mut last = 0;
+        for (number, value) in options {
+            let delta = number - last;
+            if delta < 13 {
+                msg.push(delta << 4 | value.len() as u8).unwrap();
+            } else {
+                msg.push(0xD0 | value.len() as u8).unwrap();
+                msg.push(delta - 13).unwrap();
+            }
+            msg.extend_from_slice(value).unwrap();
+            last = *number;
+        }
+        if !payload.is_empty() {
+            msg.push(0xFF).unwrap();
+            msg.extend_from_slice(payload).unwrap();
+        }
+        msg
+    }
+
+    fn empty(mtype: MessageType, id: u16) -> Datagram {
+        message(mtype, ResponseCode(0), id, &[], &[], &[])
+    }
+
+    fn piggybacked(request: &Message<'_>, code: ResponseCode, payload: &[u8]) -> Datagram {
+        message(
+            MessageType::Acknowledgement,
+            code,
+            request.id,
+            request.token,
+            &[],
+            payload,
+        )
+    }
+
+    fn block(block: Block) -> [u8; 1] {
+        [(block.num << 4 | (block.more as u32) << 3 | block.szx as u32) as u8]
+    }
+
+    fn mock_client<'t, 's>(
+        server: &'t RefCell<MockServer<'s>>,
+    ) -> CoapClient<MockSocket<'t, 's>, TestRng, 256> {
+        CoapClient::new(MockSocket(server), TestRng(1))
+            .ack_timeout(Duration::from_millis(10))
+            .response_timeout(Duration::from_millis(100))
+    }
+
+    #[test]
+    fn test_piggybacked_response() {
+        let mut script = |msg: &Message<'_>, out: &mut Vec<Datagram, 4>| {
+            assert_eq!(MessageType::Confirmable, msg.mtype);
+            assert_eq!(TOKEN_LEN, msg.token.len());
+            // A response with another token and id is ignored
+            out.push(message(
+                MessageType::Acknowledgement,
+                ResponseCode::CONTENT,
+                msg.id.wrapping_add(1),
+                &[0; 4],
+                &[],
+                b"other",
+            ))
+            .unwrap();
+            out.push(piggybacked(msg, ResponseCode::CHANGED, b"ok"))
+                .unwrap();
+        };
+        let server = MockServer::new(&mut script);
+        let mut client = mock_client(&server);
+        let response = futures::executor::block_on(
+            client.request(&Request::post("/v1/sensor", b"{\"temp\":22.3}")),
+        )
+        .unwrap();
+        assert_eq!(ResponseCode::CHANGED, response.code);
+        assert_eq!(b"ok", response.payload);
+        assert_eq!(1, server.borrow().sent.len());
+    }
+
+    #[test]
+    fn test_retransmission_and_separate_response() {
+        let mut attempts = 0;
+        let mut script = |msg: &Message<'_>, out: &mut Vec<Datagram, 4>| {
+            if msg.mtype != MessageType::Confirmable {
+                return;
+            }
+            attempts += 1;
+            // Drop the first two transmissions, then acknowledge and respond separately
+            if attempts == 3 {
+                out.push(empty(MessageType::Acknowledgement, msg.id))
+                    .unwrap();
+                out.push(message(
+                    MessageType::Confirmable,
+                    ResponseCode::CONTENT,
+                    0x7777,
+                    msg.token,
+                    &[],
+                    b"22.3",
+                ))
+                .unwrap();
+            }
+        };
+        let server = MockServer::new(&mut script);
+        let mut client = mock_client(&server);
+        let response =
+            futures::executor::block_on(client.request(&Request::get("temperature"))).unwrap();
+        assert_eq!(ResponseCode::CONTENT, response.code);
+        assert_eq!(b"22.3", response.payload);
+
+        let server = server.borrow();
+        assert_eq!(4, server.sent.len());
+        // Retransmissions are identical
+        assert_eq!(server.sent[0], server.sent[2]);
+        // The separate response is acknowledged
+        let ack = server.sent().last().unwrap();
+        assert_eq!(MessageType::Acknowledgement, ack.mtype);
+        assert_eq!(0x7777, ack.id);
+        assert!(ack.is_empty());
+    }
+
+    #[test]
+    fn test_timeout_and_reset() {
+        let mut script = |_: &Message<'_>, _: &mut Vec<Datagram, 4>| {};
+        let server = MockServer::new(&mut script);
+        let mut client = mock_client(&server).retries(2);
+        assert!(matches!(
+            futures::executor::block_on(client.request(&Request::get("temperature"))),
+            Err(CoapError::Timeout)
+        ));
+        assert_eq!(3, server.borrow().sent.len());
+
+        let mut script = |msg: &Message<'_>, out: &mut Vec<Datagram, 4>| {
+            out.push(empty(MessageType::Reset, msg.id)).unwrap();
+        };
+        let server = MockServer::new(&mut script);
+        let mut client = mock_client(&server);
+        assert!(matches!(
+            futures::executor::block_on(client.request(&Request::get("temperature"))),
+            Err(CoapError::Reset)
+        ));
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut script = |msg: &Message<'_>, out: &mut Vec<Datagram, 4>| {
+            if msg.mtype != MessageType::Confirmable {
+                return;
+            }
+            let notification = |id: u16, sequence: u8, payload: &[u8]| {
+                message(
+                    MessageType::Confirmable,
+                    ResponseCode::CONTENT,
+                    id,
+                    msg.token,
+                    &[(6, &[sequence])],
+                    payload,
+                )
+            };
+            match msg.observe() {
+                Some(0) => {
+                    out.push(message(
+                        MessageType::Acknowledgement,
+                        ResponseCode::CONTENT,
+                        msg.id,
+                        msg.token,
+                        &[(6, &[5])],
+                        b"1",
+                    ))
+                    .unwrap();
+                    out.push(notification(100, 7, b"2")).unwrap();
+                    // Reordered notification
+                    out.push(notification(101, 6, b"old")).unwrap();
+                    out.push(notification(102, 8, b"3")).unwrap();
+                }
+                Some(1) => out
+                    .push(piggybacked(msg, ResponseCode::CONTENT, b"4"))
+                    .unwrap(),
+                _ => {}
+            }
+        };
+        let server = MockServer::new(&mut script);
+        let mut client = mock_client(&server);
+        let request = Request::get("temperature");
+        futures::executor::block_on(async {
+            assert!(matches!(
+                client.notification().await,
+                Err(CoapError::NotObserving)
+            ));
+            let response = client.observe(&request).await.unwrap();
+            assert_eq!((Some(5), &b"1"[..]), (response.observe, response.payload));
+            assert!(client.is_observing());
+
+            let response = client.notification().await.unwrap();
+            assert_eq!((Some(7), &b"2"[..]), (response.observe, response.payload));
+            let response = client.notification().await.unwrap();
+            assert_eq!((Some(8), &b"3"[..]), (response.observe, response.payload));
+
+            let response = client.cancel(&request).await.unwrap();
+            assert_eq!(b"4", response.payload);
+            assert!(!client.is_observing());
+        });
+
+        // All notifications are acknowledged, including the outdated one
+        let server = server.borrow();
+        let acks: Vec<u16, 4> = server
+            .sent()
+            .filter(|msg| msg.mtype == MessageType::Acknowledgement)
+            .map(|msg| msg.id)
+            .collect();
+        assert_eq!(&[100, 101, 102], &acks[..]);
+    }
+
+    #[test]
+    fn test_fresh_notifications() {
+        let at = Instant::from_secs(1000);
+        let now = at + Duration::from_secs(1);
+        assert!(is_fresh(1, at, 2, now));
+        assert!(!is_fresh(2, at, 1, now));
+        assert!(!is_fresh(2, at, 2, now));
+        // Sequence numbers wrap around
+        assert!(is_fresh(0xFF_FFFF, at, 0, now));
+        assert!(!is_fresh(0, at, 0xFF_FFFF, now));
+        // Any notification is fresh after 128 seconds
+        assert!(is_fresh(2, at, 1, at + Duration::from_secs(129)));
+    }
+
+    const DATA: &[u8; 40] = b"0123456789abcdefghijklmnopqrstuvwxyzABCD";
+
+    #[test]
+    fn test_download() {
+        let mut script = |msg: &Message<'_>, out: &mut Vec<Datagram, 4>| {
+            // The server answers with 16 byte blocks
+            let offset = msg.block2().unwrap().offset();
+            let end = DATA.len().min(offset + 16);
+            let response = Block::new((offset / 16) as u32, end < DATA.len(), 0);
+            out.push(message(
+                MessageType::Acknowledgement,
+                ResponseCode::CONTENT,
+                msg.id,
+                msg.token,
+                &[(23, &block(response))],
+                &DATA[offset..end],
+            ))
+            .unwrap();
+        };
+        let server = MockServer::new(&mut script);
+        let mut client = mock_client(&server).block_size(32);
+        let mut payload = [0; 64];
+        let response =
+            futures::executor::block_on(client.download(&Request::get("firmware"), &mut payload))
+                .unwrap();
+        assert_eq!(ResponseCode::CONTENT, response.code);
+        assert_eq!(&DATA[..], response.payload);
+
+        let requested: Vec<(u32, u8), 4> = server
+            .borrow()
+            .sent()
+            .map(|msg| msg.block2().unwrap())
+            .map(|block| (block.num, block.szx))
+            .collect();
+        assert_eq!(&[(0, 1), (1, 0), (2, 0)], &requested[..]);
+
+        // The payload does not fit
+        let mut payload = [0; 20];
+        assert!(matches!(
+            futures::executor::block_on(client.download(&Request::get("firmware"), &mut payload)),
+            Err(CoapError::Message(MessageError::BufferTooSmall))
+        ));
+    }
+
+    #[test]
+    fn test_upload() {
+        let mut received = Vec::<u8, 64>::new();
+        let mut script = |msg: &Message<'_>, out: &mut Vec<Datagram, 4>| {
+            let request = msg.block1().unwrap();
+            assert_eq!(request.offset(), received.len());
+            received.extend_from_slice(msg.payload).unwrap();
+            if request.more {
+                // Ask for 16 byte blocks
+                let ack = Block::new(request.num, true, 0);
+                out.push(message(
+                    MessageType::Acknowledgement,
+                    ResponseCode::CONTINUE,
+                    msg.id,
+                    msg.token,
+                    &[(27, &block(ack))],
+                    &[],
+                ))
+                .unwrap();
+            } else {
+                out.push(piggybacked(msg, ResponseCode::CHANGED, &[]))
+                    .unwrap();
+            }
+        };
+        let server = MockServer::new(&mut script);
+        let mut client = mock_client(&server).block_size(32);
+        let response =
+            futures::executor::block_on(client.upload(&Request::put("firmware", DATA))).unwrap();
+        assert_eq!(ResponseCode::CHANGED, response.code);
+
+        let server = server.borrow();
+        let sent: Vec<(Block, usize), 4> = server
+            .sent()
+            .map(|msg| (msg.block1().unwrap(), msg.payload.len()))
+            .collect();
+        assert_eq!(
+            &[(Block::new(0, true, 1), 32), (Block::new(2, false, 0), 8)],
+            &sent[..]
+        );
+        // All blocks belong to the same request
+        assert!(server
+            .sent()
+            .all(|msg| msg.token == server.sent().next().unwrap().token));
+        drop(server);
+        assert_eq!(&DATA[..], &received[..]);
+    }
+}
@@ -0,0 +1,194 @@
+//! Dispatching of commands sent from the cloud to the device
+use heapless::{String, Vec};
+
+/// Name of a command received from the cloud.
+pub type CommandName = String<64>;
+
+/// A command sent to the device.
+#[derive(Debug, PartialEq)]
+pub struct Command<'m> {
+    pub name: CommandName,
+    pub payload: &'m [u8],
+}
+
+impl<'m> Command<'m> {
+    pub fn new(name: CommandName, payload: &'m [u8]) -> Self {
+        Self { name, payload }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CommandError {
+    /// No handler is registered for the command.
+    UnknownCommand,
+    /// The dispatcher has no room for another handler.
+    TooManyHandlers,
+    /// The command payload could not be decoded.
+    InvalidPayload,
+    /// The response did not fit in the response buffer.
+    BufferTooSmall,
+    /// The handler failed to execute the command.
+    Failed,
+}
+
+/// Handler of a single command.
+///
+/// Closures taking the payload and a response buffer, and returning the response length, can be
+/// used as handlers.
+pub trait CommandHandler {
+    /// Execute the command, writing the response into `response` and returning its length.
+    fn handle(&mut self, payload: &[u8], response: &mut [u8]) -> Result<usize, CommandError>;
+}
+
+impl<F> CommandHandler for F
+where
+    F: FnMut(&[u8], &mut [u8]) -> Result<usize, CommandError>,
+{
+    fn handle(&mut self, payload: &[u8], response: &mut [u8]) -> Result<usize, CommandError> {
+        self(payload, response)
+    }
+}
+
+/// Routes commands to up to `N` handlers registered by command name.
+pub struct CommandDispatcher<'a, const N: usize> {
+    handlers: Vec<(&'a str, &'a mut dyn CommandHandler), N>,
+}
+
+impl<'a, const N: usize> CommandDispatcher<'a, N> {
+    pub fn new() -> Self {
+        Self {
+            handlers: Vec::new(),
+        }
+    }
+
+    /// Register `handler` for commands named `name`, replacing any previous handler.
+    pub fn register(
+        &mut self,
+        name: &'a str,
+        handler: &'a mut dyn CommandHandler,
+    ) -> Result<(), CommandError> {
+        if let Some(entry) = self.handlers.iter_mut().find(|(n, _)| *n == name) {
+            entry.1 = handler;
+            return Ok(());
+        }
+        self.handlers
+            .push((name, handler))
+            .map_err(|_| CommandError::TooManyHandlers)
+    }
+
+    /// Pass the command to its handler, returning the response written into `response`.
+    pub fn dispatch<'r>(
+        &mut self,
+        command: &Command<'_>,
+        response: &'r mut [u8],
+    ) -> Result<&'r [u8], CommandError> {
+        let (_, handler) = self
+            .handlers
+            .iter_mut()
+            .find(|(name, _)| *name == command.name.as_str())
+            .ok_or_else(|| {
+                warn!("No handler for command {}", command.name.as_str());
+                CommandError::UnknownCommand
+            })?;
+        let len = handler.handle(command.payload, response)?;
+        response.get(..len).ok_or(CommandError::BufferTooSmall)
+    }
+}
+
+impl<'a, const N: usize> Default for CommandDispatcher<'a, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter {
+        value: u32,
+    }
+
+    impl CommandHandler for Counter {
+        fn handle(&mut self, payload: &[u8], response: &mut [u8]) -> Result<usize, CommandError> {
+            let step = core::str::from_utf8(payload)
+                .ok()
+                .and_then(|s| s.parse::<u32>().ok())
+                .ok_or(CommandError::InvalidPayload)?;
+            let value = self.value + step;
+            response
+                .get_mut(..4)
+                .ok_or(CommandError::BufferTooSmall)?
+                .copy_from_slice(&value.to_be_bytes());
+            self.value = value;
+            Ok(4)
+        }
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut counter = Counter { value: 0 };
+        let mut blinks = 0;
+        let mut blink = |_: &[u8], _: &mut [u8]| -> Result<usize, CommandError> {
+            blinks += 1;
+            Ok(0)
+        };
+
+        let mut response = [0; 8];
+        {
+            let mut dispatcher: CommandDispatcher<'_, 2> = CommandDispatcher::new();
+            dispatcher.register("count", &mut counter).unwrap();
+            dispatcher.register("blink", &mut blink).unwrap();
+
+            assert_eq!(
+                Ok(&[0, 0, 0, 3][..]),
+                dispatcher.dispatch(&Command::new("count".into(), b"3"), &mut response)
+            );
+            assert_eq!(
+                Ok(&[0, 0, 0, 5][..]),
+                dispatcher.dispatch(&Command::new("count".into(), b"2"), &mut response)
+            );
+            assert_eq!(
+                Ok(&[][..]),
+                dispatcher.dispatch(&Command::new("blink".into(), b""), &mut response)
+            );
+            assert_eq!(
+                Err(CommandError::InvalidPayload),
+                dispatcher.dispatch(&Command::new("count".into(), b"x"), &mut response)
+            );
+            assert_eq!(
+                Err(CommandError::UnknownCommand),
+                dispatcher.dispatch(&Command::new("reboot".into(), b""), &mut response)
+            );
+            assert_eq!(
+                Err(CommandError::BufferTooSmall),
+                dispatcher.dispatch(&Command::new("count".into(), b"1"), &mut response[..2])
+            );
+        }
+        assert_eq!(5, counter.value);
+        assert_eq!(1, blinks);
+    }
+
+    #[test]
+    fn test_register() {
+        let mut first = |_: &[u8], _: &mut [u8]| -> Result<usize, CommandError> { Ok(1) };
+        let mut second = |_: &[u8], _: &mut [u8]| -> Result<usize, CommandError> { Ok(2) };
+        let mut third = |_: &[u8], _: &mut [u8]| -> Result<usize, CommandError> { Ok(3) };
+
+        let mut dispatcher: CommandDispatcher<'_, 1> = CommandDispatcher::new();
+        dispatcher.register("a", &mut first).unwrap();
+        assert_eq!(
+            Err(CommandError::TooManyHandlers),
+            dispatcher.register("b", &mut second)
+        );
+
+        // Replaces the handler
+        dispatcher.register("a", &mut third).unwrap();
+        let mut response = [0; 4];
+        assert_eq!(
+            Ok(&[0, 0, 0][..]),
+            dispatcher.dispatch(&Command::new("a".into(), b""), &mut response)
+        );
+    }
+}
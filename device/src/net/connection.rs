@@ -0,0 +1,474 @@
+use {
+    embassy_sync::channel::DynamicSender,
+    embassy_time::{with_timeout, Duration, Instant, Timer},
+    embedded_io::{
+        asynch::{Read, Write},
+        Io,
+    },
+    embedded_nal_async::{SocketAddr, TcpConnect},
+};
+
+/// State of the connection kept by a [`ConnectionManager`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+    /// Waiting before the next connection attempt after `failures` consecutive failures.
+    Backoff {
+        failures: u32,
+        delay: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConnectionError<E> {
+    /// Establishing the connection failed.
+    Connect(E),
+    /// Reading from or writing to the connection failed. The connection has been closed.
+    Io(E),
+    /// The connection or operation did not complete in time. The connection has been closed.
+    Timeout,
+}
+
+impl<E> embedded_io::Error for ConnectionError<E>
+where
+    E: core::fmt::Debug,
+{
+    fn kind(&self) -> embedded_io::ErrorKind {
+        embedded_io::ErrorKind::Other
+    }
+}
+
+/// Exponential backoff between connection attempts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Backoff {
+    /// Delay after the first failure, doubled on every following failure.
+    pub initial: Duration,
+    /// Upper bound of the delay.
+    pub max: Duration,
+}
+
+impl Backoff {
+    pub const fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max }
+    }
+
+    /// Delay before the next attempt after `failures` consecutive failures.
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::from_ticks(0);
+        }
+        let factor = 1u64.checked_shl(failures - 1).unwrap_or(u64::MAX);
+        let ticks = self.initial.as_ticks().saturating_mul(factor);
+        Duration::from_ticks(ticks.min(self.max.as_ticks()))
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+/// Keeps a connection to a remote endpoint open, reconnecting when it fails.
+///
+/// The manager can be used as a regular connection through the [`Read`] and [`Write`] traits.
+/// Every operation establishes the connection if needed and is aborted after a timeout. Errors
+/// close the connection, and connection attempts after failures are delayed using
+/// exponential [`Backoff`].
+///
+/// The manager is meant for protocols keeping a connection open, like the
+/// [`MqttClient`](crate::net::mqtt::MqttClient).
+pub struct ConnectionManager<'a, T>
+where
+    T: TcpConnect + 'a,
+{
+    client: &'a T,
+    remote: SocketAddr,
+    connection: Option<T::Connection<'a>>,
+    state: ConnectionState,
+    backoff: Backoff,
+    connect_timeout: Duration,
+    timeout: Duration,
+    failures: u32,
+    retry_at: Option<Instant>,
+    events: Option<DynamicSender<'a, ConnectionState>>,
+}
+
+impl<'a, T> ConnectionManager<'a, T>
+where
+    T: TcpConnect + 'a,
+{
+    /// Create a manager connecting to `remote`, with 10 second timeouts for connecting and for
+    /// each operation.
+    pub fn new(client: &'a T, remote: SocketAddr) -> Self {
+        Self {
+            client,
+            remote,
+            connection: None,
+            state: ConnectionState::Disconnected,
+            backoff: Backoff::default(),
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+            failures: 0,
+            retry_at: None,
+            events: None,
+        }
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Maximum time to establish a connection.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Maximum time for a single read, write or flush.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send every state change to `events`. Changes are dropped if the channel is full.
+    pub fn events(mut self, events: DynamicSender<'a, ConnectionState>) -> Self {
+        self.events.replace(events);
+        self
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Number of consecutive failed connection attempts.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Make a single connection attempt unless already connected, waiting for the backoff
+    /// delay of previous failures first.
+    pub async fn connect(&mut self) -> Result<(), ConnectionError<T::Error>> {
+        if self.connection.is_some() {
+            return Ok(());
+        }
+        if let Some(at) = self.retry_at.take() {
+            Timer::at(at).await;
+        }
+
+        self.set_state(ConnectionState::Connecting);
+        let client = self.client;
+        let result = match with_timeout(self.connect_timeout, client.connect(self.remote)).await {
+            Ok(Ok(connection)) => Ok(connection),
+            Ok(Err(e)) => Err(ConnectionError::Connect(e)),
+            Err(_) => Err(ConnectionError::Timeout),
+        };
+
+        match result {
+            Ok(connection) => {
+                self.connection.replace(connection);
+                self.failures = 0;
+                self.set_state(ConnectionState::Connected);
+                Ok(())
+            }
+            Err(e) => {
+                self.failures = self.failures.saturating_add(1);
+                let delay = self.backoff.delay(self.failures);
+                warn!(
+                    "Error connecting to endpoint, retrying in {} ms",
+                    delay.as_millis()
+                );
+                self.retry_at.replace(Instant::now() + delay);
+                self.set_state(ConnectionState::Backoff {
+                    failures: self.failures,
+                    delay,
+                });
+                Err(e)
+            }
+        }
+    }
+
+    /// Return the connection, retrying until one is established.
+    pub async fn connection(&mut self) -> &mut T::Connection<'a> {
+        while self.connect().await.is_err() {}
+        self.connection.as_mut().unwrap()
+    }
+
+    /// Close the connection. The next operation reconnects immediately.
+    pub fn disconnect(&mut self) {
+        if self.connection.take().is_some() {
+            self.set_state(ConnectionState::Disconnected);
+        }
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            debug!("Connection state: {:?}", state);
+            self.state = state;
+            if let Some(events) = &self.events {
+                events.try_send(state).ok();
+            }
+        }
+    }
+
+    /// Close the connection if an operation failed.
+    fn check<R>(
+        &mut self,
+        result: Result<Result<R, T::Error>, embassy_time::TimeoutError>,
+    ) -> Result<R, ConnectionError<T::Error>> {
+        let result = match result {
+            Ok(Ok(r)) => return Ok(r),
+            Ok(Err(e)) => Err(ConnectionError::Io(e)),
+            Err(_) => Err(ConnectionError::Timeout),
+        };
+        warn!("Connection error, closing connection");
+        self.disconnect();
+        result
+    }
+}
+
+impl<'a, T> Io for ConnectionManager<'a, T>
+where
+    T: TcpConnect + 'a,
+{
+    type Error = ConnectionError<T::Error>;
+}
+
+impl<'a, T> Read for ConnectionManager<'a, T>
+where
+    T: TcpConnect + 'a,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.connect().await?;
+        let connection = self.connection.as_mut().unwrap();
+        let result = with_timeout(self.timeout, connection.read(buf)).await;
+        let len = self.check(result)?;
+        if len == 0 && !buf.is_empty() {
+            // Closed by the remote end
+            self.disconnect();
+        }
+        Ok(len)
+    }
+}
+
+impl<'a, T> Write for ConnectionManager<'a, T>
+where
+    T: TcpConnect + 'a,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.connect().await?;
+        let connection = self.connection.as_mut().unwrap();
+        let result = with_timeout(self.timeout, connection.write(buf)).await;
+        self.check(result)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        match self.connection.as_mut() {
+            Some(connection) => {
+                let result = with_timeout(self.timeout, connection.flush()).await;
+                self.check(result)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        core::cell::Cell,
+        embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel},
+        embedded_nal_async::{IpAddr, Ipv4Addr},
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct MockError;
+
+    impl embedded_io::Error for MockError {
+        fn kind(&self) -> embedded_io::ErrorKind {
+            embedded_io::ErrorKind::Other
+        }
+    }
+
+    /// Fails the first `failures` connection attempts. Connections echo written data and fail
+    /// once `broken` is set.
+    struct MockClient {
+        failures: Cell<u32>,
+        attempts: Cell<u32>,
+        broken: Cell<bool>,
+    }
+
+    impl MockClient {
+        fn new(failures: u32) -> Self {
+            Self {
+                failures: Cell::new(failures),
+                attempts: Cell::new(0),
+                broken: Cell::new(false),
+            }
+        }
+    }
+
+    struct MockConnection<'a> {
+        client: &'a MockClient,
+        data: heapless::Vec<u8, 64>,
+    }
+
+    impl TcpConnect for MockClient {
+        type Error = MockError;
+        type Connection<'m> = MockConnection<'m>;
+
+        async fn connect<'m>(&'m self, _: SocketAddr) -> Result<MockConnection<'m>, MockError>
+        where
+            Self: 'm,
+        {
+            self.attempts.set(self.attempts.get() + 1);
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(MockError);
+            }
+            self.broken.set(false);
+            Ok(MockConnection {
+                client: self,
+                data: heapless::Vec::new(),
+            })
+        }
+    }
+
+    impl Io for MockConnection<'_> {
+        type Error = MockError;
+    }
+
+    impl Read for MockConnection<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, MockError> {
+            if self.client.broken.get() {
+                return Err(MockError);
+            }
+            if self.data.is_empty() {
+                // Never answers
+                core::future::pending::<()>().await;
+            }
+            let len = buf.len().min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data.rotate_left(len);
+            self.data.truncate(self.data.len() - len);
+            Ok(len)
+        }
+    }
+
+    impl Write for MockConnection<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, MockError> {
+            if self.client.broken.get() {
+                return Err(MockError);
+            }
+            self.data.extend_from_slice(buf).map_err(|_| MockError)?;
+            Ok(buf.len())
+        }
+    }
+
+    fn remote() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1883)
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        assert_eq!(Duration::from_ticks(0), backoff.delay(0));
+        assert_eq!(Duration::from_millis(100), backoff.delay(1));
+        assert_eq!(Duration::from_millis(200), backoff.delay(2));
+        assert_eq!(Duration::from_millis(800), backoff.delay(4));
+        assert_eq!(Duration::from_secs(1), backoff.delay(5));
+        assert_eq!(Duration::from_secs(1), backoff.delay(100));
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let client = MockClient::new(2);
+        let events: Channel<NoopRawMutex, ConnectionState, 16> = Channel::new();
+        let mut manager = ConnectionManager::new(&client, remote())
+            .backoff(Backoff::new(
+                Duration::from_millis(10),
+                Duration::from_millis(100),
+            ))
+            .events(events.sender().into());
+
+        futures::executor::block_on(async {
+            let start = Instant::now();
+            assert_eq!(
+                Err(ConnectionError::Connect(MockError)),
+                manager.connect().await
+            );
+            assert_eq!(
+                ConnectionState::Backoff {
+                    failures: 1,
+                    delay: Duration::from_millis(10)
+                },
+                manager.state()
+            );
+
+            manager.connection().await;
+            assert_eq!(3, client.attempts.get());
+            assert_eq!(0, manager.failures());
+            assert!(Instant::now() - start >= Duration::from_millis(30));
+
+            // Operations reconnect after an error
+            client.broken.set(true);
+            assert_eq!(
+                Err(ConnectionError::Io(MockError)),
+                manager.write(b"hi").await
+            );
+            assert!(!manager.is_connected());
+            assert_eq!(Ok(2), manager.write(b"hi").await);
+            assert_eq!(4, client.attempts.get());
+        });
+
+        let expected = [
+            ConnectionState::Connecting,
+            ConnectionState::Backoff {
+                failures: 1,
+                delay: Duration::from_millis(10),
+            },
+            ConnectionState::Connecting,
+            ConnectionState::Backoff {
+                failures: 2,
+                delay: Duration::from_millis(20),
+            },
+            ConnectionState::Connecting,
+            ConnectionState::Connected,
+            ConnectionState::Disconnected,
+            ConnectionState::Connecting,
+            ConnectionState::Connected,
+        ];
+        for state in expected {
+            assert_eq!(Ok(state), events.try_recv());
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_operation_timeout() {
+        let client = MockClient::new(0);
+        let mut manager =
+            ConnectionManager::new(&client, remote()).timeout(Duration::from_millis(10));
+
+        futures::executor::block_on(async {
+            let mut buf = [0; 4];
+            assert_eq!(Ok(4), manager.write(b"ping").await);
+            assert_eq!(Ok(4), manager.read(&mut buf).await);
+            assert_eq!(b"ping", &buf);
+
+            assert_eq!(Err(ConnectionError::Timeout), manager.read(&mut buf).await);
+            assert_eq!(ConnectionState::Disconnected, manager.state());
+        });
+    }
+}
@@ -0,0 +1,488 @@
+use {
+    super::{lookup_static, matches_addr_type, DnsAnswer, DnsEntry, StaticDnsResolver, TtlDns},
+    core::cell::RefCell,
+    embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex},
+    embassy_time::{Duration, Instant},
+    embedded_nal_async::{AddrType, Dns, IpAddr},
+    heapless::{String, Vec},
+};
+
+/// Hosts with longer names are not cached.
+const MAX_HOST_LEN: usize = 128;
+
+struct Entry {
+    host: String<MAX_HOST_LEN>,
+    ip: IpAddr,
+    expires: Instant,
+}
+
+/// A resolver caching up to `N` answers of another resolver.
+///
+/// Entries expire after the TTL of the answer, bounded by a minimum and a maximum. A resolver
+/// only implementing [`Dns`] does not report TTLs, and is cached with a configured TTL using
+/// [`CachingResolver::with_ttl`].
+/// Expired entries are still served when the upstream resolver fails, and hosts that were never
+/// resolved fall back to a static table.
+pub struct CachingResolver<'a, D, const N: usize>
+where
+    D: TtlDns,
+{
+    upstream: D,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    fallback: &'a [DnsEntry<'a>],
+    entries: Mutex<NoopRawMutex, RefCell<Vec<Entry, N>>>,
+}
+
+impl<'a, D, const N: usize> CachingResolver<'a, D, N>
+where
+    D: TtlDns,
+{
+    /// Create a cache in front of `upstream`, keeping answers for at least 10 seconds and at
+    /// most an hour.
+    pub fn new(upstream: D) -> Self {
+        Self {
+            upstream,
+            min_ttl: Duration::from_secs(10),
+            max_ttl: Duration::from_secs(3600),
+            fallback: &[],
+            entries: Mutex::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Minimum time answers are served from the cache, to avoid resolving hosts with very
+    /// short TTLs over and over.
+    pub fn min_ttl(mut self, ttl: Duration) -> Self {
+        self.min_ttl = ttl;
+        self
+    }
+
+    /// Maximum time answers are served from the cache before resolving them again.
+    pub fn max_ttl(mut self, ttl: Duration) -> Self {
+        self.max_ttl = ttl;
+        self
+    }
+
+    /// Entries used when the upstream resolver fails and nothing is cached for a host.
+    pub fn fallback<const M: usize>(mut self, fallback: &StaticDnsResolver<'a, M>) -> Self {
+        self.fallback = fallback.entries;
+        self
+    }
+
+    /// Remove all cached entries.
+    pub fn clear(&self) {
+        self.entries.lock(|e| e.borrow_mut().clear());
+    }
+
+    /// Resolve `host` as of `now`.
+    pub async fn get_host_by_name_at(
+        &self,
+        now: Instant,
+        host: &str,
+        addr_type: AddrType,
+    ) -> Result<IpAddr, D::Error> {
+        let cached = self.cached(host, addr_type);
+        if let Some((ip, expires)) = cached {
+            if now < expires {
+                return Ok(ip);
+            }
+        }
+
+        match self.upstream.resolve(host, addr_type).await {
+            Ok(answer) => {
+                let ttl = answer.ttl.max(self.min_ttl).min(self.max_ttl);
+                self.insert(now + ttl, host, answer.addr);
+                Ok(answer.addr)
+            }
+            Err(e) => {
+                if let Some((ip, _)) = cached {
+                    warn!("Error resolving {}, using expired entry", host);
+                    Ok(ip)
+                } else if let Ok(ip) = lookup_static(self.fallback, host, addr_type) {
+                    warn!("Error resolving {}, using static entry", host);
+                    Ok(ip)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Find the freshest entry for `host` matching `addr_type`.
+    fn cached(&self, host: &str, addr_type: AddrType) -> Option<(IpAddr, Instant)> {
+        self.entries.lock(|e| {
+            e.borrow()
+                .iter()
+                .filter(|e| {
+                    e.host.eq_ignore_ascii_case(host) && matches_addr_type(&e.ip, addr_type)
+                })
+                .max_by_key(|e| e.expires)
+                .map(|e| (e.ip, e.expires))
+        })
+    }
+
+    fn insert(&self, expires: Instant, host: &str, ip: IpAddr) {
+        let mut name = String::new();
+        if name.push_str(host).is_err() {
+            return;
+        }
+        let entry = Entry {
+            host: name,
+            ip,
+            expires,
+        };
+        self.entries.lock(|e| {
+            let mut entries = e.borrow_mut();
+            // An IPv4 and an IPv6 address are kept per host
+            let existing = entries.iter().position(|e| {
+                e.host.eq_ignore_ascii_case(host)
+                    && matches!(
+                        (e.ip, ip),
+                        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_))
+                    )
+            });
+            let oldest = || {
+                entries
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, e)| e.expires)
+                    .map(|(i, _)| i)
+            };
+            match existing.or_else(|| if entries.is_full() { oldest() } else { None }) {
+                Some(i) => entries[i] = entry,
+                None => {
+                    entries.push(entry).ok();
+                }
+            }
+        })
+    }
+}
+
+impl<'a, D, const N: usize> CachingResolver<'a, FixedTtl<D>, N>
+where
+    D: Dns,
+{
+    /// Create a cache in front of `upstream`, keeping every answer for `ttl`.
+    pub fn with_ttl(upstream: D, ttl: Duration) -> Self {
+        Self::new(FixedTtl::new(upstream, ttl))
+            .min_ttl(ttl)
+            .max_ttl(ttl)
+    }
+}
+
+impl<'a, D, const N: usize> Dns for CachingResolver<'a, D, N>
+where
+    D: TtlDns,
+{
+    type Error = D::Error;
+
+    async fn get_host_by_name(&self, host: &str, addr_type: AddrType) -> Result<IpAddr, D::Error> {
+        self.get_host_by_name_at(Instant::now(), host, addr_type)
+            .await
+    }
+
+    async fn get_host_by_address(&self, addr: IpAddr) -> Result<String<256>, D::Error> {
+        self.upstream.get_host_by_address(addr).await
+    }
+}
+
+/// A resolver answering with a fixed TTL, for caching the answers of a resolver that does not
+/// report TTLs.
+pub struct FixedTtl<D>
+where
+    D: Dns,
+{
+    upstream: D,
+    ttl: Duration,
+}
+
+impl<D> FixedTtl<D>
+where
+    D: Dns,
+{
+    pub fn new(upstream: D, ttl: Duration) -> Self {
+        Self { upstream, ttl }
+    }
+}
+
+impl<D> Dns for FixedTtl<D>
+where
+    D: Dns,
+{
+    type Error = D::Error;
+
+    async fn get_host_by_name(&self, host: &str, addr_type: AddrType) -> Result<IpAddr, D::Error> {
+        self.upstream.get_host_by_name(host, addr_type).await
+    }
+
+    async fn get_host_by_address(&self, addr: IpAddr) -> Result<String<256>, D::Error> {
+        self.upstream.get_host_by_address(addr).await
+    }
+}
+
+impl<D> TtlDns for FixedTtl<D>
+where
+    D: Dns,
+{
+    async fn resolve(&self, host: &str, addr_type: AddrType) -> Result<DnsAnswer, D::Error> {
+        let addr = self.upstream.get_host_by_name(host, addr_type).await?;
+        Ok(DnsAnswer {
+            addr,
+            ttl: self.ttl,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::net::dns::DnsError,
+        core::cell::Cell,
+        embedded_nal_async::{Ipv4Addr, Ipv6Addr},
+    };
+
+    /// Resolves every host to 10.0.0.x and ::x, with x incremented on every query, and answers
+    /// with a TTL of `ttl` seconds.
+    struct MockDns {
+        queries: Cell<u8>,
+        fail: Cell<bool>,
+        ttl: Cell<u64>,
+    }
+
+    impl MockDns {
+        fn new() -> Self {
+            Self {
+                queries: Cell::new(0),
+                fail: Cell::new(false),
+                ttl: Cell::new(300),
+            }
+        }
+    }
+
+    impl Dns for &MockDns {
+        type Error = DnsError;
+
+        async fn get_host_by_name(
+            &self,
+            _host: &str,
+            addr_type: AddrType,
+        ) -> Result<IpAddr, DnsError> {
+            if self.fail.get() {
+                return Err(DnsError::Timeout);
+            }
+            let n = self.queries.get() + 1;
+            self.queries.set(n);
+            Ok(match addr_type {
+                AddrType::IPv6 => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, n as u16)),
+                _ => IpAddr::V4(Ipv4Addr::new(10, 0, 0, n)),
+            })
+        }
+
+        async fn get_host_by_address(&self, _addr: IpAddr) -> Result<String<256>, DnsError> {
+            Err(DnsError::NotFound)
+        }
+    }
+
+    impl TtlDns for &MockDns {
+        async fn resolve(&self, host: &str, addr_type: AddrType) -> Result<DnsAnswer, DnsError> {
+            Ok(DnsAnswer {
+                addr: self.get_host_by_name(host, addr_type).await?,
+                ttl: Duration::from_secs(self.ttl.get()),
+            })
+        }
+    }
+
+    fn v4(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, n))
+    }
+
+    const HOST: &str = "http.sandbox.drogue.cloud";
+
+    #[test]
+    fn test_cache_ttl() {
+        let upstream = MockDns::new();
+        upstream.ttl.set(30);
+        let cache: CachingResolver<_, 4> = CachingResolver::new(&upstream);
+        futures::executor::block_on(async {
+            let start = Instant::from_secs(100);
+            assert_eq!(
+                Ok(v4(1)),
+                cache.get_host_by_name_at(start, HOST, AddrType::IPv4).await
+            );
+
+            // Served from the cache, regardless of case
+            let now = start + Duration::from_secs(29);
+            assert_eq!(
+                Ok(v4(1)),
+                cache
+                    .get_host_by_name_at(now, "HTTP.sandbox.drogue.cloud", AddrType::Either)
+                    .await
+            );
+            assert_eq!(1, upstream.queries.get());
+
+            // Expired
+            let now = start + Duration::from_secs(30);
+            assert_eq!(
+                Ok(v4(2)),
+                cache.get_host_by_name_at(now, HOST, AddrType::IPv4).await
+            );
+            assert_eq!(2, upstream.queries.get());
+        });
+    }
+
+    #[test]
+    fn test_cache_ttl_bounds() {
+        let upstream = MockDns::new();
+        let cache: CachingResolver<_, 4> = CachingResolver::new(&upstream)
+            .min_ttl(Duration::from_secs(60))
+            .max_ttl(Duration::from_secs(600));
+        futures::executor::block_on(async {
+            // A TTL of 0 is raised to the minimum
+            upstream.ttl.set(0);
+            let start = Instant::from_secs(0);
+            cache
+                .get_host_by_name_at(start, HOST, AddrType::IPv4)
+                .await
+                .unwrap();
+            let now = start + Duration::from_secs(59);
+            assert_eq!(
+                Ok(v4(1)),
+                cache.get_host_by_name_at(now, HOST, AddrType::IPv4).await
+            );
+
+            // A TTL of a day is lowered to the maximum
+            upstream.ttl.set(86400);
+            let start = start + Duration::from_secs(60);
+            assert_eq!(
+                Ok(v4(2)),
+                cache.get_host_by_name_at(start, HOST, AddrType::IPv4).await
+            );
+            let now = start + Duration::from_secs(599);
+            assert_eq!(
+                Ok(v4(2)),
+                cache.get_host_by_name_at(now, HOST, AddrType::IPv4).await
+            );
+            let now = start + Duration::from_secs(600);
+            assert_eq!(
+                Ok(v4(3)),
+                cache.get_host_by_name_at(now, HOST, AddrType::IPv4).await
+            );
+        });
+    }
+
+    #[test]
+    fn test_fixed_ttl() {
+        // Only the plain resolver is used, whatever TTL the mock would report, and the configured
+        // TTL is kept even below the default minimum
+        let upstream = MockDns::new();
+        let cache: CachingResolver<_, 4> =
+            CachingResolver::with_ttl(&upstream, Duration::from_secs(5));
+        futures::executor::block_on(async {
+            let start = Instant::from_secs(0);
+            cache
+                .get_host_by_name_at(start, HOST, AddrType::IPv4)
+                .await
+                .unwrap();
+            let now = start + Duration::from_secs(4);
+            assert_eq!(
+                Ok(v4(1)),
+                cache.get_host_by_name_at(now, HOST, AddrType::IPv4).await
+            );
+            let now = start + Duration::from_secs(5);
+            assert_eq!(
+                Ok(v4(2)),
+                cache.get_host_by_name_at(now, HOST, AddrType::IPv4).await
+            );
+        });
+    }
+
+    #[test]
+    fn test_cache_addr_type() {
+        let upstream = MockDns::new();
+        let cache: CachingResolver<_, 4> = CachingResolver::new(&upstream);
+        futures::executor::block_on(async {
+            let now = Instant::from_secs(0);
+            cache
+                .get_host_by_name_at(now, HOST, AddrType::IPv4)
+                .await
+                .unwrap();
+
+            // A cached IPv4 address does not answer an IPv6 query
+            let ip = cache
+                .get_host_by_name_at(now, HOST, AddrType::IPv6)
+                .await
+                .unwrap();
+            assert_eq!(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), ip);
+            assert_eq!(
+                Ok(v4(1)),
+                cache.get_host_by_name_at(now, HOST, AddrType::IPv4).await
+            );
+            assert_eq!(2, upstream.queries.get());
+        });
+    }
+
+    #[test]
+    fn test_cache_stale_and_fallback() {
+        static FALLBACK: [DnsEntry<'static>; 1] = [DnsEntry::new(
+            "fallback.drogue.cloud",
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+        )];
+        let fallback = StaticDnsResolver::new(&FALLBACK);
+
+        let upstream = MockDns::new();
+        let cache: CachingResolver<_, 4> = CachingResolver::new(&upstream).fallback(&fallback);
+        futures::executor::block_on(async {
+            let now = Instant::from_secs(0);
+            cache
+                .get_host_by_name_at(now, HOST, AddrType::IPv4)
+                .await
+                .unwrap();
+
+            upstream.fail.set(true);
+            let later = now + Duration::from_secs(3600);
+            assert_eq!(
+                Ok(v4(1)),
+                cache.get_host_by_name_at(later, HOST, AddrType::IPv4).await
+            );
+            assert_eq!(
+                Ok(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+                cache
+                    .get_host_by_name_at(later, "fallback.drogue.cloud", AddrType::IPv4)
+                    .await
+            );
+            assert_eq!(
+                Err(DnsError::Timeout),
+                cache
+                    .get_host_by_name_at(later, "unknown.drogue.cloud", AddrType::IPv4)
+                    .await
+            );
+        });
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let upstream = MockDns::new();
+        let cache: CachingResolver<_, 2> = CachingResolver::new(&upstream);
+        futures::executor::block_on(async {
+            let now = Instant::from_secs(0);
+            for (i, host) in ["a", "b", "c"].iter().enumerate() {
+                let at = now + Duration::from_secs(i as u64);
+                cache
+                    .get_host_by_name_at(at, host, AddrType::IPv4)
+                    .await
+                    .unwrap();
+            }
+            // "a" expires first and was evicted
+            let at = now + Duration::from_secs(10);
+            assert_eq!(
+                Ok(v4(2)),
+                cache.get_host_by_name_at(at, "b", AddrType::IPv4).await
+            );
+            assert_eq!(
+                Ok(v4(4)),
+                cache.get_host_by_name_at(at, "a", AddrType::IPv4).await
+            );
+        });
+    }
+}
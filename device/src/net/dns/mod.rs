@@ -0,0 +1,301 @@
+use {embedded_nal_async::*, heapless::String};
+
+mod cache;
+mod udp;
+
+pub use {cache::*, udp::*};
+
+// DNS errors that can be returned by resolver.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DnsError {
+    NotFound,
+    ParseError,
+    /// No response from the DNS server.
+    Timeout,
+    /// Error communicating with the DNS server.
+    Network,
+    /// The DNS server could not process the query.
+    ServerFailure,
+}
+
+/// A resolver reporting how long its answers may be cached.
+pub trait TtlDns: Dns {
+    /// Resolve `host` to an address of the given type, along with the TTL of the answer.
+    async fn resolve(&self, host: &str, addr_type: AddrType) -> Result<DnsAnswer, Self::Error>;
+}
+
+pub struct DnsEntry<'a> {
+    host: &'a str,
+    ip: IpAddr,
+}
+
+impl<'a> DnsEntry<'a> {
+    pub const fn new(host: &'a str, ip: IpAddr) -> Self {
+        Self { host, ip }
+    }
+}
+
+// A static DNS resolver returning the first entry matching a host and address type
+pub struct StaticDnsResolver<'a, const N: usize> {
+    entries: &'a [DnsEntry<'a>; N],
+}
+
+impl<'a, const N: usize> StaticDnsResolver<'a, N> {
+    pub const fn new(entries: &'a [DnsEntry<'a>; N]) -> Self {
+        Self { entries }
+    }
+}
+impl<'a, const N: usize> Dns for StaticDnsResolver<'a, N> {
+    type Error = DnsError;
+
+    async fn get_host_by_name(&self, host: &str, addr_type: AddrType) -> Result<IpAddr, DnsError> {
+        lookup_static(self.entries, host, addr_type)
+    }
+
+    async fn get_host_by_address(&self, addr: IpAddr) -> Result<String<256>, Self::Error> {
+        for entry in self.entries.iter() {
+            if entry.ip == addr {
+                return Ok(entry.host.into()); //.map_err(|_| DnsError::ParseError)?);
+            }
+        }
+        Err(DnsError::NotFound)
+    }
+}
+
+fn lookup_static(
+    entries: &[DnsEntry<'_>],
+    host: &str,
+    addr_type: AddrType,
+) -> Result<IpAddr, DnsError> {
+    // A host may have several entries, e.g. both an IPv4 and an IPv6 address
+    for entry in entries.iter() {
+        if entry.host.eq_ignore_ascii_case(host) && matches_addr_type(&entry.ip, addr_type) {
+            return Ok(entry.ip);
+        }
+    }
+
+    match try_parse_ip(host) {
+        Ok(ip) if matches_addr_type(&ip, addr_type) => Ok(ip),
+        _ => Err(DnsError::NotFound),
+    }
+}
+
+fn matches_addr_type(ip: &IpAddr, addr_type: AddrType) -> bool {
+    match addr_type {
+        AddrType::IPv4 => matches!(ip, IpAddr::V4(_)),
+        AddrType::IPv6 => matches!(ip, IpAddr::V6(_)),
+        AddrType::Either => true,
+    }
+}
+
+/// Parse an IPv4 or IPv6 address literal.
+fn try_parse_ip(s: &str) -> Result<IpAddr, ()> {
+    if s.contains(':') {
+        parse_ipv6(s).map(IpAddr::V6)
+    } else {
+        parse_ipv4(s).map(IpAddr::V4)
+    }
+}
+
+/// Parse an IPv4 address in dotted decimal notation, e.g. `192.168.1.2`.
+fn parse_ipv4(s: &str) -> Result<Ipv4Addr, ()> {
+    let mut octets: [u8; 4] = [0; 4];
+    let mut parts = s.split('.');
+    for octet in octets.iter_mut() {
+        let part = parts.next().ok_or(())?;
+        // Leading zeros are ambiguous, as some parsers treat them as octal
+        if part.is_empty()
+            || part.len() > 3
+            || (part.len() > 1 && part.starts_with('0'))
+            || !part.bytes().all(|b| b.is_ascii_digit())
+        {
+            return Err(());
+        }
+        *octet = part.parse::<u8>().map_err(|_| ())?;
+    }
+    if parts.next().is_some() {
+        return Err(());
+    }
+    Ok(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+}
+
+/// Parse an IPv6 address as described in RFC 4291, section 2.2, e.g. `fe80::1` or
+/// `::ffff:192.168.1.2`.
+fn parse_ipv6(s: &str) -> Result<Ipv6Addr, ()> {
+    let (head, tail) = match s.find("::") {
+        Some(i) => (&s[..i], Some(&s[i + 2..])),
+        None => (s, None),
+    };
+
+    let mut head_groups = [0; 8];
+    // An embedded IPv4 address is only allowed at the end of the address
+    let head_len = parse_ipv6_groups(head, &mut head_groups, tail.is_none())?;
+    let mut groups = [0; 8];
+    match tail {
+        Some(tail) => {
+            let mut tail_groups = [0; 8];
+            let tail_len = parse_ipv6_groups(tail, &mut tail_groups, true)?;
+            // "::" replaces at least one group
+            if head_len + tail_len > 7 {
+                return Err(());
+            }
+            groups[..head_len].copy_from_slice(&head_groups[..head_len]);
+            groups[8 - tail_len..].copy_from_slice(&tail_groups[..tail_len]);
+        }
+        None if head_len == 8 => groups = head_groups,
+        None => return Err(()),
+    }
+
+    Ok(Ipv6Addr::new(
+        groups[0], groups[1], groups[2], groups[3], groups[4], groups[5], groups[6], groups[7],
+    ))
+}
+
+/// Parse colon separated groups, where the last may be an embedded IPv4 address if `ipv4` is
+/// set, and return the number of 16-bit groups written.
+fn parse_ipv6_groups(s: &str, groups: &mut [u16; 8], ipv4: bool) -> Result<usize, ()> {
+    if s.is_empty() {
+        return Ok(0);
+    }
+    let mut len = 0;
+    let mut parts = s.split(':').peekable();
+    while let Some(part) = parts.next() {
+        if ipv4 && parts.peek().is_none() && part.contains('.') {
+            if len > 6 {
+                return Err(());
+            }
+            let octets = parse_ipv4(part)?.octets();
+            groups[len] = u16::from_be_bytes([octets[0], octets[1]]);
+            groups[len + 1] = u16::from_be_bytes([octets[2], octets[3]]);
+            return Ok(len + 2);
+        }
+        if len == groups.len()
+            || part.is_empty()
+            || part.len() > 4
+            || !part.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Err(());
+        }
+        groups[len] = u16::from_str_radix(part, 16).map_err(|_| ())?;
+        len += 1;
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ipv4() {
+        let ip = try_parse_ip("192.168.1.2");
+        assert!(ip.is_ok());
+        assert_eq!(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)), ip.unwrap());
+
+        for invalid in [
+            "192.168.1.2.2",
+            "192.168.1",
+            "192.168.1.",
+            "192.168..2",
+            "192.168.1.256",
+            "192.168.1.02",
+            "192.168.1.+2",
+            "192.168.1.2 ",
+            "",
+        ] {
+            assert_eq!(Err(()), try_parse_ip(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_ipv6() {
+        let valid = [
+            ("::", Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+            ("::1", Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+            ("fe80::", Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)),
+            (
+                "2001:DB8::8:800:200c:417a",
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0x8, 0x800, 0x200c, 0x417a),
+            ),
+            (
+                "2001:db8:0:0:1:0:0:1",
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 1, 0, 0, 1),
+            ),
+            (
+                "::ffff:192.168.1.2",
+                Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0xc0a8, 0x0102),
+            ),
+            (
+                "1:2:3:4:5:6:192.168.1.2",
+                Ipv6Addr::new(1, 2, 3, 4, 5, 6, 0xc0a8, 0x0102),
+            ),
+        ];
+        for (s, expected) in valid {
+            assert_eq!(Ok(IpAddr::V6(expected)), try_parse_ip(s), "{}", s);
+        }
+
+        for invalid in [
+            ":",
+            ":::",
+            "1::2::3",
+            ":1::2",
+            "1::2:",
+            "1:2:3:4:5:6:7",
+            "1:2:3:4:5:6:7:8:9",
+            "1:2:3:4::5:6:7:8",
+            "12345::",
+            "g::",
+            "::192.168.1",
+            "::192.168.1.2:1",
+            "1:2:3:4:5:6:7:192.168.1.2",
+            "1.2.3.4::",
+            "::1:1.2.3.4::",
+            "1:1.2.3.4::1",
+        ] {
+            assert_eq!(Err(()), try_parse_ip(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_static_lookup() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+        let v6 = IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+        let entries = [
+            DnsEntry::new("v6.drogue.cloud", v6),
+            DnsEntry::new("Drogue.Cloud", v6),
+            DnsEntry::new("drogue.cloud", v4),
+        ];
+
+        assert_eq!(
+            Ok(v4),
+            lookup_static(&entries, "drogue.cloud", AddrType::IPv4)
+        );
+        assert_eq!(
+            Ok(v6),
+            lookup_static(&entries, "DROGUE.CLOUD", AddrType::IPv6)
+        );
+        assert_eq!(
+            Ok(v6),
+            lookup_static(&entries, "drogue.cloud", AddrType::Either)
+        );
+        assert_eq!(
+            Err(DnsError::NotFound),
+            lookup_static(&entries, "v6.drogue.cloud", AddrType::IPv4)
+        );
+
+        assert_eq!(
+            Ok(v4),
+            lookup_static(&entries, "192.168.1.2", AddrType::Either)
+        );
+        assert_eq!(Ok(v6), lookup_static(&entries, "fe80::1", AddrType::IPv6));
+        assert_eq!(
+            Err(DnsError::NotFound),
+            lookup_static(&entries, "fe80::1", AddrType::IPv4)
+        );
+        assert_eq!(
+            Err(DnsError::NotFound),
+            lookup_static(&entries, "unknown.drogue.cloud", AddrType::Either)
+        );
+    }
+}
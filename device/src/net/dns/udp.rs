@@ -0,0 +1,859 @@
+use {
+    super::{matches_addr_type, try_parse_ip, DnsError, TtlDns},
+    core::cell::RefCell,
+    embassy_time::{with_timeout, Duration},
+    embedded_nal_async::{
+        AddrType, ConnectedUdp, Dns, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpStack,
+    },
+    heapless::String,
+    rand_core::RngCore,
+};
+
+pub const DNS_PORT: u16 = 53;
+
+/// Maximum size of a DNS message over UDP without EDNS.
+const MAX_MESSAGE_LEN: usize = 512;
+/// Maximum length of a domain name.
+const MAX_NAME_LEN: usize = 255;
+/// Maximum number of CNAME records followed when resolving a name.
+const MAX_ALIASES: usize = 4;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NAME_ERROR: u8 = 3;
+
+type Name = String<MAX_NAME_LEN>;
+
+/// An address resolved by a DNS server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DnsAnswer {
+    pub addr: IpAddr,
+    /// How long the answer may be cached.
+    pub ttl: Duration,
+}
+
+/// A resolver querying a DNS server over UDP.
+///
+/// Every query gets a random ID drawn from `RNG`, making it harder to spoof responses.
+pub struct UdpDnsResolver<'a, S, RNG>
+where
+    S: UdpStack,
+    RNG: RngCore,
+{
+    stack: &'a S,
+    server: SocketAddr,
+    timeout: Duration,
+    attempts: usize,
+    rng: RefCell<RNG>,
+}
+
+impl<'a, S, RNG> UdpDnsResolver<'a, S, RNG>
+where
+    S: UdpStack,
+    RNG: RngCore,
+{
+    /// Create a resolver using the DNS server at `server`, waiting 2 seconds for a response and
+    /// sending each query up to 3 times.
+    pub fn new(stack: &'a S, server: SocketAddr, rng: RNG) -> Self {
+        Self {
+            stack,
+            server,
+            timeout: Duration::from_secs(2),
+            attempts: 3,
+            rng: RefCell::new(rng),
+        }
+    }
+
+    /// Time to wait for a response before resending a query.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of times a query is resent when no response is received.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.attempts = retries.saturating_add(1);
+        self
+    }
+
+    /// Resolve `host` to an address of the given type, following CNAME records. Address
+    /// literals are returned as is and never expire.
+    pub async fn resolve(&self, host: &str, addr_type: AddrType) -> Result<DnsAnswer, DnsError> {
+        if let Ok(addr) = try_parse_ip(host) {
+            return if matches_addr_type(&addr, addr_type) {
+                Ok(DnsAnswer {
+                    addr,
+                    ttl: Duration::from_secs(u32::MAX as u64),
+                })
+            } else {
+                Err(DnsError::NotFound)
+            };
+        }
+
+        let types: &[u16] = match addr_type {
+            AddrType::IPv4 => &[TYPE_A],
+            AddrType::IPv6 => &[TYPE_AAAA],
+            AddrType::Either => &[TYPE_A, TYPE_AAAA],
+        };
+
+        let mut name = Name::new();
+        name.push_str(host).map_err(|_| DnsError::ParseError)?;
+        let mut ttl = u32::MAX;
+        'aliases: for _ in 0..=MAX_ALIASES {
+            for qtype in types {
+                match self.lookup(&name, *qtype).await? {
+                    Lookup::Found(Record::Address(addr), record_ttl) => {
+                        return Ok(DnsAnswer {
+                            addr,
+                            ttl: Duration::from_secs(ttl.min(record_ttl) as u64),
+                        });
+                    }
+                    Lookup::Alias(target, alias_ttl) => {
+                        trace!("{} is an alias for {}", name.as_str(), target.as_str());
+                        ttl = ttl.min(alias_ttl);
+                        name = target;
+                        continue 'aliases;
+                    }
+                    _ => {}
+                }
+            }
+            return Err(DnsError::NotFound);
+        }
+        warn!("Too many aliases resolving {}", host);
+        Err(DnsError::NotFound)
+    }
+
+    async fn lookup(&self, name: &str, qtype: u16) -> Result<Lookup, DnsError> {
+        let id = self.rng.borrow_mut().next_u32() as u16;
+
+        let mut query = [0; MAX_MESSAGE_LEN];
+        let len = encode_query(&mut query, id, name, qtype)?;
+        let mut response = [0; MAX_MESSAGE_LEN];
+        let len = self.exchange(&query[..len], id, &mut response).await?;
+        parse_response(&response[..len], name, qtype)
+    }
+
+    async fn exchange(
+        &self,
+        query: &[u8],
+        id: u16,
+        response: &mut [u8],
+    ) -> Result<usize, DnsError> {
+        let (_, mut socket) = self
+            .stack
+            .connect(self.server)
+            .await
+            .map_err(|_| DnsError::Network)?;
+        for attempt in 1..=self.attempts {
+            socket.send(query).await.map_err(|_| DnsError::Network)?;
+            match with_timeout(self.timeout, receive(&mut socket, id, response)).await {
+                Ok(result) => return result,
+                Err(_) => debug!("DNS query timed out (attempt {})", attempt),
+            }
+        }
+        Err(DnsError::Timeout)
+    }
+}
+
+/// Wait for the response to the query with the given id, skipping any other datagrams.
+async fn receive<C: ConnectedUdp>(
+    socket: &mut C,
+    id: u16,
+    response: &mut [u8],
+) -> Result<usize, DnsError> {
+    loop {
+        let len = socket
+            .receive_into(response)
+            .await
+            .map_err(|_| DnsError::Network)?;
+        if len >= HEADER_LEN && u16::from_be_bytes([response[0], response[1]]) == id {
+            return Ok(len);
+        }
+        warn!("Ignoring unexpected DNS response");
+    }
+}
+
+impl<'a, S, RNG> Dns for UdpDnsResolver<'a, S, RNG>
+where
+    S: UdpStack,
+    RNG: RngCore,
+{
+    type Error = DnsError;
+
+    async fn get_host_by_name(&self, host: &str, addr_type: AddrType) -> Result<IpAddr, DnsError> {
+        Ok(self.resolve(host, addr_type).await?.addr)
+    }
+
+    async fn get_host_by_address(&self, addr: IpAddr) -> Result<String<256>, DnsError> {
+        let name = reverse_name(addr)?;
+        match self.lookup(&name, TYPE_PTR).await? {
+            Lookup::Found(Record::Name(host), _) => {
+                let mut result = String::new();
+                result.push_str(&host).map_err(|_| DnsError::ParseError)?;
+                Ok(result)
+            }
+            _ => Err(DnsError::NotFound),
+        }
+    }
+}
+
+impl<'a, S, RNG> TtlDns for UdpDnsResolver<'a, S, RNG>
+where
+    S: UdpStack,
+    RNG: RngCore,
+{
+    async fn resolve(&self, host: &str, addr_type: AddrType) -> Result<DnsAnswer, DnsError> {
+        UdpDnsResolver::resolve(self, host, addr_type).await
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Record {
+    Address(IpAddr),
+    Name(Name),
+}
+
+/// Outcome of a single query.
+#[derive(Debug, PartialEq)]
+enum Lookup {
+    /// A record of the requested type and its TTL in seconds.
+    Found(Record, u32),
+    /// The name is an alias without records of the requested type in the response.
+    Alias(Name, u32),
+    /// The name exists, but has no records of the requested type.
+    Empty,
+}
+
+/// Write a recursive query for `name` into `buf`, returning the length of the message.
+fn encode_query(buf: &mut [u8], id: u16, name: &str, qtype: u16) -> Result<usize, DnsError> {
+    let mut header = [0; HEADER_LEN];
+    header[0..2].copy_from_slice(&id.to_be_bytes());
+    // Recursion desired
+    header[2] = 0x01;
+    // One question
+    header[5] = 1;
+
+    let mut writer = Writer { buf, pos: 0 };
+    writer.write(&header)?;
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.len() > MAX_NAME_LEN - 2 {
+        return Err(DnsError::ParseError);
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::ParseError);
+        }
+        writer.write(&[label.len() as u8])?;
+        writer.write(label.as_bytes())?;
+    }
+    writer.write(&[0])?;
+    writer.write(&qtype.to_be_bytes())?;
+    writer.write(&CLASS_IN.to_be_bytes())?;
+    Ok(writer.pos)
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn write(&mut self, data: &[u8]) -> Result<(), DnsError> {
+        let end = self.pos + data.len();
+        if end > self.buf.len() {
+            return Err(DnsError::ParseError);
+        }
+        self.buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+}
+
+/// Extract the records for `name` of type `qtype` from a response, following any CNAME records
+/// included in the answer.
+fn parse_response(msg: &[u8], name: &str, qtype: u16) -> Result<Lookup, DnsError> {
+    if msg.len() < HEADER_LEN || msg[2] & 0x80 == 0 {
+        return Err(DnsError::ParseError);
+    }
+    if msg[2] & 0x02 != 0 {
+        // Without the complete answer, there is nothing reliable to return
+        warn!("Truncated DNS response");
+        return Err(DnsError::ParseError);
+    }
+    match msg[3] & 0x0F {
+        0 => {}
+        RCODE_NAME_ERROR => return Err(DnsError::NotFound),
+        rcode => {
+            warn!("DNS server failure, response code {}", rcode);
+            return Err(DnsError::ServerFailure);
+        }
+    }
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        pos = read_name(msg, pos, None)? + 4;
+    }
+
+    let mut current = Name::new();
+    current
+        .push_str(name.strip_suffix('.').unwrap_or(name))
+        .map_err(|_| DnsError::ParseError)?;
+    let mut aliased = false;
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        let mut owner = Name::new();
+        pos = read_name(msg, pos, Some(&mut owner))?;
+        let rtype = read_u16(msg, pos)?;
+        let rclass = read_u16(msg, pos + 2)?;
+        let record_ttl = read_u32(msg, pos + 4)?;
+        let rdlen = read_u16(msg, pos + 8)? as usize;
+        let rdata = pos + 10;
+        pos = rdata + rdlen;
+        if pos > msg.len() {
+            return Err(DnsError::ParseError);
+        }
+        if rclass != CLASS_IN || !owner.eq_ignore_ascii_case(&current) {
+            continue;
+        }
+
+        let record = match rtype {
+            TYPE_CNAME if qtype != TYPE_CNAME => {
+                let mut target = Name::new();
+                read_name(msg, rdata, Some(&mut target))?;
+                current = target;
+                aliased = true;
+                ttl = ttl.min(record_ttl);
+                continue;
+            }
+            t if t != qtype => continue,
+            TYPE_A if rdlen == 4 => Record::Address(IpAddr::V4(Ipv4Addr::new(
+                msg[rdata],
+                msg[rdata + 1],
+                msg[rdata + 2],
+                msg[rdata + 3],
+            ))),
+            TYPE_AAAA if rdlen == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&msg[rdata..rdata + 16]);
+                Record::Address(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            TYPE_PTR => {
+                let mut target = Name::new();
+                read_name(msg, rdata, Some(&mut target))?;
+                Record::Name(target)
+            }
+            _ => return Err(DnsError::ParseError),
+        };
+        return Ok(Lookup::Found(record, ttl.min(record_ttl)));
+    }
+
+    if aliased {
+        Ok(Lookup::Alias(current, ttl))
+    } else {
+        Ok(Lookup::Empty)
+    }
+}
+
+/// Read a possibly compressed name starting at `pos`, returning the position following it.
+fn read_name(msg: &[u8], mut pos: usize, mut out: Option<&mut Name>) -> Result<usize, DnsError> {
+    let mut end = None;
+    // Bound the number of compression pointers followed to guard against loops
+    for _ in 0..MAX_NAME_LEN {
+        let len = *msg.get(pos).ok_or(DnsError::ParseError)? as usize;
+        match len & 0xC0 {
+            0x00 if len == 0 => return Ok(end.unwrap_or(pos + 1)),
+            0x00 => {
+                let label = msg
+                    .get(pos + 1..pos + 1 + len)
+                    .ok_or(DnsError::ParseError)?;
+                if let Some(out) = out.as_mut() {
+                    let label = core::str::from_utf8(label).map_err(|_| DnsError::ParseError)?;
+                    if !out.is_empty() {
+                        out.push('.').map_err(|_| DnsError::ParseError)?;
+                    }
+                    out.push_str(label).map_err(|_| DnsError::ParseError)?;
+                }
+                pos += 1 + len;
+            }
+            0xC0 => {
+                let pointer = (read_u16(msg, pos)? & 0x3FFF) as usize;
+                end.get_or_insert(pos + 2);
+                pos = pointer;
+            }
+            _ => return Err(DnsError::ParseError),
+        }
+    }
+    Err(DnsError::ParseError)
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16, DnsError> {
+    let bytes = msg.get(pos..pos + 2).ok_or(DnsError::ParseError)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Result<u32, DnsError> {
+    let bytes = msg.get(pos..pos + 4).ok_or(DnsError::ParseError)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Name used for reverse lookups of `addr` in the in-addr.arpa and ip6.arpa domains.
+fn reverse_name(addr: IpAddr) -> Result<Name, DnsError> {
+    use core::fmt::Write;
+    let mut name = Name::new();
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            write!(name, "{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(addr) => addr
+            .octets()
+            .iter()
+            .rev()
+            .try_for_each(|b| write!(name, "{:x}.{:x}.", b & 0x0F, b >> 4))
+            .and_then(|_| name.write_str("ip6.arpa")),
+    }
+    .map_err(|_| DnsError::ParseError)?;
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::testing::TestRng,
+        embedded_nal_async::UnconnectedUdp,
+        heapless::{Deque, Vec},
+        std::rc::Rc,
+    };
+
+    type Message = Vec<u8, MAX_MESSAGE_LEN>;
+
+    /// Build a response to `query` with the given answers of (owner, type, ttl, rdata).
+    fn respond(query: &[u8], rcode: u8, answers: &[(&str, u16, u32, &[u8])]) -> Message {
+        let mut msg = Message::from_slice(query).unwrap();
+        msg[2] |= 0x80;
+        msg[3] = 0x80 | rcode;
+        msg[7] = answers.len() as u8;
+        for (owner, rtype, ttl, rdata) in answers {
+            msg.extend_from_slice(&name(owner)).unwrap();
+            msg.extend_from_slice(&rtype.to_be_bytes()).unwrap();
+            msg.extend_from_slice(&CLASS_IN.to_be_bytes()).unwrap();
+            msg.extend_from_slice(&ttl.to_be_bytes()).unwrap();
+            msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes())
+                .unwrap();
+            msg.extend_from_slice(rdata).unwrap();
+        }
+        msg
+    }
+
+    fn name(name: &str) -> Message {
+        let mut encoded = Message::new();
+        for label in name.split('.') {
+            encoded.push(label.len() as u8).unwrap();
+            encoded.extend_from_slice(label.as_bytes()).unwrap();
+        }
+        encoded.push(0).unwrap();
+        encoded
+    }
+
+    fn query(name: &str, qtype: u16) -> Message {
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        let len = encode_query(&mut buf, 0x1234, name, qtype).unwrap();
+        Message::from_slice(&buf[..len]).unwrap()
+    }
+
+    #[test]
+    fn test_encode_query() {
+        let expected: &[u8] = &[
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 4, b'h', b't',
+            b't', b'p', 7, b's', b'a', b'n', b'd', b'b', b'o', b'x', 0, 0x00, 0x01, 0x00, 0x01,
+        ];
+        assert_eq!(expected, &query("http.sandbox", TYPE_A)[..]);
+        assert_eq!(expected, &query("http.sandbox.", TYPE_A)[..]);
+
+        let mut buf = [0; MAX_MESSAGE_LEN];
+        assert_eq!(
+            Err(DnsError::ParseError),
+            encode_query(&mut buf, 1, "http..sandbox", TYPE_A)
+        );
+        assert_eq!(
+            Err(DnsError::ParseError),
+            encode_query(&mut buf, 1, "", TYPE_A)
+        );
+    }
+
+    #[test]
+    fn test_parse_address() {
+        let q = query("example.com", TYPE_A);
+        let msg = respond(&q, 0, &[("EXAMPLE.com", TYPE_A, 300, &[93, 184, 216, 34])]);
+        assert_eq!(
+            Ok(Lookup::Found(
+                Record::Address(IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))),
+                300
+            )),
+            parse_response(&msg, "example.com", TYPE_A)
+        );
+
+        let q = query("example.com", TYPE_AAAA);
+        let addr = [
+            0x26, 0x06, 0x28, 0, 0x02, 0x20, 0, 1, 0x2, 0x48, 0x18, 0x93, 0x25, 0xc8, 0x19, 0x46,
+        ];
+        let msg = respond(&q, 0, &[("example.com", TYPE_AAAA, 60, &addr)]);
+        assert_eq!(
+            Ok(Lookup::Found(
+                Record::Address(IpAddr::V6(Ipv6Addr::from(addr))),
+                60
+            )),
+            parse_response(&msg, "example.com", TYPE_AAAA)
+        );
+    }
+
+    #[test]
+    fn test_parse_cname() {
+        let q = query("www.example.com", TYPE_A);
+        let target = name("example.net");
+        let msg = respond(
+            &q,
+            0,
+            &[
+                ("www.example.com", TYPE_CNAME, 30, &target),
+                ("example.net", TYPE_A, 300, &[10, 0, 0, 1]),
+            ],
+        );
+        assert_eq!(
+            Ok(Lookup::Found(
+                Record::Address(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+                30
+            )),
+            parse_response(&msg, "www.example.com", TYPE_A)
+        );
+
+        let msg = respond(&q, 0, &[("www.example.com", TYPE_CNAME, 30, &target)]);
+        assert_eq!(
+            Ok(Lookup::Alias(Name::from("example.net"), 30)),
+            parse_response(&msg, "www.example.com", TYPE_A)
+        );
+    }
+
+    #[test]
+    fn test_parse_compressed_names() {
+        let q = query("www.example.com", TYPE_A);
+        // Owner names pointing at the question name and the CNAME target
+        let mut msg = respond(&q, 0, &[]);
+        msg[7] = 2;
+        let cname_at = msg.len();
+        msg.extend_from_slice(&[
+            0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 30, 0, 6, 3, b'w', b'e', b'b', 0xC0, 16,
+        ])
+        .unwrap();
+        msg.extend_from_slice(&[
+            0xC0,
+            (cname_at + 12) as u8,
+            0,
+            1,
+            0,
+            1,
+            0,
+            0,
+            0,
+            60,
+            0,
+            4,
+            10,
+            0,
+            0,
+            2,
+        ])
+        .unwrap();
+        assert_eq!(
+            Ok(Lookup::Found(
+                Record::Address(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))),
+                30
+            )),
+            parse_response(&msg, "www.example.com", TYPE_A)
+        );
+
+        // A pointer loop must not hang the parser
+        let mut msg = respond(&q, 0, &[]);
+        msg[7] = 1;
+        let at = msg.len() as u8;
+        msg.extend_from_slice(&[0xC0, at]).unwrap();
+        assert_eq!(
+            Err(DnsError::ParseError),
+            parse_response(&msg, "www.example.com", TYPE_A)
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let q = query("example.com", TYPE_A);
+        assert_eq!(
+            Err(DnsError::NotFound),
+            parse_response(&respond(&q, 3, &[]), "example.com", TYPE_A)
+        );
+        assert_eq!(
+            Err(DnsError::ServerFailure),
+            parse_response(&respond(&q, 2, &[]), "example.com", TYPE_A)
+        );
+        assert_eq!(
+            Ok(Lookup::Empty),
+            parse_response(&respond(&q, 0, &[]), "example.com", TYPE_A)
+        );
+        // The query itself is not a response
+        assert_eq!(
+            Err(DnsError::ParseError),
+            parse_response(&q, "example.com", TYPE_A)
+        );
+        // Record data exceeding the message
+        let mut msg = respond(&q, 0, &[("example.com", TYPE_A, 300, &[1, 2, 3, 4])]);
+        msg.truncate(msg.len() - 1);
+        assert_eq!(
+            Err(DnsError::ParseError),
+            parse_response(&msg, "example.com", TYPE_A)
+        );
+    }
+
+    #[test]
+    fn test_reverse_name() {
+        assert_eq!(
+            "4.3.2.1.in-addr.arpa",
+            reverse_name(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
+                .unwrap()
+                .as_str()
+        );
+        let name =
+            reverse_name(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))).unwrap();
+        assert_eq!(
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa",
+            name.as_str()
+        );
+    }
+
+    #[derive(Debug)]
+    struct StubError;
+
+    impl embedded_io::Error for StubError {
+        fn kind(&self) -> embedded_io::ErrorKind {
+            embedded_io::ErrorKind::Other
+        }
+    }
+
+    /// A DNS server answering from a fixed table, dropping the first `drop` queries.
+    struct StubServer {
+        records: &'static [(&'static str, u16, &'static [u8])],
+        drop: usize,
+        queries: usize,
+    }
+
+    impl StubServer {
+        fn answer(&mut self, query: &[u8]) -> Option<Message> {
+            self.queries += 1;
+            if self.queries <= self.drop {
+                return None;
+            }
+            let mut qname = Name::new();
+            let pos = read_name(query, HEADER_LEN, Some(&mut qname)).unwrap();
+            let qtype = read_u16(query, pos).unwrap();
+            let answers: Vec<(&str, u16, u32, &[u8]), 4> = self
+                .records
+                .iter()
+                .filter(|(owner, rtype, _)| {
+                    *owner == qname.as_str() && (*rtype == qtype || *rtype == TYPE_CNAME)
+                })
+                .map(|(owner, rtype, rdata)| (*owner, *rtype, 60, *rdata))
+                .collect();
+            let rcode = if self
+                .records
+                .iter()
+                .any(|(owner, _, _)| *owner == qname.as_str())
+            {
+                0
+            } else {
+                RCODE_NAME_ERROR
+            };
+            Some(respond(query, rcode, &answers))
+        }
+    }
+
+    #[derive(Clone)]
+    struct StubStack(Rc<RefCell<StubServer>>);
+
+    struct StubSocket {
+        server: Rc<RefCell<StubServer>>,
+        responses: Deque<Message, 4>,
+    }
+
+    impl ConnectedUdp for StubSocket {
+        type Error = StubError;
+
+        async fn send(&mut self, data: &[u8]) -> Result<(), StubError> {
+            if let Some(response) = self.server.borrow_mut().answer(data) {
+                self.responses.push_back(response).unwrap();
+            }
+            Ok(())
+        }
+
+        async fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, StubError> {
+            match self.responses.pop_front() {
+                Some(response) => {
+                    buffer[..response.len()].copy_from_slice(&response);
+                    Ok(response.len())
+                }
+                None => core::future::pending().await,
+            }
+        }
+    }
+
+    impl UnconnectedUdp for StubSocket {
+        type Error = StubError;
+
+        async fn send(&mut self, _: SocketAddr, _: SocketAddr, _: &[u8]) -> Result<(), StubError> {
+            Err(StubError)
+        }
+
+        async fn receive_into(
+            &mut self,
+            _: &mut [u8],
+        ) -> Result<(usize, SocketAddr, SocketAddr), StubError> {
+            Err(StubError)
+        }
+    }
+
+    impl UdpStack for StubStack {
+        type Error = StubError;
+        type Connected = StubSocket;
+        type UniquelyBound = StubSocket;
+        type MultiplyBound = StubSocket;
+
+        async fn connect(&self, remote: SocketAddr) -> Result<(SocketAddr, StubSocket), StubError> {
+            self.connect_from(server(), remote).await
+        }
+
+        async fn connect_from(
+            &self,
+            local: SocketAddr,
+            _: SocketAddr,
+        ) -> Result<(SocketAddr, StubSocket), StubError> {
+            Ok((
+                local,
+                StubSocket {
+                    server: self.0.clone(),
+                    responses: Deque::new(),
+                },
+            ))
+        }
+
+        async fn bind_single(&self, _: SocketAddr) -> Result<(SocketAddr, StubSocket), StubError> {
+            Err(StubError)
+        }
+
+        async fn bind_multiple(&self, _: SocketAddr) -> Result<StubSocket, StubError> {
+            Err(StubError)
+        }
+    }
+
+    const RECORDS: &[(&str, u16, &[u8])] = &[
+        (
+            "http.sandbox.drogue.cloud",
+            TYPE_CNAME,
+            &[
+                7, b's', b'a', b'n', b'd', b'b', b'o', b'x', 6, b'd', b'r', b'o', b'g', b'u', b'e',
+                5, b'c', b'l', b'o', b'u', b'd', 0,
+            ],
+        ),
+        (
+            "sandbox.drogue.cloud",
+            TYPE_AAAA,
+            &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        ),
+        (
+            "4.3.2.1.in-addr.arpa",
+            TYPE_PTR,
+            &[4, b'h', b'o', b's', b't', 0],
+        ),
+    ];
+
+    fn stub(drop: usize) -> StubStack {
+        StubStack(Rc::new(RefCell::new(StubServer {
+            records: RECORDS,
+            drop,
+            queries: 0,
+        })))
+    }
+
+    fn server() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), DNS_PORT)
+    }
+
+    #[test]
+    fn test_resolve() {
+        let stack = stub(0);
+        let resolver = UdpDnsResolver::new(&stack, server(), TestRng(1));
+        futures::executor::block_on(async {
+            // No A record, falls back to AAAA for the CNAME target
+            let answer = resolver
+                .resolve("http.sandbox.drogue.cloud", AddrType::Either)
+                .await
+                .unwrap();
+            assert_eq!(
+                IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                answer.addr
+            );
+            assert_eq!(Duration::from_secs(60), answer.ttl);
+
+            assert_eq!(
+                Err(DnsError::NotFound),
+                resolver
+                    .get_host_by_name("sandbox.drogue.cloud", AddrType::IPv4)
+                    .await
+            );
+            assert_eq!(
+                Err(DnsError::NotFound),
+                resolver
+                    .get_host_by_name("unknown.drogue.cloud", AddrType::Either)
+                    .await
+            );
+            assert_eq!(
+                "host",
+                resolver
+                    .get_host_by_address(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)))
+                    .await
+                    .unwrap()
+                    .as_str()
+            );
+        });
+    }
+
+    #[test]
+    fn test_retries() {
+        let stack = stub(2);
+        let resolver =
+            UdpDnsResolver::new(&stack, server(), TestRng(1)).timeout(Duration::from_millis(10));
+        futures::executor::block_on(async {
+            assert!(resolver
+                .get_host_by_name("sandbox.drogue.cloud", AddrType::IPv6)
+                .await
+                .is_ok());
+            assert_eq!(3, stack.0.borrow().queries);
+
+            let stack = stub(usize::MAX);
+            let resolver = UdpDnsResolver::new(&stack, server(), TestRng(1))
+                .timeout(Duration::from_millis(10))
+                .retries(1);
+            assert_eq!(
+                Err(DnsError::Timeout),
+                resolver
+                    .get_host_by_name("sandbox.drogue.cloud", AddrType::IPv6)
+                    .await
+            );
+            assert_eq!(2, stack.0.borrow().queries);
+        });
+    }
+}
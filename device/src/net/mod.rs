@@ -1 +1,10 @@
+pub mod cloud;
+pub mod coap;
+pub mod command;
 pub mod dns;
+pub mod mqtt;
+pub mod tls;
+
+mod connection;
+
+pub use connection::*;
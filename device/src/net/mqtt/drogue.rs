@@ -0,0 +1,138 @@
+//! Topics of the Drogue Cloud MQTT endpoint
+//!
+//! Devices publish telemetry to a topic named after the channel, and receive commands by
+//! subscribing to [`COMMAND_FILTER`]. Gateways publish on behalf of other devices by appending
+//! the device name to the channel.
+use {
+    super::MessageHandler,
+    crate::net::command::{Command, CommandDispatcher, CommandName},
+    core::fmt::Write,
+    heapless::String,
+};
+
+/// Subscription filter for commands sent to the device, or to devices behind a gateway.
+pub const COMMAND_FILTER: &str = "command/inbox/#";
+
+const COMMAND_PREFIX: &str = "command/inbox/";
+
+/// Topic for publishing telemetry on `channel` on behalf of another `device`.
+pub fn gateway_topic<const N: usize>(channel: &str, device: &str) -> Option<String<N>> {
+    let mut topic = String::new();
+    write!(topic, "{}/{}", channel, device).ok()?;
+    Some(topic)
+}
+
+/// A command received on an inbox topic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandTopic<'t> {
+    /// The device behind a gateway the command is for, or `None` for the device itself.
+    pub device: Option<&'t str>,
+    pub command: &'t str,
+}
+
+/// Parse an inbox topic of the form `command/inbox/<device>/<command>`, where the device is
+/// empty for commands sent to the device itself.
+pub fn parse_command_topic(topic: &str) -> Option<CommandTopic<'_>> {
+    let (device, command) = topic.strip_prefix(COMMAND_PREFIX)?.split_once('/')?;
+    if command.is_empty() {
+        return None;
+    }
+    Some(CommandTopic {
+        device: if device.is_empty() {
+            None
+        } else {
+            Some(device)
+        },
+        command,
+    })
+}
+
+/// Passes commands sent to the device to a [`CommandDispatcher`]. Responses of the handlers are
+/// discarded.
+pub struct CommandRouter<'d, 'a, const N: usize> {
+    dispatcher: &'d mut CommandDispatcher<'a, N>,
+}
+
+impl<'d, 'a, const N: usize> CommandRouter<'d, 'a, N> {
+    pub fn new(dispatcher: &'d mut CommandDispatcher<'a, N>) -> Self {
+        Self { dispatcher }
+    }
+}
+
+impl<'d, 'a, const N: usize> MessageHandler for CommandRouter<'d, 'a, N> {
+    fn handle(&mut self, topic: &str, payload: &[u8]) {
+        let command = match parse_command_topic(topic) {
+            Some(CommandTopic {
+                device: None,
+                command,
+            }) => command,
+            _ => {
+                debug!("Ignoring message on {}", topic);
+                return;
+            }
+        };
+        let mut name = CommandName::new();
+        if name.push_str(command).is_err() {
+            warn!("Command name too long");
+            return;
+        }
+        let mut response = [0; 64];
+        if let Err(e) = self
+            .dispatcher
+            .dispatch(&Command::new(name, payload), &mut response)
+        {
+            warn!("Error handling command {}: {:?}", command, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::net::command::CommandError};
+
+    #[test]
+    fn test_topics() {
+        assert_eq!(
+            Some(CommandTopic {
+                device: None,
+                command: "reboot"
+            }),
+            parse_command_topic("command/inbox//reboot")
+        );
+        assert_eq!(
+            Some(CommandTopic {
+                device: Some("sensor1"),
+                command: "set/interval"
+            }),
+            parse_command_topic("command/inbox/sensor1/set/interval")
+        );
+        assert_eq!(None, parse_command_topic("command/inbox//"));
+        assert_eq!(None, parse_command_topic("command/inbox/reboot"));
+        assert_eq!(None, parse_command_topic("temperature"));
+
+        assert_eq!(
+            Some(String::<32>::from("temperature/sensor1")),
+            gateway_topic("temperature", "sensor1")
+        );
+        assert_eq!(None, gateway_topic::<8>("temperature", "sensor1"));
+    }
+
+    #[test]
+    fn test_command_router() {
+        let mut reboots = 0;
+        let mut reboot = |_: &[u8], _: &mut [u8]| -> Result<usize, CommandError> {
+            reboots += 1;
+            Ok(0)
+        };
+        {
+            let mut dispatcher: CommandDispatcher<'_, 1> = CommandDispatcher::new();
+            dispatcher.register("reboot", &mut reboot).unwrap();
+            let mut router = CommandRouter::new(&mut dispatcher);
+            router.handle("command/inbox//reboot", b"");
+            // Commands for other devices and unknown commands are ignored
+            router.handle("command/inbox/other/reboot", b"");
+            router.handle("command/inbox//unknown", b"");
+        }
+        assert_eq!(1, reboots);
+    }
+}
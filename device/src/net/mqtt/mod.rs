@@ -0,0 +1,716 @@
+//! MQTT client for publishing telemetry and receiving messages
+//!
+//! The client supports MQTT 3.1.1 and 5 with QoS 0 and 1. It keeps the connection alive,
+//! reconnects using a [`ConnectionManager`] and restores subscriptions after reconnecting. For
+//! TLS, use a [`TlsConnector`](crate::net::tls::TlsConnector) as the network stack.
+use {
+    crate::net::{ConnectionError, ConnectionManager},
+    embassy_futures::select::{select, Either},
+    embassy_time::{with_timeout, Duration, Instant, Timer},
+    embedded_io::asynch::{Read, Write},
+    embedded_nal_async::TcpConnect,
+    heapless::Vec,
+    packet::{Connect, Packet, PacketError},
+};
+
+pub mod drogue;
+mod packet;
+
+pub use packet::topic_matches;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProtocolVersion {
+    V311,
+    V5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+/// Settings of an MQTT session.
+pub struct MqttConfig<'a> {
+    client_id: &'a str,
+    username: Option<&'a str>,
+    password: Option<&'a str>,
+    version: ProtocolVersion,
+    keep_alive: Duration,
+    ack_timeout: Duration,
+    retries: u8,
+}
+
+impl<'a> MqttConfig<'a> {
+    /// Settings for MQTT 3.1.1 with a 60 second keep alive, waiting 10 seconds for
+    /// acknowledgements and retrying QoS 1 messages twice.
+    pub fn new(client_id: &'a str) -> Self {
+        Self {
+            client_id,
+            username: None,
+            password: None,
+            version: ProtocolVersion::V311,
+            keep_alive: Duration::from_secs(60),
+            ack_timeout: Duration::from_secs(10),
+            retries: 2,
+        }
+    }
+
+    pub fn credentials(mut self, username: &'a str, password: &'a str) -> Self {
+        self.username.replace(username);
+        self.password.replace(password);
+        self
+    }
+
+    pub fn version(mut self, version: ProtocolVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Maximum time between packets sent to the broker. Pings are sent after half of this time.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// How long to wait for the broker to acknowledge a packet.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    /// How many times unacknowledged QoS 1 messages are sent again.
+    pub fn retries(mut self, retries: u8) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+/// Handler of messages received on a subscription.
+///
+/// Closures taking the topic and payload can be used as handlers.
+pub trait MessageHandler {
+    fn handle(&mut self, topic: &str, payload: &[u8]);
+}
+
+impl<F> MessageHandler for F
+where
+    F: FnMut(&str, &[u8]),
+{
+    fn handle(&mut self, topic: &str, payload: &[u8]) {
+        self(topic, payload)
+    }
+}
+
+struct Subscription<'a> {
+    filter: &'a str,
+    qos: QoS,
+    handler: &'a mut dyn MessageHandler,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MqttError<E> {
+    Network(ConnectionError<E>),
+    Packet(PacketError),
+    /// The broker refused the connection with the given code.
+    ConnectionRefused(u8),
+    /// The broker rejected a subscription.
+    SubscriptionRejected,
+    /// No room for another subscription.
+    TooManySubscriptions,
+    /// The broker did not acknowledge a packet in time.
+    Timeout,
+    /// The broker closed the connection.
+    Disconnected,
+}
+
+impl<E> From<ConnectionError<E>> for MqttError<E> {
+    fn from(e: ConnectionError<E>) -> Self {
+        Self::Network(e)
+    }
+}
+
+impl<E> From<PacketError> for MqttError<E> {
+    fn from(e: PacketError) -> Self {
+        Self::Packet(e)
+    }
+}
+
+/// Acknowledgements and other packets handled by the client.
+enum Received {
+    ConnAck(u8),
+    PubAck(u16),
+    SubAck(u16, u8),
+    PingResp,
+    Message,
+}
+
+/// An MQTT client with up to `N` subscriptions.
+pub struct MqttClient<'a, T, const N: usize>
+where
+    T: TcpConnect + 'a,
+{
+    connection: ConnectionManager<'a, T>,
+    config: MqttConfig<'a>,
+    subscriptions: Vec<Subscription<'a>, N>,
+    tx: &'a mut [u8],
+    rx: &'a mut [u8],
+    /// An MQTT session is established on the current connection
+    session: bool,
+    packet_id: u16,
+    last_sent: Instant,
+}
+
+impl<'a, T, const N: usize> MqttClient<'a, T, N>
+where
+    T: TcpConnect + 'a,
+{
+    /// Create a client using the provided buffers for outgoing and incoming packets.
+    ///
+    /// The operation timeout of `connection` is set to 1.5 times the keep alive, after which
+    /// a silent broker is considered gone.
+    pub fn new(
+        connection: ConnectionManager<'a, T>,
+        config: MqttConfig<'a>,
+        tx: &'a mut [u8],
+        rx: &'a mut [u8],
+    ) -> Self {
+        let timeout = config.keep_alive + config.keep_alive / 2;
+        Self {
+            connection: connection.timeout(timeout),
+            config,
+            subscriptions: Vec::new(),
+            tx,
+            rx,
+            session: false,
+            packet_id: 0,
+            last_sent: Instant::from_ticks(0),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.session && self.connection.is_connected()
+    }
+
+    /// Connect to the broker unless already connected, and restore subscriptions.
+    pub async fn connect(&mut self) -> Result<(), MqttError<T::Error>> {
+        if self.is_connected() {
+            return Ok(());
+        }
+        self.session = false;
+        let result = self.open().await;
+        self.check(result)
+    }
+
+    /// Publish a message, waiting for the broker to acknowledge QoS 1 messages.
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<(), MqttError<T::Error>> {
+        self.connect().await?;
+        let result = self.send_publish(topic, payload, qos).await;
+        self.check(result)
+    }
+
+    /// Subscribe to topics matching `filter`, passing messages to `handler`. The subscription is
+    /// sent when connecting if the client is not connected.
+    pub async fn subscribe(
+        &mut self,
+        filter: &'a str,
+        qos: QoS,
+        handler: &'a mut dyn MessageHandler,
+    ) -> Result<(), MqttError<T::Error>> {
+        self.subscriptions
+            .push(Subscription {
+                filter,
+                qos,
+                handler,
+            })
+            .map_err(|_| MqttError::TooManySubscriptions)?;
+
+        if self.is_connected() {
+            let result = self.send_subscribe(filter, qos).await;
+            if let Err(MqttError::SubscriptionRejected) = result {
+                self.subscriptions.pop();
+            }
+            self.check(result)?;
+        }
+        Ok(())
+    }
+
+    /// Wait for an incoming message, passing it to its handler, or send a ping when the keep
+    /// alive is due. Must be called regularly to keep the connection open.
+    pub async fn poll(&mut self) -> Result<(), MqttError<T::Error>> {
+        self.connect().await?;
+        let result = self.keep_alive().await;
+        self.check(result)
+    }
+
+    /// Close the session and the connection.
+    pub async fn disconnect(&mut self) {
+        if self.is_connected() {
+            if let Ok(len) = packet::encode_disconnect(self.tx) {
+                self.send(len).await.ok();
+            }
+        }
+        self.session = false;
+        self.connection.disconnect();
+    }
+
+    /// Close the connection after errors that leave the session in an unknown state.
+    fn check(
+        &mut self,
+        result: Result<(), MqttError<T::Error>>,
+    ) -> Result<(), MqttError<T::Error>> {
+        match result {
+            Ok(()) | Err(MqttError::SubscriptionRejected) => result,
+            Err(_) => {
+                warn!("MQTT error, closing connection");
+                self.session = false;
+                self.connection.disconnect();
+                result
+            }
+        }
+    }
+
+    async fn open(&mut self) -> Result<(), MqttError<T::Error>> {
+        self.connection.connect().await?;
+        let connect = Connect {
+            version: self.config.version,
+            client_id: self.config.client_id,
+            keep_alive: self.config.keep_alive.as_secs().min(u16::MAX as u64) as u16,
+            username: self.config.username,
+            password: self.config.password.map(|p| p.as_bytes()),
+        };
+        let len = packet::encode_connect(self.tx, &connect)?;
+        self.send(len).await?;
+
+        match self.wait(|r| matches!(r, Received::ConnAck(_))).await? {
+            Received::ConnAck(0) => {}
+            Received::ConnAck(code) => {
+                warn!("MQTT connection refused: {}", code);
+                return Err(MqttError::ConnectionRefused(code));
+            }
+            _ => unreachable!(),
+        }
+        self.session = true;
+        info!("MQTT session established");
+
+        for i in 0..self.subscriptions.len() {
+            let (filter, qos) = (self.subscriptions[i].filter, self.subscriptions[i].qos);
+            self.send_subscribe(filter, qos).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<(), MqttError<T::Error>> {
+        let id = self.next_packet_id();
+        for attempt in 0..=self.config.retries {
+            let len = packet::encode_publish(
+                self.tx,
+                self.config.version,
+                topic,
+                payload,
+                qos,
+                id,
+                attempt > 0,
+            )?;
+            self.send(len).await?;
+            if qos == QoS::AtMostOnce {
+                return Ok(());
+            }
+            match self
+                .wait(|r| matches!(r, Received::PubAck(i) if *i == id))
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(MqttError::Timeout) => warn!("Message {} not acknowledged, retrying", id),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(MqttError::Timeout)
+    }
+
+    async fn send_subscribe(&mut self, filter: &str, qos: QoS) -> Result<(), MqttError<T::Error>> {
+        let id = self.next_packet_id();
+        let len = packet::encode_subscribe(self.tx, self.config.version, id, filter, qos)?;
+        self.send(len).await?;
+        match self
+            .wait(|r| matches!(r, Received::SubAck(i, _) if *i == id))
+            .await?
+        {
+            Received::SubAck(_, code) if code < 0x80 => Ok(()),
+            _ => {
+                warn!("Subscription to {} rejected", filter);
+                Err(MqttError::SubscriptionRejected)
+            }
+        }
+    }
+
+    async fn keep_alive(&mut self) -> Result<(), MqttError<T::Error>> {
+        let ping_at = self.last_sent + self.config.keep_alive / 2;
+        if Instant::now() < ping_at && self.receive(ping_at).await?.is_some() {
+            return Ok(());
+        }
+        trace!("Sending MQTT ping");
+        let len = packet::encode_pingreq(self.tx)?;
+        self.send(len).await?;
+        self.wait(|r| matches!(r, Received::PingResp)).await?;
+        Ok(())
+    }
+
+    /// Handle incoming packets until one matching `expected` is received.
+    async fn wait<F>(&mut self, expected: F) -> Result<Received, MqttError<T::Error>>
+    where
+        F: Fn(&Received) -> bool,
+    {
+        let deadline = Instant::now() + self.config.ack_timeout;
+        loop {
+            match self.receive(deadline).await? {
+                Some(received) if expected(&received) => return Ok(received),
+                Some(_) => {}
+                None => return Err(MqttError::Timeout),
+            }
+        }
+    }
+
+    /// Receive and handle a single packet, unless none arrives before `deadline`.
+    async fn receive(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<Option<Received>, MqttError<T::Error>> {
+        let mut header = [0; 1];
+        // Only waiting for the start of a packet is cancelled, to not lose parts of a packet
+        match select(Timer::at(deadline), self.read(&mut header)).await {
+            Either::First(_) => return Ok(None),
+            Either::Second(result) => result?,
+        }
+
+        let mut len = 0;
+        let mut byte = [0; 1];
+        for i in 0.. {
+            self.read(&mut byte).await?;
+            if packet::decode_remaining_len(&mut len, i, byte[0])?.is_some() {
+                break;
+            }
+        }
+        if len > self.rx.len() {
+            warn!("Received packet of {} bytes does not fit in buffer", len);
+            return Err(MqttError::Packet(PacketError::BufferTooSmall));
+        }
+
+        let mut pos = 0;
+        while pos < len {
+            let n = self.connection.read(&mut self.rx[pos..len]).await?;
+            if n == 0 {
+                return Err(MqttError::Disconnected);
+            }
+            pos += n;
+        }
+
+        let received = match packet::decode(self.config.version, header[0], &self.rx[..len])? {
+            Packet::ConnAck { code, .. } => Received::ConnAck(code),
+            Packet::PubAck { id } => Received::PubAck(id),
+            Packet::SubAck { id, code } => Received::SubAck(id, code),
+            Packet::PingResp => Received::PingResp,
+            Packet::Disconnect => return Err(MqttError::Disconnected),
+            Packet::Publish {
+                topic, payload, id, ..
+            } => {
+                let mut handled = false;
+                for subscription in self.subscriptions.iter_mut() {
+                    if topic_matches(subscription.filter, topic) {
+                        subscription.handler.handle(topic, payload);
+                        handled = true;
+                    }
+                }
+                if !handled {
+                    debug!("No subscription for message on {}", topic);
+                }
+                if let Some(id) = id {
+                    let len = packet::encode_puback(self.tx, id)?;
+                    self.send(len).await?;
+                }
+                Received::Message
+            }
+        };
+        Ok(Some(received))
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<(), MqttError<T::Error>> {
+        // A reconnect by the connection manager would not restore the session
+        if !self.connection.is_connected() {
+            return Err(MqttError::Disconnected);
+        }
+        match self.connection.read(buf).await? {
+            0 => Err(MqttError::Disconnected),
+            _ => Ok(()),
+        }
+    }
+
+    async fn send(&mut self, len: usize) -> Result<(), MqttError<T::Error>> {
+        if !self.connection.is_connected() {
+            return Err(MqttError::Disconnected);
+        }
+        let timeout = self.config.ack_timeout;
+        let write = async {
+            let mut pos = 0;
+            while pos < len {
+                match self.connection.write(&self.tx[pos..len]).await? {
+                    0 => return Err(MqttError::Disconnected),
+                    n => pos += n,
+                }
+            }
+            self.connection.flush().await?;
+            Ok::<_, MqttError<T::Error>>(())
+        };
+        with_timeout(timeout, write)
+            .await
+            .map_err(|_| MqttError::Timeout)??;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        self.packet_id = self.packet_id.wrapping_add(1).max(1);
+        self.packet_id
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use {
+        super::*,
+        core::cell::{Cell, RefCell},
+        embedded_io::Io,
+        embedded_nal_async::{IpAddr, Ipv4Addr, SocketAddr},
+        std::{collections::VecDeque, vec::Vec as StdVec},
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct MockError;
+
+    impl embedded_io::Error for MockError {
+        fn kind(&self) -> embedded_io::ErrorKind {
+            embedded_io::ErrorKind::Other
+        }
+    }
+
+    /// A broker answering each connection with scripted packets and pings, recording what was
+    /// sent.
+    #[derive(Default)]
+    struct MockBroker {
+        scripts: RefCell<VecDeque<StdVec<u8>>>,
+        sent: RefCell<StdVec<u8>>,
+        connections: Cell<u32>,
+    }
+
+    struct MockConnection<'a> {
+        broker: &'a MockBroker,
+        incoming: VecDeque<u8>,
+    }
+
+    impl TcpConnect for MockBroker {
+        type Error = MockError;
+        type Connection<'m> = MockConnection<'m>;
+
+        async fn connect<'m>(&'m self, _: SocketAddr) -> Result<MockConnection<'m>, MockError>
+        where
+            Self: 'm,
+        {
+            self.connections.set(self.connections.get() + 1);
+            let script = self.scripts.borrow_mut().pop_front().ok_or(MockError)?;
+            Ok(MockConnection {
+                broker: self,
+                incoming: script.into(),
+            })
+        }
+    }
+
+    impl Io for MockConnection<'_> {
+        type Error = MockError;
+    }
+
+    impl Read for MockConnection<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, MockError> {
+            if self.incoming.is_empty() {
+                // The broker stays silent
+                core::future::pending::<()>().await;
+            }
+            let mut len = 0;
+            while len < buf.len() {
+                match self.incoming.pop_front() {
+                    Some(b) => buf[len] = b,
+                    None => break,
+                }
+                len += 1;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for MockConnection<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, MockError> {
+            if buf == [0xC0, 0] {
+                self.incoming.extend([0xD0, 0]);
+            }
+            self.broker.sent.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    const CONNACK: &[u8] = &[0x20, 2, 0, 0];
+
+    fn remote() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1883)
+    }
+
+    fn config() -> MqttConfig<'static> {
+        MqttConfig::new("dev")
+            .credentials("u", "p")
+            .ack_timeout(Duration::from_millis(50))
+            .retries(1)
+    }
+
+    /// Split sent bytes into packets.
+    fn packets(sent: &[u8]) -> StdVec<StdVec<u8>> {
+        let mut packets = StdVec::new();
+        let mut rest = sent;
+        while !rest.is_empty() {
+            // All test packets are shorter than 128 bytes
+            let len = 2 + rest[1] as usize;
+            packets.push(rest[..len].to_vec());
+            rest = &rest[len..];
+        }
+        packets
+    }
+
+    #[test]
+    fn test_publish_subscribe() {
+        let broker = MockBroker::default();
+        broker.scripts.borrow_mut().push_back(
+            [
+                CONNACK,
+                // SUBACK granting QoS 1
+                &[0x90, 3, 0, 1, 1],
+                // Message delivered while waiting for the PUBACK
+                &[0x32, 9, 0, 4, b'c', b'/', b'o', b'n', 0, 9, b'!'],
+                &[0x40, 2, 0, 2],
+            ]
+            .concat(),
+        );
+
+        let mut tx = [0; 128];
+        let mut rx = [0; 128];
+        let mut messages = StdVec::new();
+        let mut handler = |topic: &str, payload: &[u8]| {
+            messages.push((topic.to_string(), payload.to_vec()));
+        };
+        {
+            let connection = ConnectionManager::new(&broker, remote());
+            let mut client: MqttClient<'_, _, 2> =
+                MqttClient::new(connection, config(), &mut tx, &mut rx);
+            futures::executor::block_on(async {
+                client
+                    .subscribe("c/+", QoS::AtLeastOnce, &mut handler)
+                    .await
+                    .unwrap();
+                client.connect().await.unwrap();
+                client
+                    .publish("telemetry", b"22", QoS::AtLeastOnce)
+                    .await
+                    .unwrap();
+            });
+        }
+        assert_eq!(vec![("c/on".to_string(), b"!".to_vec())], messages);
+
+        let sent = packets(&broker.sent.borrow());
+        assert_eq!(4, sent.len());
+        assert_eq!(0x10, sent[0][0]);
+        assert_eq!(vec![0x82, 8, 0, 1, 0, 3, b'c', b'/', b'+', 1], sent[1]);
+        assert_eq!(
+            vec![
+                0x32, 15, 0, 9, b't', b'e', b'l', b'e', b'm', b'e', b't', b'r', b'y', 0, 2, b'2',
+                b'2'
+            ],
+            sent[2]
+        );
+        assert_eq!(vec![0x40, 2, 0, 9], sent[3]);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let broker = MockBroker::default();
+        // The first connection never acknowledges the message
+        broker
+            .scripts
+            .borrow_mut()
+            .push_back([CONNACK, &[0x90, 3, 0, 1, 0]].concat());
+        broker
+            .scripts
+            .borrow_mut()
+            .push_back([CONNACK, &[0x90, 3, 0, 3, 0], &[0x40, 2, 0, 4]].concat());
+
+        let mut tx = [0; 128];
+        let mut rx = [0; 128];
+        let mut handler = |_: &str, _: &[u8]| {};
+        let connection = ConnectionManager::new(&broker, remote());
+        let mut client: MqttClient<'_, _, 1> =
+            MqttClient::new(connection, config(), &mut tx, &mut rx);
+        futures::executor::block_on(async {
+            client.connect().await.unwrap();
+            client
+                .subscribe("c", QoS::AtMostOnce, &mut handler)
+                .await
+                .unwrap();
+            assert_eq!(
+                Err(MqttError::Timeout),
+                client.publish("t", b"1", QoS::AtLeastOnce).await
+            );
+            assert!(!client.is_connected());
+
+            // Reconnects and subscribes again
+            client.publish("t", b"2", QoS::AtLeastOnce).await.unwrap();
+        });
+        assert_eq!(2, broker.connections.get());
+
+        let sent = packets(&broker.sent.borrow());
+        let headers: StdVec<u8> = sent.iter().map(|p| p[0]).collect();
+        // CONNECT, SUBSCRIBE, PUBLISH, PUBLISH (DUP), CONNECT, SUBSCRIBE, PUBLISH
+        assert_eq!(vec![0x10, 0x82, 0x32, 0x3A, 0x10, 0x82, 0x32], headers);
+    }
+
+    #[test]
+    fn test_keep_alive_and_refused() {
+        let broker = MockBroker::default();
+        broker.scripts.borrow_mut().push_back(CONNACK.to_vec());
+        broker.scripts.borrow_mut().push_back(vec![0x20, 2, 0, 5]);
+
+        let mut tx = [0; 128];
+        let mut rx = [0; 128];
+        let connection = ConnectionManager::new(&broker, remote());
+        let config = config().keep_alive(Duration::from_millis(20));
+        let mut client: MqttClient<'_, _, 1> =
+            MqttClient::new(connection, config, &mut tx, &mut rx);
+        futures::executor::block_on(async {
+            client.connect().await.unwrap();
+            // Nothing is received, so a ping is sent after 10 ms
+            client.poll().await.unwrap();
+            assert_eq!(vec![0xC0, 0], packets(&broker.sent.borrow())[1]);
+
+            client.disconnect().await;
+            assert_eq!(Err(MqttError::ConnectionRefused(5)), client.connect().await);
+        });
+    }
+}
@@ -0,0 +1,590 @@
+//! Encoding and decoding of MQTT 3.1.1 and 5 control packets
+use super::{ProtocolVersion, QoS};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+/// Largest value of the remaining length field.
+const MAX_REMAINING_LEN: usize = 268_435_455;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PacketError {
+    /// The packet does not fit in the buffer.
+    BufferTooSmall,
+    /// The packet is malformed or unsupported.
+    Malformed,
+}
+
+/// A packet received from the broker.
+#[derive(Debug, PartialEq)]
+pub enum Packet<'m> {
+    ConnAck {
+        session_present: bool,
+        /// Return code for MQTT 3.1.1, reason code for MQTT 5.
+        code: u8,
+    },
+    Publish {
+        topic: &'m str,
+        payload: &'m [u8],
+        qos: QoS,
+        /// Packet identifier, only present for QoS 1.
+        id: Option<u16>,
+        dup: bool,
+    },
+    PubAck {
+        id: u16,
+    },
+    SubAck {
+        id: u16,
+        /// Granted QoS, or a failure code of 0x80 or higher.
+        code: u8,
+    },
+    PingResp,
+    /// Sent by MQTT 5 brokers before closing the connection.
+    Disconnect,
+}
+
+/// Credentials and settings sent in the CONNECT packet.
+pub struct Connect<'a> {
+    pub version: ProtocolVersion,
+    pub client_id: &'a str,
+    pub keep_alive: u16,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a [u8]>,
+}
+
+/// Writes packet fields into a buffer.
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), PacketError> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), PacketError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> Result<(), PacketError> {
+        let end = self.pos + value.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(PacketError::BufferTooSmall)?
+            .copy_from_slice(value);
+        self.pos = end;
+        Ok(())
+    }
+
+    /// Length prefixed binary data or UTF-8 string.
+    fn binary(&mut self, value: &[u8]) -> Result<(), PacketError> {
+        let len = u16::try_from(value.len()).map_err(|_| PacketError::Malformed)?;
+        self.u16(len)?;
+        self.bytes(value)
+    }
+
+    fn varint(&mut self, mut value: usize) -> Result<(), PacketError> {
+        if value > MAX_REMAINING_LEN {
+            return Err(PacketError::Malformed);
+        }
+        loop {
+            let mut byte = (value % 128) as u8;
+            value /= 128;
+            if value > 0 {
+                byte |= 0x80;
+            }
+            self.u8(byte)?;
+            if value == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Write a packet with the given header, with the variable header and payload written by
+/// `body`. Returns the packet length.
+fn encode<F>(buf: &mut [u8], header: u8, body: F) -> Result<usize, PacketError>
+where
+    F: FnOnce(&mut Writer<'_>) -> Result<(), PacketError>,
+{
+    // Reserve room for the largest remaining length field and move the body afterwards
+    const OFFSET: usize = 5;
+    let len = {
+        let mut w = Writer::new(buf.get_mut(OFFSET..).ok_or(PacketError::BufferTooSmall)?);
+        body(&mut w)?;
+        w.pos
+    };
+    let mut w = Writer::new(buf);
+    w.u8(header)?;
+    w.varint(len)?;
+    let start = w.pos;
+    buf.copy_within(OFFSET..OFFSET + len, start);
+    Ok(start + len)
+}
+
+/// Write the empty property list of MQTT 5 packets.
+fn properties(w: &mut Writer<'_>, version: ProtocolVersion) -> Result<(), PacketError> {
+    match version {
+        ProtocolVersion::V311 => Ok(()),
+        ProtocolVersion::V5 => w.varint(0),
+    }
+}
+
+pub fn encode_connect(buf: &mut [u8], connect: &Connect<'_>) -> Result<usize, PacketError> {
+    encode(buf, CONNECT, |w| {
+        w.binary(b"MQTT")?;
+        w.u8(match connect.version {
+            ProtocolVersion::V311 => 4,
+            ProtocolVersion::V5 => 5,
+        })?;
+        // Always start a clean session, subscriptions are restored by the client
+        let mut flags = 0x02;
+        if connect.username.is_some() {
+            flags |= 0x80;
+        }
+        if connect.password.is_some() {
+            flags |= 0x40;
+        }
+        w.u8(flags)?;
+        w.u16(connect.keep_alive)?;
+        properties(w, connect.version)?;
+
+        w.binary(connect.client_id.as_bytes())?;
+        if let Some(username) = connect.username {
+            w.binary(username.as_bytes())?;
+        }
+        if let Some(password) = connect.password {
+            w.binary(password)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn encode_publish(
+    buf: &mut [u8],
+    version: ProtocolVersion,
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    id: u16,
+    dup: bool,
+) -> Result<usize, PacketError> {
+    let mut header = PUBLISH | ((qos as u8) << 1);
+    if dup {
+        header |= 0x08;
+    }
+    encode(buf, header, |w| {
+        w.binary(topic.as_bytes())?;
+        if qos != QoS::AtMostOnce {
+            w.u16(id)?;
+        }
+        properties(w, version)?;
+        w.bytes(payload)
+    })
+}
+
+pub fn encode_puback(buf: &mut [u8], id: u16) -> Result<usize, PacketError> {
+    // The MQTT 5 reason code and properties are omitted on success
+    encode(buf, PUBACK, |w| w.u16(id))
+}
+
+pub fn encode_subscribe(
+    buf: &mut [u8],
+    version: ProtocolVersion,
+    id: u16,
+    filter: &str,
+    qos: QoS,
+) -> Result<usize, PacketError> {
+    encode(buf, SUBSCRIBE, |w| {
+        w.u16(id)?;
+        properties(w, version)?;
+        w.binary(filter.as_bytes())?;
+        w.u8(qos as u8)
+    })
+}
+
+pub fn encode_pingreq(buf: &mut [u8]) -> Result<usize, PacketError> {
+    encode(buf, PINGREQ, |_| Ok(()))
+}
+
+pub fn encode_disconnect(buf: &mut [u8]) -> Result<usize, PacketError> {
+    encode(buf, DISCONNECT, |_| Ok(()))
+}
+
+/// Add a byte of the remaining length field, returning the length once complete.
+pub fn decode_remaining_len(
+    len: &mut usize,
+    index: usize,
+    byte: u8,
+) -> Result<Option<usize>, PacketError> {
+    if index >= 4 {
+        return Err(PacketError::Malformed);
+    }
+    *len += ((byte & 0x7F) as usize) << (7 * index);
+    if byte & 0x80 == 0 {
+        Ok(Some(*len))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads packet fields from a buffer.
+struct Reader<'m> {
+    buf: &'m [u8],
+}
+
+impl<'m> Reader<'m> {
+    fn u8(&mut self) -> Result<u8, PacketError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, PacketError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'m [u8], PacketError> {
+        if self.buf.len() < len {
+            return Err(PacketError::Malformed);
+        }
+        let (value, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(value)
+    }
+
+    fn str(&mut self) -> Result<&'m str, PacketError> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.bytes(len)?).map_err(|_| PacketError::Malformed)
+    }
+
+    fn varint(&mut self) -> Result<usize, PacketError> {
+        let mut len = 0;
+        for i in 0.. {
+            if let Some(len) = decode_remaining_len(&mut len, i, self.u8()?)? {
+                return Ok(len);
+            }
+        }
+        unreachable!()
+    }
+
+    /// Skip the property list of MQTT 5 packets.
+    fn properties(&mut self, version: ProtocolVersion) -> Result<(), PacketError> {
+        if version == ProtocolVersion::V5 {
+            let len = self.varint()?;
+            self.bytes(len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decode a packet from its fixed header byte and the rest of the packet following the
+/// remaining length field.
+pub fn decode(
+    version: ProtocolVersion,
+    header: u8,
+    body: &[u8],
+) -> Result<Packet<'_>, PacketError> {
+    let mut r = Reader { buf: body };
+    match header & 0xF0 {
+        CONNACK => {
+            let flags = r.u8()?;
+            let code = r.u8()?;
+            Ok(Packet::ConnAck {
+                session_present: flags & 0x01 != 0,
+                code,
+            })
+        }
+        PUBLISH => {
+            let qos = match (header >> 1) & 0x03 {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                // Only QoS 0 and 1 are requested when subscribing
+                _ => return Err(PacketError::Malformed),
+            };
+            let topic = r.str()?;
+            let id = if qos == QoS::AtLeastOnce {
+                Some(r.u16()?)
+            } else {
+                None
+            };
+            r.properties(version)?;
+            Ok(Packet::Publish {
+                topic,
+                payload: r.buf,
+                qos,
+                id,
+                dup: header & 0x08 != 0,
+            })
+        }
+        PUBACK => Ok(Packet::PubAck { id: r.u16()? }),
+        SUBACK => {
+            let id = r.u16()?;
+            r.properties(version)?;
+            Ok(Packet::SubAck { id, code: r.u8()? })
+        }
+        PINGRESP => Ok(Packet::PingResp),
+        DISCONNECT => Ok(Packet::Disconnect),
+        _ => Err(PacketError::Malformed),
+    }
+}
+
+/// Check if `topic` matches a subscription `filter`, which may contain `+` and `#` wildcards.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Wildcards do not match topics reserved by the broker
+    if topic.starts_with('$') && !filter.starts_with('$') {
+        return false;
+    }
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_connect() {
+        let mut buf = [0; 64];
+        let connect = Connect {
+            version: ProtocolVersion::V311,
+            client_id: "dev",
+            keep_alive: 60,
+            username: Some("u"),
+            password: Some(b"p"),
+        };
+        let len = encode_connect(&mut buf, &connect).unwrap();
+        assert_eq!(
+            &[
+                0x10, 21, 0, 4, b'M', b'Q', b'T', b'T', 4, 0xC2, 0, 60, 0, 3, b'd', b'e', b'v', 0,
+                1, b'u', 0, 1, b'p'
+            ][..],
+            &buf[..len]
+        );
+
+        let connect = Connect {
+            version: ProtocolVersion::V5,
+            client_id: "dev",
+            keep_alive: 30,
+            username: None,
+            password: None,
+        };
+        let len = encode_connect(&mut buf, &connect).unwrap();
+        assert_eq!(
+            &[0x10, 16, 0, 4, b'M', b'Q', b'T', b'T', 5, 0x02, 0, 30, 0, 0, 3, b'd', b'e', b'v'][..],
+            &buf[..len]
+        );
+    }
+
+    #[test]
+    fn test_encode_publish() {
+        let mut buf = [0; 256];
+        let len = encode_publish(
+            &mut buf,
+            ProtocolVersion::V311,
+            "a/b",
+            b"hi",
+            QoS::AtMostOnce,
+            0,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            &[0x30, 7, 0, 3, b'a', b'/', b'b', b'h', b'i'][..],
+            &buf[..len]
+        );
+
+        let len = encode_publish(
+            &mut buf,
+            ProtocolVersion::V5,
+            "a",
+            b"x",
+            QoS::AtLeastOnce,
+            7,
+            true,
+        )
+        .unwrap();
+        assert_eq!(&[0x3A, 7, 0, 1, b'a', 0, 7, 0, b'x'][..], &buf[..len]);
+
+        // Two byte remaining length
+        let payload = [0xAA; 200];
+        let len = encode_publish(
+            &mut buf,
+            ProtocolVersion::V311,
+            "t",
+            &payload,
+            QoS::AtMostOnce,
+            0,
+            false,
+        )
+        .unwrap();
+        assert_eq!(206, len);
+        assert_eq!(&[0x30, 0xCB, 0x01, 0, 1, b't', 0xAA][..], &buf[..7]);
+
+        assert_eq!(
+            Err(PacketError::BufferTooSmall),
+            encode_publish(
+                &mut buf[..64],
+                ProtocolVersion::V311,
+                "t",
+                &payload,
+                QoS::AtMostOnce,
+                0,
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn test_encode_small_packets() {
+        let mut buf = [0; 16];
+        let len =
+            encode_subscribe(&mut buf, ProtocolVersion::V311, 1, "c/#", QoS::AtLeastOnce).unwrap();
+        assert_eq!(&[0x82, 8, 0, 1, 0, 3, b'c', b'/', b'#', 1][..], &buf[..len]);
+        let len = encode_subscribe(&mut buf, ProtocolVersion::V5, 2, "c", QoS::AtMostOnce).unwrap();
+        assert_eq!(&[0x82, 7, 0, 2, 0, 0, 1, b'c', 0][..], &buf[..len]);
+
+        let len = encode_puback(&mut buf, 0x1234).unwrap();
+        assert_eq!(&[0x40, 2, 0x12, 0x34][..], &buf[..len]);
+        let len = encode_pingreq(&mut buf).unwrap();
+        assert_eq!(&[0xC0, 0][..], &buf[..len]);
+        let len = encode_disconnect(&mut buf).unwrap();
+        assert_eq!(&[0xE0, 0][..], &buf[..len]);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Ok(Packet::ConnAck {
+                session_present: true,
+                code: 0
+            }),
+            decode(ProtocolVersion::V311, 0x20, &[1, 0])
+        );
+        assert_eq!(
+            Ok(Packet::ConnAck {
+                session_present: false,
+                code: 0x87
+            }),
+            decode(ProtocolVersion::V5, 0x20, &[0, 0x87, 0])
+        );
+        assert_eq!(
+            Ok(Packet::Publish {
+                topic: "a/b",
+                payload: b"hi",
+                qos: QoS::AtLeastOnce,
+                id: Some(5),
+                dup: true,
+            }),
+            decode(
+                ProtocolVersion::V311,
+                0x3A,
+                &[0, 3, b'a', b'/', b'b', 0, 5, b'h', b'i']
+            )
+        );
+        // MQTT 5 properties are skipped
+        assert_eq!(
+            Ok(Packet::Publish {
+                topic: "a",
+                payload: b"hi",
+                qos: QoS::AtMostOnce,
+                id: None,
+                dup: false,
+            }),
+            decode(
+                ProtocolVersion::V5,
+                0x30,
+                &[0, 1, b'a', 2, 0x01, 0x01, b'h', b'i']
+            )
+        );
+        assert_eq!(
+            Ok(Packet::PubAck { id: 7 }),
+            decode(ProtocolVersion::V5, 0x40, &[0, 7, 0x10, 0])
+        );
+        assert_eq!(
+            Ok(Packet::SubAck { id: 1, code: 0x80 }),
+            decode(ProtocolVersion::V311, 0x90, &[0, 1, 0x80])
+        );
+        assert_eq!(
+            Ok(Packet::SubAck { id: 1, code: 1 }),
+            decode(ProtocolVersion::V5, 0x90, &[0, 1, 0, 1])
+        );
+        assert_eq!(
+            Ok(Packet::PingResp),
+            decode(ProtocolVersion::V311, 0xD0, &[])
+        );
+
+        assert_eq!(
+            Err(PacketError::Malformed),
+            decode(ProtocolVersion::V311, 0x30, &[0, 5, b'a'])
+        );
+        assert_eq!(
+            Err(PacketError::Malformed),
+            decode(ProtocolVersion::V311, 0x34, &[0, 1, b'a', 0, 1])
+        );
+        assert_eq!(
+            Err(PacketError::Malformed),
+            decode(ProtocolVersion::V311, 0x20, &[0])
+        );
+    }
+
+    #[test]
+    fn test_remaining_len() {
+        let mut len = 0;
+        assert_eq!(Ok(None), decode_remaining_len(&mut len, 0, 0xCB));
+        assert_eq!(Ok(Some(203)), decode_remaining_len(&mut len, 1, 0x01));
+
+        let mut len = 0;
+        for i in 0..3 {
+            assert_eq!(Ok(None), decode_remaining_len(&mut len, i, 0xFF));
+        }
+        assert_eq!(
+            Ok(Some(MAX_REMAINING_LEN)),
+            decode_remaining_len(&mut len, 3, 0x7F)
+        );
+        assert_eq!(
+            Err(PacketError::Malformed),
+            decode_remaining_len(&mut len, 4, 0x01)
+        );
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/c"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+        assert!(topic_matches("a/+", "a/b"));
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/+", "a/"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("#", "a/b"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+        assert!(topic_matches("command/inbox/#", "command/inbox//reboot"));
+    }
+}
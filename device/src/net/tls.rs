@@ -0,0 +1,211 @@
+use {
+    core::{
+        cell::{Cell, RefCell, UnsafeCell},
+        marker::PhantomData,
+    },
+    embedded_io::{
+        asynch::{Read, Write},
+        Io,
+    },
+    embedded_nal_async::{SocketAddr, TcpConnect},
+    embedded_tls::{
+        Aes128GcmSha256, Certificate, NoVerify, TlsConfig, TlsConnection, TlsContext, TlsError,
+        TlsVerifier,
+    },
+    rand_core::{CryptoRng, RngCore},
+};
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TlsConnectError<E> {
+    Tcp(E),
+    Tls(TlsError),
+    /// The connector only supports one connection at a time.
+    InUse,
+}
+
+impl<E> embedded_io::Error for TlsConnectError<E>
+where
+    E: core::fmt::Debug,
+{
+    fn kind(&self) -> embedded_io::ErrorKind {
+        embedded_io::ErrorKind::Other
+    }
+}
+
+/// A verifier of server certificates that can be used with [`TlsConnector::new`].
+///
+/// Implement it for the verifier of the application. [`NoVerify`] does not implement it, so
+/// a connector that skips verification can only be created with
+/// [`TlsConnector::new_insecure`].
+pub trait ServerVerifier: for<'v> TlsVerifier<'v, Aes128GcmSha256> {}
+
+/// A network stack establishing TLS connections on top of another stack.
+///
+/// One connection can be open at a time, using the `RX` and `TX` sized record buffers of the
+/// connector. Server certificates are checked by the verifier `V`, optionally against a trusted
+/// CA certificate.
+///
+/// # Security
+///
+/// A connector created with [`TlsConnector::new_insecure`] uses [`NoVerify`] and accepts any
+/// server certificate. The connection is encrypted, but anyone able to intercept it can
+/// impersonate the server. Only use it for testing or on trusted networks.
+pub struct TlsConnector<'a, T, RNG, const RX: usize, const TX: usize, V>
+where
+    T: TcpConnect + 'a,
+    RNG: CryptoRng + RngCore,
+    V: for<'v> TlsVerifier<'v, Aes128GcmSha256>,
+{
+    tcp: &'a T,
+    server_name: &'a str,
+    ca: Option<&'a [u8]>,
+    rng: RefCell<RNG>,
+    buffers: UnsafeCell<([u8; RX], [u8; TX])>,
+    in_use: Cell<bool>,
+    _verifier: PhantomData<V>,
+}
+
+impl<'a, T, RNG, const RX: usize, const TX: usize> TlsConnector<'a, T, RNG, RX, TX, NoVerify>
+where
+    T: TcpConnect + 'a,
+    RNG: CryptoRng + RngCore,
+{
+    /// Create a connector for connections to `server_name` that does not verify the server
+    /// certificate. See the security note on [`TlsConnector`].
+    pub fn new_insecure(tcp: &'a T, server_name: &'a str, rng: RNG) -> Self {
+        warn!(
+            "TLS server certificates of {} will not be verified",
+            server_name
+        );
+        Self::create(tcp, server_name, rng)
+    }
+}
+
+impl<'a, T, RNG, const RX: usize, const TX: usize, V> TlsConnector<'a, T, RNG, RX, TX, V>
+where
+    T: TcpConnect + 'a,
+    RNG: CryptoRng + RngCore,
+    V: ServerVerifier,
+{
+    /// Create a connector for connections to `server_name`, verifying server certificates with
+    /// `V`.
+    pub fn new(tcp: &'a T, server_name: &'a str, rng: RNG) -> Self {
+        Self::create(tcp, server_name, rng)
+    }
+}
+
+impl<'a, T, RNG, const RX: usize, const TX: usize, V> TlsConnector<'a, T, RNG, RX, TX, V>
+where
+    T: TcpConnect + 'a,
+    RNG: CryptoRng + RngCore,
+    V: for<'v> TlsVerifier<'v, Aes128GcmSha256>,
+{
+    fn create(tcp: &'a T, server_name: &'a str, rng: RNG) -> Self {
+        Self {
+            tcp,
+            server_name,
+            ca: None,
+            rng: RefCell::new(rng),
+            buffers: UnsafeCell::new(([0; RX], [0; TX])),
+            in_use: Cell::new(false),
+            _verifier: PhantomData,
+        }
+    }
+
+    /// DER encoded X.509 certificate of the CA passed to the verifier as trust anchor.
+    pub fn ca(mut self, ca: &'a [u8]) -> Self {
+        self.ca.replace(ca);
+        self
+    }
+}
+
+impl<'a, T, RNG, const RX: usize, const TX: usize, V> TcpConnect
+    for TlsConnector<'a, T, RNG, RX, TX, V>
+where
+    T: TcpConnect + 'a,
+    RNG: CryptoRng + RngCore,
+    V: for<'v> TlsVerifier<'v, Aes128GcmSha256>,
+{
+    type Error = TlsConnectError<T::Error>;
+    type Connection<'m> = TlsStream<'m, T::Connection<'m>> where Self: 'm;
+
+    async fn connect<'m>(&'m self, remote: SocketAddr) -> Result<Self::Connection<'m>, Self::Error>
+    where
+        Self: 'm,
+    {
+        if self.in_use.replace(true) {
+            return Err(TlsConnectError::InUse);
+        }
+        let guard = InUse(&self.in_use);
+
+        let socket = self
+            .tcp
+            .connect(remote)
+            .await
+            .map_err(TlsConnectError::Tcp)?;
+        // SAFETY: The in use flag, cleared when the stream is dropped, ensures that only one
+        // connection at a time uses the buffers
+        let (rx, tx) = unsafe { &mut *self.buffers.get() };
+        let mut tls = TlsConnection::new(socket, rx, tx);
+
+        let mut config = TlsConfig::new().with_server_name(self.server_name);
+        if let Some(ca) = self.ca {
+            config = config.with_ca(Certificate::X509(ca));
+        }
+        let mut rng = self.rng.borrow_mut();
+        tls.open::<RNG, V>(TlsContext::new(&config, &mut *rng))
+            .await
+            .map_err(TlsConnectError::Tls)?;
+        debug!("TLS connection to {} established", self.server_name);
+
+        Ok(TlsStream { tls, _guard: guard })
+    }
+}
+
+/// Clears the in use flag when dropped.
+struct InUse<'m>(&'m Cell<bool>);
+
+impl Drop for InUse<'_> {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+/// A TLS connection established by a [`TlsConnector`].
+pub struct TlsStream<'m, S>
+where
+    S: Read + Write + 'm,
+{
+    tls: TlsConnection<'m, S, Aes128GcmSha256>,
+    _guard: InUse<'m>,
+}
+
+impl<'m, S> Io for TlsStream<'m, S>
+where
+    S: Read + Write + 'm,
+{
+    type Error = TlsError;
+}
+
+impl<'m, S> Read for TlsStream<'m, S>
+where
+    S: Read + Write + 'm,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.tls.read(buf).await
+    }
+}
+
+impl<'m, S> Write for TlsStream<'m, S>
+where
+    S: Read + Write + 'm,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.tls.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.tls.flush().await
+    }
+}
@@ -96,3 +96,105 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::lora::simulator::{NetworkServer, RxWindow, SimulatedRadio},
+        crate::testing::TestRng,
+        lorawan_device::async_device::{region, JoinMode},
+    };
+
+    const DEV_EUI: [u8; 8] = [0x70, 0xB3, 0xD5, 0x7E, 0xD0, 0x05, 0x00, 0x01];
+    const APP_EUI: [u8; 8] = [0x00; 8];
+    const APP_KEY: [u8; 16] = [
+        0x2B, 0x7E, 0x15, 0x16, 0x28, 0xAE, 0xD2, 0xA6, 0xAB, 0xF7, 0x15, 0x88, 0x09, 0xCF, 0x4F,
+        0x3C,
+    ];
+
+    fn device(server: &NetworkServer) -> Device<SimulatedRadio<'_>, Crypto, LoraTimer, TestRng> {
+        let region: region::Configuration = region::EU868::default().into();
+        Device::new(
+            region,
+            SimulatedRadio::new(server),
+            LoraTimer::new(),
+            TestRng(0x1234_5678),
+        )
+    }
+
+    async fn join(device: &mut Device<SimulatedRadio<'_>, Crypto, LoraTimer, TestRng>) {
+        device
+            .join(&JoinMode::OTAA {
+                deveui: DEV_EUI,
+                appeui: APP_EUI,
+                appkey: APP_KEY,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_join_uplink_downlink() {
+        let server = NetworkServer::new(DEV_EUI, APP_EUI, APP_KEY);
+        let mut device = device(&server);
+        futures::executor::block_on(async {
+            join(&mut device).await;
+            assert!(server.is_joined());
+
+            device.send(b"ping", 1, false).await.unwrap();
+            let uplink = server.uplink().unwrap();
+            assert_eq!(0, uplink.fcnt);
+            assert_eq!(Some(1), uplink.port);
+            assert_eq!(b"ping", &uplink.data[..]);
+
+            server.enqueue(2, b"pong").unwrap();
+            let mut rx = [0; 16];
+            let len = device.send_recv(b"ping", &mut rx, 1, true).await.unwrap();
+            assert_eq!(b"pong", &rx[..len]);
+            let uplink = server.uplink().unwrap();
+            assert_eq!(1, uplink.fcnt);
+            assert!(uplink.confirmed);
+        });
+    }
+
+    #[test]
+    fn test_rx2_downlink() {
+        let server = NetworkServer::new(DEV_EUI, APP_EUI, APP_KEY);
+        server.set_rx_window(RxWindow::Rx2);
+        let mut device = device(&server);
+        futures::executor::block_on(async {
+            join(&mut device).await;
+
+            server.enqueue(2, b"pong").unwrap();
+            let mut rx = [0; 16];
+            let len = device.send_recv(b"ping", &mut rx, 1, false).await.unwrap();
+            assert_eq!(b"pong", &rx[..len]);
+        });
+    }
+
+    #[test]
+    fn test_dfu_command() {
+        let server = NetworkServer::new(DEV_EUI, APP_EUI, APP_KEY);
+        let mut device = device(&server);
+        futures::executor::block_on(async {
+            join(&mut device).await;
+            let mut service = LorawanService::new(device);
+
+            // The initial status is only sent, the command is polled for later
+            let status = Status::first(b"1", Some(MTU as u32), None);
+            let command = service.request(&status).await.unwrap();
+            assert!(matches!(command, Command::Wait { .. }));
+            assert_eq!(Some(1), server.uplink().unwrap().port);
+
+            let write = Command::new_write(b"2", 0, &[1, 2, 3, 4], None);
+            server
+                .enqueue(223, &serde_cbor::ser::to_vec_packed(&write).unwrap())
+                .unwrap();
+            let status = Status::update(b"1", Some(MTU as u32), 0, b"2", None);
+            let command = service.request(&status).await.unwrap();
+            assert!(matches!(command, Command::Write { .. }));
+            assert_eq!(Some(223), server.uplink().unwrap().port);
+        });
+    }
+}
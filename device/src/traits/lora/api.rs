@@ -1,5 +1,7 @@
 use super::types::*;
+use crate::lora::{P2pConfig, PacketStatus};
 use core::future::Future;
+use embassy_time::Duration;
 
 /// API for accessing LoRa modules
 pub trait LoraDriver {
@@ -27,6 +29,65 @@ pub trait LoraDriver {
         data: &'a [u8],
         rx: &'a mut [u8],
     ) -> Self::SendRecvFuture<'a>;
+
+    type ReceiveFuture<'a>: Future<Output = Result<(Port, usize), LoraError>>
+    where
+        Self: 'a;
+    /// Wait for data sent by the network without sending an uplink first, write it into the
+    /// provided buffer and return the port and size of the data read.
+    ///
+    /// Only class B and class C devices receive data outside of the receive windows following an uplink.
+    fn receive<'a>(&'a mut self, rx: &'a mut [u8]) -> Self::ReceiveFuture<'a>;
+
+    type LinkQualityFuture<'a>: Future<Output = Result<Option<LinkQuality>, LoraError>>
+    where
+        Self: 'a;
+    /// Signal quality of the last downlink received, if any.
+    fn link_quality<'a>(&'a mut self) -> Self::LinkQualityFuture<'a>;
+}
+
+/// API for LoRa modules able to send MAC commands on behalf of the application.
+pub trait LoraMacCommands: LoraDriver {
+    type LinkCheckFuture<'a>: Future<Output = Result<LinkCheck, LoraError>>
+    where
+        Self: 'a;
+    /// Request a link check (LinkCheckReq) from the network, returning the demodulation margin and
+    /// the number of gateways that received the request.
+    fn link_check<'a>(&'a mut self) -> Self::LinkCheckFuture<'a>;
+
+    type DeviceTimeFuture<'a>: Future<Output = Result<NetworkTime, LoraError>>
+    where
+        Self: 'a;
+    /// Request the current network time (DeviceTimeReq).
+    fn device_time<'a>(&'a mut self) -> Self::DeviceTimeFuture<'a>;
+}
+
+/// API for raw LoRa communication between devices, without a LoRaWAN network.
+pub trait LoraP2p {
+    type Error;
+
+    type ConfigureFuture<'a>: Future<Output = Result<(), Self::Error>>
+    where
+        Self: 'a;
+    /// Apply the radio settings used for subsequent transmissions and receptions.
+    fn configure_p2p<'a>(&'a mut self, config: &'a P2pConfig) -> Self::ConfigureFuture<'a>;
+
+    type TransmitFuture<'a>: Future<Output = Result<(), Self::Error>>
+    where
+        Self: 'a;
+    /// Transmit a single packet.
+    fn transmit_packet<'a>(&'a mut self, data: &'a [u8]) -> Self::TransmitFuture<'a>;
+
+    type ReceiveFuture<'a>: Future<Output = Result<(usize, Option<PacketStatus>), Self::Error>>
+    where
+        Self: 'a;
+    /// Wait up to `timeout` for a packet, write it into the provided buffer and return its
+    /// size and signal quality, if reported by the radio.
+    fn receive_packet<'a>(
+        &'a mut self,
+        rx: &'a mut [u8],
+        timeout: Duration,
+    ) -> Self::ReceiveFuture<'a>;
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -42,5 +103,7 @@ pub enum LoraError {
     NotInitialized,
     NotImplemented,
     UnsupportedRegion,
+    UnsupportedDeviceClass,
+    UnsupportedModulation,
     OtherError,
 }
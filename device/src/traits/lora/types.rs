@@ -1,3 +1,141 @@
+pub type Port = u8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LoraMode {
+    WAN,
+    P2P,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LoraRegion {
+    EU868,
+    US915,
+    AU915,
+    KR920,
+    AS923,
+    IN865,
+    CN470,
+    UNKNOWN,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SpreadingFactor {
+    SF7,
+    SF8,
+    SF9,
+    SF10,
+    SF11,
+    SF12,
+}
+
+/// LoRaWAN device class, determining when the device listens for downlinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceClass {
+    /// Receive windows only open after an uplink.
+    A,
+    /// Additional receive slots are scheduled using network beacons.
+    B,
+    /// Continuously listening whenever not transmitting.
+    C,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QoS {
+    Unconfirmed,
+    Confirmed,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResetMode {
+    Restart,
+    Reload,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum JoinMode {
+    OTAA {
+        dev_eui: EUI,
+        app_eui: EUI,
+        app_key: AppKey,
+    },
+    ABP {
+        news_key: NwksKey,
+        apps_key: AppsKey,
+        dev_addr: DevAddr,
+    },
+}
+
+/// Answer to a link check request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkCheck {
+    /// Demodulation margin in dB of the request as received by the network.
+    pub margin: u8,
+    /// Number of gateways that received the request.
+    pub gateway_count: u8,
+}
+
+/// Network time as reported in a DeviceTimeAns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NetworkTime {
+    /// Seconds since the GPS epoch.
+    pub seconds: u32,
+    /// Fractional second in 1/256 s steps.
+    pub fraction: u8,
+}
+
+/// Signal quality of a received frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkQuality {
+    pub rssi: i16,
+    pub snr: i8,
+}
+
+/// Configuration of a LoRa driver. Settings that are not set are left at the driver default.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LoraConfig {
+    pub region: Option<LoraRegion>,
+    pub lora_mode: Option<LoraMode>,
+    pub spreading_factor: Option<SpreadingFactor>,
+    pub device_class: Option<DeviceClass>,
+}
+
+impl LoraConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn region(mut self, region: LoraRegion) -> Self {
+        self.region.replace(region);
+        self
+    }
+
+    pub fn lora_mode(mut self, lora_mode: LoraMode) -> Self {
+        self.lora_mode.replace(lora_mode);
+        self
+    }
+
+    pub fn spreading_factor(mut self, spreading_factor: SpreadingFactor) -> Self {
+        self.spreading_factor.replace(spreading_factor);
+        self
+    }
+
+    pub fn device_class(mut self, device_class: DeviceClass) -> Self {
+        self.device_class.replace(device_class);
+        self
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DevAddr(pub [u8; 4]);
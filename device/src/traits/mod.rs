@@ -1,6 +1,2 @@
-pub mod button;
-pub mod i2c;
-pub mod led;
+#[cfg(feature = "lora")]
 pub mod lora;
-pub mod sensors;
-pub mod wifi;
@@ -7,6 +7,7 @@ MEMORY
   DFU                               : ORIGIN = 0x00049000, LENGTH = 188416
   BOOTLOADER                        : ORIGIN = 0x00077000, LENGTH = 24K
   BOOTLOADER_STATE                  : ORIGIN = 0x0007D000, LENGTH = 4K
+  STORAGE                           : ORIGIN = 0x0007E000, LENGTH = 4K
   RAM                               : ORIGIN = 0x2000baa8, LENGTH = 83288
 }
 
@@ -6,27 +6,46 @@
 #![allow(incomplete_features)]
 
 use {
+    core::cell::Cell,
     drogue_device::{
-        drivers::ble::gatt::{
-            device_info::{DeviceInformationService, DeviceInformationServiceEvent},
-            dfu::{FirmwareGattService, FirmwareService, FirmwareServiceEvent},
-            environment::*,
+        drivers::ble::{
+            advertisement::{
+                AdvertisementBuilder, FLAG_BR_EDR_NOT_SUPPORTED, FLAG_LE_GENERAL_DISCOVERABLE,
+            },
+            bonder::Bonder,
+            gatt::{
+                device_info::{DeviceInformationService, DeviceInformationServiceEvent},
+                dfu::{FirmwareGattService, FirmwareService, FirmwareServiceEvent},
+                environment::*,
+                microbit::{
+                    Acceleration, AccelerometerGattService, AccelerometerService,
+                    ButtonGattService, ButtonId, ButtonService, ButtonState, ButtonTracker,
+                    LedCommand, LedService, UartGattService, UartService,
+                    DEFAULT_ACCELEROMETER_PERIOD, DEFAULT_SCROLLING_DELAY, LED_COLS, LED_ROWS,
+                },
+            },
+            security::{BondStore, IoCapabilities, PairingHandler, SecurityLevel},
+            softdevice::SoftdeviceConfig,
+            supervisor::ConnectionSupervisor,
         },
-        firmware::FirmwareManager,
+        firmware::{FirmwareManager, SharedFlash, SharedFlashHandle},
     },
     embassy_executor::Spawner,
-    embassy_futures::select::{select, Either},
+    embassy_futures::select::{select, select3, Either, Either3},
+    embassy_nrf::interrupt::{self, InterruptExt},
     embassy_sync::{
-        blocking_mutex::raw::ThreadModeRawMutex,
+        blocking_mutex::{raw::ThreadModeRawMutex, Mutex},
         channel::{Channel, DynamicReceiver, DynamicSender},
+        pubsub::{DynPublisher, PubSubChannel},
+        signal::Signal,
     },
-    embassy_time::{Duration, Ticker, Timer},
+    embassy_time::{Duration, Instant, Ticker, Timer},
     futures::StreamExt,
     heapless::Vec,
-    microbit_bsp::*,
+    microbit_bsp::{accelerometer::Accelerometer, *},
     nrf_softdevice::{
-        ble::{gatt_server, peripheral, Connection},
-        raw, temperature_celsius, Flash, Softdevice,
+        ble::{gatt_server, Connection},
+        temperature_celsius, Flash, Softdevice,
     },
     static_cell::StaticCell,
 };
@@ -45,6 +64,38 @@ use panic_reset as _;
 const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
 const FIRMWARE_REVISION: Option<&str> = option_env!("REVISION");
 
+/// ATT MTU of connections, determining the size of firmware chunks
+const ATT_MTU: u16 = 128;
+
+/// Maximum number of concurrent connections
+const MAX_CONNECTIONS: usize = 2;
+
+/// Maximum number of bonded peers
+const MAX_BONDS: usize = 4;
+
+/// Start of the STORAGE region of memory.x, which holds the bonds
+const BONDS_OFFSET: u32 = 0x7E000;
+
+/// Time new peers may pair after button A is held
+const PAIRING_WINDOW: Duration = Duration::from_secs(60);
+
+/// Flash of the softdevice, shared by the firmware updater and the bonds
+type FlashHandle = SharedFlashHandle<'static, ThreadModeRawMutex, Flash>;
+
+/// Commands of the LED service to the display
+static LED_COMMANDS: Channel<ThreadModeRawMutex, LedCommand, 2> = Channel::new();
+
+/// Button and accelerometer input published to all connections
+static BOARD_EVENTS: PubSubChannel<ThreadModeRawMutex, BoardEvent, 8, MAX_CONNECTIONS, 3> =
+    PubSubChannel::new();
+
+/// Accelerometer period in ms written by a client
+static ACCELEROMETER_PERIOD: Signal<ThreadModeRawMutex, u16> = Signal::new();
+
+/// End of the pairing window opened by holding button A
+static PAIRING_UNTIL: Mutex<ThreadModeRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
 // Application must run at a lower priority than softdevice
 fn config() -> Config {
     let mut config = microbit_bsp::Config::default();
@@ -58,7 +109,10 @@ async fn main(s: Spawner) {
     let board = Microbit::new(config());
 
     // Spawn the underlying softdevice task
-    let sd = enable_softdevice("Drogue Low Energy");
+    let sd = SoftdeviceConfig::new("Drogue Low Energy")
+        .att_mtu(ATT_MTU)
+        .connections(MAX_CONNECTIONS as u8)
+        .enable();
 
     let version = FIRMWARE_REVISION.unwrap_or(FIRMWARE_VERSION);
     defmt::info!("Running firmware version {}", version);
@@ -72,7 +126,8 @@ async fn main(s: Spawner) {
         .unwrap();
     server
         .env
-        .descriptor_set(
+        .configure(
+            Quantity::Temperature,
             &MeasurementDescriptor {
                 flags: 0,
                 sampling_fn: SamplingFunction::ArithmeticMean,
@@ -80,27 +135,41 @@ async fn main(s: Spawner) {
                 update_interval: Interval::Value(5),
                 application: MeasurementApp::Air,
                 uncertainty: Uncertainty::Unknown,
-            }
-            .to_vec(),
+            },
+            &TriggerSetting::FixedInterval(5),
         )
         .unwrap();
-    server
-        .env
-        .trigger_set(&TriggerSetting::FixedInterval(5).to_vec())
-        .unwrap();
+    server.led.initialize().unwrap();
+    server.accelerometer.initialize().unwrap();
 
     s.spawn(softdevice_task(sd)).unwrap();
 
+    static FLASH: StaticCell<SharedFlash<ThreadModeRawMutex, Flash>> = StaticCell::new();
+    let flash: &'static SharedFlash<ThreadModeRawMutex, Flash> =
+        FLASH.init(SharedFlash::new(Flash::take(sd)));
+
+    // Bond with peers, which must pair with the passkey shown on the display to update the firmware
+    let mut bonds = BondStore::new(BONDS_OFFSET);
+    if bonds.load(&mut flash.handle()).await.is_err() {
+        defmt::warn!("Error loading bonds");
+    }
+    static BONDER: StaticCell<Bonder<PasskeyPairing, MAX_BONDS>> = StaticCell::new();
+    let bonder: &'static Bonder<PasskeyPairing, MAX_BONDS> =
+        BONDER.init(Bonder::new(PasskeyPairing, bonds));
+    s.spawn(bonder_task(bonder, flash.handle())).unwrap();
+
     // Firmware update service event channel and task
-    static EVENTS: Channel<ThreadModeRawMutex, FirmwareServiceEvent, 10> = Channel::new();
+    static EVENTS: Channel<ThreadModeRawMutex, (Connection, FirmwareEvent), 10> = Channel::new();
     // The updater is the 'application' part of the bootloader that knows where bootloader
     // settings and the firmware update partition is located based on memory.x linker script.
-    let dfu: FirmwareManager<Flash, 4, 64> = FirmwareManager::new(
-        Flash::take(sd),
+    let dfu: FirmwareManager<FlashHandle, 4, 64> = FirmwareManager::new(
+        flash.handle(),
         FirmwareUpdater::default(),
         version.as_bytes(),
     );
-    let updater = FirmwareGattService::new(&server.firmware, dfu, version.as_bytes(), 64).unwrap();
+    let updater = FirmwareGattService::new(&server.firmware, dfu, version.as_bytes(), ATT_MTU)
+        .unwrap()
+        .security(SecurityLevel::Authenticated);
     s.spawn(updater_task(updater, EVENTS.receiver().into()))
         .unwrap();
 
@@ -110,21 +179,38 @@ async fn main(s: Spawner) {
 
     // Starts the bluetooth advertisement and GATT server
     s.spawn(advertiser_task(
-        s,
         sd,
         server,
+        bonder,
         EVENTS.sender().into(),
         "Drogue Low Energy",
     ))
     .unwrap();
 
-    // Finally, a blinker application.
-    let mut display = board.display;
-    display.set_brightness(display::Brightness::MAX);
-    loop {
-        let _ = display.display('A'.into(), Duration::from_secs(1)).await;
-        Timer::after(Duration::from_secs(1)).await;
-    }
+    // Finally, the display, buttons and accelerometer exposed by the micro:bit services
+    s.spawn(display_task(board.display, LED_COMMANDS.receiver().into()))
+        .unwrap();
+    s.spawn(button_task(
+        board.btn_a,
+        ButtonId::A,
+        BOARD_EVENTS.dyn_publisher().unwrap(),
+    ))
+    .unwrap();
+    s.spawn(button_task(
+        board.btn_b,
+        ButtonId::B,
+        BOARD_EVENTS.dyn_publisher().unwrap(),
+    ))
+    .unwrap();
+
+    let irq = interrupt::take!(SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0);
+    irq.set_priority(Priority::P2);
+    let accelerometer = Accelerometer::new(board.twispi0, irq, board.p23, board.p22).unwrap();
+    s.spawn(accelerometer_task(
+        accelerometer,
+        BOARD_EVENTS.dyn_publisher().unwrap(),
+    ))
+    .unwrap();
 }
 
 #[nrf_softdevice::gatt_server]
@@ -132,71 +218,160 @@ pub struct GattServer {
     pub firmware: FirmwareService,
     pub env: EnvironmentSensingService,
     pub device_info: DeviceInformationService,
+    pub led: LedService,
+    pub buttons: ButtonService,
+    pub accelerometer: AccelerometerService,
+    pub uart: UartService,
+}
+
+/// Events of the connections for the firmware update task
+pub enum FirmwareEvent {
+    /// The ATT MTU of the connection changed
+    AttMtu(u16),
+    Service(FirmwareServiceEvent),
+}
+
+/// Input of the board published to the connections
+#[derive(Clone)]
+pub enum BoardEvent {
+    Button(ButtonId, ButtonState),
+    Acceleration(Acceleration),
 }
 
 #[embassy_executor::task]
 pub async fn updater_task(
-    mut dfu: FirmwareGattService<'static, FirmwareManager<Flash, 4, 64>>,
-    events: DynamicReceiver<'static, FirmwareServiceEvent>,
+    mut dfu: FirmwareGattService<'static, FirmwareManager<FlashHandle, 4, 64>>,
+    events: DynamicReceiver<'static, (Connection, FirmwareEvent)>,
 ) {
     loop {
-        let event = events.recv().await;
-        if let Err(e) = dfu.handle(&event).await {
-            defmt::warn!("Error applying firmware event: {:?}", e);
+        match events.recv().await {
+            (_, FirmwareEvent::AttMtu(att_mtu)) => dfu.set_att_mtu(att_mtu),
+            (conn, FirmwareEvent::Service(event)) => {
+                if let Err(e) = dfu.handle(&conn, &event).await {
+                    defmt::warn!("Error applying firmware event: {:?}", e);
+                }
+            }
         }
     }
 }
 
-#[embassy_executor::task(pool_size = "4")]
-pub async fn gatt_server_task(
+#[embassy_executor::task]
+pub async fn advertiser_task(
+    sd: &'static Softdevice,
+    server: &'static GattServer,
+    bonder: &'static Bonder<PasskeyPairing, MAX_BONDS>,
+    events: DynamicSender<'static, (Connection, FirmwareEvent)>,
+    name: &'static str,
+) {
+    let adv_data = AdvertisementBuilder::new()
+        .flags(FLAG_LE_GENERAL_DISCOVERABLE | FLAG_BR_EDR_NOT_SUPPORTED)
+        .services_16(&[0x181A])
+        .name(name)
+        .build()
+        .unwrap();
+    let scan_data = AdvertisementBuilder::new()
+        .services_16(&[0x180A])
+        .build()
+        .unwrap();
+
+    let supervisor: ConnectionSupervisor<MAX_CONNECTIONS> =
+        ConnectionSupervisor::new(sd, &adv_data, &scan_data).security(bonder);
+    let e = supervisor
+        .run(|conn| handle_connection(sd, conn, server, events.clone()))
+        .await;
+    defmt::warn!("Advertising stopped with error: {:?}", e);
+}
+
+pub async fn handle_connection(
     sd: &'static Softdevice,
     conn: Connection,
     server: &'static GattServer,
-    events: DynamicSender<'static, FirmwareServiceEvent>,
+    events: DynamicSender<'static, (Connection, FirmwareEvent)>,
 ) {
-    let mut notify = false;
+    let mut env = EnvironmentGattService::new(&server.env);
+    let mut buttons = ButtonGattService::new(&server.buttons);
+    let mut accelerometer = AccelerometerGattService::new(&server.accelerometer);
+    let mut uart = UartGattService::new(&server.uart);
+    let mut board = match BOARD_EVENTS.dyn_subscriber() {
+        Ok(board) => board,
+        Err(_) => {
+            defmt::warn!("Too many connections subscribed to board events");
+            return;
+        }
+    };
+    let mut sensor = ChipTemperature(sd);
     let mut ticker = Ticker::every(Duration::from_secs(5));
-    let env_service = &server.env;
+
+    // Size firmware chunks for the MTU of the connection. The softdevice answers the MTU exchange
+    // of the client without an event, so look for a new MTU with every write of the client.
+    let mut att_mtu = conn.att_mtu();
+    let _ = events.try_send((conn.clone(), FirmwareEvent::AttMtu(att_mtu)));
     loop {
         let mut interval = None;
         let next = ticker.next();
-        match select(
-            gatt_server::run(&conn, server, |e| match e {
-                GattServerEvent::Env(e) => match e {
-                    EnvironmentSensingServiceEvent::TemperatureCccdWrite { notifications } => {
-                        notify = notifications;
+        match select3(
+            gatt_server::run(&conn, server, |e| {
+                if conn.att_mtu() != att_mtu {
+                    att_mtu = conn.att_mtu();
+                    defmt::info!("ATT MTU changed to {}", att_mtu);
+                    let _ = events.try_send((conn.clone(), FirmwareEvent::AttMtu(att_mtu)));
+                }
+                match e {
+                    GattServerEvent::Env(e) => {
+                        if let EnvironmentSensingServiceEvent::PeriodWrite(period) = e {
+                            defmt::info!("Setting interval to {} seconds", period);
+                            interval.replace(Duration::from_secs(period as u64));
+                        }
+                        env.handle(&e);
                     }
-                    EnvironmentSensingServiceEvent::PeriodWrite(period) => {
-                        defmt::info!("Setting interval to {} seconds", period);
-                        interval.replace(Duration::from_secs(period as u64));
+                    GattServerEvent::Firmware(e) => {
+                        let _ = events.try_send((conn.clone(), FirmwareEvent::Service(e)));
                     }
-                },
-                GattServerEvent::Firmware(e) => {
-                    let _ = events.try_send(e);
+                    GattServerEvent::Led(e) => {
+                        if let Some(command) = server.led.handle(&e) {
+                            let _ = LED_COMMANDS.try_send(command);
+                        }
+                    }
+                    GattServerEvent::Buttons(e) => buttons.handle(&e),
+                    GattServerEvent::Accelerometer(e) => {
+                        if let Some(period) = accelerometer.handle(&e) {
+                            defmt::info!("Setting accelerometer period to {} ms", period);
+                            ACCELEROMETER_PERIOD.signal(period);
+                        }
+                    }
+                    GattServerEvent::Uart(e) => {
+                        // Echo data received over UART
+                        if let Some(data) = uart.handle(&e) {
+                            if uart.send(&conn, data).is_err() {
+                                defmt::warn!("Error sending UART data");
+                            }
+                        }
+                    }
+                    _ => {}
                 }
-                _ => {}
             }),
             next,
+            board.next_message_pure(),
         )
         .await
         {
-            Either::First(res) => {
+            Either3::First(res) => {
                 if let Err(e) = res {
                     defmt::warn!("gatt_server run exited with error: {:?}", e);
                     return;
                 }
             }
-            Either::Second(_) => {
-                let value: i8 = temperature_celsius(sd).unwrap().to_num();
-                defmt::info!("Measured temperature: {}℃", value);
-                let value = value as i16 * 10;
-
-                env_service.temperature_set(&value).unwrap();
-                if notify {
-                    defmt::trace!("Notifying");
-                    env_service.temperature_notify(&conn, &value).unwrap();
+            Either3::Second(_) => {
+                if env.update(&conn, &mut sensor).await.is_err() {
+                    defmt::warn!("Error measuring temperature");
                 }
             }
+            Either3::Third(BoardEvent::Button(button, state)) => {
+                buttons.update(&conn, button, state);
+            }
+            Either3::Third(BoardEvent::Acceleration(acceleration)) => {
+                accelerometer.update(&conn, &acceleration);
+            }
         }
 
         if let Some(interval) = interval.take() {
@@ -205,78 +380,122 @@ pub async fn gatt_server_task(
     }
 }
 
+/// Pairing with a passkey scrolled across the display, for the user to enter on the peer. New
+/// peers may only pair within the pairing window, outside of which no passkey is shown.
+pub struct PasskeyPairing;
+
+impl PasskeyPairing {
+    fn is_open(&self) -> bool {
+        PAIRING_UNTIL.lock(|until| until.get().map_or(false, |until| Instant::now() < until))
+    }
+}
+
+impl PairingHandler for PasskeyPairing {
+    fn io_capabilities(&self) -> IoCapabilities {
+        IoCapabilities::DisplayOnly
+    }
+
+    fn can_bond(&self) -> bool {
+        self.is_open()
+    }
+
+    fn display_passkey(&self, passkey: &[u8; 6]) {
+        if !self.is_open() {
+            defmt::warn!("Peer pairing outside of the pairing window");
+            return;
+        }
+        if let Some(command) = LedCommand::text(passkey, DEFAULT_SCROLLING_DELAY) {
+            let _ = LED_COMMANDS.try_send(command);
+        }
+    }
+}
+
 #[embassy_executor::task]
-pub async fn advertiser_task(
-    spawner: Spawner,
-    sd: &'static Softdevice,
-    server: &'static GattServer,
-    events: DynamicSender<'static, FirmwareServiceEvent>,
-    name: &'static str,
-) {
-    let mut adv_data: Vec<u8, 31> = Vec::new();
-    #[rustfmt::skip]
-    adv_data.extend_from_slice(&[
-        0x02, 0x01, raw::BLE_GAP_ADV_FLAGS_LE_ONLY_GENERAL_DISC_MODE as u8,
-        0x03, 0x03, 0x1A, 0x18,
-        (1 + name.len() as u8), 0x09]).unwrap();
+async fn bonder_task(bonder: &'static Bonder<PasskeyPairing, MAX_BONDS>, mut flash: FlashHandle) {
+    if bonder.persist(&mut flash).await.is_err() {
+        defmt::warn!("Error writing bonds");
+    }
+}
 
-    adv_data.extend_from_slice(name.as_bytes()).ok().unwrap();
+/// Temperature sensor of the nRF chip, read through the softdevice
+pub struct ChipTemperature(&'static Softdevice);
 
-    #[rustfmt::skip]
-    let scan_data = &[
-        0x03, 0x03, 0x0A, 0x18,
-    ];
+impl EnvironmentSensor for ChipTemperature {
+    type Error = ();
 
+    async fn measure(&mut self) -> Result<Vec<Measurement, MAX_MEASUREMENTS>, ()> {
+        let value: i8 = temperature_celsius(self.0).map_err(|_| ())?.to_num();
+        defmt::info!("Measured temperature: {}℃", value);
+        let mut measurements = Vec::new();
+        measurements
+            .push(Measurement::Temperature(value as i16 * 10))
+            .ok();
+        Ok(measurements)
+    }
+}
+
+#[embassy_executor::task]
+async fn display_task(mut display: LedMatrix, commands: DynamicReceiver<'static, LedCommand>) {
+    display.set_brightness(display::Brightness::MAX);
+    let mut frame: display::Frame<LED_COLS, LED_ROWS> = 'A'.into();
     loop {
-        let config = peripheral::Config::default();
-        let adv = peripheral::ConnectableAdvertisement::ScannableUndirected {
-            adv_data: &adv_data[..],
-            scan_data,
-        };
-        defmt::debug!("Advertising");
-        let conn = peripheral::advertise_connectable(sd, adv, &config)
-            .await
-            .unwrap();
-
-        defmt::debug!("connection established");
-        if let Err(e) = spawner.spawn(gatt_server_task(sd, conn, server, events.clone())) {
-            defmt::warn!("Error spawning gatt task: {:?}", e);
+        match select(
+            display.display(frame, Duration::from_secs(60)),
+            commands.recv(),
+        )
+        .await
+        {
+            Either::First(_) => {}
+            Either::Second(LedCommand::Matrix(rows)) => {
+                frame = display::Frame::new(rows.map(|row| display::Bitmap::new(row, LED_COLS)));
+            }
+            Either::Second(LedCommand::Text { text, duration }) => {
+                display.scroll_with_speed(&text, duration).await;
+            }
         }
     }
 }
 
-fn enable_softdevice(name: &'static str) -> &'static mut Softdevice {
-    let config = nrf_softdevice::Config {
-        clock: Some(raw::nrf_clock_lf_cfg_t {
-            source: raw::NRF_CLOCK_LF_SRC_RC as u8,
-            rc_ctiv: 4,
-            rc_temp_ctiv: 2,
-            accuracy: 7,
-        }),
-        conn_gap: Some(raw::ble_gap_conn_cfg_t {
-            conn_count: 2,
-            event_length: 24,
-        }),
-        conn_gatt: Some(raw::ble_gatt_conn_cfg_t { att_mtu: 128 }),
-        gatts_attr_tab_size: Some(raw::ble_gatts_cfg_attr_tab_size_t {
-            attr_tab_size: 32768,
-        }),
-        gap_role_count: Some(raw::ble_gap_cfg_role_count_t {
-            adv_set_count: 1,
-            periph_role_count: 3,
-        }),
-        gap_device_name: Some(raw::ble_gap_cfg_device_name_t {
-            p_value: name.as_ptr() as *const u8 as _,
-            current_len: name.len() as u16,
-            max_len: name.len() as u16,
-            write_perm: unsafe { core::mem::zeroed() },
-            _bitfield_1: raw::ble_gap_cfg_device_name_t::new_bitfield_1(
-                raw::BLE_GATTS_VLOC_STACK as u8,
-            ),
-        }),
-        ..Default::default()
-    };
-    Softdevice::enable(&config)
+#[embassy_executor::task(pool_size = 2)]
+async fn button_task(mut button: Button, id: ButtonId, events: DynPublisher<'static, BoardEvent>) {
+    let mut tracker = ButtonTracker::new();
+    loop {
+        match tracker.deadline() {
+            Some(deadline) => {
+                select(button.wait_for_any_edge(), Timer::at(deadline)).await;
+            }
+            None => button.wait_for_any_edge().await,
+        }
+        // Buttons are pulled up, and low while pressed
+        if let Some(state) = tracker.update(button.is_low(), Instant::now()) {
+            if id == ButtonId::A && state == ButtonState::LongPressed {
+                defmt::info!("Pairing window open");
+                PAIRING_UNTIL.lock(|until| until.set(Some(Instant::now() + PAIRING_WINDOW)));
+            }
+            events.publish_immediate(BoardEvent::Button(id, state));
+        }
+    }
+}
+
+#[embassy_executor::task]
+async fn accelerometer_task(
+    mut accelerometer: Accelerometer<'static>,
+    events: DynPublisher<'static, BoardEvent>,
+) {
+    let mut ticker = Ticker::every(Duration::from_millis(DEFAULT_ACCELEROMETER_PERIOD as u64));
+    loop {
+        match select(ticker.next(), ACCELEROMETER_PERIOD.wait()).await {
+            Either::First(_) => match accelerometer.accel_data() {
+                Ok(m) => events.publish_immediate(BoardEvent::Acceleration(
+                    Acceleration::from_milli_g(m.x, m.y, m.z),
+                )),
+                Err(_) => defmt::warn!("Error reading accelerometer"),
+            },
+            Either::Second(period) => {
+                ticker = Ticker::every(Duration::from_millis(period as u64));
+            }
+        }
+    }
 }
 
 #[embassy_executor::task]
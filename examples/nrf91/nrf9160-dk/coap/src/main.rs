@@ -4,13 +4,12 @@
 #![feature(type_alias_impl_trait)]
 #![feature(async_fn_in_trait)]
 #![allow(incomplete_features)]
-#![feature(alloc_error_handler)]
 
 use {
-    alloc_cortex_m::CortexMHeap,
-    coap_lite::{CoapRequest, ContentFormat, RequestType},
-    core::mem::MaybeUninit,
-    drogue_device::*,
+    drogue_device::{
+        net::coap::{CoapClient, ContentFormat, Request},
+        *,
+    },
     embassy_executor::Spawner,
     embassy_futures::select::{select, Either},
     embassy_nrf::{
@@ -21,10 +20,12 @@ use {
         blocking_mutex::raw::ThreadModeRawMutex,
         channel::{Channel, DynamicReceiver, DynamicSender},
     },
-    embassy_time::{Duration, Ticker, Timer},
+    embassy_time::{Duration, Instant, Ticker, Timer},
+    embedded_nal_async::ConnectedUdp,
     futures::StreamExt,
     heapless::Vec,
     nrf_modem::{ConnectionPreference, DtlsSocket, LteLink, PeerVerification, SystemMode},
+    rand::{rngs::SmallRng, SeedableRng},
     static_cell::StaticCell,
 };
 
@@ -61,10 +62,6 @@ const PSK: &str = drogue::config!("password");
 
 #[embassy_executor::main]
 async fn main(_s: Spawner) {
-    unsafe {
-        ALLOCATOR.init(HEAP_DATA.as_ptr() as usize, HEAP_DATA.len());
-    }
-
     let p = embassy_nrf::init(Default::default());
     let version = FIRMWARE_REVISION.unwrap_or(FIRMWARE_VERSION);
     defmt::info!("Running firmware version {}", version);
@@ -106,6 +103,10 @@ async fn main(_s: Spawner) {
     defmt::info!("Waiting until connected to network");
     link.wait_for_link().await.unwrap();
 
+    // The hardware RNG belongs to the secure partition, so CoAP tokens are drawn from a
+    // generator seeded with the time taken to attach to the network
+    let mut rng = SmallRng::seed_from_u64(Instant::now().as_ticks());
+
     let host = HOSTNAME.trim_start();
     let port: u16 = PORT.trim_start().parse::<u16>().unwrap();
 
@@ -120,33 +121,47 @@ async fn main(_s: Spawner) {
                 .unwrap();
 
         defmt::info!("Connected!");
-        defmt::info!("Encoding request");
-        let mut request: CoapRequest<DtlsSocket> = CoapRequest::new();
-        request.set_method(RequestType::Post);
-        request.set_path("/v1/sensor");
-        request
-            .message
-            .set_content_format(ContentFormat::ApplicationJSON);
-        request.message.payload = "{\"temp\":22.3}".into();
+        let mut client: CoapClient<_, _, 256> = CoapClient::new(ModemSocket(socket), &mut rng);
+        let request =
+            Request::post("/v1/sensor", b"{\"temp\":22.3}").content_format(ContentFormat::JSON);
 
         defmt::info!("Sending CoAP request");
-        socket
-            .send(&request.message.to_bytes().unwrap())
-            .await
-            .unwrap();
-        defmt::info!("CoAP request sent!");
+        match client.request(&request).await {
+            Ok(response) => defmt::info!(
+                "CoAP response {}.{:02}",
+                response.code.class(),
+                response.code.detail()
+            ),
+            Err(e) => defmt::warn!("CoAP request failed: {:?}", defmt::Debug2Format(&e)),
+        }
         Timer::after(Duration::from_secs(30)).await;
     }
 }
 
-#[global_allocator]
-static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+/// Exchanges datagrams over a modem DTLS socket.
+struct ModemSocket(DtlsSocket);
 
-static mut HEAP_DATA: [MaybeUninit<u8>; 16384] = [MaybeUninit::uninit(); 16384];
+#[derive(Debug)]
+struct ModemError(nrf_modem::Error);
 
-/// Default alloc error handler for when allocation fails
-#[alloc_error_handler]
-fn alloc_error(_: core::alloc::Layout) -> ! {
-    defmt::info!("Alloc error!");
-    cortex_m::asm::udf()
+impl embedded_io::Error for ModemError {
+    fn kind(&self) -> embedded_io::ErrorKind {
+        embedded_io::ErrorKind::Other
+    }
+}
+
+impl ConnectedUdp for ModemSocket {
+    type Error = ModemError;
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.0.send(data).await.map_err(ModemError)
+    }
+
+    async fn receive_into(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0
+            .receive(buffer)
+            .await
+            .map(|data| data.len())
+            .map_err(ModemError)
+    }
 }
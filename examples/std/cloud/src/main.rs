@@ -5,19 +5,21 @@
 
 use {
     async_io::Async,
-    drogue_device::*,
+    drogue_device::{
+        net::{
+            cloud::{CloudConfig, DrogueCloud, Telemetry},
+            command::{CommandDispatcher, CommandError},
+        },
+        *,
+    },
     embassy_futures::select::{select, Either},
     embassy_time::{Duration, Timer},
     embedded_io::adapters::FromFutures,
     embedded_nal_async::*,
     futures::io::BufReader,
     rand::RngCore,
-    reqwless::{
-        client::{HttpClient, TlsConfig, TlsVerify},
-        headers::ContentType,
-        request::{Method, RequestBuilder},
-    },
-    std::net::TcpStream,
+    reqwless::client::{TlsConfig, TlsVerify},
+    std::{cell::Cell, net::TcpStream},
 };
 
 #[path = "../../../common/dns.rs"]
@@ -47,20 +49,36 @@ async fn main(_spawner: embassy_executor::Spawner) {
         .format_timestamp_nanos()
         .init();
 
-    let url = format!(
-        "https://{}:{}/v1/temperature?data_schema=urn:drogue:iot:temperature",
-        HOSTNAME, PORT
-    );
-
     let mut tls_rx = [0; 16384];
     let mut tls_tx = [0; 1024];
     let mut rng = rand::rngs::OsRng;
-    let mut client = HttpClient::new_with_tls(
+    let mut cloud = DrogueCloud::new_with_tls(
         &TcpClient,
         &DNS,
         TlsConfig::new(rng.next_u64(), &mut tls_rx, &mut tls_tx, TlsVerify::None),
+        CloudConfig {
+            hostname: HOSTNAME,
+            port: PORT.parse().unwrap(),
+            username: USERNAME.trim_end(),
+            password: PASSWORD.trim_end(),
+        },
     );
 
+    // Commands sent from the cloud in response to telemetry
+    let interval = Cell::new(10);
+    let mut set_interval = |payload: &[u8], _: &mut [u8]| -> Result<usize, CommandError> {
+        let seconds = core::str::from_utf8(payload)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or(CommandError::InvalidPayload)?;
+        interval.set(seconds);
+        Ok(0)
+    };
+    let mut dispatcher: CommandDispatcher<'_, 1> = CommandDispatcher::new();
+    dispatcher
+        .register("set-interval", &mut set_interval)
+        .unwrap();
+
     loop {
         let sensor_data = TemperatureData {
             geoloc: None,
@@ -68,41 +86,35 @@ async fn main(_spawner: embassy_executor::Spawner) {
             hum: None,
         };
 
-        match select(Timer::after(Duration::from_secs(20)), async {
-            let tx: heapless::String<1024> = serde_json_core::ser::to_string(&sensor_data).unwrap();
-            let mut rx_buf = [0; 1024];
-
-            let mut req = client
-                .request(Method::POST, &url)
-                .await
-                .unwrap()
-                .basic_auth(USERNAME.trim_end(), PASSWORD.trim_end())
-                .body(tx.as_bytes())
-                .content_type(ContentType::ApplicationJson);
-            let response = req.send(&mut rx_buf[..]).await;
-
-            match response {
-                Ok(response) => {
-                    log::info!("Response status: {:?}", response.status);
-                    if let Ok(body) = response.body() {
-                        if let Ok(payload) = body.read_to_end().await {
-                            let _s = core::str::from_utf8(payload).unwrap();
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::warn!("Error doing HTTP request: {:?}", e);
-                }
-            }
-        })
+        let telemetry = Telemetry::new("temperature", &sensor_data)
+            .data_schema("urn:drogue:iot:temperature")
+            .wait(5);
+        let mut rx_buf = [0; 1024];
+        match select(
+            Timer::after(Duration::from_secs(20)),
+            cloud.publish(&telemetry, &mut rx_buf),
+        )
         .await
         {
             Either::First(_) => {
                 log::info!("Request timeout");
             }
-            Either::Second(_) => {}
+            Either::Second(Ok(Some(command))) => {
+                log::info!("Received command: {}", command.name);
+                let mut rx_buf = [0; 1024];
+                if let Err(e) = cloud
+                    .dispatch(&command, &mut dispatcher, "response", &mut rx_buf)
+                    .await
+                {
+                    log::warn!("Error handling command: {:?}", e);
+                }
+            }
+            Either::Second(Ok(None)) => {}
+            Either::Second(Err(e)) => {
+                log::warn!("Error publishing telemetry: {:?}", e);
+            }
         }
-        Timer::after(Duration::from_secs(10)).await;
+        Timer::after(Duration::from_secs(interval.get())).await;
     }
 }
 
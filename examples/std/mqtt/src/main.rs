@@ -0,0 +1,159 @@
+#![macro_use]
+#![feature(type_alias_impl_trait)]
+#![feature(async_fn_in_trait)]
+#![allow(incomplete_features)]
+
+use {
+    async_io::Async,
+    drogue_device::{
+        net::{
+            command::{CommandDispatcher, CommandError},
+            mqtt::{
+                drogue::{CommandRouter, COMMAND_FILTER},
+                MqttClient, MqttConfig, QoS,
+            },
+            tls::TlsConnector,
+            Backoff, ConnectionManager,
+        },
+        *,
+    },
+    embassy_time::{Duration, Instant},
+    embedded_io::adapters::FromFutures,
+    embedded_nal_async::*,
+    futures::io::BufReader,
+    std::{
+        cell::Cell,
+        net::{TcpStream, ToSocketAddrs},
+    },
+};
+
+#[path = "../../../common/temperature.rs"]
+mod temperature;
+use temperature::*;
+
+/// MQTT endpoint hostname
+const HOSTNAME: &str = drogue::config!("hostname");
+
+/// MQTT endpoint port
+const PORT: &str = drogue::config!("port");
+
+/// MQTT username
+const USERNAME: &str = drogue::config!("username");
+
+/// MQTT password
+const PASSWORD: &str = drogue::config!("password");
+
+#[embassy_executor::main]
+async fn main(_spawner: embassy_executor::Spawner) {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Trace)
+        .format_timestamp_nanos()
+        .init();
+
+    let port = PORT.parse().unwrap();
+    let remote = resolve(HOSTNAME, port);
+
+    // Commands sent from the cloud
+    let interval = Cell::new(10);
+    let mut set_interval = |payload: &[u8], _: &mut [u8]| -> Result<usize, CommandError> {
+        let seconds = core::str::from_utf8(payload)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .ok_or(CommandError::InvalidPayload)?;
+        interval.set(seconds);
+        Ok(0)
+    };
+    let mut dispatcher: CommandDispatcher<'_, 1> = CommandDispatcher::new();
+    dispatcher
+        .register("set-interval", &mut set_interval)
+        .unwrap();
+    let mut router = CommandRouter::new(&mut dispatcher);
+
+    let tls: TlsConnector<'_, _, _, 16384, 1024, _> =
+        TlsConnector::new_insecure(&TcpClient, HOSTNAME, rand::rngs::OsRng);
+    // The manager reconnects on the next operation after errors, backing off while the
+    // endpoint is unreachable
+    let connection = ConnectionManager::new(&tls, remote).backoff(Backoff::new(
+        Duration::from_secs(1),
+        Duration::from_secs(30),
+    ));
+
+    let mut tx = [0; 1024];
+    let mut rx = [0; 1024];
+    let mut client: MqttClient<'_, _, 1> = MqttClient::new(
+        connection,
+        MqttConfig::new(USERNAME.trim_end())
+            .credentials(USERNAME.trim_end(), PASSWORD.trim_end())
+            .keep_alive(Duration::from_secs(10)),
+        &mut tx,
+        &mut rx,
+    );
+    // Sent when connecting, and again after every reconnect
+    client
+        .subscribe(COMMAND_FILTER, QoS::AtMostOnce, &mut router)
+        .await
+        .unwrap();
+
+    let mut next_report = Instant::now();
+    loop {
+        // Polling returns after every message, or at the latest after a ping at half the
+        // keep alive, and must not be cancelled halfway through a packet
+        if let Err(e) = client.poll().await {
+            log::warn!("Error receiving messages: {:?}", e);
+            continue;
+        }
+        if Instant::now() < next_report {
+            continue;
+        }
+
+        let sensor_data = TemperatureData {
+            geoloc: None,
+            temp: Some(22.2),
+            hum: None,
+        };
+        let payload: serde_json_core::heapless::Vec<u8, 128> =
+            serde_json_core::to_vec(&sensor_data).unwrap();
+        match client
+            .publish("temperature", &payload, QoS::AtLeastOnce)
+            .await
+        {
+            Ok(()) => log::info!("Telemetry published"),
+            Err(e) => log::warn!("Error publishing telemetry: {:?}", e),
+        }
+        next_report = Instant::now() + Duration::from_secs(interval.get());
+    }
+}
+
+/// Resolve the IPv4 address of `host` using the operating system resolver.
+fn resolve(host: &str, port: u16) -> SocketAddr {
+    let addr = (host, port)
+        .to_socket_addrs()
+        .unwrap()
+        .find_map(|addr| match addr {
+            std::net::SocketAddr::V4(addr) => Some(addr),
+            _ => None,
+        })
+        .unwrap();
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::from(addr.ip().octets())), port)
+}
+
+pub struct TcpClient;
+
+impl TcpConnect for TcpClient {
+    type Error = std::io::Error;
+    type Connection<'m> = FromFutures<BufReader<Async<TcpStream>>>;
+    async fn connect<'m>(&'m self, remote: SocketAddr) -> Result<Self::Connection<'m>, Self::Error>
+    where
+        Self: 'm,
+    {
+        match TcpStream::connect(format!("{}:{}", remote.ip(), remote.port())) {
+            Ok(stream) => {
+                let stream = Async::new(stream).unwrap();
+                let stream = futures::io::BufReader::new(stream);
+                let stream = FromFutures::new(stream);
+                Ok(stream)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -11,10 +11,12 @@ use {
     embassy_boot_stm32::FirmwareUpdater,
     embassy_embedded_hal::adapter::BlockingAsync,
     embassy_executor::Spawner,
+    embassy_lora::LoraTimer,
     embassy_stm32::flash::Flash,
-    embassy_time::{Delay, Duration, Timer},
+    embassy_time::{Delay, Duration, Instant, Timer},
     embedded_storage::nor_flash::{NorFlash, ReadNorFlash},
     nucleo_wl55jc::*,
+    static_cell::StaticCell,
 };
 
 #[cfg(feature = "panic-probe")]
@@ -38,7 +40,12 @@ async fn main(_spawner: Spawner) {
     // NOTE: This is specific for TTN, as they have a special RX1 delay
     region.set_receive_delay1(5000);
 
-    let mut device = NucleoWl55::lorawan(region, board.radio, board.rng);
+    // Enforce EU868 duty cycles and the fair use policy of TTN on every uplink
+    static LIMITER: StaticCell<AirtimeLimiter> = StaticCell::new();
+    let limiter = &*LIMITER.init(AirtimeLimiter::eu868_ttn());
+    let radio = DutyCycleRadio::new(board.radio, limiter, DutyCyclePolicy::Reject);
+
+    let mut device = Device::new(region, radio, LoraTimer::new(), board.rng);
 
     // Depending on network, this might be part of JOIN
     device.set_datarate(region::DR::_0); // SF12
@@ -65,8 +72,8 @@ async fn main(_spawner: Spawner) {
         version.as_bytes(),
     );
 
-    /// Matches fair usage policy of TTN
-    const INTERVAL_MS: u32 = 1_124_000;
+    /// Uplinks exceeding the airtime budget are rejected, so retrying often is cheap
+    const INTERVAL_MS: u32 = 60_000;
 
     let mut updater = embedded_update::FirmwareUpdater::new(
         service,
@@ -91,6 +98,9 @@ async fn main(_spawner: Spawner) {
                 defmt::warn!("Error running updater: {:?}", e);
             }
         }
+        if let Some(remaining) = limiter.remaining(Instant::now()) {
+            defmt::info!("Remaining airtime today: {} ms", remaining.as_millis());
+        }
         board.green_led.set_low();
         Timer::after(Duration::from_millis(INTERVAL_MS as u64)).await;
     }